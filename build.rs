@@ -0,0 +1,17 @@
+use std::process::Command;
+
+// Embeds the git commit being built, when there is one, for the version
+// shown in logs and sent to the signaling server
+fn main() {
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .and_then(|o| String::from_utf8(o.stdout).ok());
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=BRIDGE_GIT_COMMIT={}", commit.trim());
+    }
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
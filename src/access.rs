@@ -0,0 +1,103 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub type SharedAccess = Arc<AccessGate>;
+
+// Wrong codes a Discord user may send per call before theirs are ignored,
+// so the six digits can't be guessed one message after another
+const MAX_FAILURES: u32 = 5;
+
+// Optional per-call gate for big Discord servers: a fresh code is posted
+// into the Talk room when a session starts, and only Discord users who DM
+// it to the bot are forwarded to Talk. Talk participants decide who gets it.
+pub struct AccessGate {
+    enabled: bool,
+    code: Mutex<Option<String>>,
+    allowed: Mutex<HashSet<u64>>,
+    failures: Mutex<HashMap<u64, u32>>,
+}
+
+impl AccessGate {
+    pub fn new(enabled: bool) -> SharedAccess {
+        Arc::new(Self {
+            enabled,
+            code: Mutex::new(None),
+            allowed: Mutex::new(HashSet::new()),
+            failures: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // A new call: everyone has to ask again. Returns the code to post.
+    pub fn start(&self) -> Option<String> {
+        if !self.enabled {
+            return None;
+        }
+        let code = format!("{:06}", random() % 1_000_000);
+        *self.code.lock().unwrap() = Some(code.clone());
+        self.allowed.lock().unwrap().clear();
+        self.failures.lock().unwrap().clear();
+        Some(code)
+    }
+
+    // Whether audio from this speaker may reach Talk. Speakers whose SSRC
+    // isn't mapped to a user yet are held back too.
+    pub fn allows(&self, user_id: Option<u64>) -> bool {
+        !self.enabled || user_id.is_some_and(|u| self.allowed.lock().unwrap().contains(&u))
+    }
+
+    // A code DMed by a Discord user; returns whether it let them in. After
+    // MAX_FAILURES wrong ones, not even the right one does until the next call.
+    pub fn redeem(&self, user_id: u64, code: &str) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        let mut failures = self.failures.lock().unwrap();
+        let failed = failures.entry(user_id).or_default();
+        if *failed >= MAX_FAILURES {
+            return false;
+        }
+        if self.code.lock().unwrap().as_deref() != Some(code.trim()) {
+            *failed += 1;
+            if *failed == MAX_FAILURES {
+                log!("Discord user {} sent {} wrong access codes, ignoring them until the next call", user_id, MAX_FAILURES);
+            }
+            return false;
+        }
+        self.allowed.lock().unwrap().insert(user_id);
+        true
+    }
+}
+
+// std's hasher keys are seeded from the OS, which is unpredictable enough
+// for a code that only lives as long as a call
+fn random() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos());
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrong_codes_lock_a_user_out_until_the_next_call() {
+        let gate = AccessGate::new(true);
+        let code = gate.start().unwrap();
+        let wrong = if code == "000000" { "000001" } else { "000000" };
+        for _ in 0..MAX_FAILURES {
+            assert!(!gate.redeem(1, wrong));
+        }
+        assert!(!gate.redeem(1, &code));
+        assert!(!gate.allows(Some(1)));
+        // Others aren't held back by it
+        assert!(gate.redeem(2, &code));
+
+        let code = gate.start().unwrap();
+        assert!(gate.redeem(1, &code));
+        assert!(gate.allows(Some(1)));
+    }
+}
@@ -0,0 +1,401 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use serenity::model::id::ChannelId;
+
+use crate::commands::parse_gain_db;
+use crate::config::Direction;
+use crate::logging;
+use crate::manager::BridgeManager;
+use crate::settings::Scope;
+
+// Local admin API: JSON-RPC 2.0 over a unix socket, one message per line.
+// Only reachable by users who can open the socket file, so nothing has to
+// listen on a TCP port on shared hosts.
+const JSONRPC_VERSION: &str = "2.0";
+
+// Standard JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+// Implementation-defined range starts here
+const SERVER_ERROR: i64 = -32000;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Request {
+    pub jsonrpc: String,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+    // Requests without an id are notifications and get no response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
+}
+
+impl Request {
+    fn new(method: &str, params: Value, id: u64) -> Self {
+        Self {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            method: method.to_string(),
+            params,
+            id: Some(json!(id)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Response {
+    pub jsonrpc: String,
+    pub id: Value,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Result(Value),
+    Error(RpcError),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::new(SERVER_ERROR, format!("{:#}", e))
+    }
+}
+
+impl From<serde_json::Error> for RpcError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::new(SERVER_ERROR, e.to_string())
+    }
+}
+
+pub async fn serve(path: String, manager: Arc<BridgeManager>) -> Result<()> {
+    // A socket left behind by a previous run would make bind fail
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).with_context(|| format!("Failed to bind admin socket {}", path))?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("Failed to restrict admin socket {}", path))?;
+    log!("Admin socket listening on {}", path);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let manager = manager.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, manager).await {
+                log!("Admin connection error: {:?}", e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(stream: UnixStream, manager: Arc<BridgeManager>) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let Some(response) = handle_message(&manager, &line).await else {
+            continue;
+        };
+
+        let mut text = serde_json::to_string(&response)?;
+        text.push('\n');
+        write.write_all(text.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+async fn handle_message(manager: &BridgeManager, line: &str) -> Option<Response> {
+    let error = |id: Value, code, message: String| Response {
+        jsonrpc: JSONRPC_VERSION.to_string(),
+        id,
+        outcome: Outcome::Error(RpcError::new(code, message)),
+    };
+
+    let message = match serde_json::from_str::<Value>(line) {
+        Ok(message) => message,
+        Err(e) => return Some(error(Value::Null, PARSE_ERROR, format!("Parse error: {}", e))),
+    };
+    let id = message.get("id").cloned().unwrap_or(Value::Null);
+    let request = match serde_json::from_value::<Request>(message) {
+        Ok(request) if request.jsonrpc == JSONRPC_VERSION => request,
+        Ok(_) => return Some(error(id, INVALID_REQUEST, "Only JSON-RPC 2.0 is supported".to_string())),
+        Err(e) => return Some(error(id, INVALID_REQUEST, format!("Invalid request: {}", e))),
+    };
+
+    let id = request.id.clone();
+    let outcome = match dispatch(manager, request).await {
+        Ok(v) => Outcome::Result(v),
+        Err(e) => Outcome::Error(e),
+    };
+    id.map(|id| Response {
+        jsonrpc: JSONRPC_VERSION.to_string(),
+        id,
+        outcome,
+    })
+}
+
+fn str_param<'a>(params: &'a Value, name: &str) -> Result<&'a str, RpcError> {
+    params
+        .get(name)
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("Missing {}", name)))
+}
+
+async fn dispatch(manager: &BridgeManager, request: Request) -> Result<Value, RpcError> {
+    let params = &request.params;
+    let bridge = params.get("bridge").and_then(|v| v.as_str());
+
+    match request.method.as_str() {
+        "list" => Ok(serde_json::to_value(manager.list())?),
+        "status" => Ok(serde_json::to_value(manager.status(bridge)?)?),
+        "start" => {
+            manager.start(bridge).await?;
+            Ok(json!("started"))
+        }
+        "stop" => {
+            manager.stop(bridge).await?;
+            Ok(json!("stopped"))
+        }
+        "effects" => {
+            let direction = str_param(params, "direction")?;
+            let direction: Direction = direction.parse().map_err(|e: String| RpcError::new(INVALID_PARAMS, e))?;
+            let chain = str_param(params, "chain")?;
+            let specs = manager.set_effects(bridge, direction, chain)?;
+            let names: Vec<String> = specs.iter().map(|s| s.to_string()).collect();
+            Ok(json!({ "effects": names }))
+        }
+        "ring" => {
+            let rung = manager.ring(bridge).await?;
+            Ok(json!({ "rung": rung }))
+        }
+        "unmute" => {
+            manager.unmute_talk(bridge)?;
+            Ok(json!("unmuted"))
+        }
+        "record" => {
+            let enabled = params
+                .get("enabled")
+                .and_then(|v| v.as_bool())
+                .ok_or_else(|| RpcError::new(INVALID_PARAMS, "Missing enabled"))?;
+            let path = manager.set_recording(bridge, enabled)?;
+            Ok(json!({ "recording": enabled, "file": path.map(|p| p.display().to_string()) }))
+        }
+        "clip" => {
+            let seconds = params.get("seconds").and_then(|v| v.as_u64()).unwrap_or(30);
+            let (path, length) = manager.clip(bridge, seconds).await?;
+            Ok(json!({ "file": path.display().to_string(), "seconds": length.as_secs() }))
+        }
+        "volume" => {
+            let participant = str_param(params, "participant")?;
+            let gain = str_param(params, "gain")?;
+            let db = parse_gain_db(gain)
+                .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("Invalid gain {:?}", gain)))?;
+            let (participant, db) = manager.set_volume(bridge, participant, db)?;
+            Ok(json!({ "participant": participant, "gain_db": db }))
+        }
+        "rebind" => {
+            let channel = params
+                .get("channel_id")
+                .and_then(|v| v.as_u64())
+                .filter(|id| *id != 0)
+                .ok_or_else(|| RpcError::new(INVALID_PARAMS, "Missing channel_id"))?;
+            manager.rebind(bridge, ChannelId::new(channel)).await?;
+            Ok(json!({ "channel_id": channel }))
+        }
+        "room" => {
+            let room = str_param(params, "room")?;
+            let name = manager.switch_room(bridge, room).await?;
+            Ok(json!({ "room": name }))
+        }
+        "exclude" => {
+            let user = params
+                .get("user_id")
+                .and_then(|v| v.as_u64())
+                .filter(|id| *id != 0)
+                .ok_or_else(|| RpcError::new(INVALID_PARAMS, "Missing user_id"))?;
+            let excluded = params.get("excluded").and_then(|v| v.as_bool()).unwrap_or(true);
+            let changed = manager.set_discord_user_excluded(user, excluded)?;
+            Ok(json!({ "user_id": user, "excluded": excluded, "changed": changed }))
+        }
+        "excluded" => Ok(json!(manager.excluded_discord_users())),
+        "settings" => Ok(serde_json::to_value(manager.settings(bridge)?)?),
+        "set" => {
+            let scope: Scope = str_param(params, "scope")?
+                .parse()
+                .map_err(|e: String| RpcError::new(INVALID_PARAMS, e))?;
+            let key = str_param(params, "key")?;
+            let value = params.get("value").and_then(|v| v.as_str());
+            manager.set_setting(&scope, key, value)?;
+            Ok(json!({ "key": key, "value": value }))
+        }
+        "logs" => {
+            let count = params.get("count").and_then(|v| v.as_u64()).unwrap_or(50) as usize;
+            Ok(json!(logging::tail(count)))
+        }
+        other => Err(RpcError::new(METHOD_NOT_FOUND, format!("Unknown method {:?}", other))),
+    }
+}
+
+const SHELL_HELP: &str = "\
+Commands:
+  list                                   list configured bridges
+  status [bridge]                        show bridge state
+  start [bridge]                         start a bridge
+  stop [bridge]                          stop a bridge
+  effects <d2n|n2d> <chain> [bridge]     swap an effect chain, e.g. effects n2d gain:-3,gate
+  ring [bridge]                          ring Talk room members not in the call
+  unmute [bridge]                        lift a Talk moderator's mute (TALK_ALLOW_UNMUTE)
+  record <on|off> [bridge]               start or stop recording the call
+  clip [seconds] [bridge]                post the last seconds of the call to Discord
+                                         (default 30)
+  volume <participant> <gain> [bridge]   set Talk participant volume, e.g. volume @alice -6dB
+  rebind <channel id> [bridge]           bridge another Discord channel, or resume a
+                                         paused bridge
+  room <token or link> [bridge]          bridge another Talk room, moving a running
+                                         call over
+  exclude <user id>                      stop forwarding a Discord user's audio to Talk
+  include <user id>                      forward an excluded Discord user again
+  excluded                               list excluded Discord users
+  settings [bridge]                      show the settings in effect for a bridge
+  set <scope> <key> [value]              change a setting (scope: global, guild:<id> or
+                                         bridge:<name>; no value unsets it)
+  logs [count]                           show recent log lines
+  help                                   show this help
+  quit                                   leave the shell";
+
+// `bridge shell`: interactive REPL against a running instance's admin socket
+pub async fn run_shell(path: &str) -> Result<()> {
+    if !Path::new(path).exists() {
+        anyhow::bail!("No admin socket at {} (is the bridge running?)", path);
+    }
+    let stream = UnixStream::connect(path)
+        .await
+        .with_context(|| format!("Failed to connect to admin socket {}", path))?;
+    let (read, mut write) = stream.into_split();
+    let mut responses = BufReader::new(read).lines();
+    let mut input = BufReader::new(tokio::io::stdin()).lines();
+
+    println!("Connected to {}. Type 'help' for commands.", path);
+    let mut next_id = 0u64;
+    loop {
+        print!("bridge> ");
+        std::io::Write::flush(&mut std::io::stdout())?;
+
+        let Some(line) = input.next_line().await? else {
+            break;
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        next_id += 1;
+        let request = match words.as_slice() {
+            [] => continue,
+            ["quit"] | ["exit"] => break,
+            ["help"] => {
+                println!("{}", SHELL_HELP);
+                continue;
+            }
+            ["list"] => Request::new("list", json!({}), next_id),
+            [cmd @ ("status" | "start" | "stop" | "ring" | "unmute" | "settings"), rest @ ..] if rest.len() <= 1 => {
+                Request::new(cmd, json!({ "bridge": rest.first() }), next_id)
+            }
+            ["effects", direction, chain, rest @ ..] if rest.len() <= 1 => Request::new(
+                "effects",
+                json!({ "direction": direction, "chain": chain, "bridge": rest.first() }),
+                next_id,
+            ),
+            ["record", state @ ("on" | "off"), rest @ ..] if rest.len() <= 1 => Request::new(
+                "record",
+                json!({ "enabled": *state == "on", "bridge": rest.first() }),
+                next_id,
+            ),
+            ["clip", rest @ ..] if rest.len() <= 2 => {
+                let (seconds, bridge) = match rest.first().map(|s| s.parse::<u64>()) {
+                    Some(Ok(seconds)) => (Some(seconds), rest.get(1)),
+                    _ if rest.len() == 2 => {
+                        println!("Invalid seconds {:?}", rest[0]);
+                        continue;
+                    }
+                    _ => (None, rest.first()),
+                };
+                Request::new("clip", json!({ "seconds": seconds, "bridge": bridge }), next_id)
+            }
+            ["volume", participant, gain, rest @ ..] if rest.len() <= 1 => Request::new(
+                "volume",
+                json!({ "participant": participant, "gain": gain, "bridge": rest.first() }),
+                next_id,
+            ),
+            ["rebind", channel, rest @ ..] if rest.len() <= 1 => match channel.parse::<u64>() {
+                Ok(channel) => Request::new("rebind", json!({ "channel_id": channel, "bridge": rest.first() }), next_id),
+                Err(_) => {
+                    println!("Invalid channel id {:?}", channel);
+                    continue;
+                }
+            },
+            ["room", room, rest @ ..] if rest.len() <= 1 => {
+                Request::new("room", json!({ "room": room, "bridge": rest.first() }), next_id)
+            }
+            [cmd @ ("exclude" | "include"), user] => match user.parse::<u64>() {
+                Ok(user) => Request::new("exclude", json!({ "user_id": user, "excluded": *cmd == "exclude" }), next_id),
+                Err(_) => {
+                    println!("Invalid user id {:?}", user);
+                    continue;
+                }
+            },
+            ["excluded"] => Request::new("excluded", json!({}), next_id),
+            ["set", scope, key, rest @ ..] if rest.len() <= 1 => Request::new(
+                "set",
+                json!({ "scope": scope, "key": key, "value": rest.first() }),
+                next_id,
+            ),
+            ["logs"] => Request::new("logs", json!({}), next_id),
+            ["logs", count] => match count.parse::<u64>() {
+                Ok(count) => Request::new("logs", json!({ "count": count }), next_id),
+                Err(_) => {
+                    println!("Invalid count {:?}", count);
+                    continue;
+                }
+            },
+            _ => {
+                println!("Unknown command. Type 'help' for commands.");
+                continue;
+            }
+        };
+
+        let mut text = serde_json::to_string(&request)?;
+        text.push('\n');
+        write.write_all(text.as_bytes()).await?;
+
+        let Some(reply) = responses.next_line().await? else {
+            println!("Bridge closed the connection");
+            break;
+        };
+        match serde_json::from_str::<Response>(&reply)?.outcome {
+            Outcome::Result(Value::Array(lines)) if request.method == "logs" => {
+                for line in lines {
+                    println!("{}", line.as_str().unwrap_or_default());
+                }
+            }
+            Outcome::Result(value) => println!("{}", serde_json::to_string_pretty(&value)?),
+            Outcome::Error(e) => println!("Error: {} ({})", e.message, e.code),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,108 @@
+use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+use webrtc::sdp::SessionDescription;
+
+use super::{red, SAMPLE_RATE};
+use crate::config::{AudioConfig, ChannelLayout, PipelineMode};
+
+// Cross-checks of what was negotiated against what the pipeline assumes.
+// Everything between the codecs runs at 48kHz, so a stream that isn't Opus
+// at that rate plays too fast or too slow (or as noise) without any error.
+
+// Once per session, after the pipeline is resolved
+pub fn pipeline(audio: &AudioConfig, mixer_channels: usize) {
+    let d2n = audio.discord_to_nextcloud.channels;
+    let n2d = audio.nextcloud_to_discord.channels;
+    log!(
+        "Audio: Discord 48kHz stereo -> Talk {} Opus in {}ms packets ({}); Talk -> Discord decoded as {} at {}Hz",
+        layout(d2n),
+        audio.talk_frame_ms,
+        if audio.pipeline == PipelineMode::Passthrough { "passthrough" } else { "transcoded" },
+        layout(n2d),
+        SAMPLE_RATE
+    );
+    if mixer_channels != n2d.count() {
+        log!(
+            "Audio mismatch: the Discord-bound mixer has {} channels, Talk audio is decoded to {}",
+            mixer_channels,
+            n2d.count()
+        );
+    }
+    if audio.pipeline == PipelineMode::Passthrough && d2n == ChannelLayout::Mono {
+        log!("Audio mismatch: Talk is told to expect mono, passthrough sends Discord's stereo Opus as is");
+    }
+}
+
+// A remote offer or answer, against the track we publish (`stereo`, and
+// whether it is RED)
+pub fn sdp(sdp: &SessionDescription, stereo: bool, red: bool) {
+    for media in sdp.media_descriptions.iter().filter(|m| m.media_name.media == "audio") {
+        let attribute = |key: &str, prefix: &str| {
+            media
+                .attributes
+                .iter()
+                .filter(|a| a.key == key)
+                .filter_map(|a| a.value.as_deref())
+                .find_map(|v| v.strip_prefix(prefix).map(str::trim).map(str::to_string))
+        };
+        let Some(pt) = media.media_name.formats.iter().find(|pt| {
+            attribute("rtpmap", &format!("{} ", pt)).is_some_and(|codec| codec.to_lowercase().starts_with("opus/"))
+        }) else {
+            log!("Audio mismatch: Talk's SDP offers no Opus, nothing can be decoded");
+            continue;
+        };
+        let rtpmap = attribute("rtpmap", &format!("{} ", pt)).unwrap_or_default();
+        if rtpmap.to_lowercase() != "opus/48000/2" {
+            log!("Audio mismatch: Talk negotiated {}, Opus in RTP is always opus/48000/2", rtpmap);
+        }
+        let fmtp = attribute("fmtp", &format!("{} ", pt)).unwrap_or_default();
+        let param = |name: &str| {
+            fmtp.split(';')
+                .filter_map(|p| p.trim().split_once('='))
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.trim().to_string())
+        };
+        // What the remote side wants to receive
+        if stereo && param("stereo").as_deref() != Some("1") {
+            log!("Audio mismatch: publishing stereo, but Talk asks for mono and will downmix");
+        }
+        let has_red = media.media_name.formats.iter().any(|pt| {
+            attribute("rtpmap", &format!("{} ", pt)).is_some_and(|codec| codec.to_lowercase().starts_with("red/"))
+        });
+        if red && !has_red {
+            log!("Audio mismatch: TALK_AUDIO_RED is set, but Talk's SDP has no RED; Talk won't get the bridge's audio");
+        }
+    }
+}
+
+// A remote Talk track about to be decoded to `layout`. Returns the RTP
+// clock rate to time it by, or None if it can't be decoded at all.
+pub fn track(ssrc: u32, codec: &RTCRtpCodecCapability, layout: ChannelLayout) -> Option<u32> {
+    // RED carries Opus as well
+    let opus = ["audio/opus", red::MIME_TYPE].iter().any(|mime| codec.mime_type.eq_ignore_ascii_case(mime));
+    if !opus {
+        log!("Audio mismatch: Talk track {} is {}, not Opus; ignoring it", ssrc, codec.mime_type);
+        return None;
+    }
+    if codec.clock_rate != SAMPLE_RATE {
+        // Opus decodes to 48kHz whatever the SDP says; only timestamps
+        // follow the negotiated clock
+        log!(
+            "Audio mismatch: Talk track {} runs at {}Hz, timing it by that instead of {}Hz",
+            ssrc,
+            codec.clock_rate,
+            SAMPLE_RATE
+        );
+    }
+    let sends_stereo = codec.sdp_fmtp_line.contains("sprop-stereo=1");
+    if sends_stereo && layout == ChannelLayout::Mono {
+        log!("Talk track {} is stereo, decoding it as mono (NC_TO_DISCORD_CHANNELS)", ssrc);
+    }
+    Some(if codec.clock_rate == 0 { SAMPLE_RATE } else { codec.clock_rate })
+}
+
+fn layout(layout: ChannelLayout) -> &'static str {
+    match layout {
+        ChannelLayout::Mono => "mono",
+        ChannelLayout::Stereo => "stereo",
+    }
+}
@@ -0,0 +1,56 @@
+use std::time::{Duration, Instant};
+
+// How often the bitrate may change; receiver reports come about once a second
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+// Loss above this backs off, below CLEAN_LOSS the rate creeps back up
+const HEAVY_LOSS: u8 = 10;
+const CLEAN_LOSS: u8 = 2;
+const INCREASE: f32 = 1.08;
+
+// Loss-based rate control for the Talk encoder, along the lines of WebRTC's
+// sender-side estimate from receiver reports: back off in proportion to the
+// loss when it is heavy, recover slowly once the link is clean again.
+pub struct BitrateController {
+    min: u32,
+    max: u32,
+    current: u32,
+    last_update: Instant,
+}
+
+impl BitrateController {
+    // Starts at `max`
+    pub fn new(min: u32, max: u32) -> Self {
+        Self {
+            min: min.min(max),
+            max,
+            current: max,
+            last_update: Instant::now(),
+        }
+    }
+
+    pub fn current(&self) -> u32 {
+        self.current
+    }
+
+    // Returns the new bitrate (bits/s) when it changed
+    pub fn update(&mut self, loss_percent: u8) -> Option<u32> {
+        if self.last_update.elapsed() < UPDATE_INTERVAL {
+            return None;
+        }
+        self.last_update = Instant::now();
+
+        let target = if loss_percent > HEAVY_LOSS {
+            self.current as f32 * (1.0 - 0.5 * loss_percent.min(100) as f32 / 100.0)
+        } else if loss_percent < CLEAN_LOSS {
+            self.current as f32 * INCREASE
+        } else {
+            return None;
+        };
+        let target = (target as u32).clamp(self.min, self.max);
+        if target == self.current {
+            return None;
+        }
+        self.current = target;
+        Some(target)
+    }
+}
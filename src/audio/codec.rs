@@ -0,0 +1,91 @@
+use anyhow::Result;
+use bytes::Bytes;
+use songbird::driver::opus::coder::{Decoder, Encoder};
+use songbird::driver::opus::packet::Packet;
+use songbird::driver::opus::{Application, Bitrate, Channels, MutSignals, SampleRate};
+
+use crate::config::ChannelLayout;
+
+// Largest frame Opus can produce (120ms) in samples per channel
+const MAX_FRAME_SAMPLES: usize = 5760;
+// Recommended upper bound for an encoded packet
+const MAX_PACKET_SIZE: usize = 4000;
+
+fn opus_channels(layout: ChannelLayout) -> Channels {
+    match layout {
+        ChannelLayout::Mono => Channels::Mono,
+        ChannelLayout::Stereo => Channels::Stereo,
+    }
+}
+
+// Opus handles up/downmixing itself, so decoding a stereo stream with a mono
+// decoder is how the pipeline downmixes.
+pub struct OpusDecoder {
+    decoder: Decoder,
+    channels: usize,
+    buf: Vec<i16>,
+}
+
+impl OpusDecoder {
+    pub fn new(layout: ChannelLayout) -> Result<Self> {
+        Ok(Self {
+            decoder: Decoder::new(SampleRate::Hz48000, opus_channels(layout))?,
+            channels: layout.count(),
+            buf: vec![0; MAX_FRAME_SAMPLES * layout.count()],
+        })
+    }
+
+    // Decode one Opus packet into interleaved PCM. The returned slice is only
+    // valid until the next call.
+    pub fn decode(&mut self, payload: &[u8]) -> Result<&mut [i16]> {
+        let packet = Packet::try_from(payload)?;
+        let signals = MutSignals::try_from(&mut self.buf[..])?;
+        let samples = self.decoder.decode(Some(packet), signals, false)?;
+        Ok(&mut self.buf[..samples * self.channels])
+    }
+
+    // Stands in for a lost packet of `samples` per channel: rebuilt from the
+    // FEC data in `next` (the packet after it) when it carries any,
+    // otherwise extrapolated (PLC). Same lifetime as `decode`.
+    pub fn recover(&mut self, next: Option<&[u8]>, samples: usize) -> Result<&mut [i16]> {
+        let len = samples.min(MAX_FRAME_SAMPLES) * self.channels;
+        let signals = MutSignals::try_from(&mut self.buf[..len])?;
+        let samples = match next {
+            Some(payload) => self.decoder.decode(Some(Packet::try_from(payload)?), signals, true)?,
+            None => self.decoder.decode(None, signals, false)?,
+        };
+        Ok(&mut self.buf[..samples * self.channels])
+    }
+}
+
+pub struct OpusEncoder {
+    encoder: Encoder,
+    buf: Vec<u8>,
+}
+
+impl OpusEncoder {
+    pub fn new(layout: ChannelLayout) -> Result<Self> {
+        Ok(Self {
+            encoder: Encoder::new(SampleRate::Hz48000, opus_channels(layout), Application::Voip)?,
+            buf: vec![0; MAX_PACKET_SIZE],
+        })
+    }
+
+    // In-band FEC costs bitrate, so it is only switched on while the link
+    // is actually losing packets
+    pub fn set_expected_loss(&mut self, percent: u8) -> Result<()> {
+        self.encoder.set_inband_fec(percent > 0)?;
+        self.encoder.set_packet_loss_perc(percent.min(100))?;
+        Ok(())
+    }
+
+    pub fn set_bitrate(&mut self, bits_per_second: u32) -> Result<()> {
+        self.encoder.set_bitrate(Bitrate::BitsPerSecond(bits_per_second as i32))?;
+        Ok(())
+    }
+
+    pub fn encode(&mut self, pcm: &[i16]) -> Result<Bytes> {
+        let len = self.encoder.encode(pcm, &mut self.buf)?;
+        Ok(Bytes::copy_from_slice(&self.buf[..len]))
+    }
+}
@@ -0,0 +1,44 @@
+use super::{db_to_amplitude, FRAME_SAMPLES};
+
+// Low-level noise to fill the gaps when nobody on Discord is talking, so Talk
+// listeners don't hear the dead digital silence of a dropped call.
+// Uses a small xorshift PRNG; it only has to sound like a quiet line.
+pub struct ComfortNoise {
+    amplitude: f32,
+    channels: usize,
+    state: u32,
+    last: f32,
+}
+
+impl ComfortNoise {
+    pub fn new(level_db: f32, channels: usize) -> Self {
+        Self {
+            amplitude: db_to_amplitude(level_db) * i16::MAX as f32,
+            channels: channels.max(1),
+            state: 0x9E37_79B9,
+            last: 0.0,
+        }
+    }
+
+    // One 20ms frame of interleaved PCM
+    pub fn next_frame(&mut self) -> Vec<i16> {
+        let mut frame = Vec::with_capacity(FRAME_SAMPLES * self.channels);
+        for _ in 0..FRAME_SAMPLES {
+            // Light one-pole low-pass so it reads as hiss rather than static
+            self.last = 0.7 * self.last + 0.3 * self.white();
+            let s = (self.last * self.amplitude) as i16;
+            frame.extend(std::iter::repeat_n(s, self.channels));
+        }
+        frame
+    }
+
+    // Uniform white noise in [-1, 1)
+    fn white(&mut self) -> f32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        (x as f32 / u32::MAX as f32) * 2.0 - 1.0
+    }
+}
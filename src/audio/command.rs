@@ -0,0 +1,60 @@
+use anyhow::{Context, Result};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use super::wav;
+
+// A local program that reads something on stdin and writes a WAV file to
+// stdout: a TTS engine fed text, or a decoder fed an audio file
+pub struct WavCommand {
+    program: String,
+    args: Vec<String>,
+    timeout: Duration,
+}
+
+impl WavCommand {
+    // `vars` are substituted into the arguments, e.g. ("{language}", "en")
+    pub fn new(command: &str, vars: &[(&str, &str)], timeout: Duration) -> Result<Self> {
+        let mut parts = command.split_whitespace().map(|part| {
+            vars.iter()
+                .fold(part.to_string(), |part, (name, value)| part.replace(name, value))
+        });
+        let program = parts.next().context("Empty command")?;
+        Ok(Self {
+            program,
+            args: parts.collect(),
+            timeout,
+        })
+    }
+
+    // Mono 48kHz samples
+    pub async fn run(&self, input: &[u8]) -> Result<Vec<f32>> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Failed to run {}", self.program))?;
+
+        // Written from a task of its own: a decoder may start writing
+        // before it has read everything, and would block on a full pipe
+        let mut stdin = child.stdin.take().context("No stdin for command")?;
+        let input = input.to_vec();
+        let writer = tokio::spawn(async move {
+            let _ = stdin.write_all(&input).await;
+        });
+
+        let output = tokio::time::timeout(self.timeout, child.wait_with_output())
+            .await
+            .with_context(|| format!("{} timed out", self.program))??;
+        writer.abort();
+        if !output.status.success() {
+            anyhow::bail!("{} exited with {}", self.program, output.status);
+        }
+        wav::decode(&output.stdout)
+    }
+}
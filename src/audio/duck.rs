@@ -0,0 +1,44 @@
+use crate::config::DuckingConfig;
+
+use super::{db_to_amplitude, ramp_step};
+
+// Sidechain ducker: pulls background sources (music, announcements) down
+// while someone is speaking so the voice stays intelligible.
+pub struct Ducker {
+    threshold: f32,
+    ducked_gain: f32,
+    attack_step: f32,
+    release_step: f32,
+    channels: usize,
+    gain: f32,
+}
+
+impl Ducker {
+    pub fn new(config: &DuckingConfig, channels: usize) -> Self {
+        Self {
+            threshold: db_to_amplitude(config.threshold_db),
+            ducked_gain: db_to_amplitude(-config.ratio_db.abs()),
+            attack_step: ramp_step(config.attack_ms),
+            release_step: ramp_step(config.release_ms),
+            channels: channels.max(1),
+            gain: 1.0,
+        }
+    }
+
+    // `voice_level` is the RMS of the speech mixed into this frame
+    pub fn process(&mut self, background: &mut [f32], voice_level: f32) {
+        let target = if voice_level >= self.threshold { self.ducked_gain } else { 1.0 };
+        for samples in background.chunks_mut(self.channels) {
+            // Attack pulls the gain down, release lets it back up
+            if self.gain > target {
+                self.gain = (self.gain - self.attack_step).max(target);
+            } else if self.gain < target {
+                self.gain = (self.gain + self.release_step).min(target);
+            }
+
+            for s in samples.iter_mut() {
+                *s *= self.gain;
+            }
+        }
+    }
+}
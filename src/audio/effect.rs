@@ -0,0 +1,199 @@
+use anyhow::{Context, Result};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use super::db_to_amplitude;
+use super::gate::NoiseGate;
+use crate::config::DirectionConfig;
+
+// A PCM stage in a direction's effect chain. `process` gets one interleaved
+// 20ms frame at 48kHz and works on it in place. Instances are per stream
+// (one per Discord speaker / Talk track), so they can keep state.
+//
+// Downstream effects implement this, provide an `EffectFactory` and call
+// `register` before the bridges start; they can then be named in
+// DISCORD_TO_NC_EFFECTS / NC_TO_DISCORD_EFFECTS or swapped in at runtime.
+pub trait AudioEffect: Send {
+    fn process(&mut self, frame: &mut [i16]);
+}
+
+// What a factory gets to build an effect from
+pub struct EffectContext<'a> {
+    pub channels: usize,
+    pub direction: &'a DirectionConfig,
+    // Everything after the name in the chain spec, e.g. ["-6"] for "gain:-6"
+    pub args: &'a [String],
+}
+
+pub type EffectFactory = fn(&EffectContext) -> Result<Box<dyn AudioEffect>>;
+
+static REGISTRY: Mutex<Vec<(&'static str, EffectFactory)>> = Mutex::new(Vec::new());
+
+// Later registrations with the same name win
+pub fn register(name: &'static str, factory: EffectFactory) {
+    REGISTRY.lock().unwrap().push((name, factory));
+}
+
+fn factory(name: &str) -> Option<EffectFactory> {
+    REGISTRY
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .find(|(n, _)| *n == name)
+        .map(|(_, f)| *f)
+}
+
+pub fn register_builtins() {
+    register("gain", |ctx| {
+        let db: f32 = ctx
+            .args
+            .first()
+            .context("gain needs a dB value, e.g. gain:-6")?
+            .trim_end_matches("dB")
+            .parse()
+            .context("Invalid gain")?;
+        Ok(Box::new(Gain(db_to_amplitude(db))))
+    });
+    register("gate", |ctx| Ok(Box::new(NoiseGate::new(&ctx.direction.gate, ctx.channels))));
+}
+
+struct Gain(f32);
+
+impl AudioEffect for Gain {
+    fn process(&mut self, frame: &mut [i16]) {
+        for s in frame.iter_mut() {
+            *s = (*s as f32 * self.0).clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        }
+    }
+}
+
+// One entry of a chain spec: "name" or "name:arg:arg"
+#[derive(Debug, Clone, PartialEq)]
+pub struct EffectSpec {
+    pub name: String,
+    pub args: Vec<String>,
+}
+
+impl FromStr for EffectSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().split(':').map(|p| p.trim().to_string());
+        let name = parts.next().filter(|n| !n.is_empty()).ok_or("empty effect name")?;
+        Ok(Self {
+            name: name.to_lowercase(),
+            args: parts.collect(),
+        })
+    }
+}
+
+impl fmt::Display for EffectSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        for arg in &self.args {
+            write!(f, ":{}", arg)?;
+        }
+        Ok(())
+    }
+}
+
+// "gain:-3, gate" -> chain, first effect runs first. Empty or "none" means no effects.
+pub fn parse_chain(s: &str) -> Result<Vec<EffectSpec>, String> {
+    if s.trim().eq_ignore_ascii_case("none") {
+        return Ok(Vec::new());
+    }
+    let specs = s
+        .split(',')
+        .filter(|p| !p.trim().is_empty())
+        .map(EffectSpec::from_str)
+        .collect::<Result<Vec<_>, _>>()?;
+    if let Some(unknown) = specs.iter().find(|s| factory(&s.name).is_none()) {
+        return Err(format!("unknown effect: {}", unknown.name));
+    }
+    Ok(specs)
+}
+
+// The chain configured for one direction of a bridge. Streams pick up a new
+// chain on their next frame after `set`.
+pub struct ChainSlot {
+    specs: RwLock<Vec<EffectSpec>>,
+    version: AtomicU64,
+}
+
+pub type SharedChain = Arc<ChainSlot>;
+
+impl ChainSlot {
+    pub fn new(specs: Vec<EffectSpec>) -> SharedChain {
+        Arc::new(Self {
+            specs: RwLock::new(specs),
+            version: AtomicU64::new(0),
+        })
+    }
+
+    pub fn set(&self, specs: Vec<EffectSpec>) {
+        *self.specs.write().unwrap() = specs;
+        self.version.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn specs(&self) -> Vec<EffectSpec> {
+        self.specs.read().unwrap().clone()
+    }
+}
+
+// Per-stream instance of a direction's chain
+pub struct EffectChain {
+    slot: SharedChain,
+    direction: DirectionConfig,
+    // None until first built
+    version: Option<u64>,
+    effects: Vec<Box<dyn AudioEffect>>,
+}
+
+impl EffectChain {
+    pub fn new(slot: SharedChain, direction: DirectionConfig) -> Self {
+        Self {
+            slot,
+            direction,
+            version: None,
+            effects: Vec::new(),
+        }
+    }
+
+    fn rebuild(&mut self) {
+        let ctx_channels = self.direction.channels.count();
+        self.effects = self
+            .slot
+            .specs()
+            .iter()
+            .filter_map(|spec| {
+                let factory = factory(&spec.name)?;
+                let ctx = EffectContext {
+                    channels: ctx_channels,
+                    direction: &self.direction,
+                    args: &spec.args,
+                };
+                match factory(&ctx) {
+                    Ok(effect) => Some(effect),
+                    Err(e) => {
+                        log!("Skipping effect {}: {:#}", spec.name, e);
+                        None
+                    }
+                }
+            })
+            .collect();
+    }
+
+    pub fn process(&mut self, frame: &mut [i16]) {
+        let version = self.slot.version.load(Ordering::Acquire);
+        if self.version != Some(version) {
+            self.rebuild();
+            self.version = Some(version);
+        }
+        for effect in &mut self.effects {
+            effect.process(frame);
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use crate::config::GateConfig;
+
+use super::effect::AudioEffect;
+use super::{db_to_amplitude, ramp_step};
+
+// Simple hysteresis noise gate. The open/close decision is made once per
+// frame from the frame's RMS level; the gain is then ramped per sample so
+// opening and closing doesn't click.
+pub struct NoiseGate {
+    open_threshold: f32,
+    close_threshold: f32,
+    attack_step: f32,
+    release_step: f32,
+    channels: usize,
+    open: bool,
+    gain: f32,
+}
+
+impl NoiseGate {
+    pub fn new(config: &GateConfig, channels: usize) -> Self {
+        Self {
+            open_threshold: db_to_amplitude(config.open_threshold_db),
+            close_threshold: db_to_amplitude(config.close_threshold_db),
+            attack_step: ramp_step(config.attack_ms),
+            release_step: ramp_step(config.release_ms),
+            channels: channels.max(1),
+            open: false,
+            gain: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, frame: &mut [i16]) {
+        if frame.is_empty() {
+            return;
+        }
+
+        let level = rms(frame);
+        if self.open && level < self.close_threshold {
+            self.open = false;
+        } else if !self.open && level >= self.open_threshold {
+            self.open = true;
+        }
+
+        let target = if self.open { 1.0 } else { 0.0 };
+        for samples in frame.chunks_mut(self.channels) {
+            if self.gain < target {
+                self.gain = (self.gain + self.attack_step).min(target);
+            } else if self.gain > target {
+                self.gain = (self.gain - self.release_step).max(target);
+            }
+
+            for s in samples.iter_mut() {
+                *s = (*s as f32 * self.gain) as i16;
+            }
+        }
+    }
+}
+
+impl AudioEffect for NoiseGate {
+    fn process(&mut self, frame: &mut [i16]) {
+        NoiseGate::process(self, frame);
+    }
+}
+
+fn rms(frame: &[i16]) -> f32 {
+    let sum: f64 = frame
+        .iter()
+        .map(|&s| {
+            let v = s as f64 / i16::MAX as f64;
+            v * v
+        })
+        .sum();
+    (sum / frame.len() as f64).sqrt() as f32
+}
@@ -0,0 +1,257 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use crate::config::{DuckingConfig, JitterConfig};
+use crate::stats::BufferCounters;
+
+use super::duck::Ducker;
+use super::{db_to_amplitude, FRAME_SAMPLES};
+
+// Drop the oldest audio once an input has more than this many frames queued
+// beyond its buffer target, so one stalled or bursty track can't build up
+// unbounded latency.
+const MAX_QUEUED_FRAMES: usize = 10;
+
+// A track that runs dry and resumes within this many frames was late; after
+// a longer gap it simply stopped sending (silence, mute) and isn't counted.
+const UNDERRUN_GAP_FRAMES: u64 = 5;
+
+// Frames without an underrun before a track's buffer shrinks by one (10s)
+const SETTLE_FRAMES: u32 = 500;
+
+pub type SharedMixer = Arc<Mutex<Mixer>>;
+
+// Mixes the decoded Talk tracks into the single stream played into Discord.
+// Inputs are keyed by Talk participant so per-participant gain can be applied.
+pub struct Mixer {
+    channels: usize,
+    inputs: HashMap<u64, MixerSlot>,
+    next_id: u64,
+    // Gain (dB) per participant, kept even while they have no input so it
+    // applies again when they rejoin.
+    gains_db: HashMap<String, f32>,
+    // For participants without a gain of their own
+    default_gain_db: f32,
+    // Ducks background inputs under voice inputs, when enabled
+    ducker: Option<Ducker>,
+    background_participants: Vec<String>,
+    jitter: JitterConfig,
+    // Frames mixed so far
+    tick: u64,
+    counters: BufferCounters,
+}
+
+struct MixerSlot {
+    participant: String,
+    queue: VecDeque<i16>,
+    gain: f32,
+    background: bool,
+    // Jitter buffer: frames to queue before playing, whether the track is
+    // playing or waiting for that, when it last ran dry, and how long since
+    // its last underrun
+    target: usize,
+    playing: bool,
+    dry_since: Option<u64>,
+    steady: u32,
+}
+
+impl Mixer {
+    pub fn new(
+        channels: usize,
+        gains_db: HashMap<String, f32>,
+        ducking: &DuckingConfig,
+        jitter: &JitterConfig,
+    ) -> SharedMixer {
+        Arc::new(Mutex::new(Self {
+            channels,
+            inputs: HashMap::new(),
+            next_id: 0,
+            gains_db,
+            default_gain_db: 0.0,
+            ducker: ducking.enabled.then(|| Ducker::new(ducking, channels)),
+            background_participants: ducking
+                .background_participants
+                .iter()
+                .map(|p| normalize_participant(p))
+                .collect(),
+            jitter: jitter.clone(),
+            tick: 0,
+            counters: BufferCounters::default(),
+        }))
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn add_input(mixer: &SharedMixer, participant: &str) -> MixerInput {
+        let mut m = mixer.lock().unwrap();
+        let id = m.next_id;
+        m.next_id += 1;
+
+        let participant = normalize_participant(participant);
+        let gain = db_to_amplitude(m.gains_db.get(&participant).copied().unwrap_or(m.default_gain_db));
+        let background = m.background_participants.contains(&participant);
+        let target = m.jitter.min_frames;
+        m.inputs.insert(
+            id,
+            MixerSlot {
+                participant,
+                queue: VecDeque::new(),
+                gain,
+                background,
+                target,
+                playing: false,
+                dry_since: None,
+                steady: 0,
+            },
+        );
+
+        MixerInput {
+            id,
+            mixer: mixer.clone(),
+        }
+    }
+
+    pub fn set_gain_db(&mut self, participant: &str, db: f32) {
+        let participant = normalize_participant(participant);
+        for slot in self.inputs.values_mut() {
+            if slot.participant == participant {
+                slot.gain = db_to_amplitude(db);
+            }
+        }
+        self.gains_db.insert(participant, db);
+    }
+
+    pub fn set_default_gain_db(&mut self, db: f32) {
+        self.default_gain_db = db;
+        for slot in self.inputs.values_mut() {
+            if !self.gains_db.contains_key(&slot.participant) {
+                slot.gain = db_to_amplitude(db);
+            }
+        }
+    }
+
+    // Underruns and overruns since the last reset, and the current depth
+    pub fn buffer_counters(&self) -> BufferCounters {
+        let depth = self.inputs.values().map(|s| s.target).max().unwrap_or(0);
+        BufferCounters {
+            depth_ms: depth as u64 * 20,
+            ..self.counters
+        }
+    }
+
+    pub fn reset_buffer_counters(&mut self) {
+        self.counters = BufferCounters::default();
+    }
+
+    // Mix one 20ms frame from every playing input. Inputs that don't have a
+    // full frame queued contribute what they have, padded with silence, and
+    // then wait to fill their buffer again.
+    // Voice and background are summed separately so the background bus can
+    // be ducked against the voice level.
+    pub fn mix_frame(&mut self) -> Vec<f32> {
+        let len = FRAME_SAMPLES * self.channels;
+        let mut voice = vec![0.0f32; len];
+        let mut background = vec![0.0f32; len];
+        for slot in self.inputs.values_mut() {
+            if !slot.playing {
+                if slot.queue.is_empty() || slot.queue.len() < slot.target * len {
+                    continue;
+                }
+                slot.playing = true;
+            }
+
+            let bus = if slot.background { &mut background } else { &mut voice };
+            let short = slot.queue.len() < len;
+            let n = len.min(slot.queue.len());
+            for (o, s) in bus.iter_mut().zip(slot.queue.drain(..n)) {
+                *o += s as f32 / i16::MAX as f32 * slot.gain;
+            }
+
+            if short {
+                slot.playing = false;
+                slot.dry_since = Some(self.tick);
+            } else if self.jitter.adaptive {
+                slot.steady += 1;
+                if slot.steady >= SETTLE_FRAMES && slot.target > self.jitter.min_frames {
+                    slot.target -= 1;
+                    slot.steady = 0;
+                    // Give back the latency the deeper buffer added
+                    if slot.queue.len() > (slot.target + 1) * len {
+                        slot.queue.drain(..len);
+                    }
+                }
+            }
+        }
+        self.tick += 1;
+
+        if let Some(ducker) = self.ducker.as_mut() {
+            ducker.process(&mut background, rms(&voice));
+        }
+
+        for (v, b) in voice.iter_mut().zip(background) {
+            *v = (*v + b).clamp(-1.0, 1.0);
+        }
+        voice
+    }
+}
+
+// Handle for feeding one track into the mixer; removes the input on drop
+pub struct MixerInput {
+    id: u64,
+    mixer: SharedMixer,
+}
+
+impl MixerInput {
+    pub fn push(&self, samples: &[i16]) {
+        let mut m = self.mixer.lock().unwrap();
+        let Mixer {
+            inputs,
+            channels,
+            jitter,
+            tick,
+            counters,
+            ..
+        } = &mut *m;
+        let Some(slot) = inputs.get_mut(&self.id) else {
+            return;
+        };
+
+        if let Some(since) = slot.dry_since.take() {
+            if *tick - since <= UNDERRUN_GAP_FRAMES {
+                counters.underruns += 1;
+                slot.steady = 0;
+                if jitter.adaptive && slot.target < jitter.max_frames {
+                    slot.target += 1;
+                }
+            }
+        }
+
+        slot.queue.extend(samples);
+        let max = FRAME_SAMPLES * *channels * (slot.target + MAX_QUEUED_FRAMES);
+        if slot.queue.len() > max {
+            let excess = slot.queue.len() - max;
+            slot.queue.drain(..excess);
+            counters.overruns += 1;
+        }
+    }
+}
+
+impl Drop for MixerInput {
+    fn drop(&mut self) {
+        self.mixer.lock().unwrap().inputs.remove(&self.id);
+    }
+}
+
+// "@Alice" and "alice" refer to the same Talk participant
+pub fn normalize_participant(name: &str) -> String {
+    name.trim().trim_start_matches('@').to_lowercase()
+}
+
+fn rms(frame: &[f32]) -> f32 {
+    if frame.is_empty() {
+        return 0.0;
+    }
+    (frame.iter().map(|s| s * s).sum::<f32>() / frame.len() as f32).sqrt()
+}
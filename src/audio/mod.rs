@@ -0,0 +1,36 @@
+pub mod audit;
+pub mod bitrate;
+pub mod codec;
+pub mod command;
+pub mod comfort_noise;
+pub mod duck;
+pub mod effect;
+pub mod gate;
+pub mod mixer;
+pub mod ogg;
+pub mod red;
+pub mod reorder;
+pub mod repacketizer;
+pub mod source;
+pub mod wav;
+
+// Both Discord and Talk speak 48kHz Opus, so the whole PCM path runs at that rate.
+// The channel count is configured per direction (see config::ChannelLayout).
+pub const SAMPLE_RATE: u32 = 48_000;
+
+// Samples per channel in one 20ms frame
+pub const FRAME_SAMPLES: usize = SAMPLE_RATE as usize / 50;
+
+pub fn db_to_amplitude(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+// Gain change per sample needed to ramp fully from 0 to 1 in `ms`
+pub fn ramp_step(ms: f32) -> f32 {
+    let samples = ms * SAMPLE_RATE as f32 / 1000.0;
+    if samples < 1.0 {
+        1.0
+    } else {
+        1.0 / samples
+    }
+}
@@ -0,0 +1,111 @@
+use std::io::{self, Write};
+
+use super::SAMPLE_RATE;
+
+// Ogg's CRC: polynomial 0x04c11db7, not reflected, zero init and xor
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04c1_1db7 } else { crc << 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc(data: &[u8]) -> u32 {
+    data.iter()
+        .fold(0, |crc, b| (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ b) as usize])
+}
+
+const FLAG_BOS: u8 = 0x02;
+const FLAG_EOS: u8 = 0x04;
+
+// Samples the decoder drops at the start, libopus' usual encoder lookahead
+const PRE_SKIP: u16 = 312;
+
+// Writes an Ogg Opus file (RFC 7845), one packet per page. The last packet
+// is held back so it can carry the end-of-stream flag.
+pub struct OggOpusWriter<W: Write> {
+    out: W,
+    serial: u32,
+    sequence: u32,
+    granule: u64,
+    pending: Option<(Vec<u8>, u64)>,
+}
+
+impl<W: Write> OggOpusWriter<W> {
+    pub fn new(out: W, channels: usize, serial: u32) -> io::Result<Self> {
+        let mut writer = Self {
+            out,
+            serial,
+            sequence: 0,
+            granule: 0,
+            pending: None,
+        };
+
+        let mut head = b"OpusHead".to_vec();
+        head.push(1);
+        head.push(channels as u8);
+        head.extend(PRE_SKIP.to_le_bytes());
+        head.extend(SAMPLE_RATE.to_le_bytes());
+        // Output gain, channel mapping family 0
+        head.extend(0i16.to_le_bytes());
+        head.push(0);
+        writer.write_page(&head, 0, FLAG_BOS)?;
+
+        let vendor = env!("CARGO_PKG_NAME").as_bytes();
+        let mut tags = b"OpusTags".to_vec();
+        tags.extend((vendor.len() as u32).to_le_bytes());
+        tags.extend(vendor);
+        tags.extend(0u32.to_le_bytes());
+        writer.write_page(&tags, 0, 0)?;
+
+        Ok(writer)
+    }
+
+    // `samples` is the packet's duration in 48kHz samples per channel
+    pub fn write_packet(&mut self, packet: &[u8], samples: u64) -> io::Result<()> {
+        self.granule += samples;
+        if let Some((data, granule)) = self.pending.replace((packet.to_vec(), self.granule)) {
+            self.write_page(&data, granule, 0)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some((data, granule)) = self.pending.take() {
+            self.write_page(&data, granule, FLAG_EOS)?;
+        }
+        self.out.flush()
+    }
+
+    fn write_page(&mut self, packet: &[u8], granule: u64, flags: u8) -> io::Result<()> {
+        // Lacing: runs of 255 and a final shorter segment (0 if it divides evenly)
+        let mut segments = vec![255u8; packet.len() / 255];
+        segments.push((packet.len() % 255) as u8);
+
+        let mut page = b"OggS".to_vec();
+        page.push(0);
+        page.push(flags);
+        page.extend(granule.to_le_bytes());
+        page.extend(self.serial.to_le_bytes());
+        page.extend(self.sequence.to_le_bytes());
+        page.extend(0u32.to_le_bytes());
+        page.push(segments.len() as u8);
+        page.extend(&segments);
+        page.extend(packet);
+
+        let checksum = crc(&page);
+        page[22..26].copy_from_slice(&checksum.to_le_bytes());
+
+        self.sequence += 1;
+        self.out.write_all(&page)
+    }
+}
@@ -0,0 +1,68 @@
+// RFC 2198 redundant audio (audio/red), which Talk's clients and Janus use
+// for Opus when both sides negotiate it: a packet carries the frames before
+// it again, ahead of its own, so one that went missing can be rebuilt from
+// the next.
+
+pub const MIME_TYPE: &str = "audio/red";
+
+// A redundant block's header holds its offset in 14 bits and its length in 10
+const MAX_OFFSET: u32 = (1 << 14) - 1;
+const MAX_LENGTH: usize = (1 << 10) - 1;
+
+// A RED payload taken apart
+pub struct Blocks<'a> {
+    // The earlier frames, each with how far (RTP timestamp) it is behind
+    // the primary
+    pub redundant: Vec<(u32, &'a [u8])>,
+    pub primary: &'a [u8],
+}
+
+impl<'a> Blocks<'a> {
+    // A payload that isn't RED, just its own frame
+    pub fn plain(payload: &'a [u8]) -> Self {
+        Self { redundant: Vec::new(), primary: payload }
+    }
+}
+
+// None if the headers don't fit the payload
+pub fn split(payload: &[u8]) -> Option<Blocks<'_>> {
+    let mut headers = Vec::new();
+    let mut at = 0;
+    // Every header but the primary's has the F bit set
+    while *payload.get(at)? & 0x80 != 0 {
+        let header = payload.get(at..at + 4)?;
+        let offset = (u32::from(header[1]) << 6) | (u32::from(header[2]) >> 2);
+        let length = (usize::from(header[2] & 0x03) << 8) | usize::from(header[3]);
+        headers.push((offset, length));
+        at += 4;
+    }
+    at += 1;
+
+    let mut redundant = Vec::with_capacity(headers.len());
+    for (offset, length) in headers {
+        redundant.push((offset, payload.get(at..at + length)?));
+        at += length;
+    }
+    Some(Blocks { redundant, primary: &payload[at..] })
+}
+
+// `primary` with `previous` (its offset and frame) ahead of it. Without a
+// previous frame, or one too far behind or too long for a block header, the
+// packet only carries the primary.
+pub fn join(payload_type: u8, primary: &[u8], previous: Option<(u32, &[u8])>) -> Vec<u8> {
+    let previous = previous.filter(|(offset, frame)| *offset <= MAX_OFFSET && frame.len() <= MAX_LENGTH);
+    let mut payload = Vec::with_capacity(5 + primary.len() + previous.map_or(0, |(_, frame)| frame.len()));
+    if let Some((offset, frame)) = previous {
+        let length = frame.len() as u32;
+        payload.push(0x80 | payload_type);
+        payload.push((offset >> 6) as u8);
+        payload.push(((offset << 2) as u8 & 0xfc) | (length >> 8) as u8);
+        payload.push(length as u8);
+    }
+    payload.push(payload_type & 0x7f);
+    if let Some((_, frame)) = previous {
+        payload.extend_from_slice(frame);
+    }
+    payload.extend_from_slice(primary);
+    payload
+}
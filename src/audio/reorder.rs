@@ -0,0 +1,140 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use crate::stats::RtpCounters;
+
+// Recently delivered sequence numbers remembered for duplicate detection
+const HISTORY: u16 = 64;
+
+// Puts one SSRC's packets back into sequence order. Packets that arrive
+// ahead of a gap are held for up to `window` packets; if the missing ones
+// haven't turned up by then they're counted as lost and skipped. Nor are
+// they held longer than `hold`: once that has passed, `flush_expired`
+// gives up on the gap, so the last packets before a pause still go out.
+pub struct ReorderBuffer<T> {
+    window: usize,
+    hold: Duration,
+    // When the packets now held started waiting
+    since: Option<Instant>,
+    next: Option<u16>,
+    // Keyed by distance from `next`, so ordering survives wraparound
+    held: BTreeMap<u16, (u16, T)>,
+    // Bit i set = sequence `next - 1 - i` was delivered
+    delivered: u64,
+}
+
+impl<T> ReorderBuffer<T> {
+    pub fn new(window: usize, hold: Duration) -> Self {
+        Self {
+            window,
+            hold,
+            since: None,
+            next: None,
+            held: BTreeMap::new(),
+            delivered: 0,
+        }
+    }
+
+    // Returns the packets that can be sent now, in order
+    pub fn push(&mut self, seq: u16, packet: T, counters: &mut RtpCounters) -> Vec<T> {
+        let Some(next) = self.next else {
+            self.next = Some(seq);
+            return self.deliver(seq, packet);
+        };
+
+        let ahead = seq.wrapping_sub(next);
+        if ahead >= u16::MAX / 2 {
+            // Behind `next`: either delivered already or given up on
+            let behind = next.wrapping_sub(seq);
+            if behind <= HISTORY && self.delivered & (1 << (behind - 1)) != 0 {
+                counters.duplicates += 1;
+            } else {
+                counters.late += 1;
+            }
+            return Vec::new();
+        }
+
+        if self.held.contains_key(&ahead) {
+            counters.duplicates += 1;
+            return Vec::new();
+        }
+        if ahead > 0 {
+            counters.reordered += 1;
+        }
+        self.held.insert(ahead, (seq, packet));
+
+        let mut out = self.drain();
+        // Stop waiting for the gap once enough packets queued up behind it,
+        // or if the jump is too big to ever fill
+        let too_far = ahead as usize > self.window * 4;
+        while !self.held.is_empty() && (self.held.len() > self.window || too_far) {
+            let (&skip, _) = self.held.iter().next().unwrap();
+            counters.lost += skip as u64;
+            self.advance(skip);
+            out.extend(self.drain());
+        }
+        // The ones still held have waited since the last delivery
+        self.since = match (self.held.is_empty(), out.is_empty()) {
+            (true, _) => None,
+            (false, true) => self.since.or_else(|| Some(Instant::now())),
+            (false, false) => Some(Instant::now()),
+        };
+        out
+    }
+
+    // When `flush_expired` should next be called, while packets are held
+    pub fn deadline(&self) -> Option<Instant> {
+        self.since.map(|since| since + self.hold)
+    }
+
+    // Skips whatever gaps are left and returns the held packets once
+    // they've waited out `hold`
+    pub fn flush_expired(&mut self, now: Instant, counters: &mut RtpCounters) -> Vec<T> {
+        if self.deadline().is_none_or(|deadline| now < deadline) {
+            return Vec::new();
+        }
+        let mut out = Vec::new();
+        while let Some((&skip, _)) = self.held.iter().next() {
+            counters.lost += skip as u64;
+            self.advance(skip);
+            out.extend(self.drain());
+        }
+        self.since = None;
+        out
+    }
+
+    fn deliver(&mut self, seq: u16, packet: T) -> Vec<T> {
+        self.next = Some(seq.wrapping_add(1));
+        self.delivered = (self.delivered << 1) | 1;
+        vec![packet]
+    }
+
+    // Deliver everything that is now in sequence
+    fn drain(&mut self) -> Vec<T> {
+        let mut out = Vec::new();
+        while let Some((seq, packet)) = self.held.remove(&0) {
+            out.extend(self.deliver(seq, packet));
+            self.rekey(1);
+        }
+        out
+    }
+
+    // Skip `by` missing packets
+    fn advance(&mut self, by: u16) {
+        if let Some(next) = self.next {
+            self.next = Some(next.wrapping_add(by));
+        }
+        self.delivered = self.delivered.checked_shl(by as u32).unwrap_or(0);
+        self.rekey(by);
+    }
+
+    fn rekey(&mut self, by: u16) {
+        if by == 0 || self.held.is_empty() {
+            return;
+        }
+        self.held = std::mem::take(&mut self.held)
+            .into_iter()
+            .map(|(k, v)| (k - by, v))
+            .collect();
+    }
+}
@@ -0,0 +1,65 @@
+use songbird::input::core::io::MediaSource;
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use super::mixer::SharedMixer;
+
+// Live PCM input for Songbird, pulling mixed Talk audio from the mixer.
+// Songbird's RawAdapter wants interleaved little-endian f32, so each mixed
+// frame is converted to bytes and handed out as the reader asks for them.
+//
+// Reads never block: the mixer thread pulls from every track each tick, so
+// when nothing has arrived from Talk we just hand out a frame of silence.
+// The same goes while the bot is server-muted: the mixer keeps draining so
+// nothing piles up, but what reaches Discord is silence.
+pub struct PcmSource {
+    mixer: SharedMixer,
+    muted: Arc<AtomicBool>,
+    pending: VecDeque<u8>,
+}
+
+impl PcmSource {
+    pub fn new(mixer: SharedMixer, muted: Arc<AtomicBool>) -> Self {
+        Self {
+            mixer,
+            muted,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl Read for PcmSource {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pending.is_empty() {
+            let frame = self.mixer.lock().unwrap().mix_frame();
+            let muted = self.muted.load(Ordering::Relaxed);
+            for s in frame {
+                self.pending.extend(if muted { 0.0 } else { s }.to_le_bytes());
+            }
+        }
+
+        let n = buf.len().min(self.pending.len());
+        for (dst, src) in buf.iter_mut().zip(self.pending.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Seek for PcmSource {
+    fn seek(&mut self, _: SeekFrom) -> std::io::Result<u64> {
+        Err(ErrorKind::Unsupported.into())
+    }
+}
+
+impl MediaSource for PcmSource {
+    fn is_seekable(&self) -> bool {
+        false
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        None
+    }
+}
@@ -0,0 +1,67 @@
+use anyhow::{Context, Result};
+
+use super::SAMPLE_RATE;
+
+// 16-bit PCM WAV, downmixed to mono and resampled to 48kHz. Tools writing
+// to a pipe (espeak) can't fill in the data length, so it is capped to
+// what's actually there.
+pub fn decode(data: &[u8]) -> Result<Vec<f32>> {
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        anyhow::bail!("Not a WAV file");
+    }
+
+    let mut format = None;
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let id = &data[pos..pos + 4];
+        let len = u32::from_le_bytes(data[pos + 4..pos + 8].try_into()?) as usize;
+        let body = &data[pos + 8..(pos + 8).saturating_add(len).min(data.len())];
+        match id {
+            b"fmt " if body.len() >= 16 => {
+                let tag = u16::from_le_bytes([body[0], body[1]]);
+                let channels = u16::from_le_bytes([body[2], body[3]]) as usize;
+                let rate = u32::from_le_bytes(body[4..8].try_into()?);
+                let bits = u16::from_le_bytes([body[14], body[15]]);
+                if tag != 1 || bits != 16 || channels == 0 || rate == 0 {
+                    anyhow::bail!("Only 16-bit PCM WAV is supported");
+                }
+                format = Some((channels, rate));
+            }
+            b"data" => {
+                let (channels, rate) = format.context("WAV data before format")?;
+                let samples: Vec<f32> = body
+                    .chunks_exact(2)
+                    .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / i16::MAX as f32)
+                    .collect();
+                let mono: Vec<f32> = samples
+                    .chunks_exact(channels)
+                    .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+                    .collect();
+                return Ok(resample(&mono, rate));
+            }
+            _ => {}
+        }
+        // Chunks are padded to even lengths
+        pos = pos.saturating_add(8 + len + (len & 1));
+    }
+    anyhow::bail!("No audio data in WAV file")
+}
+
+// Linear interpolation, plenty for chimes and speech
+fn resample(samples: &[f32], rate: u32) -> Vec<f32> {
+    if rate == SAMPLE_RATE || samples.is_empty() {
+        return samples.to_vec();
+    }
+    let step = rate as f64 / SAMPLE_RATE as f64;
+    let len = (samples.len() as f64 / step) as usize;
+    (0..len)
+        .map(|i| {
+            let pos = i as f64 * step;
+            let idx = pos as usize;
+            let frac = (pos - idx as f64) as f32;
+            let a = samples[idx];
+            let b = samples.get(idx + 1).copied().unwrap_or(a);
+            a + (b - a) * frac
+        })
+        .collect()
+}
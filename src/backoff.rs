@@ -0,0 +1,39 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+use crate::config::ReconnectConfig;
+
+// Delays between reconnect tries: doubling from the configured initial
+// delay up to the maximum, each with up to half taken off at random, so
+// bridges that lost the server together don't all come back at once.
+pub struct Backoff {
+    config: ReconnectConfig,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(config: ReconnectConfig) -> Self {
+        Self { config, attempt: 0 }
+    }
+
+    // How long to wait before the next try; None once the tries are used up
+    pub fn next(&mut self) -> Option<Duration> {
+        if self.config.max_retries != 0 && self.attempt >= self.config.max_retries {
+            return None;
+        }
+        let delay = self.config.initial.saturating_mul(1 << self.attempt.min(16)).min(self.config.max_delay);
+        self.attempt += 1;
+        Some(delay.mul_f64(1.0 - jitter() / 2.0))
+    }
+
+    // Tries so far, counting the one waited for last
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+}
+
+// Between 0 and 1; every RandomState is seeded afresh
+fn jitter() -> f64 {
+    RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64
+}
@@ -1,61 +1,795 @@
-use anyhow::{Result, Context};
+use anyhow::{Context, Result};
 use serenity::async_trait;
 use songbird::{
-    Call,
     Songbird,
-    events::{Event, EventContext, EventHandler as VoiceEventHandler},
-    model::payload::Speaking,
+    driver::DecodeMode,
+    events::{context_data::RtpData, Event, EventContext, EventHandler as VoiceEventHandler},
+    input::RawAdapter,
+    packet::Packet as _,
 };
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use std::sync::Arc;
-use tokio::sync::{Mutex, mpsc};
-use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
-use webrtc::media::Sample;
-use std::time::Duration;
+use tokio::sync::{Mutex, mpsc, oneshot, watch};
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::track::track_remote::TrackRemote;
+use std::time::{Duration, Instant, SystemTime};
 use bytes::Bytes;
 
+use crate::audio::{
+    self, audit,
+    bitrate::BitrateController,
+    codec::{OpusDecoder, OpusEncoder},
+    comfort_noise::ComfortNoise,
+    effect::{EffectChain, SharedChain},
+    mixer::{Mixer, MixerInput, SharedMixer},
+    red,
+    reorder::ReorderBuffer,
+    repacketizer::{packet_duration, Repacketizer},
+    source::PcmSource,
+};
+use crate::config::{AudioConfig, BridgeConfig, ChannelLayout, ComfortNoiseConfig, Direction, DirectionConfig, PipelineMode, ReceiveMode};
+use crate::nextcloud::call::{CallClient, InCall};
+use crate::nextcloud::peers::{PeerCandidate, PeerManager, PeerState, PeerStatus, TrackHandler};
 use crate::nextcloud::webrtc::NextcloudWebRTC;
-use crate::nextcloud::signaling::{SignalingClient, SignalingMessage};
+use crate::nextcloud::protocol::{self, SignalingMessage};
+use crate::backoff::Backoff;
+use crate::nextcloud::signaling::{CallTarget, SignalingClient, SignalingSender};
+use crate::publisher::{MergedRoom, PublisherPool};
+use crate::speakers::SharedSpeakers;
+use crate::stats::{CallStats, SharedStats, WebRtcStats};
+use crate::cues::{Cue, CueGuard, CueTargets, SharedCues};
+use crate::recorder::{RecorderTap, RecordingGuard, SharedRecorder};
+use crate::talk_track::{TalkTrack, Timing};
+use crate::loop_guard::{LoopGuard, SharedLoopGuard};
+use crate::encryption::SharedEncryption;
+use crate::access::SharedAccess;
+use crate::roster::{self, Roster, SharedRoster};
 use serenity::model::id::{GuildId, ChannelId};
 
+// Discord sends a few of these Opus silence frames when a user stops talking
+const DISCORD_SILENCE_FRAME: [u8; 3] = [0xF8, 0xFF, 0xFE];
+
+// How long a packet is taken to play when it doesn't say: Discord's frames,
+// and what Talk's clients send by default
+const DEFAULT_FRAME: Duration = Duration::from_millis(20);
+
+// Talk packets missing in a row that are concealed on receive (100ms at
+// 20ms frames)
+const MAX_CONCEALED_PACKETS: u16 = 5;
+
+// Subscriber connections to the MCU, one per Talk publisher
+const MAX_SUBSCRIPTIONS: usize = 32;
+
+// How long the Talk connection may stay disconnected before ICE is
+// restarted; it often comes back by itself
+const DISCONNECTED_GRACE: Duration = Duration::from_secs(5);
+
+// ICE restarts tried, each given this long to connect, before the session
+// is started over
+const ICE_RESTARTS: u32 = 3;
+const ICE_RESTART_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Talk's TURN credentials are renewed this long before they expire, and
+// tried again this often when that fails
+const TURN_RENEWAL_MARGIN: Duration = Duration::from_secs(3600);
+const TURN_RENEWAL_RETRY: Duration = Duration::from_secs(60);
+
+// How often the Talk connections' WebRTC stats are sampled for the status
+const WEBRTC_STATS_INTERVAL: Duration = Duration::from_secs(5);
+
+// Talk's in-call flag for participants publishing audio
+const IN_CALL_WITH_AUDIO: u64 = 2;
+
+// Per-SSRC decode state. Each Discord speaker is a separate Opus stream, so
+// decoders and effect state can't be shared between them. The decoder is
+// only needed when we decode ourselves (RTP receive mode).
+struct SpeakerState {
+    decoder: Option<OpusDecoder>,
+    effects: EffectChain,
+}
+
+// Encoder for the Talk track that follows the loss Talk reports, with FEC
+// and/or a lower bitrate
+struct TalkEncoder {
+    encoder: OpusEncoder,
+    // Measured loss toward Talk, and the value the encoder was last tuned for
+    loss: Arc<AtomicU8>,
+    fec: bool,
+    applied_loss: u8,
+    bitrate: Option<BitrateController>,
+}
+
+impl TalkEncoder {
+    fn new(audio: &AudioConfig, loss: Arc<AtomicU8>) -> Result<Self> {
+        let mut encoder = OpusEncoder::new(audio.discord_to_nextcloud.channels)?;
+        // Never above what the SDPs announce
+        let cap = |rate: u32| audio.talk_bandwidth.map_or(rate, |cap| rate.min(cap));
+        let bitrate = audio
+            .adaptive_bitrate
+            .then(|| BitrateController::new(cap(audio.min_bitrate), cap(audio.max_bitrate)));
+        match (&bitrate, audio.talk_bandwidth) {
+            (Some(bitrate), _) => encoder.set_bitrate(bitrate.current())?,
+            (None, Some(bandwidth)) => encoder.set_bitrate(bandwidth)?,
+            (None, None) => {}
+        }
+        Ok(Self {
+            encoder,
+            loss,
+            fec: audio.dynamic_fec,
+            applied_loss: 0,
+            bitrate,
+        })
+    }
+
+    fn encode(&mut self, pcm: &[i16]) -> Result<Bytes> {
+        let loss = self.loss.load(Ordering::Relaxed);
+        if let Some(bitrate) = self.bitrate.as_mut() {
+            let before = bitrate.current();
+            if let Some(rate) = bitrate.update(loss) {
+                if rate < before {
+                    log!("Talk reports {}% loss, bitrate down to {} kbps", loss, rate / 1000);
+                }
+                self.encoder.set_bitrate(rate)?;
+            }
+        }
+        if self.fec && loss != self.applied_loss {
+            if (loss > 0) != (self.applied_loss > 0) {
+                log!("Talk reports {}% loss, FEC {}", loss, if loss > 0 { "on" } else { "off" });
+            }
+            self.encoder.set_expected_loss(loss)?;
+            self.applied_loss = loss;
+        }
+        self.encoder.encode(pcm)
+    }
+}
+
+// Decode -> PCM stages -> encode, used when a PCM stage is enabled for the
+// Discord -> Nextcloud leg. Otherwise packets are passed through untouched.
+struct DiscordTranscoder {
+    config: DirectionConfig,
+    effects: SharedChain,
+    speakers: HashMap<u32, SpeakerState>,
+    encoder: TalkEncoder,
+}
+
+impl DiscordTranscoder {
+    fn new(audio: &AudioConfig, effects: SharedChain, loss: Arc<AtomicU8>) -> Result<Self> {
+        Ok(Self {
+            encoder: TalkEncoder::new(audio, loss)?,
+            speakers: HashMap::new(),
+            effects,
+            config: audio.discord_to_nextcloud.clone(),
+        })
+    }
+
+    // Borrows only the speaker map, so the encoder stays usable alongside it
+    fn speaker<'a>(
+        speakers: &'a mut HashMap<u32, SpeakerState>,
+        config: &DirectionConfig,
+        effects: &SharedChain,
+        ssrc: u32,
+    ) -> &'a mut SpeakerState {
+        speakers.entry(ssrc).or_insert_with(|| SpeakerState {
+            decoder: None,
+            effects: EffectChain::new(effects.clone(), config.clone()),
+        })
+    }
+
+    // From an Opus payload (RTP receive mode)
+    fn transcode(&mut self, ssrc: u32, payload: &[u8]) -> Result<Bytes> {
+        let speaker = Self::speaker(&mut self.speakers, &self.config, &self.effects, ssrc);
+        let decoder = match &mut speaker.decoder {
+            Some(decoder) => decoder,
+            None => speaker.decoder.insert(OpusDecoder::new(self.config.channels)?),
+        };
+
+        let pcm = decoder.decode(payload)?;
+        speaker.effects.process(pcm);
+        self.encoder.encode(pcm)
+    }
+
+    // From PCM Songbird already decoded (decoded receive mode)
+    fn encode_pcm(&mut self, ssrc: u32, pcm: &mut [i16]) -> Result<Bytes> {
+        Self::speaker(&mut self.speakers, &self.config, &self.effects, ssrc).effects.process(pcm);
+        self.encoder.encode(pcm)
+    }
+}
+
+// Aborts a background task when the session that owns it goes away
+pub struct TaskGuard(pub tokio::task::JoinHandle<()>);
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+// Settles the session's Discord -> Nextcloud pipeline, which decides the
+// Talk track type before anything is connected. Discord always sends stereo,
+// so anything but a stereo passthrough with no PCM stages needs a
+// decode/encode round trip; effects swapped onto a passthrough leg only apply
+// from the next session.
+pub fn resolve_pipeline(audio: &mut AudioConfig, effects: &SharedChain) {
+    let needs_pcm = !effects.specs().is_empty()
+        || audio.discord_to_nextcloud.channels == ChannelLayout::Mono
+        || audio.discord_receive == ReceiveMode::Decoded;
+    audio.pipeline = match audio.pipeline {
+        PipelineMode::Auto if needs_pcm => PipelineMode::Transcode,
+        PipelineMode::Auto => PipelineMode::Passthrough,
+        // Songbird only hands out PCM in that mode
+        PipelineMode::Passthrough if audio.discord_receive == ReceiveMode::Decoded => {
+            log!("Passthrough needs DISCORD_RECEIVE_MODE=rtp, transcoding instead");
+            PipelineMode::Transcode
+        }
+        PipelineMode::Passthrough if needs_pcm => {
+            log!("Passthrough: Discord -> Nextcloud effects and the mono downmix are skipped");
+            PipelineMode::Passthrough
+        }
+        mode => mode,
+    };
+}
+
+// One 20ms frame received from a Discord speaker
+enum DiscordFrame<'a> {
+    Opus(&'a [u8]),
+    Pcm(Vec<i16>),
+}
+
 pub struct DiscordToNextcloudHandler {
-    pub track: Arc<TrackLocalStaticSample>,
+    pub track: Arc<TalkTrack>,
+    transcoder: Option<std::sync::Mutex<DiscordTranscoder>>,
+    // Per-SSRC, so frames from different speakers never share a packet
+    repacketizers: std::sync::Mutex<HashMap<u32, Repacketizer>>,
+    // RTP mode only; Songbird's decoder has its own playout buffer
+    reorder: std::sync::Mutex<HashMap<u32, ReorderBuffer<(u32, Bytes)>>>,
+    reorder_window: usize,
+    recording: std::sync::Mutex<HashMap<u32, RecorderTap>>,
+    recorder: SharedRecorder,
+    channels: ChannelLayout,
+    frame_duration: Duration,
+    speakers: SharedSpeakers,
+    // Multi-track mode only
+    publishers: Option<Arc<PublisherPool>>,
+    // The merged room's track, which gets every speaker
+    merge: Option<Arc<TalkTrack>>,
+    stats: SharedStats,
+    loops: SharedLoopGuard,
+    access: SharedAccess,
+    encryption: SharedEncryption,
+    // When audio was last written to the track, for comfort noise
+    last_write: Arc<std::sync::Mutex<Instant>>,
+}
+
+impl DiscordToNextcloudHandler {
+    pub fn new(
+        track: Arc<TalkTrack>,
+        config: &BridgeConfig,
+        shared: &SessionShared,
+        publishers: Option<Arc<PublisherPool>>,
+        merge: Option<Arc<TalkTrack>>,
+        publish_loss: Arc<AtomicU8>,
+        last_write: Arc<std::sync::Mutex<Instant>>,
+    ) -> Result<Self> {
+        let d2n = &config.audio.discord_to_nextcloud;
+        let effects = shared.effects.discord_to_nextcloud.clone();
+        let transcoder = if config.audio.pipeline == PipelineMode::Transcode {
+            Some(std::sync::Mutex::new(DiscordTranscoder::new(&config.audio, effects, publish_loss)?))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            track,
+            transcoder,
+            repacketizers: std::sync::Mutex::new(HashMap::new()),
+            reorder: std::sync::Mutex::new(HashMap::new()),
+            reorder_window: config.audio.reorder_window,
+            recording: std::sync::Mutex::new(HashMap::new()),
+            recorder: shared.recorder.clone(),
+            channels: d2n.channels,
+            frame_duration: Duration::from_millis(config.audio.talk_frame_ms),
+            speakers: shared.speakers.clone(),
+            publishers,
+            merge,
+            stats: shared.stats.clone(),
+            loops: shared.loops.clone(),
+            access: shared.access.clone(),
+            encryption: shared.encryption.clone(),
+            last_write,
+        })
+    }
+
+    // The CoreEvent this handler has to be registered for
+    pub fn event(mode: ReceiveMode) -> songbird::events::CoreEvent {
+        match mode {
+            ReceiveMode::Rtp => songbird::events::CoreEvent::RtpPacket,
+            ReceiveMode::Decoded => songbird::events::CoreEvent::VoiceTick,
+        }
+    }
+
+    async fn forward(&self, ssrc: u32, timestamp: Option<u32>, frame: DiscordFrame<'_>) {
+        // Songbird's decoded ticks are 20ms; Opus says how long it plays
+        let duration = match &frame {
+            DiscordFrame::Opus(payload) => packet_duration(payload),
+            DiscordFrame::Pcm(_) => None,
+        }
+        .unwrap_or(DEFAULT_FRAME);
+        let user_id = self.speakers.lock().unwrap().user(ssrc);
+        // Another bridge speaking into the channel, or an excluded user
+        if user_id.is_some_and(|u| self.loops.ignores_discord_user(u)) {
+            return;
+        }
+        // Access codes on and the speaker hasn't sent theirs
+        if !self.access.allows(user_id) {
+            return;
+        }
+        if let Some(user_id) = user_id {
+            self.stats.lock().unwrap().add_speech(user_id, duration);
+        }
+
+        self.record(ssrc, &frame);
+
+        let own_track = self.track_for(user_id);
+        let track = own_track.as_ref().unwrap_or(&self.track);
+        let timing = || match timestamp {
+            Some(timestamp) => Timing::Rtp { ssrc, timestamp, clock_rate: audio::SAMPLE_RATE, duration },
+            None => Timing::Wall,
+        };
+        track.note_frame(timing());
+        if let Some(merge) = &self.merge {
+            merge.note_frame(timing());
+        }
+
+        let data = match (&self.transcoder, frame) {
+            (Some(transcoder), frame) => {
+                let mut transcoder = transcoder.lock().unwrap();
+                let result = match frame {
+                    DiscordFrame::Opus(payload) => transcoder.transcode(ssrc, payload),
+                    DiscordFrame::Pcm(mut pcm) => transcoder.encode_pcm(ssrc, &mut pcm),
+                };
+                match result {
+                    Ok(data) => data,
+                    Err(e) => {
+                        log!("Failed to transcode Discord audio: {:?}", e);
+                        return;
+                    }
+                }
+            }
+            (None, DiscordFrame::Opus(payload)) => Bytes::copy_from_slice(payload),
+            // Passthrough only ever gets Opus frames
+            (None, DiscordFrame::Pcm(_)) => return,
+        };
+
+        let packets = {
+            let mut repacketizers = self.repacketizers.lock().unwrap();
+            let repacketizer = repacketizers
+                .entry(ssrc)
+                .or_insert_with(|| Repacketizer::new(self.frame_duration));
+            match repacketizer.push(&data) {
+                Ok(packets) => packets,
+                Err(e) => {
+                    log!("Failed to repacketize Discord audio: {:?}", e);
+                    return;
+                }
+            }
+        };
+
+        if let Some(merge) = &self.merge {
+            merge.write(packets.clone()).await;
+        }
+        track.write(packets).await;
+        if own_track.is_none() {
+            *self.last_write.lock().unwrap() = Instant::now();
+        }
+    }
+
+    // Recorded before the effects, as passthrough never has PCM to tap
+    fn record(&self, ssrc: u32, frame: &DiscordFrame<'_>) {
+        let mut taps = self.recording.lock().unwrap();
+        // Songbird decodes to the D2N layout
+        let tap = taps
+            .entry(ssrc)
+            .or_insert_with(|| RecorderTap::new(self.recorder.clone(), format!("discord:{}", ssrc), self.channels));
+        match frame {
+            DiscordFrame::Opus(payload) => tap.push_opus(payload),
+            DiscordFrame::Pcm(pcm) => tap.push_pcm(pcm),
+        }
+    }
+
+    // Puts the speaker's packets back in sequence order and drops duplicates.
+    // Returns what can be forwarded now.
+    fn reorder(&self, ssrc: u32, seq: u16, timestamp: u32, payload: &[u8]) -> Vec<(u32, Bytes)> {
+        let mut buffers = self.reorder.lock().unwrap();
+        let buffer = buffers
+            .entry(ssrc)
+            .or_insert_with(|| ReorderBuffer::new(self.reorder_window, reorder_hold(self.reorder_window)));
+        let mut stats = self.stats.lock().unwrap();
+        buffer.push(seq, (timestamp, Bytes::copy_from_slice(payload)), &mut stats.rtp)
+    }
+
+    // Whatever has been held back for a gap longer than the hold time
+    fn reorder_expired(&self) -> Vec<(u32, Vec<(u32, Bytes)>)> {
+        let now = Instant::now();
+        let mut buffers = self.reorder.lock().unwrap();
+        let mut stats = self.stats.lock().unwrap();
+        buffers
+            .iter_mut()
+            .map(|(&ssrc, buffer)| (ssrc, buffer.flush_expired(now, &mut stats.rtp)))
+            .filter(|(_, packets)| !packets.is_empty())
+            .collect()
+    }
+
+    async fn forward_in_order(&self, ssrc: u32, packets: Vec<(u32, Bytes)>) {
+        for (timestamp, payload) in packets {
+            if payload[..] == DISCORD_SILENCE_FRAME {
+                self.end_talkspurt(ssrc).await;
+            } else {
+                self.forward(ssrc, Some(timestamp), DiscordFrame::Opus(&payload)).await;
+            }
+        }
+    }
+
+    // The speaker's own publisher track in multi-track mode, once it's up
+    fn track_for(&self, user_id: Option<u64>) -> Option<Arc<TalkTrack>> {
+        self.publishers.as_ref()?.track(user_id?)
+    }
+
+    // The speaker went quiet: send out any frames still waiting to be
+    // coalesced and then stop sending (DTX) rather than forwarding the
+    // silence frames as audio. last_write is left alone so comfort noise
+    // takes over after its hangover.
+    async fn end_talkspurt(&self, ssrc: u32) {
+        let pending = self
+            .repacketizers
+            .lock()
+            .unwrap()
+            .get_mut(&ssrc)
+            .and_then(|r| r.flush());
+        if let Some(packet) = pending {
+            let user_id = self.speakers.lock().unwrap().user(ssrc);
+            let track = self.track_for(user_id).unwrap_or_else(|| self.track.clone());
+            if let Some(merge) = &self.merge {
+                merge.write(vec![packet.clone()]).await;
+            }
+            track.write(vec![packet]).await;
+        }
+    }
+}
+
+// The Opus frame inside a received packet, `suffix` bytes of which were
+// stripped off its end. Songbird decrypts in both the Decrypt and Decode
+// modes; payload_offset is relative to the RTP body (after the fixed
+// header), not the start of the packet.
+fn opus_payload(packet: &RtpData, suffix: usize) -> Option<(u32, &[u8])> {
+    let rtp = packet.rtp();
+    let header_len = packet.packet.len() - rtp.payload().len();
+    let start = header_len + packet.payload_offset;
+    let end = packet.packet.len().checked_sub(suffix)?;
+    if start > end {
+        return None;
+    }
+    Some((rtp.get_ssrc(), &packet.packet[start..end]))
+}
+
+// A raw packet event's payload_end_pad is the length of what was stripped
+// off the end; a VoiceTick's is where the payload ends in the RTP body
+// How long packets are held for a gap: as long as `window` frames play
+fn reorder_hold(window: usize) -> Duration {
+    DEFAULT_FRAME * window as u32
+}
+
+fn tick_suffix(packet: &RtpData) -> Option<usize> {
+    packet.rtp().payload().len().checked_sub(packet.payload_end_pad)
 }
 
 #[async_trait]
 impl VoiceEventHandler for DiscordToNextcloudHandler {
     async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
-        if let EventContext::RtpPacket(packet) = ctx {
-            // Forward audio packet
-            // packet.payload should contain the Opus frame (if decrypted)
-            // Note: We are assuming Songbird decrypts it before firing RtpPacket event ??
-            // Search results said "RtpPacket... does not perform audio decoding".
-            // It didn't explicitly say if it decrypts.
-            // But usually raw UDP is encrypted.
-            // If Songbird exposes this, it might be the decrypted payload.
-            // If not, we are sending garbage.
-            // However, VoicePacket (deprecated) was "decrypted".
-            // RtpPacket is the replacement for "raw RTP access".
-
-             // data.packet is the raw RTP packet
-             // data.payload_offset is start of payload
-             // data.payload_end_pad is padding at end
-
-             let payload = &packet.packet[packet.payload_offset..packet.packet.len() - packet.payload_end_pad];
-             // println!("Got RTP packet, payload len: {}", payload.len());
-
-            // For now, let's try to forward it.
-             let sample = Sample {
-                data: Bytes::copy_from_slice(payload),
-                duration: Duration::from_millis(20), // standard opus frame
-                ..Default::default()
+        match ctx {
+            EventContext::RtpPacket(packet) => {
+                if !self.encryption.discord_packet(packet.payload_end_pad) {
+                    return None;
+                }
+                let (ssrc, payload) = opus_payload(packet, packet.payload_end_pad)?;
+                let rtp = packet.rtp();
+                let (seq, timestamp) = (rtp.get_sequence().0 .0, rtp.get_timestamp().0 .0);
+                let packets = self.reorder(ssrc, seq, timestamp, payload);
+                self.forward_in_order(ssrc, packets).await;
+            }
+            // Decoded mode: only audio Songbird managed to decrypt and decode
+            // gets through. Passthrough still forwards the original Opus
+            // frame, transcoding uses Songbird's PCM instead of decoding again.
+            EventContext::VoiceTick(tick) => {
+                for (ssrc, packets) in self.reorder_expired() {
+                    self.forward_in_order(ssrc, packets).await;
+                }
+                for (ssrc, data) in &tick.speaking {
+                    let Some(pcm) = &data.decoded_voice else {
+                        continue;
+                    };
+                    let suffix = match &data.packet {
+                        Some(packet) => match tick_suffix(packet) {
+                            Some(suffix) if self.encryption.discord_packet(suffix) => Some(suffix),
+                            _ => continue,
+                        },
+                        None => None,
+                    };
+                    let timestamp = data.packet.as_ref().map(|p| p.rtp().get_timestamp().0 .0);
+                    if self.transcoder.is_some() {
+                        self.forward(*ssrc, timestamp, DiscordFrame::Pcm(pcm.clone())).await;
+                    } else if let Some((_, payload)) = data.packet.as_ref().zip(suffix).and_then(|(p, suffix)| opus_payload(p, suffix)) {
+                        if payload != DISCORD_SILENCE_FRAME {
+                            self.forward(*ssrc, timestamp, DiscordFrame::Opus(payload)).await;
+                        }
+                    }
+                }
+                for ssrc in &tick.silent {
+                    self.end_talkspurt(*ssrc).await;
+                }
+            }
+            _ => {}
+        }
+
+        None
+    }
+}
+
+// Keeps the SSRC -> Discord user map up to date
+struct SpeakerTracker {
+    speakers: SharedSpeakers,
+    publishers: Option<Arc<PublisherPool>>,
+    stats: SharedStats,
+}
+
+#[async_trait]
+impl VoiceEventHandler for SpeakerTracker {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        match ctx {
+            EventContext::SpeakingStateUpdate(speaking) => {
+                if let Some(user_id) = speaking.user_id {
+                    self.speakers.lock().unwrap().insert(speaking.ssrc, user_id.0);
+                    self.stats.lock().unwrap().note_user(user_id.0);
+                }
+            }
+            EventContext::ClientDisconnect(disconnect) => {
+                self.speakers.lock().unwrap().remove_user(disconnect.user_id.0);
+                if let Some(publishers) = &self.publishers {
+                    publishers.remove(disconnect.user_id.0);
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+// Fills the Talk track with comfort noise whenever Discord has been quiet
+// for longer than the hangover.
+async fn comfort_noise_loop(
+    track: Arc<TalkTrack>,
+    last_write: Arc<std::sync::Mutex<Instant>>,
+    config: ComfortNoiseConfig,
+    channels: ChannelLayout,
+    frame_duration: Duration,
+) {
+    let mut encoder = match OpusEncoder::new(channels) {
+        Ok(e) => e,
+        Err(e) => {
+            log!("Failed to create comfort noise encoder: {:?}", e);
+            return;
+        }
+    };
+    let mut noise = ComfortNoise::new(config.level_db, channels.count());
+    let mut repacketizer = Repacketizer::new(frame_duration);
+    let hangover = Duration::from_millis(config.hangover_ms);
+    let mut interval = tokio::time::interval(Duration::from_millis(20));
+
+    loop {
+        interval.tick().await;
+        if last_write.lock().unwrap().elapsed() < hangover {
+            continue;
+        }
+
+        track.note_frame(Timing::Wall);
+        let data = match encoder.encode(&noise.next_frame()) {
+            Ok(data) => data,
+            Err(e) => {
+                log!("Failed to encode comfort noise: {:?}", e);
+                continue;
+            }
+        };
+        match repacketizer.push(&data) {
+            Ok(packets) => track.write(packets).await,
+            Err(e) => log!("Failed to repacketize comfort noise: {:?}", e),
+        }
+    }
+}
+
+// Reads one remote Talk audio track, puts its packets back in sequence
+// order, runs them through the Nextcloud -> Discord PCM stages and queues
+// them for the Discord mixer. Short gaps in the sequence are filled from the
+// next packet's FEC data or by PLC, so brief loss on the HPB leg doesn't drop
+// out in Discord. In a merged call the packets also go, untouched, to the
+// other room's track.
+async fn forward_nextcloud_track(
+    track: Arc<TrackRemote>,
+    input: MixerInput,
+    config: DirectionConfig,
+    reorder_window: usize,
+    shared: SessionShared,
+    relay: Option<(Arc<TalkTrack>, Duration)>,
+) {
+    let Some(clock_rate) = audit::track(track.ssrc(), &track.codec().capability, config.channels) else {
+        return;
+    };
+    let cues = shared.cues;
+    cues.talk_participant(Cue::Join, None);
+    let mut recording = RecorderTap::new(shared.recorder, format!("talk:{}", track.ssrc()), config.channels);
+    let mut decoder = match OpusDecoder::new(config.channels) {
+        Ok(d) => d,
+        Err(e) => {
+            log!("Failed to create Opus decoder: {:?}", e);
+            return;
+        }
+    };
+    let channels = config.channels.count();
+    let mut effects = EffectChain::new(shared.effects.nextcloud_to_discord, config);
+    let mut relay = relay.map(|(track, frame_duration)| (track, Repacketizer::new(frame_duration)));
+    let mut reorder = ReorderBuffer::new(reorder_window, reorder_hold(reorder_window));
+    let mut expected_seq: Option<u16> = None;
+    // Per channel, of the last packet decoded; lost packets are assumed to
+    // be the same length
+    let mut frame_samples = 0;
+    // RED's payload type, once the track's packets are RED
+    let mut red = None;
+
+    loop {
+        // Packets held for a gap go out once their hold time is up, even if
+        // nothing else arrives
+        let deadline = reorder.deadline();
+        let packets = tokio::select! {
+            read = track.read_rtp() => {
+                let packet = match read {
+                    Ok((packet, _)) => packet,
+                    Err(e) => {
+                        log!("Nextcloud track {} ended: {:?}", track.ssrc(), e);
+                        cues.talk_participant(Cue::Leave, None);
+                        break;
+                    }
+                };
+
+                // The track's codec follows the payload type of what arrives
+                let codec = track.codec();
+                if codec.capability.mime_type.eq_ignore_ascii_case(red::MIME_TYPE) {
+                    red = Some(codec.payload_type);
+                }
+
+                // Late packets and duplicates go no further
+                let seq = packet.header.sequence_number;
+                reorder.push(seq, packet, &mut shared.stats.lock().unwrap().talk_rtp)
+            }
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now).into()), if deadline.is_some() => {
+                reorder.flush_expired(Instant::now(), &mut shared.stats.lock().unwrap().talk_rtp)
+            }
+        };
+
+        for packet in packets {
+            let seq = packet.header.sequence_number;
+            let lost = match expected_seq {
+                Some(expected) => seq.wrapping_sub(expected),
+                None => 0,
             };
+            expected_seq = Some(seq.wrapping_add(1));
 
-            if let Err(e) = self.track.write_sample(&sample).await {
-                 // println!("Failed to write sample: {:?}", e);
+            let blocks = match red == Some(packet.header.payload_type) {
+                true => match red::split(&packet.payload) {
+                    Some(blocks) => blocks,
+                    None => {
+                        log!("Dropping a malformed RED packet from Nextcloud track {}", track.ssrc());
+                        continue;
+                    }
+                },
+                false => red::Blocks::plain(&packet.payload),
+            };
+            if blocks.primary.is_empty() {
+                continue;
+            }
+
+            if let Some((other, repacketizer)) = &mut relay {
+                other.note_frame(Timing::Rtp {
+                    ssrc: packet.header.ssrc,
+                    timestamp: packet.header.timestamp,
+                    clock_rate,
+                    duration: packet_duration(blocks.primary).unwrap_or(DEFAULT_FRAME),
+                });
+                match repacketizer.push(&packet.payload.slice_ref(blocks.primary)) {
+                    Ok(packets) => other.write(packets).await,
+                    Err(e) => log!("Failed to relay Nextcloud audio: {:?}", e),
+                }
+            }
+
+            // Longer gaps are a new talkspurt or a stall; the stream just resumes
+            if lost > 0 && lost <= MAX_CONCEALED_PACKETS && frame_samples > 0 {
+                // RTP timestamp ticks per packet, to find them in RED
+                let frame_ticks = (frame_samples as u64 * clock_rate as u64 / audio::SAMPLE_RATE as u64) as u32;
+                for missing in 1..=lost {
+                    let behind = (lost - missing + 1) as u32 * frame_ticks;
+                    let resent = blocks.redundant.iter().find(|(offset, _)| *offset == behind);
+                    let recovered = match resent {
+                        Some((_, frame)) => decoder.decode(frame),
+                        // Only the packet right before this one is in its FEC data
+                        None => decoder.recover((missing == lost).then_some(blocks.primary), frame_samples),
+                    };
+                    match recovered {
+                        Ok(pcm) => {
+                            effects.process(pcm);
+                            input.push(pcm);
+                            recording.push_pcm(pcm);
+                        }
+                        Err(e) => log!("Failed to conceal lost Nextcloud audio: {:?}", e),
+                    }
+                    let concealment = &mut shared.stats.lock().unwrap().concealment;
+                    match (resent, missing == lost) {
+                        (Some(_), _) => concealment.red += 1,
+                        (None, true) => concealment.fec += 1,
+                        (None, false) => concealment.plc += 1,
+                    }
+                }
+            }
+
+            match decoder.decode(blocks.primary) {
+                Ok(pcm) => {
+                    frame_samples = pcm.len() / channels;
+                    effects.process(pcm);
+                    input.push(pcm);
+                    recording.push_pcm(pcm);
+                }
+                Err(e) => log!("Failed to decode Nextcloud audio: {:?}", e),
             }
         }
+    }
+}
 
-        None
+// Per-bridge state that outlives a single session; the manager reads it
+// for status and volume changes.
+#[derive(Clone)]
+pub struct SessionShared {
+    pub mixer: SharedMixer,
+    pub speakers: SharedSpeakers,
+    pub effects: DirectionEffects,
+    // Reset at the start of each session
+    pub stats: SharedStats,
+    pub recorder: SharedRecorder,
+    pub cues: SharedCues,
+    pub loops: SharedLoopGuard,
+    pub access: SharedAccess,
+    // The bot is server-muted (or suppressed) in Discord
+    pub discord_muted: Arc<AtomicBool>,
+    // A Talk moderator muted the bridge's session; reset at the start of
+    // each session
+    pub talk_muted: watch::Sender<bool>,
+    // The connection to Talk dropped and isn't back yet; outlives the
+    // session, as starting over is one way of getting it back
+    pub talk_down: watch::Sender<bool>,
+    // Reset at the start of each session
+    pub encryption: SharedEncryption,
+    // Who is in the Talk room, cleared at the start of each session
+    pub roster: SharedRoster,
+}
+
+#[derive(Clone)]
+pub struct DirectionEffects {
+    pub discord_to_nextcloud: SharedChain,
+    pub nextcloud_to_discord: SharedChain,
+}
+
+impl DirectionEffects {
+    pub fn get(&self, direction: Direction) -> &SharedChain {
+        match direction {
+            Direction::DiscordToNextcloud => &self.discord_to_nextcloud,
+            Direction::NextcloudToDiscord => &self.nextcloud_to_discord,
+        }
     }
 }
 
@@ -65,6 +799,8 @@ pub struct BridgeSession {
     pub manager: Arc<Songbird>,
     pub guild_id: GuildId,
     pub channel_id: ChannelId,
+    pub config: BridgeConfig,
+    pub shared: SessionShared,
 }
 
 impl BridgeSession {
@@ -73,18 +809,35 @@ impl BridgeSession {
         signaling: SignalingClient,
         manager: Arc<Songbird>,
         guild_id: GuildId,
-        channel_id: ChannelId
+        channel_id: ChannelId,
+        config: BridgeConfig,
+        shared: SessionShared,
     ) -> Self {
+        *shared.stats.lock().unwrap() = CallStats::default();
+        shared.encryption.reset();
+        shared.roster.clear();
+        shared.talk_muted.send_replace(false);
+        shared.mixer.lock().unwrap().reset_buffer_counters();
         Self {
             nextcloud: Arc::new(Mutex::new(nextcloud)),
             signaling: Arc::new(Mutex::new(signaling)),
             manager,
             guild_id,
             channel_id,
+            config,
+            shared,
         }
     }
 
-    pub async fn start(&self) -> Result<()> {
+    // Runs until stopped through `control` or ended by Talk, returning why
+    // if Talk ended it, then leaves Talk and Discord either way
+    pub async fn start(&self, mut in_call: InCall, control: mpsc::Receiver<SessionControl>) -> Result<Option<String>> {
+        let result = self.run(&mut in_call, control).await;
+        self.close(&mut in_call).await;
+        result
+    }
+
+    async fn run(&self, in_call: &mut InCall, mut control: mpsc::Receiver<SessionControl>) -> Result<Option<String>> {
         // 1. Join Discord
         let handler_lock = self.manager.join(self.guild_id, self.channel_id).await;
         let handler_lock = match handler_lock {
@@ -93,126 +846,925 @@ impl BridgeSession {
         };
 
         let mut handler = handler_lock.lock().await;
+        self.shared.stats.lock().unwrap().mark_started();
+        audio::audit::pipeline(&self.config.audio, self.shared.mixer.lock().unwrap().channels());
+        // Also ends a recording started by command during the session
+        let _recording = RecordingGuard(self.shared.recorder.clone());
+        let _cues = CueGuard(self.shared.cues.clone());
+        if self.shared.recorder.enabled_by_default() {
+            if let Err(e) = self.shared.recorder.start() {
+                log!("Failed to start recording: {:?}", e);
+            }
+        }
+        if let Err(e) = self.shared.recorder.start_replay() {
+            log!("Failed to start the instant replay buffer: {:?}", e);
+        }
+        self.shared.speakers.lock().unwrap().clear();
+        let mut _own_session = self.shared.loops.add_talk_session(self.signaling.lock().await.session_id());
+        let mut _flags = self.keep_flags(in_call).await;
+
+        let multi_track = &self.config.audio.multi_track;
+        let publishers = if multi_track.enabled {
+            let sig = self.signaling.lock().await;
+            Some(PublisherPool::new(
+                sig.config().clone(),
+                sig.room_token().unwrap_or_default().to_string(),
+                self.config.audio.talk_fmtp(),
+                multi_track.max_publishers,
+                self.config.audio.pipeline == PipelineMode::Passthrough,
+                &self.shared,
+            ))
+        } else {
+            None
+        };
+        let pool = publishers.clone();
+
+        for event in [
+            songbird::events::CoreEvent::SpeakingStateUpdate,
+            songbird::events::CoreEvent::ClientDisconnect,
+        ] {
+            handler.add_global_event(
+                event.into(),
+                SpeakerTracker {
+                    speakers: self.shared.speakers.clone(),
+                    publishers: publishers.clone(),
+                    stats: self.shared.stats.clone(),
+                },
+            );
+        }
+
+        // Remote Talk tracks are decoded and mixed into a live PCM input on the
+        // call; `relay` is the other room's track in a merged call.
+        let frame_duration = Duration::from_millis(self.config.audio.talk_frame_ms);
+        let talk_to_discord = |relay: Option<Arc<TalkTrack>>| -> TrackHandler {
+            let n2d = self.config.audio.nextcloud_to_discord.clone();
+            let reorder_window = self.config.audio.talk_reorder_window;
+            let shared = self.shared.clone();
+            Arc::new(move |track, session: Option<String>| {
+                // Named after the participant when the roster knows whose it
+                // is, so per-participant gains apply; else the stream id is
+                // the only stable name there is
+                let participant = session
+                    .and_then(|session| shared.roster.get(&session))
+                    .map(|entry| entry.user_id.clone().filter(|u| !u.is_empty()).unwrap_or_else(|| entry.name().to_string()));
+                if let Some(participant) = &participant {
+                    log!("Talk audio from {}", participant);
+                }
+                let input = Mixer::add_input(&shared.mixer, &participant.unwrap_or_else(|| track.stream_id()));
+                tokio::spawn(forward_nextcloud_track(
+                    track,
+                    input,
+                    n2d.clone(),
+                    reorder_window,
+                    shared.clone(),
+                    relay.clone().map(|relay| (relay, frame_duration)),
+                ));
+            })
+        };
 
         // 2. Setup Audio Forwarding (Discord -> Nextcloud)
-        {
+        let last_write = Arc::new(std::sync::Mutex::new(Instant::now()));
+        let track = TalkTrack::new(self.nextcloud.lock().await.audio_track.clone(), self.shared.talk_muted.clone());
+        let merged = match &self.config.merge_room_token {
+            Some(room_token) => {
+                let config = self.signaling.lock().await.config().clone();
+                let p2p = self.config.p2p.enabled.then_some(self.config.p2p.max_peers);
+                Some(
+                    MergedRoom::connect(
+                        config,
+                        room_token,
+                        self.config.silent_call,
+                        &self.config.audio,
+                        &self.shared,
+                        talk_to_discord(Some(track.clone())),
+                        p2p,
+                    )
+                    .await?,
+                )
+            }
+            None => None,
+        };
+        let _comfort_noise = {
             let nc = self.nextcloud.lock().await;
-            let track = nc.audio_track.clone();
+            self.shared.cues.attach(CueTargets {
+                mixer: self.shared.mixer.clone(),
+                talk: track.clone(),
+                talk_channels: self.config.audio.discord_to_nextcloud.channels,
+                frame_duration,
+            });
+
+            let receive = self.config.audio.discord_receive;
+            if receive == ReceiveMode::Decoded {
+                let channels = match self.config.audio.discord_to_nextcloud.channels {
+                    ChannelLayout::Mono => songbird::driver::Channels::Mono,
+                    ChannelLayout::Stereo => songbird::driver::Channels::Stereo,
+                };
+                let config = handler.config().clone().decode_mode(DecodeMode::Decode).decode_channels(channels);
+                handler.set_config(config);
+            }
 
             handler.add_global_event(
-                songbird::events::CoreEvent::RtpPacket.into(),
-                DiscordToNextcloudHandler { track }
+                DiscordToNextcloudHandler::event(receive).into(),
+                DiscordToNextcloudHandler::new(
+                    track.clone(),
+                    &self.config,
+                    &self.shared,
+                    publishers,
+                    merged.as_ref().map(|m| m.track.clone()),
+                    nc.publish_loss.clone(),
+                    last_write.clone(),
+                )?
             );
-        }
-        println!("Joined Discord Channel and attached Voice Handler!");
-        drop(handler); // Release lock
 
-        // 3. Setup ICE Handling
-        let (ice_tx, mut ice_rx) = mpsc::channel::<(String, String, u16)>(32);
+            let audio = &self.config.audio;
+            audio.comfort_noise.enabled.then(|| {
+                TaskGuard(tokio::spawn(comfort_noise_loop(
+                    track.clone(),
+                    last_write,
+                    audio.comfort_noise.clone(),
+                    audio.discord_to_nextcloud.channels,
+                    Duration::from_millis(audio.talk_frame_ms),
+                )))
+            })
+        };
 
+        // 3. Setup Audio Forwarding (Nextcloud -> Discord)
+        let channels = self.shared.mixer.lock().unwrap().channels();
+        let source = PcmSource::new(self.shared.mixer.clone(), self.shared.discord_muted.clone());
+        handler.play_input(RawAdapter::new(source, audio::SAMPLE_RATE, channels as u32).into());
+        let on_track = talk_to_discord(merged.as_ref().map(|m| m.track.clone()));
         {
             let nc = self.nextcloud.lock().await;
-            nc.on_ice_candidate(Box::new(move |candidate, mid, line| {
-                 let _ = ice_tx.try_send((candidate, mid, line));
-            }));
+            let on_track = on_track.clone();
+            nc.on_audio_track(Box::new(move |track| on_track(track, None)));
         }
+        log!("Joined Discord Channel and attached Voice Handler!");
+        drop(handler); // Release lock
 
-        // 4. Main Event Loop
-        println!("Starting Bridge Event Loop...");
+        // 4+5. ICE and the signaling event loop, started over in the new
+        // room after a switch
+        let _roster = TaskGuard(tokio::spawn(roster::log_changes(self.shared.roster.subscribe(), self.shared.loops.clone())));
+        let _mute = TaskGuard(tokio::spawn(acknowledge_mute(
+            self.shared.talk_muted.subscribe(),
+            self.signaling.lock().await.sender(),
+        )));
         loop {
+            let subscribe = Some(on_track.clone());
+            let p2p = self.config.p2p.enabled.then(|| PeerToPeer {
+                max_peers: self.config.p2p.max_peers,
+                on_track: on_track.clone(),
+            });
+            let signaling = run_signaling(
+                self.nextcloud.clone(),
+                self.signaling.clone(),
+                track.clone(),
+                self.shared.loops.clone(),
+                p2p,
+                subscribe,
+                Some(self.shared.clone()),
+            );
             tokio::select! {
-                // Receive Local ICE candidate -> Send to Signaling
-                Some((candidate, mid, line)) = ice_rx.recv() => {
-                    // println!("Sending ICE candidate");
-                    let mut sig = self.signaling.lock().await;
-                    // Assuming we send to "server" or broadcast?
-                    // For HPB, recipient might be needed or handled by server.
-                    // Usually for HPB: we just send it.
-                    if let Err(e) = sig.send_candidate(candidate, mid, line, "".to_string()).await {
-                        println!("Error sending candidate: {:?}", e);
-                    }
-                }
-
-                // Receive Signaling Message
-                msg_result = async {
-                    let mut sig = self.signaling.lock().await;
-                    sig.next_message().await
-                } => {
-                     match msg_result {
-                        Ok(Some(msg)) => {
-                            self.handle_signaling_message(msg).await?;
+                result = signaling => match result {
+                    // Lost without a way to resume
+                    Ok(None) => {
+                        self.reconnect(in_call, &on_track).await?;
+                        _own_session = self.shared.loops.add_talk_session(self.signaling.lock().await.session_id());
+                        _flags = self.keep_flags(in_call).await;
+                    }
+                    result => return result,
+                },
+                _ = self.shared.encryption.refused() => anyhow::bail!("Encryption downgrade, ENCRYPTION_POLICY=refuse"),
+                request = control.recv() => match request {
+                    Some(SessionControl::SwitchRoom(room_token, done)) => {
+                        if let Err(e) = self.rebind_room(&room_token, in_call, &on_track).await {
+                            let _ = done.send(Err(anyhow::anyhow!("{:#}", e)));
+                            return Err(e.context("Failed to switch Talk rooms"));
+                        }
+                        if let Some(pool) = &pool {
+                            pool.switch_room(&room_token);
+                        }
+                        _own_session = self.shared.loops.add_talk_session(self.signaling.lock().await.session_id());
+                        _flags = self.keep_flags(in_call).await;
+                        let _ = done.send(Ok(()));
+                    }
+                    Some(SessionControl::Stop) | None => return Ok(None),
+                },
+            }
+        }
+    }
+
+    // Leaves the current Talk room and joins `room_token`'s call instead,
+    // on a new peer connection publishing the same track; the Discord side
+    // carries on untouched. `on_track` gets the new room's audio.
+    pub async fn rebind_room(&self, room_token: &str, in_call: &mut InCall, on_track: &TrackHandler) -> Result<()> {
+        in_call.leave().await;
+        self.shared.roster.clear();
+        let mut sig = self.signaling.lock().await;
+        sig.switch_room(room_token).await.context("Failed to connect to Signaling")?;
+        let calls = CallClient::new(sig.config().clone()).with_session(sig.session_cookie());
+        *in_call = InCall::join(calls, room_token, self.config.silent_call)
+            .await
+            .context("Failed to join Talk call")?;
+        let ice_servers = sig.ice_servers();
+        drop(sig);
+
+        let mut nc = self.nextcloud.lock().await;
+        let replacement = NextcloudWebRTC::with_track(
+            nc.audio_track.clone(),
+            nc.publish_loss.clone(),
+            nc.bandwidth,
+            nc.munging.clone(),
+            nc.encryption.clone(),
+            ice_servers,
+        )
+        .await
+        .context("Failed to init WebRTC")?;
+        if let Err(e) = nc.close().await {
+            log!("Failed to close the Talk peer connection: {:#}", e);
+        }
+        let on_track = on_track.clone();
+        replacement.on_audio_track(Box::new(move |track| on_track(track, None)));
+        *nc = replacement;
+        log!("Bridge moved to Talk room {}", room_token);
+        Ok(())
+    }
+
+    // A new session in the same room once the old one is gone for good,
+    // with a new call join and peer connection; tried with backoff
+    async fn reconnect(&self, in_call: &mut InCall, on_track: &TrackHandler) -> Result<()> {
+        let (room_token, config) = {
+            let sig = self.signaling.lock().await;
+            (sig.room_token().map(str::to_string), sig.config().reconnect)
+        };
+        let room_token = room_token.context("Signaling was never connected")?;
+        let mut backoff = Backoff::new(config);
+        loop {
+            let Some(delay) = backoff.next() else {
+                anyhow::bail!("Gave up reconnecting to Talk after {} attempts", backoff.attempt());
+            };
+            log!("Reconnecting to Talk in {:.1}s (attempt {})", delay.as_secs_f32(), backoff.attempt());
+            tokio::time::sleep(delay).await;
+            match self.rebind_room(&room_token, in_call, on_track).await {
+                Ok(()) => return Ok(()),
+                Err(e) => log!("Reconnecting to Talk failed: {:#}", e),
+            }
+        }
+    }
+
+    // Puts the audio flag back whenever Talk drops it, for the current call
+    async fn keep_flags(&self, in_call: &InCall) -> Option<TaskGuard> {
+        let session = self.signaling.lock().await.session_id().map(str::to_string)?;
+        Some(TaskGuard(in_call.keep_flags(self.shared.roster.subscribe(), session)))
+    }
+
+    // After run() returned: says bye to the signaling server, leaves the
+    // Talk call and room, closes the peer connection and leaves Discord, so
+    // nothing lingers as a ghost participant.
+    async fn close(&self, in_call: &mut InCall) {
+        self.signaling.lock().await.bye().await;
+        in_call.leave().await;
+        if let Err(e) = self.nextcloud.lock().await.close().await {
+            log!("Failed to close the Talk peer connection: {:#}", e);
+        }
+        if self.manager.get(self.guild_id).is_some() {
+            if let Err(e) = self.manager.remove(self.guild_id).await {
+                log!("Failed to leave the Discord voice channel: {:?}", e);
+            }
+        }
+    }
+}
+
+// What the manager can ask of a running session
+pub enum SessionControl {
+    // Leave Talk and Discord and return
+    Stop,
+    // Move to another Talk room, answering once there (or not)
+    SwitchRoom(String, oneshot::Sender<Result<()>>),
+}
+
+// What run_signaling needs to connect to participants directly when the
+// signaling server has no MCU
+pub struct PeerToPeer {
+    pub max_peers: usize,
+    pub on_track: TrackHandler,
+}
+
+// Tries at resuming a dropped signaling connection while the server still
+// has the session, which keeps the media connections. Returns whether it
+// worked.
+async fn resume_signaling(signaling: &Mutex<SignalingClient>) -> bool {
+    let mut sig = signaling.lock().await;
+    let mut backoff = Backoff::new(sig.config().reconnect);
+    while sig.can_resume() {
+        let Some(delay) = backoff.next() else {
+            break;
+        };
+        tokio::time::sleep(delay).await;
+        match sig.resume().await {
+            Ok(()) => return true,
+            Err(e) => log!("Signaling resume attempt {} failed: {:#}", backoff.attempt(), e),
+        }
+    }
+    false
+}
+
+// Pumps one Talk connection: local ICE candidates out, offers/answers/
+// candidates in, moderator mutes applied to its track. Offers for streams
+// this process publishes itself are turned down. Without an MCU and with
+// `p2p` given, every participant in the call gets a connection of its own
+// instead. With an MCU and `subscribe` given, every Talk publisher's audio
+// is asked for and received on a connection of its own, going to
+// `subscribe`. Returns when the signaling connection closes, with the
+// reason if it was Talk that ended our part in the room (a bye, a kick, the
+// room deleted), after which there is no point in reconnecting. When the
+// Talk connection drops and ICE restarts don't bring it back, it returns
+// Ok(None) as well, to start the session over. With `shared`, the room's
+// roster is kept up to date and the connections' WebRTC stats go into the
+// session's stats.
+pub async fn run_signaling(
+    nextcloud: Arc<Mutex<NextcloudWebRTC>>,
+    signaling: Arc<Mutex<SignalingClient>>,
+    track: Arc<TalkTrack>,
+    loops: SharedLoopGuard,
+    p2p: Option<PeerToPeer>,
+    subscribe: Option<TrackHandler>,
+    shared: Option<SessionShared>,
+) -> Result<Option<String>> {
+    let roster = shared.as_ref().map(|s| s.roster.clone());
+    // 4. Setup ICE Handling
+    let (ice_tx, mut ice_rx) = mpsc::channel::<(String, String, u16)>(32);
+    let (peer_ice_tx, mut peer_ice_rx) = mpsc::channel::<PeerCandidate>(32);
+    let (status_tx, mut status_rx) = mpsc::channel::<PeerStatus>(32);
+    let (state_tx, mut state_rx) = mpsc::channel::<PeerState>(32);
+
+    let (has_mcu, own_session) = {
+        let sig = signaling.lock().await;
+        (sig.has_mcu(), sig.session_id().map(str::to_string))
+    };
+    let (peers, mut connection) = {
+        let nc = nextcloud.lock().await;
+        nc.on_ice_candidate(Box::new(move |candidate, mid, line| {
+             let _ = ice_tx.try_send((candidate, mid, line));
+        }));
+
+        let peers = match p2p {
+            Some(p2p) if !has_mcu => {
+                log!("Signaling server has no MCU, connecting to participants directly");
+                Some(PeerManager::new(
+                    &nc,
+                    own_session.clone(),
+                    p2p.max_peers,
+                    p2p.on_track,
+                    peer_ice_tx.clone(),
+                    status_tx.clone(),
+                    state_tx.clone(),
+                ))
+            }
+            None if !has_mcu => {
+                log!("Signaling server has no MCU and this connection doesn't do P2P; no audio will flow");
+                None
+            }
+            _ => subscribe.map(|on_track| {
+                log!("Subscribing to each Talk publisher through the MCU");
+                PeerManager::new(
+                    &nc,
+                    own_session.clone(),
+                    MAX_SUBSCRIPTIONS,
+                    on_track,
+                    peer_ice_tx.clone(),
+                    status_tx.clone(),
+                    state_tx.clone(),
+                )
+            }),
+        };
+        (peers, nc.state())
+    };
+
+    // Neither needs the signaling lock, so sending and receiving never wait
+    // on each other
+    let (sender, incoming) = {
+        let sig = signaling.lock().await;
+        (sig.sender(), sig.incoming())
+    };
+    let mut incoming = incoming.try_lock_owned().context("Signaling is already being read")?;
+
+    // 5. Main Event Loop
+    log!("Starting Bridge Event Loop...");
+    let mut ended = None;
+    // When to restart ICE next, while the connection is down
+    let mut restart_at: Option<Instant> = None;
+    let mut restarts = 0;
+    let mut renew_turn_at = turn_renewal(signaling.lock().await.ice_servers().expires);
+    let mut sample_stats = tokio::time::interval(WEBRTC_STATS_INTERVAL);
+    let mut sampled = Instant::now();
+    loop {
+        tokio::select! {
+            _ = sample_stats.tick(), if shared.is_some() => {
+                let mut sample = WebRtcStats::default();
+                sample.add(&nextcloud.lock().await.peer_connection.get_stats().await);
+                if let Some(peers) = &peers {
+                    for peer in peers.connections().await {
+                        sample.add(&peer.peer_connection.get_stats().await);
+                    }
+                }
+                if let Some(shared) = &shared {
+                    let mut stats = shared.stats.lock().unwrap();
+                    sample.rates(&stats.webrtc, sampled.elapsed());
+                    stats.webrtc = sample;
+                }
+                sampled = Instant::now();
+            }
+
+            Ok(()) = connection.changed() => {
+                let state = *connection.borrow_and_update();
+                if let Some(shared) = &shared {
+                    let counters = &mut shared.stats.lock().unwrap().connection;
+                    counters.state = Some(state.to_string());
+                    if state == RTCPeerConnectionState::Failed {
+                        counters.failures += 1;
+                    }
+                }
+                match state {
+                    RTCPeerConnectionState::Connected => {
+                        if restarts > 0 {
+                            log!("ICE restart brought the Talk connection back");
+                        }
+                        restart_at = None;
+                        restarts = 0;
+                        if let Some(shared) = &shared {
+                            shared.talk_down.send_if_modified(|down| std::mem::replace(down, false));
+                        }
+                    }
+                    RTCPeerConnectionState::Disconnected => {
+                        restart_at.get_or_insert_with(|| Instant::now() + DISCONNECTED_GRACE);
+                    }
+                    RTCPeerConnectionState::Failed => restart_at = Some(Instant::now()),
+                    RTCPeerConnectionState::Closed => {
+                        // Moving to another room closes the connection it
+                        // replaces; the new one is followed from here on
+                        let current = nextcloud.lock().await.state();
+                        if !current.same_channel(&connection) {
+                            connection = current;
+                            if let Some(shared) = &shared {
+                                shared.stats.lock().unwrap().connection.state = Some(connection.borrow().to_string());
+                            }
+                            continue;
+                        }
+                        log!("Talk connection was closed, starting over");
+                        if let Some(shared) = &shared {
+                            let counters = &mut shared.stats.lock().unwrap().connection;
+                            counters.failures += 1;
+                            counters.reconnects += 1;
+                            shared.talk_down.send_if_modified(|down| !std::mem::replace(down, true));
                         }
-                        Ok(None) => {
-                            println!("Signaling connection closed");
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+
+            _ = tokio::time::sleep_until(restart_at.unwrap_or_else(Instant::now).into()), if restart_at.is_some() => {
+                if let Some(shared) = &shared {
+                    shared.talk_down.send_if_modified(|down| !std::mem::replace(down, true));
+                }
+                if restarts == ICE_RESTARTS {
+                    log!("{} ICE restarts didn't bring the Talk connection back, starting over", ICE_RESTARTS);
+                    if let Some(shared) = &shared {
+                        shared.stats.lock().unwrap().connection.reconnects += 1;
+                    }
+                    break;
+                }
+                // An ICE restart keeps the connection's TURN credentials;
+                // renewed ones need a connection of their own
+                let expires = nextcloud.lock().await.ice_servers.expires;
+                if expires != signaling.lock().await.ice_servers().expires {
+                    log!("Talk connection is down and Talk's TURN credentials were renewed since it was made, starting over");
+                    if let Some(shared) = &shared {
+                        shared.stats.lock().unwrap().connection.reconnects += 1;
+                    }
+                    break;
+                }
+                restarts += 1;
+                if let Some(shared) = &shared {
+                    shared.stats.lock().unwrap().connection.ice_restarts += 1;
+                }
+                log!("Talk connection is down, restarting ICE ({}/{})", restarts, ICE_RESTARTS);
+                if let Err(e) = restart_ice(&nextcloud, &sender, has_mcu).await {
+                    log!("Failed to restart ICE: {:#}", e);
+                }
+                restart_at = Some(Instant::now() + ICE_RESTART_TIMEOUT);
+            }
+
+            _ = tokio::time::sleep_until(renew_turn_at.unwrap_or_else(Instant::now).into()), if renew_turn_at.is_some() => {
+                let mut sig = signaling.lock().await;
+                match sig.refresh_ice_servers().await {
+                    Ok(()) => log!("Renewed Talk's TURN credentials"),
+                    Err(e) => log!("Failed to renew Talk's TURN credentials: {:#}", e),
+                }
+                let ice_servers = sig.ice_servers();
+                renew_turn_at = turn_renewal(ice_servers.expires);
+                if let Some(peers) = &peers {
+                    peers.set_ice_servers(ice_servers);
+                }
+            }
+
+            // Receive Local ICE candidate -> Send to Signaling
+            Some((candidate, mid, line)) = ice_rx.recv() => {
+                // Gathering starts with the answer, so whose offer it was
+                // is known by now
+                let Some(remote) = nextcloud.lock().await.remote() else {
+                    log!("Dropping an ICE candidate gathered before any offer");
+                    continue;
+                };
+                if let Err(e) = sender.send_candidate(candidate, mid, line, &remote) {
+                    log!("Error sending candidate: {:?}", e);
+                }
+            }
+
+            Some((session, candidate, mid, line)) = peer_ice_rx.recv() => {
+                let to = CallTarget { session, sid: None };
+                if let Err(e) = sender.send_candidate(candidate, mid, line, &to) {
+                    log!("Error sending candidate: {:?}", e);
+                }
+            }
+
+            // A participant's or publisher's connection that failed or was
+            // closed from their end is dropped and made again
+            Some((session, state)) = state_rx.recv() => {
+                if !matches!(state, RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed) {
+                    continue;
+                }
+                let Some(peers) = &peers else {
+                    continue;
+                };
+                if !peers.remove_dead(&session).await {
+                    continue;
+                }
+                // Gone for good when they left the call with it
+                if roster.as_ref().is_some_and(|r| r.get(&session).is_none_or(|entry| !entry.in_call())) {
+                    continue;
+                }
+                if let Some(shared) = &shared {
+                    shared.stats.lock().unwrap().connection.peer_failures += 1;
+                }
+                let (own, mcu) = {
+                    let sig = signaling.lock().await;
+                    (sig.session_id().map(str::to_string), sig.has_mcu())
+                };
+                let Some(own) = own else {
+                    continue;
+                };
+                log!("Connecting to {} again", session);
+                if let Err(e) = connect_peer(&signaling, peers, &session, &own, mcu).await {
+                    log!("Failed to connect to {} again: {:#}", session, e);
+                }
+            }
+
+            // Mute, speaking and nick changes some Talk clients only send on
+            // their data channel
+            Some((session, status)) = status_rx.recv() => {
+                if let Some(roster) = &roster {
+                    roster.apply_status(&session, &status);
+                }
+            }
+
+            // Receive Signaling Message
+            Some(msg_result) = incoming.recv() => {
+                 match msg_result {
+                    Ok(Some(msg)) => {
+                        let result =
+                            handle_signaling_message(&nextcloud, &signaling, &track, &loops, peers.as_ref(), roster.as_deref(), msg)
+                                .await;
+                        match result {
+                            Ok(None) => {}
+                            Ok(Some(reason)) => {
+                                log!("Talk ended the session: {}", reason);
+                                ended = Some(reason);
+                                break;
+                            }
+                            Err(e) => {
+                                if let Some(peers) = &peers {
+                                    peers.close().await;
+                                }
+                                return Err(e);
+                            }
+                        }
+                    }
+                    Ok(None) => {
+                        log!("Signaling connection closed");
+                        if !resume_signaling(&signaling).await {
                             break;
                         }
-                        Err(e) => {
-                            println!("Signaling error: {:?}", e);
+                    }
+                    Err(e) => {
+                        log!("Signaling error: {:?}", e);
+                        if !resume_signaling(&signaling).await {
                             break;
                         }
-                     }
+                    }
+                 }
+            }
+
+        }
+    }
+
+    if let Some(peers) = &peers {
+        peers.close().await;
+    }
+    Ok(ended)
+}
+
+// When to renew TURN credentials expiring at `expires`; soon when that is
+// already past, e.g. after a failed renewal
+fn turn_renewal(expires: Option<SystemTime>) -> Option<Instant> {
+    let left = expires?.duration_since(SystemTime::now()).unwrap_or_default();
+    Some(Instant::now() + left.saturating_sub(TURN_RENEWAL_MARGIN).max(TURN_RENEWAL_RETRY))
+}
+
+// New ICE credentials for the main Talk connection. With an MCU only Janus
+// offers, so the publisher's offer is asked for again; peer to peer we send
+// an offer of our own.
+async fn restart_ice(nextcloud: &Mutex<NextcloudWebRTC>, sender: &SignalingSender, has_mcu: bool) -> Result<()> {
+    let nc = nextcloud.lock().await;
+    let Some(remote) = nc.remote() else {
+        return Ok(());
+    };
+    if has_mcu {
+        return sender.request_offer(&remote.session);
+    }
+    let offer = nc.restart_ice().await?;
+    sender.send_sdp("offer", offer, &remote)
+}
+
+// Ok(Some(reason)) when the message ends our part in the room
+async fn handle_signaling_message(
+    nextcloud: &Mutex<NextcloudWebRTC>,
+    signaling: &Mutex<SignalingClient>,
+    track: &TalkTrack,
+    loops: &LoopGuard,
+    peers: Option<&PeerManager>,
+    roster: Option<&Roster>,
+    msg: SignalingMessage,
+) -> Result<Option<String>> {
+    match msg {
+        // The server moves us out of the room when a moderator removes us
+        SignalingMessage::Room(room) if room.roomid.is_empty() => {
+            return Ok(Some("the bridge was removed from the Talk room".to_string()));
+        }
+        SignalingMessage::Room(room) => {
+            log!("Joined Nextcloud Room {} successfully!", room.roomid);
+        }
+        SignalingMessage::Message(envelope) => {
+            // Offers, answers and candidates, in Talk's call message format
+            let Some(call) = envelope.call() else {
+                log!("Ignoring signaling message without a call message: {}", envelope.data);
+                return Ok(None);
+            };
+            let from = envelope.sender_session(&call).unwrap_or_default();
+            let reply_to = CallTarget { session: from.clone(), sid: call.sid.clone() };
+            let from_us = loops.is_own_talk_session(&from);
+            // Nothing of a screen share reaches Discord; it is only noted
+            // who is sharing instead of answering it like the call's audio
+            if call.room_type.as_deref() == Some("screen") || call.kind == "unshareScreen" {
+                let sharing = call.kind == "offer";
+                if sharing || call.kind == "unshareScreen" {
+                    log!("Talk session {} {} screen sharing", from, if sharing { "started" } else { "stopped" });
+                    if let Some(roster) = roster {
+                        roster.set_screen(&from, sharing);
+                    }
+                }
+                return Ok(None);
+            }
+            match call.kind.as_str() {
+                "offer" if from_us => {
+                    // Subscribing would play our own Discord audio back into Discord
+                    log!("Ignoring offer for one of our own streams");
+                }
+                "offer" if peers.is_some() => {
+                    log!("Received Offer from {}", from);
+                    if let (Some(peers), Some(sdp)) = (peers, call.sdp()) {
+                        if let Some(answer_sdp) = peers.handle_offer(&from, sdp.to_string()).await? {
+                            signaling.lock().await.sender().send_sdp("answer", answer_sdp, &reply_to)?;
+                        }
+                    }
                 }
+                "offer" => {
+                    log!("Received Offer");
+                    if let Some(sdp) = call.sdp() {
+                        let nc = nextcloud.lock().await;
+                        nc.set_remote(reply_to.clone());
+                        // Later offers change tracks on a connection that
+                        // already carries the call; one of those failing
+                        // doesn't end it
+                        let renegotiation = nc.is_negotiated().await;
+                        let answer_sdp = match nc.handle_offer(sdp.to_string()).await {
+                            Ok(Some(answer_sdp)) => answer_sdp,
+                            Ok(None) => return Ok(None),
+                            Err(e) if renegotiation => {
+                                log!("Failed to renegotiate, keeping the media as it was: {:#}", e);
+                                return Ok(None);
+                            }
+                            Err(e) => return Err(e),
+                        };
 
-                // Keep-alive/Other check?
-                // _ = tokio::time::sleep(Duration::from_secs(60)) => {
-                //    println!("Bridge active...");
-                // }
+                        let sig = signaling.lock().await;
+                        // Send Answer to whoever sent the offer (the MCU
+                        // relays for the publisher)
+                        sig.sender().send_sdp("answer", answer_sdp, &reply_to)?;
+                        log!("Sent Answer");
+                    }
+                }
+                "answer" if peers.is_some() => {
+                    if let (Some(peers), Some(sdp)) = (peers, call.sdp()) {
+                        peers.handle_answer(&from, sdp.to_string()).await?;
+                    }
+                }
+                "answer" => {
+                    log!("Received Answer");
+                    if let Some(sdp) = call.sdp() {
+                        let nc = nextcloud.lock().await;
+                        nc.handle_answer(sdp.to_string()).await?;
+                        log!("Handled Answer");
+                    }
+                }
+                "candidate" => {
+                    if let Some((cand, mid, line)) = call.candidate() {
+                        match peers {
+                            Some(peers) => peers.add_ice_candidate(&from, cand, mid, line).await?,
+                            None => {
+                                let nc = nextcloud.lock().await;
+                                nc.add_ice_candidate(cand, mid, line).await?;
+                            }
+                        }
+                    }
+                }
+                "raiseHand" => {
+                    if let (Some(roster), Some(raised)) = (roster, call.hand()) {
+                        roster.set_hand(&from, raised);
+                    }
+                }
+                "reaction" => {
+                    if let (Some(roster), Some(reaction)) = (roster, call.reaction()) {
+                        roster.react(&from, reaction);
+                    }
+                }
+                "nickChanged" | "mute" | "unmute" => {
+                    if let Some(roster) = roster {
+                        roster.apply_status(&from, &call);
+                    }
+                }
+                // Internal signaling carries control messages as call messages
+                "control" => control(signaling, track, &call.payload).await,
+                _ => {}
             }
         }
+        SignalingMessage::Control(envelope) => control(signaling, track, &envelope.data).await,
+        SignalingMessage::Event(event) => {
+            if let Some(reason) = removed(signaling, &event).await {
+                return Ok(Some(reason));
+            }
+            if let Some(roster) = roster {
+                roster.apply(&event);
+            }
+            if event.target == "room" && event.kind == "join" {
+                let sig = signaling.lock().await;
+                if event.join.iter().any(|joined| Some(joined.sessionid.as_str()) != sig.session_id()) {
+                    sig.announce_nick();
+                }
+            }
+            if let Some(peers) = peers {
+                follow_peers(signaling, loops, peers, &event).await?;
+            }
+        }
+        SignalingMessage::Error(error) if error.is_fatal() => {
+            return Err(anyhow::Error::new(error).context("Signaling server ended the session"));
+        }
+        SignalingMessage::Error(error) => log!("Signaling server error: {}", error),
+        SignalingMessage::Bye(bye) => {
+            let reason = bye.reason.as_deref().unwrap_or("no reason given");
+            return Ok(Some(format!("the signaling server said bye ({})", reason)));
+        }
+        SignalingMessage::Unknown { kind, body } => {
+            log!("Unhandled signaling message {}: {}", kind, body);
+        }
+        SignalingMessage::Welcome(_) | SignalingMessage::Hello(_) => {}
+    }
+    Ok(None)
+}
 
-        Ok(())
+// Whether an event takes us out of our room: disinvited, the room deleted,
+// or our own session listed as leaving
+async fn removed(signaling: &Mutex<SignalingClient>, event: &protocol::Event) -> Option<String> {
+    let sig = signaling.lock().await;
+    match (event.target.as_str(), event.kind.as_str()) {
+        ("roomlist", "disinvite") => {
+            let disinvite = event.disinvite.as_ref()?;
+            if Some(disinvite.roomid.as_str()) != sig.room_token() {
+                return None;
+            }
+            Some(match disinvite.reason.as_str() {
+                "deleted" => "the Talk room was deleted".to_string(),
+                _ => "the bridge was removed from the Talk room".to_string(),
+            })
+        }
+        ("room", "leave") => {
+            let own = sig.session_id()?;
+            event
+                .leave
+                .iter()
+                .any(|s| s == own)
+                .then(|| "the bridge was removed from the Talk room".to_string())
+        }
+        _ => None,
     }
+}
 
-    async fn handle_signaling_message(&self, msg: SignalingMessage) -> Result<()> {
-        match msg {
-            SignalingMessage::Hello { .. } => {},
-            SignalingMessage::Joined { .. } => {
-                println!("Joined Nextcloud Room successfully!");
-            },
-            SignalingMessage::Message { data } => {
-                // Handle Offer/Answer/Candidate
-                // data is JSON Value
-                let type_ = data.get("type").and_then(|v| v.as_str());
-                match type_ {
-                    Some("offer") => {
-                         println!("Received Offer");
-                         if let Some(sdp) = data.get("sdp").and_then(|v| v.as_str()) {
-                             let nc = self.nextcloud.lock().await;
-                             let answer_sdp = nc.handle_offer(sdp.to_string()).await?;
-
-                             let mut sig = self.signaling.lock().await;
-                             // Send Answer
-                             // Recipient? usually whoever sent the offer.
-                             // But in HPB/Janus, we usually reply to the backend.
-                             let sender = data.get("sender").and_then(|v| v.as_str()).unwrap_or("");
-                             sig.send_sdp("answer", answer_sdp, sender.to_string()).await?;
-                             println!("Sent Answer");
-                         }
-                    },
-                    Some("answer") => {
-                         println!("Received Answer");
-                         if let Some(sdp) = data.get("sdp").and_then(|v| v.as_str()) {
-                             let nc = self.nextcloud.lock().await;
-                             nc.handle_answer(sdp.to_string()).await?;
-                             println!("Handled Answer");
-                         }
-                    },
-                    Some("candidate") => {
-                         // println!("Received Candidate");
-                         if let (Some(cand), Some(mid), Some(line)) = (
-                             data.get("candidate").and_then(|v| v.as_str()),
-                             data.get("sdpMid").and_then(|v| v.as_str()),
-                             data.get("sdpMLineIndex").and_then(|v| v.as_u64())
-                         ) {
-                             let nc = self.nextcloud.lock().await;
-                             nc.add_ice_candidate(cand.to_string(), mid.to_string(), line as u16).await?;
-                         }
-                    },
-                    _ => {}
+// Tells the room when the bridge is muted or unmuted, as Talk's clients do,
+// so it shows as muted there
+async fn acknowledge_mute(mut muted: watch::Receiver<bool>, sender: SignalingSender) {
+    while muted.changed().await.is_ok() {
+        let now = *muted.borrow_and_update();
+        if let Err(e) = sender.send_mute(now) {
+            log!("Failed to tell Talk the bridge is {}: {:#}", if now { "muted" } else { "unmuted" }, e);
+        }
+    }
+}
+
+// Moderators can mute others but not unmute them
+async fn control(signaling: &Mutex<SignalingClient>, track: &TalkTrack, data: &serde_json::Value) {
+    let action = data.get("action").and_then(|v| v.as_str());
+    let peer = data.get("peerId").and_then(|v| v.as_str());
+    if action == Some("forceMute") && peer.is_some() && peer == signaling.lock().await.session_id() {
+        track.set_muted();
+    }
+}
+
+// Connects to a participant in the call, or to their publisher: with an MCU
+// their offer is asked for; without one, of each pair the session that
+// sorts higher offers to the other. Nothing is done when already connected.
+async fn connect_peer(
+    signaling: &Mutex<SignalingClient>,
+    peers: &PeerManager,
+    session: &str,
+    own: &str,
+    mcu: bool,
+) -> Result<()> {
+    if mcu {
+        if peers.request(session).await {
+            log!("Requesting the audio of {} from the MCU", session);
+            signaling.lock().await.sender().request_offer(session)?;
+        }
+    } else if session < own {
+        if let Some(offer) = peers.offer(session).await? {
+            let to = CallTarget { session: session.to_string(), sid: None };
+            signaling.lock().await.sender().send_sdp("offer", offer, &to)?;
+        }
+    }
+    Ok(())
+}
+
+// P2P: connect to participants as they join the call and drop them when
+// they leave. Like Talk's own clients, of two participants the one with the
+// greater session id sends the offer, so both sides don't offer at once.
+// MCU: ask for the offer of everyone who joins the call with audio, and
+// drop their subscription once they are without it.
+async fn follow_peers(
+    signaling: &Mutex<SignalingClient>,
+    loops: &LoopGuard,
+    peers: &PeerManager,
+    event: &protocol::Event,
+) -> Result<()> {
+    match (event.target.as_str(), event.kind.as_str()) {
+        ("participants", "update") => {
+            let (own, mcu) = {
+                let sig = signaling.lock().await;
+                (sig.session_id().map(str::to_string), sig.has_mcu())
+            };
+            let Some(own) = own else {
+                return Ok(());
+            };
+            let users = event.update.iter().flat_map(|u| &u.users);
+            for user in users {
+                let session = user.session_id.as_str();
+                if session.is_empty() || session == own || loops.is_own_talk_session(session) {
+                    continue;
                 }
-            },
-            _ => {}
+                if user.in_call == 0 {
+                    peers.remove(session).await;
+                } else if mcu && user.in_call & IN_CALL_WITH_AUDIO == 0 {
+                    // Still in the call, but their audio publisher is gone
+                    peers.remove(session).await;
+                } else {
+                    connect_peer(signaling, peers, session, &own, mcu).await?;
+                }
+            }
         }
-        Ok(())
+        ("room", "leave") => {
+            for session in &event.leave {
+                peers.remove(session).await;
+            }
+        }
+        _ => {}
     }
+    Ok(())
 }
@@ -0,0 +1,439 @@
+use serenity::builder::{
+    CreateCommand, CreateCommandOption, CreateInteractionResponse, CreateInteractionResponseMessage,
+    EditInteractionResponse,
+};
+use serenity::model::application::{CommandInteraction, CommandOptionType, ResolvedOption, ResolvedValue};
+use serenity::model::channel::ChannelType;
+use serenity::model::permissions::Permissions;
+use serenity::prelude::*;
+use std::sync::Arc;
+
+use crate::manager::BridgeManager;
+use crate::settings::{Scope, KEYS};
+
+// Subcommands that can take longer than the 3 seconds Discord waits for an
+// answer; they are answered as thinking first and the reply edited in
+const DEFERRED: &[&str] = &["newroom", "clip", "room"];
+
+// The `/bridge` slash command and its subcommands, for one bot. Each bot
+// registers its own command, which controls that bot's bridge in the guild.
+pub struct BridgeCommands {
+    pub bot: usize,
+    pub manager: Arc<BridgeManager>,
+}
+
+impl BridgeCommands {
+    pub async fn register(&self, ctx: &Context) -> serenity::Result<()> {
+        // Its subcommands move, mute and record the call, so only those who
+        // may manage the server get it unless its settings say otherwise
+        let bridge = CreateCommand::new("bridge")
+            .description("Control the Nextcloud Talk bridge")
+            .default_member_permissions(Permissions::MANAGE_GUILD)
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "volume",
+                    "Set the playback volume of a Talk participant (remembered across calls)",
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(CommandOptionType::String, "participant", "Talk user, e.g. @alice")
+                        .required(true),
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(CommandOptionType::String, "gain", "Gain in dB, e.g. -6dB")
+                        .required(true),
+                ),
+            )
+            .add_option(CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "ring",
+                "Ring the Talk room members who aren't in the call yet",
+            ))
+            .add_option(CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "unmute",
+                "Be heard in Talk again after a Talk moderator muted the bridge",
+            ))
+            .add_option(
+                CreateCommandOption::new(CommandOptionType::SubCommand, "record", "Start or stop recording the call")
+                    .add_sub_option(
+                        CreateCommandOption::new(CommandOptionType::Boolean, "enabled", "Record the call")
+                            .required(true),
+                    ),
+            )
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "clip",
+                    "Post the last seconds of the call to this channel",
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(CommandOptionType::Integer, "seconds", "How far back (default: 30)")
+                        .min_int_value(1)
+                        .max_int_value(600),
+                ),
+            )
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "newroom",
+                    "Create a Talk room and bridge this channel to it",
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(CommandOptionType::String, "name", "Name of the Talk room").required(true),
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::String,
+                        "type",
+                        "Public rooms can be joined with the link, group rooms by invitation (default: public)",
+                    )
+                    .add_string_choice("public", "public")
+                    .add_string_choice("group", "group"),
+                )
+                .add_sub_option(CreateCommandOption::new(
+                    CommandOptionType::String,
+                    "password",
+                    "Password for joining a public room",
+                )),
+            )
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "room",
+                    "Bridge this channel to another Talk room, without leaving the call here",
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(CommandOptionType::String, "token", "Talk room token or link")
+                        .required(true),
+                ),
+            )
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "rebind",
+                    "Bridge another channel, or resume after the bridged one was changed",
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::Channel,
+                        "channel",
+                        "Voice channel (category for temporary channels)",
+                    )
+                    .required(true)
+                    .channel_types(vec![ChannelType::Voice, ChannelType::Stage, ChannelType::Category]),
+                ),
+            )
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "exclude",
+                    "Stop or resume forwarding a Discord user's audio to Talk (all bridges)",
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(CommandOptionType::User, "user", "Discord user").required(true),
+                )
+                .add_sub_option(CreateCommandOption::new(
+                    CommandOptionType::Boolean,
+                    "excluded",
+                    "Keep their audio out of Talk (default: true)",
+                )),
+            )
+            .add_option(CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "settings",
+                "Show the settings in effect for this bridge",
+            ))
+            .add_option({
+                let mut key = CreateCommandOption::new(CommandOptionType::String, "key", "Setting").required(true);
+                for name in KEYS {
+                    key = key.add_string_choice(*name, *name);
+                }
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "set",
+                    "Change a setting for this server or only this bridge",
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(CommandOptionType::String, "scope", "Where the setting applies")
+                        .required(true)
+                        .add_string_choice("server", "guild")
+                        .add_string_choice("bridge", "bridge"),
+                )
+                .add_sub_option(key)
+                .add_sub_option(CreateCommandOption::new(
+                    CommandOptionType::String,
+                    "value",
+                    "New value; leave out to inherit again",
+                ))
+            });
+
+        // Guild commands show up immediately, global ones can take an hour
+        for guild_id in self.manager.guilds_for(self.bot) {
+            guild_id.set_commands(&ctx.http, vec![bridge.clone()]).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn handle(&self, ctx: &Context, command: &CommandInteraction) {
+        let options = command.data.options();
+        let bridge = command.guild_id.and_then(|g| self.manager.bridge_for(self.bot, g));
+        let deferred = bridge.is_some() && options.first().is_some_and(|o| DEFERRED.contains(&o.name));
+        if deferred {
+            let response = CreateInteractionResponse::Defer(CreateInteractionResponseMessage::new());
+            if let Err(e) = command.create_response(&ctx.http, response).await {
+                log!("Failed to respond to command: {:?}", e);
+                return;
+            }
+        }
+        let reply = match (bridge.as_deref(), options.first()) {
+            (None, _) => "This bot doesn't bridge a channel in this server".to_string(),
+            (
+                Some(bridge),
+                Some(ResolvedOption {
+                    name: "volume",
+                    value: ResolvedValue::SubCommand(args),
+                    ..
+                }),
+            ) => self.volume(bridge, args),
+            (Some(bridge), Some(ResolvedOption { name: "ring", .. })) => self.ring(bridge).await,
+            (Some(bridge), Some(ResolvedOption { name: "unmute", .. })) => self.unmute(bridge),
+            (
+                Some(bridge),
+                Some(ResolvedOption {
+                    name: "record",
+                    value: ResolvedValue::SubCommand(args),
+                    ..
+                }),
+            ) => self.record(bridge, args),
+            (
+                Some(bridge),
+                Some(ResolvedOption {
+                    name: "clip",
+                    value: ResolvedValue::SubCommand(args),
+                    ..
+                }),
+            ) => self.clip(bridge, args).await,
+            (
+                Some(bridge),
+                Some(ResolvedOption {
+                    name: "newroom",
+                    value: ResolvedValue::SubCommand(args),
+                    ..
+                }),
+            ) => self.new_room(bridge, args).await,
+            (
+                Some(bridge),
+                Some(ResolvedOption {
+                    name: "room",
+                    value: ResolvedValue::SubCommand(args),
+                    ..
+                }),
+            ) => self.switch_room(bridge, args).await,
+            (
+                Some(bridge),
+                Some(ResolvedOption {
+                    name: "rebind",
+                    value: ResolvedValue::SubCommand(args),
+                    ..
+                }),
+            ) => self.rebind(bridge, args).await,
+            (
+                Some(_),
+                Some(ResolvedOption {
+                    name: "exclude",
+                    value: ResolvedValue::SubCommand(args),
+                    ..
+                }),
+            ) => self.exclude(args),
+            (Some(bridge), Some(ResolvedOption { name: "settings", .. })) => self.show_settings(bridge),
+            (
+                Some(bridge),
+                Some(ResolvedOption {
+                    name: "set",
+                    value: ResolvedValue::SubCommand(args),
+                    ..
+                }),
+            ) => self.set(bridge, command.guild_id.map(|g| g.get()), args),
+            _ => "Unknown bridge command".to_string(),
+        };
+
+        let sent = match deferred {
+            true => command.edit_response(&ctx.http, EditInteractionResponse::new().content(reply)).await.map(drop),
+            false => {
+                let response = CreateInteractionResponse::Message(CreateInteractionResponseMessage::new().content(reply));
+                command.create_response(&ctx.http, response).await
+            }
+        };
+        if let Err(e) = sent {
+            log!("Failed to respond to command: {:?}", e);
+        }
+    }
+
+    fn volume(&self, bridge: &str, args: &[ResolvedOption<'_>]) -> String {
+        let (Some(participant), Some(gain)) = (string_arg(args, "participant"), string_arg(args, "gain")) else {
+            return "Usage: /bridge volume <participant> <gain>".to_string();
+        };
+
+        let Some(db) = parse_gain_db(gain) else {
+            return format!("Invalid gain {:?}, expected something like -6dB", gain);
+        };
+
+        match self.manager.set_volume(Some(bridge), participant, db) {
+            Ok((participant, db)) => format!("Set {} to {:+.1} dB", participant, db),
+            Err(e) => format!("Failed to set volume: {:#}", e),
+        }
+    }
+
+    async fn ring(&self, bridge: &str) -> String {
+        match self.manager.ring(Some(bridge)).await {
+            Ok(0) => "Everyone in the Talk room is already in the call".to_string(),
+            Ok(n) => format!("Rang {} Talk participant(s)", n),
+            Err(e) => format!("Failed to ring Talk participants: {:#}", e),
+        }
+    }
+
+    fn unmute(&self, bridge: &str) -> String {
+        match self.manager.unmute_talk(Some(bridge)) {
+            Ok(()) => "Unmuted, Talk hears this channel again".to_string(),
+            Err(e) => format!("Failed to unmute the bridge: {:#}", e),
+        }
+    }
+
+    fn record(&self, bridge: &str, args: &[ResolvedOption<'_>]) -> String {
+        let Some(enabled) = bool_arg(args, "enabled") else {
+            return "Usage: /bridge record <enabled>".to_string();
+        };
+        match self.manager.set_recording(Some(bridge), enabled) {
+            Ok(Some(path)) if enabled => format!("Recording to {}", path.display()),
+            Ok(Some(path)) => format!("Stopped recording, saved {}", path.display()),
+            Ok(None) => "Not recording".to_string(),
+            Err(e) => format!("Failed to change recording: {:#}", e),
+        }
+    }
+
+    async fn clip(&self, bridge: &str, args: &[ResolvedOption<'_>]) -> String {
+        let seconds = args
+            .iter()
+            .find(|o| o.name == "seconds")
+            .and_then(|o| match o.value {
+                ResolvedValue::Integer(n) => u64::try_from(n).ok(),
+                _ => None,
+            })
+            .unwrap_or(30);
+        match self.manager.clip(Some(bridge), seconds).await {
+            Ok((_, length)) => format!("Posted the last {} seconds", length.as_secs()),
+            Err(e) => format!("Failed to clip the call: {:#}", e),
+        }
+    }
+
+    async fn switch_room(&self, bridge: &str, args: &[ResolvedOption<'_>]) -> String {
+        let Some(token) = string_arg(args, "token") else {
+            return "Usage: /bridge room <token>".to_string();
+        };
+        match self.manager.switch_room(Some(bridge), token).await {
+            Ok(name) => format!("Now bridged to Talk room **{}**", name),
+            Err(e) => format!("Failed to switch Talk rooms: {:#}", e),
+        }
+    }
+
+    async fn rebind(&self, bridge: &str, args: &[ResolvedOption<'_>]) -> String {
+        let Some(channel) = args.iter().find(|o| o.name == "channel").and_then(|o| match o.value {
+            ResolvedValue::Channel(c) => Some(c.id),
+            _ => None,
+        }) else {
+            return "Usage: /bridge rebind <channel>".to_string();
+        };
+        match self.manager.rebind(Some(bridge), channel).await {
+            Ok(()) => format!("Bridge now uses <#{}>", channel),
+            Err(e) => format!("Failed to rebind: {:#}", e),
+        }
+    }
+
+    fn exclude(&self, args: &[ResolvedOption<'_>]) -> String {
+        let Some(user) = args.iter().find(|o| o.name == "user").and_then(|o| match o.value {
+            ResolvedValue::User(user, _) => Some(user.id),
+            _ => None,
+        }) else {
+            return "Usage: /bridge exclude <user> [excluded]".to_string();
+        };
+        let excluded = bool_arg(args, "excluded").unwrap_or(true);
+        match self.manager.set_discord_user_excluded(user.get(), excluded) {
+            Ok(_) if excluded => format!("<@{}>'s audio is no longer forwarded to Talk", user),
+            Ok(_) => format!("<@{}>'s audio is forwarded to Talk again", user),
+            Err(e) => format!("Failed to change exclusion: {:#}", e),
+        }
+    }
+
+    fn show_settings(&self, bridge: &str) -> String {
+        match self.manager.settings(Some(bridge)) {
+            Ok(settings) => format!(
+                "language: {}\nannouncements: {}\ngain: {}",
+                settings.language.as_deref().unwrap_or("(default)"),
+                settings.announcements.map_or("(default)".to_string(), |s| format!("{:?}", s).to_lowercase()),
+                settings.gain_db.map_or("(default)".to_string(), |db| format!("{:+.1} dB", db)),
+            ),
+            Err(e) => format!("Failed to read settings: {:#}", e),
+        }
+    }
+
+    // Global settings are only changed through the admin socket, as they
+    // reach into other servers
+    fn set(&self, bridge: &str, guild_id: Option<u64>, args: &[ResolvedOption<'_>]) -> String {
+        let (Some(scope), Some(key)) = (string_arg(args, "scope"), string_arg(args, "key")) else {
+            return "Usage: /bridge set <scope> <key> [value]".to_string();
+        };
+        let scope = match (scope, guild_id) {
+            ("guild", Some(guild_id)) => Scope::Guild(guild_id),
+            _ => Scope::Bridge(bridge.to_string()),
+        };
+        let value = string_arg(args, "value");
+        match self.manager.set_setting(&scope, key, value) {
+            Ok(()) => match value {
+                Some(value) => format!("Set {} to {}", key, value),
+                None => format!("Unset {}", key),
+            },
+            Err(e) => format!("Failed to change {}: {:#}", key, e),
+        }
+    }
+
+    async fn new_room(&self, bridge: &str, args: &[ResolvedOption<'_>]) -> String {
+        let Some(name) = string_arg(args, "name") else {
+            return "Usage: /bridge newroom <name> [public|group] [password]".to_string();
+        };
+        let public = string_arg(args, "type") != Some("group");
+        match self
+            .manager
+            .new_room(Some(bridge), name, public, string_arg(args, "password"))
+            .await
+        {
+            Ok(link) => format!("Created Talk room **{}**, bridged to this channel. Join at {}", name, link),
+            Err(e) => format!("Failed to create Talk room: {:#}", e),
+        }
+    }
+}
+
+fn bool_arg(args: &[ResolvedOption<'_>], name: &str) -> Option<bool> {
+    args.iter().find(|o| o.name == name).and_then(|o| match o.value {
+        ResolvedValue::Boolean(b) => Some(b),
+        _ => None,
+    })
+}
+
+fn string_arg<'a>(args: &'a [ResolvedOption<'_>], name: &str) -> Option<&'a str> {
+    args.iter().find(|o| o.name == name).and_then(|o| match o.value {
+        ResolvedValue::String(s) => Some(s),
+        _ => None,
+    })
+}
+
+// "-6dB", "-6 db", "+3" -> dB value
+pub fn parse_gain_db(s: &str) -> Option<f32> {
+    let s = s.trim();
+    let s = s
+        .strip_suffix("dB")
+        .or_else(|| s.strip_suffix("db"))
+        .or_else(|| s.strip_suffix("DB"))
+        .unwrap_or(s);
+    s.trim().parse::<f32>().ok().filter(|v| v.is_finite())
+}
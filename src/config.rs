@@ -0,0 +1,1046 @@
+use serenity::cache::Settings as CacheSettings;
+use serenity::model::gateway::GatewayIntents;
+use std::env;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::audio::effect::{parse_chain, EffectSpec};
+
+// Which leg of the bridge a setting applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    DiscordToNextcloud,
+    NextcloudToDiscord,
+}
+
+impl FromStr for Direction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "d2n" | "discord_to_nc" | "discord_to_nextcloud" => Ok(Direction::DiscordToNextcloud),
+            "n2d" | "nc_to_discord" | "nextcloud_to_discord" => Ok(Direction::NextcloudToDiscord),
+            other => Err(format!("unknown direction: {}", other)),
+        }
+    }
+}
+
+impl Direction {
+    // Per-direction settings are read from env vars with this prefix,
+    // e.g. NC_TO_DISCORD_GATE_OPEN_DB
+    fn env_prefix(self) -> &'static str {
+        match self {
+            Direction::DiscordToNextcloud => "DISCORD_TO_NC",
+            Direction::NextcloudToDiscord => "NC_TO_DISCORD",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelLayout {
+    Mono,
+    Stereo,
+}
+
+impl ChannelLayout {
+    pub fn count(self) -> usize {
+        match self {
+            ChannelLayout::Mono => 1,
+            ChannelLayout::Stereo => 2,
+        }
+    }
+}
+
+impl FromStr for ChannelLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mono" | "1" => Ok(ChannelLayout::Mono),
+            "stereo" | "2" => Ok(ChannelLayout::Stereo),
+            other => Err(format!("unknown channel layout: {}", other)),
+        }
+    }
+}
+
+// What the Discord -> Nextcloud leg does with Discord's Opus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipelineMode {
+    // Passthrough unless a PCM stage (effects, mono, decoded receive) needs
+    // the audio decoded
+    Auto,
+    // Opus packets are forwarded untouched on an RTP track; lowest CPU, but
+    // effects and the mono downmix are skipped
+    Passthrough,
+    // Always decode and re-encode, written to Talk as samples
+    Transcode,
+}
+
+impl FromStr for PipelineMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(PipelineMode::Auto),
+            "passthrough" => Ok(PipelineMode::Passthrough),
+            "transcode" => Ok(PipelineMode::Transcode),
+            other => Err(format!("unknown pipeline mode: {}", other)),
+        }
+    }
+}
+
+// How Discord audio is taken from Songbird
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReceiveMode {
+    // Raw RTP (decrypted) Opus frames, decoded by us when a PCM stage needs it
+    Rtp,
+    // Songbird decrypts and decodes; only frames that decode are forwarded
+    Decoded,
+}
+
+impl FromStr for ReceiveMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "rtp" => Ok(ReceiveMode::Rtp),
+            "decoded" => Ok(ReceiveMode::Decoded),
+            other => Err(format!("unknown receive mode: {}", other)),
+        }
+    }
+}
+
+// What happens when part of the media path is less protected than expected:
+// legacy Discord voice encryption, or a Talk SDP without sound DTLS-SRTP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncryptionPolicy {
+    // Log it and show it in the status, keep bridging
+    Warn,
+    // End the session, and only accept AEAD SRTP from Talk
+    Refuse,
+}
+
+impl FromStr for EncryptionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "warn" => Ok(EncryptionPolicy::Warn),
+            "refuse" => Ok(EncryptionPolicy::Refuse),
+            other => Err(format!("unknown encryption policy: {}", other)),
+        }
+    }
+}
+
+// What a full signaling outbox gives up for another message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutboxOverflow {
+    // The oldest queued message, most likely a stale candidate
+    DropOldest,
+    // The message being sent
+    DropNewest,
+    // Nothing: sending fails, which ends the session
+    Fail,
+}
+
+impl FromStr for OutboxOverflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "drop-oldest" | "oldest" => Ok(OutboxOverflow::DropOldest),
+            "drop-newest" | "newest" => Ok(OutboxOverflow::DropNewest),
+            "fail" => Ok(OutboxOverflow::Fail),
+            other => Err(format!("unknown outbox overflow policy: {}", other)),
+        }
+    }
+}
+
+// Signaling messages kept while the connection is down, sent once it is
+// resumed
+#[derive(Debug, Clone, Copy)]
+pub struct OutboxConfig {
+    pub capacity: usize,
+    pub overflow: OutboxOverflow,
+}
+
+impl OutboxConfig {
+    pub fn from_env() -> Self {
+        Self {
+            capacity: env_or("SIGNALING_OUTBOX_SIZE", 256usize).max(1),
+            overflow: env_or("SIGNALING_OUTBOX_OVERFLOW", OutboxOverflow::DropOldest),
+        }
+    }
+}
+
+// Retries once the signaling connection dropped: resumes while the server
+// keeps the session, then new sessions in the room
+#[derive(Debug, Clone, Copy)]
+pub struct ReconnectConfig {
+    pub initial: Duration,
+    pub max_delay: Duration,
+    // 0 retries forever
+    pub max_retries: u32,
+}
+
+impl ReconnectConfig {
+    pub fn from_env() -> Self {
+        Self {
+            initial: Duration::from_millis(env_or("SIGNALING_RECONNECT_DELAY_MS", 1000u64).max(100)),
+            max_delay: Duration::from_secs(env_or("SIGNALING_RECONNECT_MAX_DELAY_SECS", 60u64).max(1)),
+            max_retries: env_or("SIGNALING_RECONNECT_MAX_RETRIES", 10u32),
+        }
+    }
+}
+
+// Certificates to trust besides the system's, e.g. an internal CA's, for
+// Nextcloud and the signaling server, and whether to check them at all
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub ca_files: Vec<PathBuf>,
+    pub insecure: bool,
+}
+
+impl TlsConfig {
+    pub fn from_env() -> Self {
+        Self {
+            ca_files: env_list("TLS_CA_FILES").into_iter().map(PathBuf::from).collect(),
+            insecure: env_flag("TLS_INSECURE_SKIP_VERIFY", false),
+        }
+    }
+}
+
+// Which ICE candidates Talk connections may use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IceTransportPolicy {
+    All,
+    // Only TURN relays, so the bridge host's addresses are never offered
+    Relay,
+}
+
+impl FromStr for IceTransportPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "all" => Ok(IceTransportPolicy::All),
+            "relay" | "relay-only" => Ok(IceTransportPolicy::Relay),
+            other => Err(format!("unknown ICE transport policy: {}", other)),
+        }
+    }
+}
+
+// Whether the bridge's candidates go to Talk one by one as they are found,
+// or all at once in its offers and answers. Auto trickles them except over
+// Talk's internal signaling, where each would be a request of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IceTrickle {
+    Auto,
+    On,
+    Off,
+}
+
+impl FromStr for IceTrickle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(IceTrickle::Auto),
+            "true" | "on" | "yes" | "1" => Ok(IceTrickle::On),
+            "false" | "off" | "no" | "0" => Ok(IceTrickle::Off),
+            other => Err(format!("unknown ICE trickle mode: {}", other)),
+        }
+    }
+}
+
+// A STUN or TURN server of our own; TURN credentials come in the URL, as
+// in turn:user:secret@turn.example.org:3478
+#[derive(Debug, Clone)]
+pub struct IceServerConfig {
+    pub url: String,
+    pub username: String,
+    pub credential: String,
+}
+
+impl IceServerConfig {
+    fn parse(entry: &str) -> Self {
+        let (scheme, rest) = entry.split_once(':').unwrap_or(("", entry));
+        match rest.rsplit_once('@') {
+            Some((login, host)) => {
+                let (username, credential) = login.split_once(':').unwrap_or((login, ""));
+                Self {
+                    url: format!("{}:{}", scheme, host),
+                    username: username.to_string(),
+                    credential: credential.to_string(),
+                }
+            }
+            None => Self { url: entry.to_string(), username: String::new(), credential: String::new() },
+        }
+    }
+}
+
+// Where the bridge host's own candidates come from, for containers and
+// hosts with interfaces Talk can't reach
+#[derive(Debug, Clone)]
+pub struct IceGathering {
+    // Only these interfaces and addresses; any when empty
+    pub interfaces: Vec<String>,
+    pub addresses: Vec<IpAddr>,
+    pub ipv6: bool,
+    // mDNS (.local) and link-local candidates, ours and Talk's
+    pub link_local: bool,
+}
+
+// STUN/TURN servers used before those configured in Talk, and the
+// candidates connections may use
+#[derive(Debug, Clone)]
+pub struct IceConfig {
+    pub servers: Vec<IceServerConfig>,
+    pub policy: IceTransportPolicy,
+    pub gathering: IceGathering,
+    pub trickle: IceTrickle,
+}
+
+impl IceConfig {
+    pub fn from_env() -> Self {
+        let addresses = env_list("ICE_ADDRESSES")
+            .into_iter()
+            .filter_map(|address| match address.parse() {
+                Ok(ip) => Some(ip),
+                Err(_) => {
+                    log!("Ignoring invalid address in ICE_ADDRESSES: {:?}", address);
+                    None
+                }
+            })
+            .collect();
+        Self {
+            servers: env_list("ICE_SERVERS").iter().map(|entry| IceServerConfig::parse(entry)).collect(),
+            policy: env_or("ICE_TRANSPORT_POLICY", IceTransportPolicy::All),
+            gathering: IceGathering {
+                interfaces: env_list("ICE_INTERFACES"),
+                addresses,
+                ipv6: env_flag("ICE_IPV6", true),
+                link_local: env_flag("ICE_LINK_LOCAL", true),
+            },
+            trickle: env_or("ICE_TRICKLE", IceTrickle::Auto),
+        }
+    }
+}
+
+// An OAuth2 access token for the OCS API, used instead of
+// NEXTCLOUD_PASSWORD. With a client and refresh token it is renewed once it
+// expires.
+#[derive(Debug, Clone)]
+pub struct OAuthConfig {
+    pub access_token: String,
+    pub refresh: Option<OAuthRefresh>,
+}
+
+#[derive(Debug, Clone)]
+pub struct OAuthRefresh {
+    pub client_id: String,
+    pub client_secret: String,
+    pub refresh_token: String,
+}
+
+impl OAuthConfig {
+    pub fn from_env() -> Option<Self> {
+        let var = |key: &str| env::var(key).ok().map(|v| v.trim().to_string()).filter(|v| !v.is_empty());
+        let access_token = var("NEXTCLOUD_TOKEN")?;
+        let refresh = match (var("NEXTCLOUD_OAUTH_CLIENT_ID"), var("NEXTCLOUD_OAUTH_CLIENT_SECRET"), var("NEXTCLOUD_REFRESH_TOKEN")) {
+            (Some(client_id), Some(client_secret), Some(refresh_token)) => Some(OAuthRefresh {
+                client_id,
+                client_secret,
+                refresh_token,
+            }),
+            (None, None, None) => None,
+            _ => {
+                log!("NEXTCLOUD_TOKEN won't be renewed: it needs NEXTCLOUD_OAUTH_CLIENT_ID, NEXTCLOUD_OAUTH_CLIENT_SECRET and NEXTCLOUD_REFRESH_TOKEN");
+                None
+            }
+        };
+        Some(Self { access_token, refresh })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GateConfig {
+    pub enabled: bool,
+    // Level (dBFS) the signal has to rise above to open the gate
+    pub open_threshold_db: f32,
+    // Level (dBFS) the signal has to fall below to close it again.
+    // Keeping this under the open threshold stops the gate chattering.
+    pub close_threshold_db: f32,
+    pub attack_ms: f32,
+    pub release_ms: f32,
+}
+
+impl GateConfig {
+    fn from_env(direction: Direction) -> Self {
+        let prefix = direction.env_prefix();
+        Self {
+            enabled: env_flag(&format!("{}_GATE", prefix), false),
+            open_threshold_db: env_or(&format!("{}_GATE_OPEN_DB", prefix), -45.0),
+            close_threshold_db: env_or(&format!("{}_GATE_CLOSE_DB", prefix), -50.0),
+            attack_ms: env_or(&format!("{}_GATE_ATTACK_MS", prefix), 5.0),
+            release_ms: env_or(&format!("{}_GATE_RELEASE_MS", prefix), 150.0),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DirectionConfig {
+    // Layout of the PCM on this leg. Mono downmixes at the decoder.
+    pub channels: ChannelLayout,
+    pub gate: GateConfig,
+    // PCM effect chain, in order. Defaults to just the gate when it's enabled.
+    pub effects: Vec<EffectSpec>,
+}
+
+impl DirectionConfig {
+    fn from_env(direction: Direction) -> Self {
+        // Discord sends stereo Opus, while Talk publishers are effectively mono
+        let default_channels = match direction {
+            Direction::DiscordToNextcloud => ChannelLayout::Stereo,
+            Direction::NextcloudToDiscord => ChannelLayout::Mono,
+        };
+
+        let prefix = direction.env_prefix();
+        let gate = GateConfig::from_env(direction);
+        let default_effects = if gate.enabled {
+            vec![EffectSpec { name: "gate".to_string(), args: Vec::new() }]
+        } else {
+            Vec::new()
+        };
+        let effects_key = format!("{}_EFFECTS", prefix);
+        let effects = match env::var(&effects_key) {
+            Ok(v) => parse_chain(&v).unwrap_or_else(|e| {
+                log!("Ignoring invalid value for {}: {}", effects_key, e);
+                default_effects
+            }),
+            Err(_) => default_effects,
+        };
+
+        Self {
+            channels: env_or(&format!("{}_CHANNELS", prefix), default_channels),
+            gate,
+            effects,
+        }
+    }
+}
+
+// Only used on the Talk side: Discord clients are used to silence between
+// speakers, Talk users tend to read it as the bridge having dropped.
+#[derive(Debug, Clone)]
+pub struct ComfortNoiseConfig {
+    pub enabled: bool,
+    pub level_db: f32,
+    // How long after the last forwarded frame before noise starts
+    pub hangover_ms: u64,
+}
+
+impl ComfortNoiseConfig {
+    fn from_env() -> Self {
+        Self {
+            enabled: env_flag("DISCORD_TO_NC_COMFORT_NOISE", false),
+            level_db: env_or("DISCORD_TO_NC_COMFORT_NOISE_DB", -70.0),
+            hangover_ms: env_or("DISCORD_TO_NC_COMFORT_NOISE_HANGOVER_MS", 200),
+        }
+    }
+}
+
+// Ducking of background sources under speech in the Discord-bound mixer
+#[derive(Debug, Clone, Default)]
+pub struct DuckingConfig {
+    pub enabled: bool,
+    // How far (dB) background sources are pulled down while someone talks
+    pub ratio_db: f32,
+    // Speech level (dBFS) that counts as talking
+    pub threshold_db: f32,
+    pub attack_ms: f32,
+    pub release_ms: f32,
+    // Talk participants treated as background (music bots, announcement accounts)
+    pub background_participants: Vec<String>,
+}
+
+impl DuckingConfig {
+    fn from_env() -> Self {
+        Self {
+            enabled: env_flag("DUCKING", false),
+            ratio_db: env_or("DUCKING_RATIO_DB", 12.0),
+            threshold_db: env_or("DUCKING_THRESHOLD_DB", -40.0),
+            attack_ms: env_or("DUCKING_ATTACK_MS", 20.0),
+            release_ms: env_or("DUCKING_RELEASE_MS", 400.0),
+            background_participants: env_list("DUCKING_BACKGROUND_PARTICIPANTS"),
+        }
+    }
+}
+
+// Per-track jitter buffer in the Discord-bound mixer. Each Talk track
+// waits until `min_frames` 20ms frames are queued before it plays; when
+// adaptive, every underrun makes that track buffer one frame more (up to
+// `max_frames`) and a quiet stretch brings it back down. The default is no
+// buffering: play whatever arrived, as the recorder's mixer does.
+#[derive(Debug, Clone, Default)]
+pub struct JitterConfig {
+    pub adaptive: bool,
+    pub min_frames: usize,
+    pub max_frames: usize,
+}
+
+impl JitterConfig {
+    fn from_env() -> Self {
+        let min_frames = (env_or("NC_TO_DISCORD_JITTER_MIN_MS", 20u64) / 20) as usize;
+        Self {
+            adaptive: env_flag("NC_TO_DISCORD_ADAPTIVE_JITTER", true),
+            min_frames,
+            max_frames: ((env_or("NC_TO_DISCORD_JITTER_MAX_MS", 200u64) / 20) as usize).max(min_frames),
+        }
+    }
+}
+
+// Multi-track mode: each Discord speaker gets their own publisher toward the
+// HPB instead of sharing the bridge's one merged track
+#[derive(Debug, Clone)]
+pub struct MultiTrackConfig {
+    pub enabled: bool,
+    // Each publisher is a full Talk connection, so keep this modest
+    pub max_publishers: usize,
+}
+
+impl MultiTrackConfig {
+    fn from_env() -> Self {
+        Self {
+            enabled: env_flag("DISCORD_TO_NC_MULTI_TRACK", false),
+            max_publishers: env_or("DISCORD_TO_NC_MAX_PUBLISHERS", 8),
+        }
+    }
+}
+
+// One edit of the Opus in Talk's SDPs: "key=value" sets an fmtp parameter,
+// "-key" removes it. ptime and maxptime are attributes of their own, and
+// are set or removed as such.
+#[derive(Debug, Clone)]
+pub struct SdpRule {
+    pub key: String,
+    // None removes the key
+    pub value: Option<String>,
+}
+
+impl SdpRule {
+    fn parse(entry: &str) -> Self {
+        match entry.strip_prefix('-') {
+            Some(key) => Self { key: key.trim().to_string(), value: None },
+            None => {
+                let (key, value) = entry.split_once('=').unwrap_or((entry, ""));
+                Self { key: key.trim().to_string(), value: Some(value.trim().to_string()) }
+            }
+        }
+    }
+
+    pub fn is_attribute(&self) -> bool {
+        matches!(self.key.to_lowercase().as_str(), "ptime" | "maxptime")
+    }
+}
+
+// Edits for MCUs with quirks the bridge doesn't cater for, e.g. a Janus
+// that only plays stereo with sprop-stereo in the offer it gets. Those for
+// `remote` apply to Talk's offers and answers before they are used, those
+// for `local` to the bridge's as they are sent.
+#[derive(Debug, Clone, Default)]
+pub struct SdpMunging {
+    pub remote: Vec<SdpRule>,
+    pub local: Vec<SdpRule>,
+}
+
+impl SdpMunging {
+    fn from_env() -> Self {
+        let rules = |key: &str| env_list(key).iter().map(|entry| SdpRule::parse(entry)).collect();
+        Self { remote: rules("TALK_SDP_REMOTE"), local: rules("TALK_SDP_LOCAL") }
+    }
+}
+
+// What the fmtp line of the bridge's Opus tells Talk: whether the bridge
+// sends and would like to receive stereo, the bitrate it wants at most and
+// whether to add in-band FEC. Besides it, the SDPs the bridge sends cap its
+// audio at `bandwidth`, and `sdp` edits them and Talk's.
+#[derive(Debug, Clone)]
+pub struct OpusFmtp {
+    pub stereo: bool,
+    // bits/s
+    pub max_average_bitrate: Option<u32>,
+    pub inband_fec: bool,
+    pub bandwidth: Option<u32>,
+    // Publish the Opus wrapped in RED, each packet repeating the one before
+    pub red: bool,
+    pub sdp: SdpMunging,
+}
+
+impl OpusFmtp {
+    pub fn line(&self) -> String {
+        let mut line = format!("minptime=10;useinbandfec={}", self.inband_fec as u8);
+        if self.stereo {
+            line.push_str(";stereo=1;sprop-stereo=1");
+        }
+        if let Some(bitrate) = self.max_average_bitrate {
+            line.push_str(&format!(";maxaveragebitrate={}", bitrate));
+        }
+        line
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AudioConfig {
+    pub discord_to_nextcloud: DirectionConfig,
+    pub nextcloud_to_discord: DirectionConfig,
+    pub comfort_noise: ComfortNoiseConfig,
+    pub ducking: DuckingConfig,
+    pub jitter: JitterConfig,
+    // Duration of the Opus packets written to the Talk track. Discord's 20ms
+    // frames are coalesced into packets this long.
+    pub talk_frame_ms: u64,
+    // Follow the loss Talk reports with the encoder's FEC. Only applies when
+    // the Discord -> Nextcloud leg is transcoded; passthrough keeps
+    // whatever Discord's encoder chose.
+    pub dynamic_fec: bool,
+    // Likewise transcode only: lower the encoder bitrate while Talk reports
+    // heavy loss, between these bounds (bits/s)
+    pub adaptive_bitrate: bool,
+    pub min_bitrate: u32,
+    pub max_bitrate: u32,
+    pub discord_receive: ReceiveMode,
+    // Resolved to Passthrough or Transcode when a session starts
+    pub pipeline: PipelineMode,
+    // RTP mode only: how many packets may queue up behind a gap before the
+    // missing ones are given up on. Each packet held adds 20ms of delay.
+    pub reorder_window: usize,
+    // The same for the Talk tracks played into Discord
+    pub talk_reorder_window: usize,
+    pub multi_track: MultiTrackConfig,
+    // Negotiated with Talk, see OpusFmtp
+    pub talk_inband_fec: bool,
+    pub talk_max_average_bitrate: Option<u32>,
+    // The most the bridge publishes into Talk (bits/s): announced in its
+    // SDPs and, when transcoding, the encoder's ceiling
+    pub talk_bandwidth: Option<u32>,
+    // Publish RED rather than bare Opus; only for Talk servers that
+    // negotiate it, as nothing else can be sent on the track
+    pub talk_red: bool,
+    pub talk_sdp: SdpMunging,
+}
+
+impl AudioConfig {
+    pub fn talk_fmtp(&self) -> OpusFmtp {
+        OpusFmtp {
+            stereo: self.discord_to_nextcloud.channels == ChannelLayout::Stereo,
+            max_average_bitrate: self.talk_max_average_bitrate,
+            inband_fec: self.talk_inband_fec,
+            bandwidth: self.talk_bandwidth,
+            red: self.talk_red,
+            sdp: self.talk_sdp.clone(),
+        }
+    }
+}
+
+// Archive of the call with both directions mixed, one .ogg per session
+#[derive(Debug, Clone)]
+pub struct RecordingConfig {
+    // Start recording with every session; /bridge record works either way
+    pub enabled: bool,
+    pub dir: PathBuf,
+    pub channels: ChannelLayout,
+    // How much of the call /bridge clip can go back; 0 turns the buffer off
+    pub replay_secs: u64,
+}
+
+impl RecordingConfig {
+    fn from_env() -> Self {
+        Self {
+            enabled: env_flag("RECORDING", false),
+            dir: PathBuf::from(env::var("RECORDING_DIR").unwrap_or("recordings".to_string())),
+            channels: env_or("RECORDING_CHANNELS", ChannelLayout::Mono),
+            replay_secs: env_or("RECORDING_REPLAY_SECS", 60u64).min(600),
+        }
+    }
+}
+
+// Join/leave sounds; files are 16-bit 48kHz WAV, built-in tones otherwise
+#[derive(Debug, Clone)]
+pub struct CuesConfig {
+    pub enabled: bool,
+    pub volume_db: f32,
+    pub join_file: Option<String>,
+    pub leave_file: Option<String>,
+}
+
+impl CuesConfig {
+    fn from_env() -> Self {
+        Self {
+            enabled: env_flag("CUES", false),
+            volume_db: env_or("CUE_VOLUME_DB", -12.0),
+            join_file: env::var("CUE_JOIN_FILE").ok().filter(|v| !v.trim().is_empty()),
+            leave_file: env::var("CUE_LEAVE_FILE").ok().filter(|v| !v.trim().is_empty()),
+        }
+    }
+}
+
+// Spoken "Alice joined the Discord call" announcements, after the cue
+#[derive(Debug, Clone)]
+pub struct AnnouncementConfig {
+    pub enabled: bool,
+    // Reads text on stdin, writes WAV to stdout
+    pub command: String,
+    // A TTS service to POST the text to instead of running the command
+    pub url: Option<String>,
+    // Substituted for {language} in the command, e.g. `espeak-ng -v {language}`
+    pub language: String,
+    pub volume_db: f32,
+}
+
+impl AnnouncementConfig {
+    fn from_env() -> Self {
+        Self {
+            enabled: env_flag("TTS_ANNOUNCEMENTS", false),
+            command: env_or("TTS_COMMAND", "espeak-ng --stdout".to_string()),
+            url: env::var("TTS_URL").ok().filter(|v| !v.trim().is_empty()),
+            language: env_or("TTS_LANGUAGE", "en".to_string()),
+            volume_db: env_or("TTS_VOLUME_DB", -6.0),
+        }
+    }
+}
+
+// Talk without an MCU: the bridge connects to each participant in the call
+// directly. Every participant costs a connection of its own, so this is
+// meant for small rooms.
+#[derive(Debug, Clone)]
+pub struct PeerToPeerConfig {
+    pub enabled: bool,
+    pub max_peers: usize,
+}
+
+impl PeerToPeerConfig {
+    fn from_env() -> Self {
+        Self {
+            enabled: env_flag("TALK_P2P", true),
+            max_peers: env_or("TALK_P2P_MAX_PEERS", 4),
+        }
+    }
+}
+
+// Settings that belong to a single bridge (one Discord channel <-> one Talk room)
+#[derive(Debug, Clone)]
+pub struct BridgeConfig {
+    pub audio: AudioConfig,
+    // Post a summary into Talk and Discord chat when a call ends
+    pub call_summary: bool,
+    // Tell the Discord channel when someone in Talk shares their screen
+    pub screen_share_notices: bool,
+    // Post Talk's in-call reactions into the Discord channel
+    pub reaction_notices: bool,
+    // Tell the Discord channel when someone in Talk raises their hand
+    pub raise_hand_notices: bool,
+    // Let /bridge unmute lift a Talk moderator's mute
+    pub talk_unmute: bool,
+    // Join the Talk call without notifying room members; /bridge ring can
+    // still ring them on demand
+    pub silent_call: bool,
+    // Create a voice channel named after the Talk room while someone is in
+    // the Talk call and delete it afterwards. The Discord channel ID is then
+    // the category it is created in.
+    pub temporary_channel: bool,
+    pub recording: RecordingConfig,
+    pub cues: CuesConfig,
+    pub announcements: AnnouncementConfig,
+    pub p2p: PeerToPeerConfig,
+    // A second Talk room on the same server merged into the call: each room
+    // hears Discord and the other room, Discord hears both
+    pub merge_room_token: Option<String>,
+    pub encryption: EncryptionPolicy,
+    // Discord users are only forwarded to Talk after DMing the bot the code
+    // posted into the Talk room for the call
+    pub access_codes: bool,
+}
+
+impl BridgeConfig {
+    pub fn from_env() -> Self {
+        Self {
+            audio: AudioConfig {
+                discord_to_nextcloud: DirectionConfig::from_env(Direction::DiscordToNextcloud),
+                nextcloud_to_discord: DirectionConfig::from_env(Direction::NextcloudToDiscord),
+                comfort_noise: ComfortNoiseConfig::from_env(),
+                ducking: DuckingConfig::from_env(),
+                jitter: JitterConfig::from_env(),
+                talk_frame_ms: talk_frame_ms_from_env(),
+                dynamic_fec: env_flag("DISCORD_TO_NC_DYNAMIC_FEC", true),
+                adaptive_bitrate: env_flag("DISCORD_TO_NC_ADAPTIVE_BITRATE", true),
+                min_bitrate: env_or("DISCORD_TO_NC_MIN_BITRATE_KBPS", 12u32) * 1000,
+                max_bitrate: env_or("DISCORD_TO_NC_MAX_BITRATE_KBPS", 64u32) * 1000,
+                discord_receive: env_or("DISCORD_RECEIVE_MODE", ReceiveMode::Rtp),
+                pipeline: env_or("DISCORD_TO_NC_PIPELINE", PipelineMode::Auto),
+                reorder_window: env_or("DISCORD_TO_NC_REORDER_WINDOW", 3),
+                talk_reorder_window: env_or("NC_TO_DISCORD_REORDER_WINDOW", 3),
+                multi_track: MultiTrackConfig::from_env(),
+                talk_inband_fec: env_flag("TALK_OPUS_FEC", true),
+                talk_max_average_bitrate: Some(env_or("TALK_OPUS_MAX_AVERAGE_BITRATE_KBPS", 0u32) * 1000)
+                    .filter(|b| *b > 0),
+                talk_bandwidth: Some(env_or("TALK_AUDIO_BANDWIDTH_KBPS", 0u32) * 1000).filter(|b| *b > 0),
+                talk_red: env_flag("TALK_AUDIO_RED", false),
+                talk_sdp: SdpMunging::from_env(),
+            },
+            call_summary: env_flag("CALL_SUMMARY", false),
+            screen_share_notices: env_flag("SCREEN_SHARE_NOTICES", false),
+            reaction_notices: env_flag("TALK_REACTION_NOTICES", false),
+            raise_hand_notices: env_flag("RAISE_HAND_NOTICES", false),
+            talk_unmute: env_flag("TALK_ALLOW_UNMUTE", false),
+            silent_call: env_flag("TALK_SILENT_CALL", true),
+            temporary_channel: env_flag("DISCORD_TEMP_CHANNEL", false),
+            recording: RecordingConfig::from_env(),
+            cues: CuesConfig::from_env(),
+            announcements: AnnouncementConfig::from_env(),
+            p2p: PeerToPeerConfig::from_env(),
+            merge_room_token: env::var("NEXTCLOUD_MERGE_ROOM_TOKEN").ok().filter(|v| !v.trim().is_empty()),
+            encryption: env_or("ENCRYPTION_POLICY", EncryptionPolicy::Warn),
+            access_codes: env_flag("DISCORD_ACCESS_CODES", false),
+        }
+    }
+}
+
+// Settings for the Discord client itself, shared by all bridges
+#[derive(Debug, Clone)]
+pub struct DiscordConfig {
+    // Text chat relaying, which needs the message intents (MESSAGE_CONTENT
+    // is privileged and has to be enabled in the developer portal)
+    pub chat_bridge: bool,
+    // Only ask for what voice bridging needs and keep the cache small
+    pub minimal_footprint: bool,
+    // Further bot logins besides DISCORD_TOKEN, to bridge several voice
+    // channels of one guild
+    pub extra_tokens: Vec<String>,
+    // Bots of other bridge instances, whose audio isn't forwarded
+    pub ignore_users: Vec<u64>,
+    // Also ignore every bot account (music bots and the like)
+    pub ignore_bots: bool,
+    // Play soundboard sounds into Talk, decoded with this command (any
+    // audio on stdin, WAV on stdout)
+    pub soundboard: bool,
+    pub soundboard_decoder: String,
+}
+
+impl DiscordConfig {
+    pub fn from_env() -> Self {
+        let config = Self {
+            chat_bridge: env_flag("CHAT_BRIDGE", true),
+            minimal_footprint: env_flag("DISCORD_MINIMAL_FOOTPRINT", false),
+            extra_tokens: env_list("DISCORD_EXTRA_TOKENS"),
+            ignore_users: env_list("DISCORD_IGNORE_USERS")
+                .iter()
+                .filter_map(|id| match id.parse() {
+                    Ok(id) => Some(id),
+                    Err(_) => {
+                        log!("Ignoring invalid user ID in DISCORD_IGNORE_USERS: {:?}", id);
+                        None
+                    }
+                })
+                .collect(),
+            ignore_bots: env_flag("DISCORD_IGNORE_BOTS", false),
+            soundboard: env_flag("DISCORD_SOUNDBOARD", true),
+            soundboard_decoder: env_or(
+                "DISCORD_SOUNDBOARD_DECODER",
+                "ffmpeg -v error -i pipe:0 -f wav -ac 1 -ar 48000 pipe:1".to_string(),
+            ),
+        };
+        if config.minimal_footprint && config.chat_bridge {
+            log!("DISCORD_MINIMAL_FOOTPRINT keeps the message intents while CHAT_BRIDGE is on");
+        }
+        config
+    }
+
+    pub fn intents(&self) -> GatewayIntents {
+        // GUILDS for the guild/channel events Songbird and the slash
+        // commands rely on, voice states for the calls themselves
+        let mut intents = GatewayIntents::GUILDS | GatewayIntents::GUILD_VOICE_STATES;
+        if self.chat_bridge || !self.minimal_footprint {
+            intents |= GatewayIntents::GUILD_MESSAGES | GatewayIntents::MESSAGE_CONTENT;
+        }
+        intents
+    }
+
+    // Nothing reads users or channels from the cache (names come over
+    // HTTP), so minimal mode only keeps guilds and expires the rest quickly
+    pub fn cache_settings(&self) -> CacheSettings {
+        let mut settings = CacheSettings::default();
+        if self.minimal_footprint {
+            settings.max_messages = 0;
+            settings.cache_users = false;
+            settings.cache_channels = false;
+            settings.time_to_live = Duration::from_secs(60);
+        }
+        settings
+    }
+}
+
+// Set by AppAPI when the bridge is deployed as a Nextcloud ExApp
+#[derive(Debug, Clone)]
+pub struct ExAppConfig {
+    pub app_id: String,
+    pub app_secret: String,
+    pub app_version: String,
+    pub aa_version: String,
+    pub host: String,
+    pub port: u16,
+    pub nextcloud_url: String,
+}
+
+impl ExAppConfig {
+    // None unless running under AppAPI
+    pub fn from_env() -> Option<Self> {
+        Some(Self {
+            app_id: env::var("APP_ID").ok()?,
+            app_secret: env::var("APP_SECRET").ok()?,
+            app_version: env::var("APP_VERSION").unwrap_or_default(),
+            aa_version: env::var("AA_VERSION").ok()?,
+            host: env::var("APP_HOST").unwrap_or("0.0.0.0".to_string()),
+            port: env_or("APP_PORT", 23000),
+            nextcloud_url: env::var("NEXTCLOUD_URL").ok()?,
+        })
+    }
+}
+
+// Health reports pushed to a companion Nextcloud app, off unless an
+// endpoint is set
+#[derive(Debug, Clone)]
+pub struct MetricsConfig {
+    pub endpoint: Option<String>,
+    pub interval: Duration,
+    // Tells reports from several bridge processes apart
+    pub instance: String,
+}
+
+impl MetricsConfig {
+    pub fn from_env() -> Self {
+        Self {
+            endpoint: env::var("NEXTCLOUD_METRICS_URL").ok().filter(|v| !v.trim().is_empty()),
+            interval: Duration::from_secs(env_or("NEXTCLOUD_METRICS_INTERVAL_SECS", 60u64).max(5)),
+            instance: env::var("BRIDGE_INSTANCE").unwrap_or("default".to_string()),
+        }
+    }
+}
+
+// Compares the running version against the latest release; off by default
+// so the bridge doesn't phone home unasked
+#[derive(Debug, Clone)]
+pub struct UpdateCheckConfig {
+    pub url: String,
+    pub interval: Duration,
+}
+
+impl UpdateCheckConfig {
+    pub fn from_env() -> Option<Self> {
+        env_flag("UPDATE_CHECK", false).then(|| Self {
+            url: env::var("UPDATE_CHECK_URL").unwrap_or(
+                "https://api.github.com/repos/j-cray/nextcloud-discord-bridge/releases/latest".to_string(),
+            ),
+            interval: Duration::from_secs(env_or("UPDATE_CHECK_INTERVAL_HOURS", 24u64).max(1) * 3600),
+        })
+    }
+}
+
+// Where panic reports go, and optionally where they are sent
+#[derive(Debug, Clone)]
+pub struct CrashConfig {
+    pub dir: PathBuf,
+    pub webhook: Option<String>,
+}
+
+impl CrashConfig {
+    pub fn from_env() -> Self {
+        Self {
+            dir: PathBuf::from(env::var("CRASH_DIR").unwrap_or("crashes".to_string())),
+            webhook: env::var("CRASH_REPORT_WEBHOOK").ok().filter(|v| !v.trim().is_empty()),
+        }
+    }
+}
+
+// How long the signaling WebSocket may stay quiet before it counts as dead;
+// it is pinged three times within that
+pub fn signaling_timeout_from_env() -> Duration {
+    Duration::from_secs(env_or("SIGNALING_TIMEOUT_SECS", 60u64).max(10))
+}
+
+// Talk API versions to use instead of the ones Talk's capabilities
+// announce: signaling, conversations. Given as 3 or v3, empty or auto to
+// detect.
+pub fn talk_api_versions_from_env() -> (Option<u8>, Option<u8>) {
+    let version = |key: &str| {
+        let value = env::var(key).unwrap_or_default();
+        let value = value.trim().trim_start_matches(['v', 'V']);
+        if value.is_empty() || value.eq_ignore_ascii_case("auto") {
+            return None;
+        }
+        value.parse().map_err(|_| log!("Ignoring invalid value for {}: {:?}", key, value)).ok()
+    };
+    (version("TALK_SIGNALING_API"), version("TALK_CONVERSATION_API"))
+}
+
+// The bridge's name in Talk, when it shouldn't be the account's
+pub fn talk_display_name_from_env() -> Option<String> {
+    env::var("TALK_DISPLAY_NAME").ok().filter(|v| !v.trim().is_empty())
+}
+
+// Where the DTLS certificate for Talk connections is kept; empty for a new
+// one per connection
+pub fn dtls_certificate_from_env() -> Option<PathBuf> {
+    let path = env::var("DTLS_CERT_FILE").unwrap_or("dtls-cert.pem".to_string());
+    (!path.trim().is_empty()).then(|| PathBuf::from(path.trim()))
+}
+
+// Further signaling servers of a cluster, for when the one Talk hands out
+// can't be reached
+pub fn signaling_servers_from_env() -> Vec<String> {
+    env_list("SIGNALING_SERVERS")
+}
+
+// Opus packets hold up to 120ms, and Discord frames are 20ms
+fn talk_frame_ms_from_env() -> u64 {
+    let ms = env_or("DISCORD_TO_NC_FRAME_MS", 20u64);
+    if ms == 0 || ms > 120 || !ms.is_multiple_of(20) {
+        log!("DISCORD_TO_NC_FRAME_MS must be 20, 40, 60, 80, 100 or 120, using 20");
+        return 20;
+    }
+    ms
+}
+
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    match env::var(key) {
+        Ok(v) => match v.trim().parse() {
+            Ok(parsed) => parsed,
+            Err(_) => {
+                log!("Ignoring invalid value for {}: {:?}", key, v);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+fn env_flag(key: &str, default: bool) -> bool {
+    env::var(key).map(|v| parse_flag(&v)).unwrap_or(default)
+}
+
+pub fn parse_flag(v: &str) -> bool {
+    matches!(v.trim().to_lowercase().as_str(), "1" | "true" | "yes" | "on")
+}
+
+// Comma separated list, empty entries dropped
+fn env_list(key: &str) -> Vec<String> {
+    env::var(key)
+        .map(|v| {
+            v.split(',')
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
@@ -0,0 +1,271 @@
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::f32::consts::PI;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::audio::codec::OpusEncoder;
+use crate::audio::mixer::{Mixer, SharedMixer};
+use crate::audio::repacketizer::Repacketizer;
+use crate::audio::{db_to_amplitude, wav, FRAME_SAMPLES, SAMPLE_RATE};
+use crate::config::{AnnouncementConfig, ChannelLayout, CuesConfig};
+use crate::talk_track::{TalkTrack, Timing};
+use crate::tts::{self, TtsEngine};
+
+// Tracks that are already there when a session starts aren't announced
+const SETTLE_TIME: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy)]
+pub enum Cue {
+    Join,
+    Leave,
+}
+
+// Where a session's cues go: the Discord-bound mixer and the Talk track
+pub struct CueTargets {
+    pub mixer: SharedMixer,
+    pub talk: Arc<TalkTrack>,
+    pub talk_channels: ChannelLayout,
+    pub frame_duration: Duration,
+}
+
+struct Session {
+    targets: CueTargets,
+    started: Instant,
+}
+
+// Short sounds played to one side when someone joins or leaves on the
+// other, optionally followed by a spoken announcement. Each platform already
+// chimes for its own participants, so Discord joins are announced into Talk
+// and Talk joins into Discord.
+pub struct CuePlayer {
+    chimes: AtomicBool,
+    // Mono, 48kHz, volume applied
+    join: Arc<Vec<i16>>,
+    leave: Arc<Vec<i16>>,
+    tts: Mutex<Option<Arc<dyn TtsEngine>>>,
+    tts_gain: f32,
+    session: Mutex<Option<Session>>,
+    // Discord users seen in the bridged channel
+    discord_members: Mutex<HashSet<u64>>,
+}
+
+pub type SharedCues = Arc<CuePlayer>;
+
+// What to play for one event: the chime, then the announcement
+struct Playback {
+    chime: Option<Arc<Vec<i16>>>,
+    speech: Option<(Arc<dyn TtsEngine>, String, f32)>,
+}
+
+impl Playback {
+    async fn pcm(self) -> Vec<Arc<Vec<i16>>> {
+        let mut sounds: Vec<_> = self.chime.into_iter().collect();
+        if let Some((tts, text, gain)) = self.speech {
+            match tts.synthesize(&text).await {
+                Ok(samples) => sounds.push(Arc::new(to_pcm(&samples, gain))),
+                Err(e) => log!("Failed to synthesize {:?}: {:#}", text, e),
+            }
+        }
+        sounds
+    }
+}
+
+impl CuePlayer {
+    pub fn new(config: &CuesConfig, announcements: &AnnouncementConfig) -> SharedCues {
+        let gain = db_to_amplitude(config.volume_db);
+        let load = |file: &Option<String>, default: fn() -> Vec<f32>| {
+            let samples = match file {
+                Some(path) => read_wav(Path::new(path)).unwrap_or_else(|e| {
+                    log!("Failed to load cue {}, using the built-in one: {:#}", path, e);
+                    default()
+                }),
+                None => default(),
+            };
+            Arc::new(to_pcm(&samples, gain))
+        };
+
+        Arc::new(Self {
+            chimes: AtomicBool::new(config.enabled),
+            join: load(&config.join_file, || tones(&[660.0, 880.0])),
+            leave: load(&config.leave_file, || tones(&[880.0, 660.0])),
+            tts: Mutex::new(tts::engine(announcements)),
+            tts_gain: db_to_amplitude(announcements.volume_db),
+            session: Mutex::new(None),
+            discord_members: Mutex::new(HashSet::new()),
+        })
+    }
+
+    // Settings changed; the sounds themselves stay
+    pub fn reconfigure(&self, chimes: bool, announcements: &AnnouncementConfig) {
+        self.chimes.store(chimes, Ordering::Relaxed);
+        *self.tts.lock().unwrap() = tts::engine(announcements);
+    }
+
+    pub fn attach(&self, targets: CueTargets) {
+        *self.session.lock().unwrap() = Some(Session {
+            targets,
+            started: Instant::now(),
+        });
+    }
+
+    pub fn detach(&self) {
+        *self.session.lock().unwrap() = None;
+        self.discord_members.lock().unwrap().clear();
+    }
+
+    // A Discord voice state change in the bridged channel's guild
+    pub fn discord_voice_state(&self, user_id: u64, name: Option<String>, was_here: bool, is_here: bool) {
+        let mut members = self.discord_members.lock().unwrap();
+        if is_here {
+            // Mutes, deafens and streams of those already here are voice
+            // state updates as well; only arriving chimes
+            if members.insert(user_id) && !was_here {
+                drop(members);
+                self.play_to_talk(self.playback(Cue::Join, "Discord", name));
+            }
+        } else if members.remove(&user_id) || was_here {
+            drop(members);
+            self.play_to_talk(self.playback(Cue::Leave, "Discord", name));
+        }
+    }
+
+    // A Talk participant's audio track appeared or ended
+    pub fn talk_participant(&self, cue: Cue, name: Option<String>) {
+        self.play_to_discord(self.playback(cue, "Nextcloud", name));
+    }
+
+    // Someone played a soundboard sound in the bridged Discord channel
+    pub fn discord_sound(&self, samples: &[f32], gain: f32) {
+        self.play_to_talk(Playback {
+            chime: Some(Arc::new(to_pcm(samples, gain))),
+            speech: None,
+        });
+    }
+
+    fn playback(&self, cue: Cue, platform: &str, name: Option<String>) -> Playback {
+        let chime = self.chimes.load(Ordering::Relaxed).then(|| match cue {
+            Cue::Join => self.join.clone(),
+            Cue::Leave => self.leave.clone(),
+        });
+        let speech = self.tts.lock().unwrap().clone().map(|tts| {
+            let verb = match cue {
+                Cue::Join => "joined",
+                Cue::Leave => "left",
+            };
+            let name = name.unwrap_or_else(|| "Someone".to_string());
+            (tts, format!("{} {} the {} call", name, verb, platform), self.tts_gain)
+        });
+        Playback { chime, speech }
+    }
+
+    // The session's targets, unless it only just started
+    fn targets<R>(&self, f: impl FnOnce(&CueTargets) -> R) -> Option<R> {
+        let session = self.session.lock().unwrap();
+        let session = session.as_ref().filter(|s| s.started.elapsed() >= SETTLE_TIME)?;
+        Some(f(&session.targets))
+    }
+
+    fn play_to_discord(&self, playback: Playback) {
+        if playback.chime.is_none() && playback.speech.is_none() {
+            return;
+        }
+        let Some(mixer) = self.targets(|t| t.mixer.clone()) else {
+            return;
+        };
+        tokio::spawn(async move {
+            let sounds = playback.pcm().await;
+            let input = Mixer::add_input(&mixer, "cue");
+            let channels = mixer.lock().unwrap().channels();
+            let mut interval = tokio::time::interval(Duration::from_millis(20));
+            for chunk in sounds.iter().flat_map(|s| s.chunks(FRAME_SAMPLES)) {
+                interval.tick().await;
+                input.push(&upmix(chunk, channels));
+            }
+            // Let the last frame be mixed before the input goes away
+            interval.tick().await;
+        });
+    }
+
+    fn play_to_talk(&self, playback: Playback) {
+        if playback.chime.is_none() && playback.speech.is_none() {
+            return;
+        }
+        let Some((track, channels, frame_duration)) =
+            self.targets(|t| (t.talk.clone(), t.talk_channels, t.frame_duration))
+        else {
+            return;
+        };
+        tokio::spawn(async move {
+            let sounds = playback.pcm().await;
+            let mut encoder = match OpusEncoder::new(channels) {
+                Ok(e) => e,
+                Err(e) => {
+                    log!("Failed to create cue encoder: {:?}", e);
+                    return;
+                }
+            };
+            let mut repacketizer = Repacketizer::new(frame_duration);
+            let mut interval = tokio::time::interval(Duration::from_millis(20));
+            for chunk in sounds.iter().flat_map(|s| s.chunks(FRAME_SAMPLES)) {
+                interval.tick().await;
+                let mut frame = upmix(chunk, channels.count());
+                frame.resize(FRAME_SAMPLES * channels.count(), 0);
+                track.note_frame(Timing::Wall);
+                let packets = encoder
+                    .encode(&frame)
+                    .and_then(|data| repacketizer.push(&data));
+                match packets {
+                    Ok(packets) => track.write(packets).await,
+                    Err(e) => log!("Failed to send cue to Talk: {:?}", e),
+                }
+            }
+            if let Some(packet) = repacketizer.flush() {
+                track.write(vec![packet]).await;
+            }
+        });
+    }
+}
+
+// Stops cues going to a session that has ended
+pub struct CueGuard(pub SharedCues);
+
+impl Drop for CueGuard {
+    fn drop(&mut self) {
+        self.0.detach();
+    }
+}
+
+fn to_pcm(samples: &[f32], gain: f32) -> Vec<i16> {
+    samples
+        .iter()
+        .map(|s| ((s * gain).clamp(-1.0, 1.0) * i16::MAX as f32) as i16)
+        .collect()
+}
+
+// Mono -> interleaved with `channels` channels
+fn upmix(mono: &[i16], channels: usize) -> Vec<i16> {
+    mono.iter().flat_map(|&s| std::iter::repeat_n(s, channels)).collect()
+}
+
+// The built-in cue: short sine notes with soft edges
+fn tones(freqs: &[f32]) -> Vec<f32> {
+    let note = SAMPLE_RATE as usize * 120 / 1000;
+    let fade = SAMPLE_RATE as usize * 10 / 1000;
+    freqs
+        .iter()
+        .flat_map(|&freq| {
+            (0..note).map(move |i| {
+                let edge = (i.min(note - 1 - i) as f32 / fade as f32).min(1.0);
+                (2.0 * PI * freq * i as f32 / SAMPLE_RATE as f32).sin() * edge * 0.5
+            })
+        })
+        .collect()
+}
+
+fn read_wav(path: &Path) -> Result<Vec<f32>> {
+    let data = std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    wav::decode(&data)
+}
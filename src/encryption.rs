@@ -0,0 +1,163 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use webrtc::dtls::extension::extension_use_srtp::SrtpProtectionProfile;
+use webrtc::sdp::SessionDescription;
+
+use crate::config::EncryptionPolicy;
+
+// What Songbird leaves after the payload. The AEAD "rtpsize" modes end
+// packets with a 16 byte tag and a 4 byte nonce; the nonce isn't counted
+// once a packet is decrypted. The deprecated xsalsa20 modes put the tag in
+// front, so their decrypted packets have 0 and undecrypted ones 0, 4 or 24.
+const DISCORD_AEAD_SUFFIXES: [usize; 2] = [16, 20];
+
+// DTLS fingerprint hashes that still count as sound
+const STRONG_FINGERPRINTS: &[&str] = &["sha-256", "sha-384", "sha-512"];
+
+pub type SharedEncryption = Arc<EncryptionMonitor>;
+
+// What each leg of the media path is protected with, as far as the bridge
+// can tell. None until the leg has carried media (Discord) or been
+// negotiated (Talk).
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct EncryptionStatus {
+    pub discord: Option<&'static str>,
+    pub talk: Option<&'static str>,
+    // The first thing found weaker than expected, anywhere in the path
+    pub downgrade: Option<String>,
+    // Both legs known and nothing downgraded
+    pub ok: bool,
+}
+
+// Watches a session's Discord packets and Talk SDPs. With the refuse policy
+// any downgrade ends the session; with warn it is logged once and shown in
+// the status.
+pub struct EncryptionMonitor {
+    policy: EncryptionPolicy,
+    status: Mutex<EncryptionStatus>,
+    refused: Notify,
+}
+
+impl EncryptionMonitor {
+    pub fn new(policy: EncryptionPolicy) -> SharedEncryption {
+        Arc::new(Self {
+            policy,
+            status: Mutex::new(EncryptionStatus::default()),
+            refused: Notify::new(),
+        })
+    }
+
+    // A new session starts out unverified
+    pub fn reset(&self) {
+        *self.status.lock().unwrap() = EncryptionStatus::default();
+    }
+
+    pub fn status(&self) -> EncryptionStatus {
+        let mut status = self.status.lock().unwrap().clone();
+        status.ok = status.discord.is_some() && status.talk.is_some() && status.downgrade.is_none();
+        status
+    }
+
+    // What the Talk peer connections may negotiate. webrtc-rs doesn't say
+    // which profile was picked, so refusing is done by only offering AEAD.
+    pub fn srtp_profiles(&self) -> Vec<SrtpProtectionProfile> {
+        match self.policy {
+            EncryptionPolicy::Refuse => vec![SrtpProtectionProfile::Srtp_Aead_Aes_128_Gcm],
+            EncryptionPolicy::Warn => vec![
+                SrtpProtectionProfile::Srtp_Aead_Aes_128_Gcm,
+                SrtpProtectionProfile::Srtp_Aes128_Cm_Hmac_Sha1_80,
+            ],
+        }
+    }
+
+    // A decrypted Discord packet, by how much Songbird stripped off its end.
+    // Returns false if it must not be forwarded.
+    pub fn discord_packet(&self, suffix: usize) -> bool {
+        if DISCORD_AEAD_SUFFIXES.contains(&suffix) {
+            self.status.lock().unwrap().discord.get_or_insert("aead_rtpsize");
+            return true;
+        }
+        let first = self.status.lock().unwrap().discord.replace("xsalsa20_poly1305").is_none();
+        if first {
+            self.downgrade("Discord voice uses the deprecated xsalsa20_poly1305 encryption".to_string());
+        }
+        self.policy == EncryptionPolicy::Warn
+    }
+
+    // A remote offer or answer from Talk, before it is applied
+    pub fn talk_sdp(&self, sdp: &SessionDescription) -> Result<()> {
+        let problem = sdp_problem(sdp);
+        if let Some(problem) = &problem {
+            self.downgrade(format!("Talk {}", problem));
+            if self.policy == EncryptionPolicy::Refuse {
+                anyhow::bail!("Refusing Talk media: {}", problem);
+            }
+        }
+        // Every Talk connection of the session reports here; one weak one
+        // marks the whole leg
+        let mut status = self.status.lock().unwrap();
+        if status.talk != Some("weak") {
+            status.talk = Some(match (problem, self.policy) {
+                (Some(_), _) => "weak",
+                (None, EncryptionPolicy::Refuse) => "dtls_srtp_aead",
+                // GCM or AES-CM with HMAC-SHA1, whichever the peer preferred
+                (None, EncryptionPolicy::Warn) => "dtls_srtp",
+            });
+        }
+        Ok(())
+    }
+
+    // Resolves once the refuse policy has stopped the session's media. A
+    // wakeup left over from an earlier session is ignored.
+    pub async fn refused(&self) {
+        loop {
+            self.refused.notified().await;
+            if self.status.lock().unwrap().downgrade.is_some() {
+                return;
+            }
+        }
+    }
+
+    fn downgrade(&self, what: String) {
+        let mut status = self.status.lock().unwrap();
+        if status.downgrade.is_none() {
+            match self.policy {
+                EncryptionPolicy::Warn => log!("Encryption downgrade: {}", what),
+                EncryptionPolicy::Refuse => {
+                    log!("Encryption downgrade, refusing: {}", what);
+                    self.refused.notify_one();
+                }
+            }
+            status.downgrade = Some(what);
+        }
+    }
+}
+
+// Plaintext RTP, SDES keys sent through signaling, or a weak or missing
+// DTLS fingerprint on any audio or video section
+fn sdp_problem(sdp: &SessionDescription) -> Option<String> {
+    let session_fingerprint = sdp.attribute("fingerprint").map(String::as_str);
+    for media in &sdp.media_descriptions {
+        let kind = &media.media_name.media;
+        if (kind != "audio" && kind != "video") || media.media_name.port.value == 0 {
+            continue;
+        }
+        if !media.media_name.protos.iter().any(|p| p.contains("SAVP")) {
+            return Some(format!("{} is offered as plaintext RTP", kind));
+        }
+        if media.attribute("crypto").is_some() {
+            return Some(format!("{} carries SDES keys in the SDP", kind));
+        }
+        let fingerprint = media.attribute("fingerprint").flatten().or(session_fingerprint);
+        let Some(fingerprint) = fingerprint else {
+            return Some(format!("{} has no DTLS fingerprint", kind));
+        };
+        let hash = fingerprint.split_whitespace().next().unwrap_or_default().to_lowercase();
+        if !STRONG_FINGERPRINTS.contains(&hash.as_str()) {
+            return Some(format!("{} has a {} DTLS fingerprint", kind, hash));
+        }
+    }
+    None
+}
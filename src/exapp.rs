@@ -0,0 +1,269 @@
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use reqwest::Method;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use url::Url;
+
+use crate::config::ExAppConfig;
+use crate::manager::BridgeManager;
+use crate::nextcloud::tls::Tls;
+
+// Settings the Nextcloud admin can set for the ExApp (appconfig_ex), pulled
+// into the environment under their upper-cased names before the rest of
+// the config is read
+const CONFIG_KEYS: &[&str] = &[
+    "discord_token",
+    "discord_guild_id",
+    "discord_channel_id",
+    "nextcloud_username",
+    "nextcloud_password",
+    "nextcloud_room_token",
+    "bridge_name",
+];
+
+// Free-form KEY=VALUE lines for everything else in .env.example
+const EXTRA_ENV_KEY: &str = "extra_env";
+
+// Requests to AppAPI, signed as the ExApp
+struct AppApiClient {
+    config: ExAppConfig,
+    http: reqwest::Client,
+}
+
+impl AppApiClient {
+    fn new(config: ExAppConfig, tls: &Tls) -> Self {
+        Self {
+            config,
+            http: tls.http.clone(),
+        }
+    }
+
+    async fn request(&self, method: Method, path: &str, body: Value) -> Result<Value> {
+        let base_url = Url::parse(&self.config.nextcloud_url).context("Invalid Nextcloud URL")?;
+        let api_url = base_url.join(&format!("/ocs/v1.php/apps/app_api/{}", path))?;
+
+        // No user: the ExApp acts on its own behalf
+        let auth = BASE64.encode(format!(":{}", self.config.app_secret));
+        let resp = self
+            .http
+            .request(method, api_url)
+            .header("EX-APP-ID", &self.config.app_id)
+            .header("EX-APP-VERSION", &self.config.app_version)
+            .header("AA-VERSION", &self.config.aa_version)
+            .header("AUTHORIZATION-APP-API", auth)
+            .header("OCS-APIRequest", "true")
+            .header("Accept", "application/json")
+            .json(&body)
+            .send()
+            .await
+            .context("Failed to send request to AppAPI")?;
+
+        if !resp.status().is_success() {
+            anyhow::bail!("AppAPI returned error: {}", resp.status());
+        }
+        Ok(resp.json().await.unwrap_or(Value::Null))
+    }
+
+    async fn config_values(&self) -> Result<Vec<(String, String)>> {
+        let mut keys: Vec<&str> = CONFIG_KEYS.to_vec();
+        keys.push(EXTRA_ENV_KEY);
+        let body = self
+            .request(Method::POST, "api/v1/ex-app/config/get-values", json!({ "configKeys": keys }))
+            .await?;
+        let values = body
+            .get("ocs")
+            .and_then(|o| o.get("data"))
+            .and_then(|d| d.as_array())
+            .context("No config values in response")?;
+
+        Ok(values
+            .iter()
+            .filter_map(|v| {
+                let key = v.get("configkey")?.as_str()?;
+                let value = v.get("configvalue")?.as_str()?;
+                Some((key.to_string(), value.to_string()))
+            })
+            .collect())
+    }
+
+    async fn report_progress(&self, progress: u8) -> Result<()> {
+        self.request(Method::PUT, "api/v1/ex-app/status", json!({ "progress": progress }))
+            .await?;
+        Ok(())
+    }
+}
+
+// Copy the settings made in Nextcloud into the environment. Anything left
+// empty there keeps its value from the environment / .env.
+pub async fn pull_config(config: &ExAppConfig, tls: &Tls) -> Result<()> {
+    let values = AppApiClient::new(config.clone(), tls).config_values().await?;
+    let mut applied = 0;
+    for (key, value) in values {
+        if value.trim().is_empty() {
+            continue;
+        }
+        if key == EXTRA_ENV_KEY {
+            for line in value.lines().map(str::trim) {
+                if let Some((k, v)) = line.split_once('=').filter(|_| !line.starts_with('#')) {
+                    std::env::set_var(k.trim(), v.trim());
+                    applied += 1;
+                }
+            }
+        } else if CONFIG_KEYS.contains(&key.as_str()) {
+            std::env::set_var(key.to_uppercase(), value);
+            applied += 1;
+        }
+    }
+    log!("Applied {} settings from Nextcloud", applied);
+    Ok(())
+}
+
+// The HTTP endpoints AppAPI drives the ExApp through: heartbeat, init and
+// enable/disable. Bridges only run while the app is enabled in Nextcloud
+// (`occ app_api:app:enable` / `disable` or the apps page).
+pub async fn serve(config: ExAppConfig, tls: &Tls, manager: Arc<BridgeManager>) -> Result<()> {
+    let addr = format!("{}:{}", config.host, config.port);
+    let listener = TcpListener::bind(&addr)
+        .await
+        .with_context(|| format!("Failed to bind ExApp endpoint {}", addr))?;
+    log!("ExApp endpoint listening on {}", addr);
+
+    let client = Arc::new(AppApiClient::new(config, tls));
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let client = client.clone();
+        let manager = manager.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &client, &manager).await {
+                log!("ExApp connection error: {:?}", e);
+            }
+        });
+    }
+}
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    query: String,
+    auth: Option<String>,
+}
+
+async fn read_request(stream: &mut TcpStream) -> Result<HttpRequest> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().context("Empty request")?.to_string();
+    let target = parts.next().context("No request target")?;
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let (path, query) = (path.to_string(), query.to_string());
+
+    let mut auth = None;
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        match name.trim().to_ascii_lowercase().as_str() {
+            "authorization-app-api" => auth = Some(value.trim().to_string()),
+            "content-length" => content_length = value.trim().parse().unwrap_or(0),
+            _ => {}
+        }
+    }
+    // Nothing here needs the body, but it has to be read off the socket
+    let mut body = vec![0; content_length.min(64 * 1024)];
+    reader.read_exact(&mut body).await?;
+
+    Ok(HttpRequest {
+        method,
+        path,
+        query,
+        auth,
+    })
+}
+
+// AUTHORIZATION-APP-API is base64("<user>:<app secret>")
+fn authorized(request: &HttpRequest, secret: &str) -> bool {
+    let Some(decoded) = request.auth.as_deref().and_then(|a| BASE64.decode(a).ok()) else {
+        return false;
+    };
+    String::from_utf8_lossy(&decoded)
+        .split_once(':')
+        .is_some_and(|(_, s)| s == secret)
+}
+
+async fn handle_connection(mut stream: TcpStream, client: &Arc<AppApiClient>, manager: &BridgeManager) -> Result<()> {
+    let request = read_request(&mut stream).await?;
+
+    let (status, body) = if request.path == "/heartbeat" {
+        (200, json!({ "status": "ok" }))
+    } else if !authorized(&request, &client.config.app_secret) {
+        (401, json!({ "error": "unauthorized" }))
+    } else {
+        match (request.method.as_str(), request.path.as_str()) {
+            ("POST", "/init") => {
+                // Nothing to download or migrate, so init is done right away
+                let client = client.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = client.report_progress(100).await {
+                        log!("Failed to report ExApp init: {:?}", e);
+                    }
+                });
+                (200, json!({}))
+            }
+            ("PUT", "/enabled") => {
+                let enabled = request.query.split('&').any(|p| p == "enabled=1");
+                (200, json!({ "error": set_enabled(manager, enabled).await }))
+            }
+            _ => (404, json!({ "error": "not found" })),
+        }
+    };
+
+    let body = body.to_string();
+    let reason = match status {
+        200 => "OK",
+        401 => "Unauthorized",
+        _ => "Not Found",
+    };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+// Start or stop every bridge. Returns an error message for AppAPI, empty on
+// success.
+async fn set_enabled(manager: &BridgeManager, enabled: bool) -> String {
+    log!("ExApp {} by Nextcloud", if enabled { "enabled" } else { "disabled" });
+    let mut errors = Vec::new();
+    // Bridges with a temporary channel come and go with the Talk call
+    for bridge in manager.list().into_iter().filter(|b| !enabled || !b.temporary_channel) {
+        let name = Some(bridge.name.as_str());
+        let result = if enabled {
+            match manager.start(name).await {
+                // Already running is fine when AppAPI re-sends enable
+                Err(_) if manager.is_running(name) => Ok(()),
+                result => result,
+            }
+        } else {
+            manager.stop(name).await
+        };
+        if let Err(e) = result {
+            errors.push(format!("{}: {:#}", bridge.name, e));
+        }
+    }
+    errors.join("; ")
+}
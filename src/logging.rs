@@ -0,0 +1,41 @@
+use std::collections::VecDeque;
+use std::sync::{Mutex, TryLockError};
+
+// How many recent lines are kept for `logs` on the admin socket
+const MAX_LINES: usize = 1000;
+
+static LINES: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+// Prints a line to stdout like println!, and keeps it in the in-memory tail
+// so operators can read recent output over the admin socket.
+#[macro_export]
+macro_rules! log {
+    ($($arg:tt)*) => {{
+        let line = format!($($arg)*);
+        println!("{}", line);
+        $crate::logging::record(line);
+    }};
+}
+
+pub fn record(line: String) {
+    let mut lines = LINES.lock().unwrap();
+    if lines.len() >= MAX_LINES {
+        lines.pop_front();
+    }
+    lines.push_back(line);
+}
+
+pub fn tail(count: usize) -> Vec<String> {
+    let lines = LINES.lock().unwrap();
+    lines.iter().skip(lines.len().saturating_sub(count)).cloned().collect()
+}
+
+// For the panic hook, which may have interrupted a `record` on its own thread
+pub fn try_tail(count: usize) -> Vec<String> {
+    let lines = match LINES.try_lock() {
+        Ok(lines) => lines,
+        Err(TryLockError::Poisoned(e)) => e.into_inner(),
+        Err(TryLockError::WouldBlock) => return Vec::new(),
+    };
+    lines.iter().skip(lines.len().saturating_sub(count)).cloned().collect()
+}
@@ -0,0 +1,80 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+// Everything in this process (and known other bridges) that produces bridged
+// audio: the Talk sessions we publish from and the Discord users we speak
+// as. Audio coming from one of them is never forwarded again, or two
+// bridges between the same rooms would feed each other forever. Discord
+// users an operator excluded at runtime are kept apart, as they can be let
+// back in.
+pub struct LoopGuard {
+    talk_sessions: Mutex<HashSet<String>>,
+    discord_users: Mutex<HashSet<u64>>,
+    excluded: Mutex<HashSet<u64>>,
+}
+
+pub type SharedLoopGuard = Arc<LoopGuard>;
+
+impl LoopGuard {
+    // `discord_users`: bots of other bridge instances and anyone else never
+    // to be forwarded, from config
+    pub fn new(discord_users: impl IntoIterator<Item = u64>) -> SharedLoopGuard {
+        Arc::new(Self {
+            talk_sessions: Mutex::new(HashSet::new()),
+            discord_users: Mutex::new(discord_users.into_iter().collect()),
+            excluded: Mutex::new(HashSet::new()),
+        })
+    }
+
+    // Registered for as long as the returned guard lives
+    pub fn add_talk_session(self: &Arc<Self>, session_id: Option<&str>) -> TalkSessionGuard {
+        let session_id = session_id.map(str::to_string);
+        if let Some(id) = &session_id {
+            self.talk_sessions.lock().unwrap().insert(id.clone());
+        }
+        TalkSessionGuard {
+            guard: self.clone(),
+            session_id,
+        }
+    }
+
+    pub fn is_own_talk_session(&self, session_id: &str) -> bool {
+        self.talk_sessions.lock().unwrap().contains(session_id)
+    }
+
+    pub fn add_discord_user(&self, user_id: u64) {
+        self.discord_users.lock().unwrap().insert(user_id);
+    }
+
+    pub fn is_own_discord_user(&self, user_id: u64) -> bool {
+        self.discord_users.lock().unwrap().contains(&user_id)
+    }
+
+    // Returns whether anything changed
+    pub fn set_excluded(&self, user_id: u64, excluded: bool) -> bool {
+        let mut set = self.excluded.lock().unwrap();
+        if excluded {
+            set.insert(user_id)
+        } else {
+            set.remove(&user_id)
+        }
+    }
+
+    // Audio from this Discord user is not to be forwarded
+    pub fn ignores_discord_user(&self, user_id: u64) -> bool {
+        self.is_own_discord_user(user_id) || self.excluded.lock().unwrap().contains(&user_id)
+    }
+}
+
+pub struct TalkSessionGuard {
+    guard: SharedLoopGuard,
+    session_id: Option<String>,
+}
+
+impl Drop for TalkSessionGuard {
+    fn drop(&mut self) {
+        if let Some(id) = &self.session_id {
+            self.guard.talk_sessions.lock().unwrap().remove(id);
+        }
+    }
+}
@@ -1,53 +1,240 @@
 use anyhow::Context as _;
 use serenity::async_trait;
+use serenity::model::application::Interaction;
+use serenity::model::channel::{GuildChannel, Message};
+use serenity::model::event::Event;
 use serenity::model::gateway::Ready;
+use serenity::model::voice::VoiceState;
 use serenity::prelude::*;
 use songbird::SerenityInit;
 use std::env;
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
 
-mod nextcloud;
+// Declared first so log! is available in every other module
+#[macro_use]
+mod logging;
+
+mod access;
+mod admin;
+mod audio;
+mod backoff;
 mod bridge;
+mod commands;
+mod config;
+mod crash;
+mod encryption;
+mod cues;
+mod exapp;
+mod loop_guard;
+mod manager;
+mod migrate;
+mod nextcloud;
+mod publisher;
+mod recorder;
+mod roster;
+mod settings;
+mod soundboard;
+mod speakers;
+mod stats;
+mod store;
+mod summary;
+mod talk_track;
+mod tts;
+mod update;
 
-struct Handler;
+struct Handler {
+    commands: commands::BridgeCommands,
+    ignore_bots: bool,
+}
+
+// Gateway events serenity has no type for
+struct RawHandler {
+    bot: usize,
+    manager: Arc<manager::BridgeManager>,
+    soundboard: Option<Arc<soundboard::Soundboard>>,
+}
+
+#[async_trait]
+impl RawEventHandler for RawHandler {
+    async fn raw_event(&self, _ctx: Context, event: Event) {
+        let (Event::Unknown(event), Some(soundboard)) = (event, &self.soundboard) else {
+            return;
+        };
+        if event.kind != "VOICE_CHANNEL_EFFECT_SEND" {
+            return;
+        }
+        match serde_json::from_value::<soundboard::VoiceChannelEffect>(event.value) {
+            Ok(effect) => self.manager.discord_soundboard(self.bot, soundboard, &effect).await,
+            Err(e) => log!("Failed to parse voice channel effect: {}", e),
+        }
+    }
+}
 
 #[async_trait]
 impl EventHandler for Handler {
-    async fn ready(&self, _: Context, ready: Ready) {
-        println!("{} is connected!", ready.user.name);
+    async fn ready(&self, ctx: Context, ready: Ready) {
+        log!("{} is connected!", ready.user.name);
+        self.commands.manager.add_bot_user(ready.user.id.get());
+
+        if let Err(e) = self.commands.register(&ctx).await {
+            log!("Failed to register slash commands: {:?}", e);
+        }
+    }
+
+    async fn voice_state_update(&self, ctx: Context, old: Option<VoiceState>, new: VoiceState) {
+        let Some(guild_id) = new.guild_id else {
+            return;
+        };
+        if new.user_id == ctx.cache.current_user().id {
+            self.commands
+                .manager
+                .discord_bot_muted(self.commands.bot, guild_id, new.mute || new.suppress);
+            return;
+        }
+        if self.ignore_bots && new.member.as_ref().is_some_and(|m| m.user.bot) {
+            self.commands.manager.ignore_discord_user(new.user_id.get());
+        }
+        // `old` is only there if the guild is cached
+        let before = old.and_then(|o| o.channel_id);
+        let name = new.member.as_ref().map(|m| m.display_name().to_string());
+        self.commands.manager.discord_voice_state(
+            self.commands.bot,
+            guild_id,
+            new.user_id.get(),
+            name,
+            before,
+            new.channel_id,
+        );
+    }
+
+    async fn channel_delete(&self, _ctx: Context, channel: GuildChannel, _messages: Option<Vec<Message>>) {
+        self.commands
+            .manager
+            .discord_channel_deleted(self.commands.bot, channel.guild_id, channel.id)
+            .await;
+    }
+
+    async fn channel_update(&self, _ctx: Context, old: Option<GuildChannel>, new: GuildChannel) {
+        self.commands
+            .manager
+            .discord_channel_updated(self.commands.bot, old.as_ref(), &new)
+            .await;
+    }
+
+    // Only direct messages, which carry access codes
+    async fn message(&self, ctx: Context, msg: Message) {
+        if msg.guild_id.is_some() || msg.author.bot {
+            return;
+        }
+        let Some(bridges) =
+            self.commands
+                .manager
+                .redeem_access_code(self.commands.bot, msg.author.id.get(), &msg.content)
+        else {
+            return;
+        };
+        let reply = if bridges.is_empty() {
+            "That isn't the access code of a running call.".to_string()
+        } else {
+            format!("Thanks, Talk can hear you now in {}.", bridges.join(", "))
+        };
+        if let Err(e) = msg.channel_id.say(&ctx.http, reply).await {
+            log!("Failed to answer an access code: {:?}", e);
+        }
     }
+
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        if let Interaction::Command(command) = interaction {
+            if command.data.name == "bridge" {
+                self.commands.handle(&ctx, &command).await;
+            }
+        }
+    }
+}
+
+// EXTRA_BRIDGES=standup,music adds bridges set up through
+// BRIDGE_<NAME>_CHANNEL_ID and BRIDGE_<NAME>_ROOM_TOKEN, plus optionally
+// BRIDGE_<NAME>_GUILD_ID (default: DISCORD_GUILD_ID) and BRIDGE_<NAME>_BOT
+// (index into the bot tokens, default: picked automatically) and
+// BRIDGE_<NAME>_TTS_ANNOUNCEMENTS / BRIDGE_<NAME>_TEMP_CHANNEL /
+// BRIDGE_<NAME>_ACCESS_CODES (default: TTS_ANNOUNCEMENTS /
+// DISCORD_TEMP_CHANNEL / DISCORD_ACCESS_CODES) and
+// BRIDGE_<NAME>_MERGE_ROOM_TOKEN (not inherited). Everything else is shared with the main bridge.
+fn extra_bridges(primary: &manager::BridgeDefinition) -> anyhow::Result<Vec<(manager::BridgeDefinition, Option<usize>)>> {
+    let names = env::var("EXTRA_BRIDGES").unwrap_or_default();
+    names
+        .split(',')
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+        .map(|name| {
+            let key = |suffix: &str| format!("BRIDGE_{}_{}", name.to_uppercase(), suffix);
+            let var = |suffix: &str| env::var(key(suffix)).ok().filter(|v| !v.trim().is_empty());
+            let id = |suffix: &str| var(suffix).and_then(|v| v.trim().parse::<u64>().ok()).filter(|id| *id != 0);
+
+            let channel_id = id("CHANNEL_ID").with_context(|| format!("{} not set", key("CHANNEL_ID")))?;
+            let room_token = var("ROOM_TOKEN").with_context(|| format!("{} not set", key("ROOM_TOKEN")))?;
+            let mut config = primary.config.clone();
+            if let Some(v) = var("TTS_ANNOUNCEMENTS") {
+                config.announcements.enabled = config::parse_flag(&v);
+            }
+            if let Some(v) = var("TEMP_CHANNEL") {
+                config.temporary_channel = config::parse_flag(&v);
+            }
+            if let Some(v) = var("ACCESS_CODES") {
+                config.access_codes = config::parse_flag(&v);
+            }
+            config.merge_room_token = var("MERGE_ROOM_TOKEN");
+            let definition = manager::BridgeDefinition {
+                name: name.to_string(),
+                guild_id: id("GUILD_ID").map(serenity::model::id::GuildId::new).unwrap_or(primary.guild_id),
+                channel_id: serenity::model::id::ChannelId::new(channel_id),
+                nextcloud: nextcloud::signaling::Config {
+                    room_password: var("ROOM_PASSWORD"),
+                    ..primary.nextcloud.clone()
+                },
+                room_token,
+                config,
+                bot: 0,
+            };
+            let bot = var("BOT").and_then(|b| b.trim().parse().ok());
+            Ok((definition, bot))
+        })
+        .collect()
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    // Load .env file if it exists
+    // Upgrade .env from older releases, then load it if it exists
+    migrate::config_file(std::path::Path::new(".env")).context("Failed to upgrade .env")?;
     dotenv::dotenv().ok();
+    migrate::check_env()?;
 
-    // Configure the client with your Discord bot token in the environment.
-    let token = env::var("DISCORD_TOKEN").context("Expected a token in the environment")?;
+    let admin_socket = env::var("BRIDGE_ADMIN_SOCKET").unwrap_or("bridge.sock".to_string());
 
-    // Set gateway intents, which decides what events the bot will be notified about
-    let intents = GatewayIntents::GUILD_VOICE_STATES
-        | GatewayIntents::GUILD_MESSAGES
-        | GatewayIntents::MESSAGE_CONTENT;
+    // `bridge shell` talks to an already running instance instead of starting one
+    if env::args().nth(1).as_deref() == Some("shell") {
+        return admin::run_shell(&admin_socket).await;
+    }
+    log!("Starting {} {}", env!("CARGO_PKG_NAME"), update::version());
 
-    // Create a new instance of the Client, logging in as a bot.
-    let mut client = Client::builder(&token, intents)
-        .event_handler(Handler)
-        .register_songbird()
-        .await
-        .context("Err creating client")?;
+    // Reaching Nextcloud needs these already, so they can't come from there
+    let tls = nextcloud::tls::Tls::new(&config::TlsConfig::from_env()).context("Invalid TLS settings")?;
 
-    // Start a single shard, and start listening to events.
-    println!("Starting Discord Bridge Client...");
+    // As an ExApp, settings made in Nextcloud take precedence over .env
+    let exapp = config::ExAppConfig::from_env();
+    if let Some(exapp) = &exapp {
+        log!("Running as Nextcloud ExApp {}", exapp.app_id);
+        if let Err(e) = exapp::pull_config(exapp, &tls).await {
+            log!("Failed to pull settings from Nextcloud, using the environment: {:#}", e);
+        }
+    }
 
-    let songbird = client.data.read().await.get::<songbird::SongbirdKey>().unwrap().clone();
+    // Configure the client with your Discord bot token in the environment.
+    let token = env::var("DISCORD_TOKEN").context("Expected a token in the environment")?;
 
-    // Spawn Discord Client
-    let _client_handle = tokio::spawn(async move {
-        if let Err(why) = client.start().await {
-            println!("Client error: {:?}", why);
-        }
-    });
+    // Gateway intents decide what events the bot will be notified about
+    let discord = config::DiscordConfig::from_env();
 
     // Initialize Bridge Session
     // In a real app, these would come from config or command arguments
@@ -74,35 +261,180 @@ async fn main() -> anyhow::Result<()> {
 
     // Initialize Nextcloud Config
     let nc_url = env::var("NEXTCLOUD_URL").context("NEXTCLOUD_URL not set")?;
-    let nc_user = env::var("NEXTCLOUD_USERNAME").context("NEXTCLOUD_USERNAME not set")?;
-    let nc_pass = env::var("NEXTCLOUD_PASSWORD").context("NEXTCLOUD_PASSWORD not set")?;
+    // Without a login the bridge joins as a guest, which only public rooms take
+    let nc_user = env::var("NEXTCLOUD_USERNAME").unwrap_or_default();
+    let nc_pass = env::var("NEXTCLOUD_PASSWORD").unwrap_or_default();
     let nc_room = env::var("NEXTCLOUD_ROOM_TOKEN").context("NEXTCLOUD_ROOM_TOKEN not set")?;
 
-    println!("Initializing Nextcloud Signaling...");
-    let config = nextcloud::signaling::Config {
+    // Renewed OAuth2 refresh tokens are kept here
+    let state_file = env::var("BRIDGE_STATE_FILE").unwrap_or("bridge-state.json".to_string());
+    let store = Arc::new(store::Store::open(&state_file).context("Failed to open state file")?);
+    if let Some(path) = config::dtls_certificate_from_env() {
+        nextcloud::webrtc::load_certificate(&path).context("Failed to set up the DTLS certificate")?;
+    }
+    let bearer = config::OAuthConfig::from_env().map(|oauth| {
+        log!("Authenticating to Nextcloud with an OAuth2 token");
+        let refresh = oauth.refresh.map(|refresh| nextcloud::auth::Refresh {
+            client_id: refresh.client_id,
+            client_secret: refresh.client_secret,
+            token: refresh.refresh_token,
+            store: store.clone(),
+        });
+        nextcloud::auth::BearerToken::new(oauth.access_token, refresh)
+    });
+    if nc_user.is_empty() && bearer.is_none() {
+        log!("No NEXTCLOUD_USERNAME set, joining Talk as a guest");
+    }
+
+    // Effects have to be known before the chains in the config are parsed
+    audio::effect::register_builtins();
+
+    let mut nc_config = nextcloud::signaling::Config {
         nextcloud_url: nc_url,
         username: nc_user,
         password: nc_pass,
+        bearer,
+        timeout: config::signaling_timeout_from_env(),
+        outbox: config::OutboxConfig::from_env(),
+        reconnect: config::ReconnectConfig::from_env(),
+        tls: tls.clone(),
+        servers: config::signaling_servers_from_env(),
+        ice: config::IceConfig::from_env(),
+        api: Default::default(),
+        nick: config::talk_display_name_from_env(),
+        room_password: env::var("NEXTCLOUD_ROOM_PASSWORD").ok().filter(|v| !v.is_empty()),
+    };
+    let (signaling_api, conversation_api) = config::talk_api_versions_from_env();
+    nc_config.api = nextcloud::capabilities::ApiVersions::resolve(&nc_config, signaling_api, conversation_api).await;
+
+    let definition = manager::BridgeDefinition {
+        name: env::var("BRIDGE_NAME").unwrap_or("default".to_string()),
+        guild_id,
+        channel_id,
+        nextcloud: nc_config,
+        room_token: nc_room,
+        config: config::BridgeConfig::from_env(),
+        bot: 0,
     };
 
-    let mut signaling = nextcloud::signaling::SignalingClient::new(config);
-    signaling.connect(&nc_room).await.context("Failed to connect to Signaling")?;
+    let nextcloud_config = definition.nextcloud.clone();
+    let tokens: Vec<String> = std::iter::once(token).chain(discord.extra_tokens.clone()).collect();
+    let mut definitions = vec![(definition.clone(), None)];
+    definitions.extend(extra_bridges(&definition)?);
+    let definitions = manager::assign_bots(definitions, tokens.len());
+    let mut intents = discord.intents();
+    // Access codes arrive as direct messages
+    if definitions.iter().any(|d| d.config.access_codes) {
+        intents |= GatewayIntents::DIRECT_MESSAGES;
+    }
 
-    println!("Initializing Nextcloud WebRTC...");
-    let nc_webrtc = nextcloud::webrtc::NextcloudWebRTC::new().await.context("Failed to init WebRTC")?;
+    // Created up front so the manager can be shared with the event handlers
+    let bots: Vec<manager::DiscordBot> = tokens
+        .iter()
+        .map(|token| manager::DiscordBot {
+            songbird: songbird::Songbird::serenity(),
+            http: Arc::new(serenity::http::Http::new(token)),
+        })
+        .collect();
+    let songbirds: Vec<_> = bots.iter().map(|b| b.songbird.clone()).collect();
+    let loops = loop_guard::LoopGuard::new(discord.ignore_users.iter().copied());
+    let manager = Arc::new(manager::BridgeManager::new(definitions, bots, store, loops));
+    let soundboard = discord
+        .soundboard
+        .then(|| soundboard::Soundboard::new(&discord.soundboard_decoder))
+        .and_then(|soundboard| match soundboard {
+            Ok(soundboard) => Some(Arc::new(soundboard)),
+            Err(e) => {
+                log!("Soundboard relay disabled: {:#}", e);
+                None
+            }
+        });
 
-    let session = bridge::BridgeSession::new(
-        nc_webrtc,
-        signaling,
-        songbird,
-        guild_id,
-        channel_id
-    );
+    for (bot, (token, songbird)) in tokens.iter().zip(songbirds).enumerate() {
+        let handler = Handler {
+            commands: commands::BridgeCommands {
+                bot,
+                manager: manager.clone(),
+            },
+            ignore_bots: discord.ignore_bots,
+        };
+
+        // Create a new instance of the Client, logging in as a bot.
+        let mut client = Client::builder(token, intents)
+            .cache_settings(discord.cache_settings())
+            .event_handler(handler)
+            .raw_event_handler(RawHandler {
+                bot,
+                manager: manager.clone(),
+                soundboard: soundboard.clone(),
+            })
+            .register_songbird_with(songbird)
+            .await
+            .context("Err creating client")?;
+
+        // Start a single shard, and start listening to events.
+        log!("Starting Discord Bridge Client {}...", bot);
+
+        // Spawn Discord Client
+        tokio::spawn(async move {
+            if let Err(why) = client.start().await {
+                log!("Client error: {:?}", why);
+            }
+        });
+    }
+
+    let crash = config::CrashConfig::from_env();
+    let crashes = crash::install(&crash, manager.clone());
+    tokio::spawn(crash::upload(crash, crashes));
+
+    let admin_manager = manager.clone();
+    tokio::spawn(async move {
+        if let Err(e) = admin::serve(admin_socket, admin_manager).await {
+            log!("Admin socket failed: {:?}", e);
+        }
+    });
+
+    let metrics = config::MetricsConfig::from_env();
+    if let Some(endpoint) = &metrics.endpoint {
+        let client = nextcloud::metrics::MetricsClient::new(nextcloud_config, endpoint)?;
+        log!("Pushing metrics to {} every {:?}", endpoint, metrics.interval);
+        tokio::spawn(nextcloud::metrics::run(client, metrics, manager.clone()));
+    }
+
+    if let Some(update_check) = config::UpdateCheckConfig::from_env() {
+        tokio::spawn(update::run(update_check));
+    }
+
+    // AppAPI decides when an ExApp runs; otherwise start right away
+    match exapp {
+        Some(exapp) => {
+            let exapp_manager = manager.clone();
+            tokio::spawn(async move {
+                if let Err(e) = exapp::serve(exapp, &tls, exapp_manager).await {
+                    log!("ExApp endpoint failed: {:?}", e);
+                }
+            });
+        }
+        None => {
+            // Bridges with a temporary channel start with the Talk call
+            for bridge in manager.list().into_iter().filter(|b| !b.temporary_channel) {
+                manager.start(Some(&bridge.name)).await?;
+            }
+        }
+    }
+    tokio::spawn(manager.clone().follow_calls());
 
-    println!("Starting Bridge Session...");
-    if let Err(e) = session.start().await {
-         println!("Bridge Session failed: {:?}", e);
+    // Bridges are started/stopped through the manager from here on, so keep
+    // running until asked to exit: Ctrl+C, or SIGTERM from a service manager
+    // or container runtime.
+    let mut terminate = signal(SignalKind::terminate()).context("Failed to listen for SIGTERM")?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        _ = terminate.recv() => {}
     }
+    log!("Shutting down");
+    manager.stop_all().await;
+    manager.remove_temporary_channels().await;
 
     Ok(())
 }
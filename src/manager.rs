@@ -0,0 +1,1142 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serenity::builder::{CreateAttachment, CreateChannel, CreateMessage};
+use serenity::http::Http;
+use serenity::model::channel::{ChannelType, GuildChannel};
+use serenity::model::id::{ChannelId, GuildId};
+use songbird::Songbird;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+
+use crate::audio::mixer::{normalize_participant, Mixer};
+use crate::audio::effect::{parse_chain, ChainSlot, EffectSpec};
+use crate::bridge::{self, BridgeSession, DirectionEffects, SessionControl, SessionShared, TaskGuard};
+use crate::config::{BridgeConfig, Direction, PipelineMode};
+use crate::cues::CuePlayer;
+use crate::encryption::{EncryptionMonitor, EncryptionStatus};
+use crate::access::AccessGate;
+use crate::loop_guard::SharedLoopGuard;
+use crate::nextcloud;
+use crate::nextcloud::call::{CallClient, InCall};
+use crate::nextcloud::chat::ChatClient;
+use crate::recorder::RecorderSlot;
+use crate::roster::{self, Roster, RosterEntry};
+use crate::settings::{Scope, Settings};
+use crate::soundboard::{Soundboard, VoiceChannelEffect};
+use crate::speakers::SpeakerMap;
+use crate::stats::{BufferCounters, CallStats, ConcealmentCounters, ConnectionCounters, RtpCounters, WebRtcStats};
+use crate::store::Store;
+use crate::summary::CallSummary;
+use crate::update;
+
+// Accepted range for per-participant gain
+const MIN_GAIN_DB: f32 = -60.0;
+const MAX_GAIN_DB: f32 = 20.0;
+
+// Requests a session hasn't picked up yet. A full queue can only mean it
+// is stuck; stop() aborts it then.
+const CONTROL: usize = 4;
+
+// How long a stopping session gets to leave Talk and Discord
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How often bridges with a temporary channel look at their Talk call
+const CALL_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+// How often a bridge waiting in a Talk room's lobby checks whether it opened
+const LOBBY_POLL_INTERVAL: Duration = Duration::from_secs(15);
+
+// Everything needed to (re)build a bridge session from scratch
+#[derive(Debug, Clone)]
+pub struct BridgeDefinition {
+    pub name: String,
+    pub guild_id: GuildId,
+    pub channel_id: ChannelId,
+    pub nextcloud: nextcloud::signaling::Config,
+    pub room_token: String,
+    pub config: BridgeConfig,
+    // Index of the Discord bot that joins the voice channel
+    pub bot: usize,
+}
+
+// One Discord bot login. A bot can only be in one voice channel per guild,
+// so bridging several channels of a guild takes several bots.
+pub struct DiscordBot {
+    pub songbird: Arc<Songbird>,
+    pub http: Arc<Http>,
+}
+
+// Settles which bot runs each bridge. Explicit choices are kept; the others
+// get a bot that isn't in a voice channel of that guild yet, round-robin
+// once every bot is.
+pub fn assign_bots(definitions: Vec<(BridgeDefinition, Option<usize>)>, bots: usize) -> Vec<BridgeDefinition> {
+    let definitions: Vec<_> = definitions
+        .into_iter()
+        .map(|(d, bot)| {
+            let bot = bot.filter(|b| {
+                if *b >= bots {
+                    log!("Bridge {} asks for bot {}, but only {} are configured", d.name, b, bots);
+                }
+                *b < bots
+            });
+            (d, bot)
+        })
+        .collect();
+
+    // Explicit choices first, so the automatic ones steer around them
+    let mut taken: Vec<(GuildId, usize)> = definitions
+        .iter()
+        .filter_map(|(d, bot)| bot.map(|b| (d.guild_id, b)))
+        .collect();
+    let mut round_robin = 0;
+    let definitions: Vec<BridgeDefinition> = definitions
+        .into_iter()
+        .map(|(mut definition, explicit)| {
+            definition.bot = explicit.unwrap_or_else(|| {
+                let guild = definition.guild_id;
+                let bot = (0..bots).find(|b| !taken.contains(&(guild, *b))).unwrap_or_else(|| {
+                    round_robin += 1;
+                    (round_robin - 1) % bots
+                });
+                taken.push((guild, bot));
+                bot
+            });
+            definition
+        })
+        .collect();
+
+    for (i, d) in definitions.iter().enumerate() {
+        if definitions[..i].iter().any(|o| o.guild_id == d.guild_id && o.bot == d.bot) {
+            log!(
+                "Bridge {} shares bot {} with another bridge in its guild; only one of them can be in a call",
+                d.name,
+                d.bot
+            );
+        }
+    }
+    definitions
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "state", content = "reason", rename_all = "lowercase")]
+pub enum BridgeState {
+    Stopped,
+    Starting,
+    Running,
+    Failed(String),
+    // The Discord channel went away or changed; waits for /bridge rebind
+    Paused(String),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BridgeStatus {
+    pub name: String,
+    #[serde(flatten)]
+    pub state: BridgeState,
+    pub guild_id: u64,
+    pub channel_id: u64,
+    pub room_token: String,
+    // Discord users currently in the bridged voice channel
+    pub discord_users: Vec<u64>,
+    // Who is in the Talk room, as of the current or last call
+    pub talk_participants: Vec<RosterEntry>,
+    // Of the current or last call
+    pub rtp: RtpCounters,
+    pub talk_rtp: RtpCounters,
+    pub concealment: ConcealmentCounters,
+    // Talk's peer connections as WebRTC reports them
+    pub webrtc: WebRtcStats,
+    pub connection: ConnectionCounters,
+    // What the current or last call was encrypted with
+    pub encryption: EncryptionStatus,
+    // Talk -> Discord buffering, also of the current or last call
+    pub buffer: BufferCounters,
+    // File being recorded to
+    pub recording: Option<String>,
+    // Started and stopped with the Talk call, see DISCORD_TEMP_CHANNEL
+    pub temporary_channel: bool,
+    pub version: String,
+    // Newer release found by the update check (UPDATE_CHECK)
+    pub update_available: Option<String>,
+}
+
+struct ManagedBridge {
+    definition: BridgeDefinition,
+    shared: SessionShared,
+    state: Arc<std::sync::Mutex<BridgeState>>,
+    task: tokio::sync::Mutex<Option<JoinHandle<()>>>,
+    // Asks the running session to shut down cleanly or switch rooms
+    control: std::sync::Mutex<Option<mpsc::Sender<SessionControl>>>,
+    // The voice channel created for the current Talk call
+    temporary_channel: std::sync::Mutex<Option<ChannelId>>,
+    // The configured room, unless /bridge newroom replaced it
+    room_token: std::sync::Mutex<String>,
+    // The configured channel (category for temporary channels), unless
+    // /bridge rebind replaced it
+    channel: std::sync::Mutex<ChannelId>,
+}
+
+impl ManagedBridge {
+    fn room_token(&self) -> String {
+        self.room_token.lock().unwrap().clone()
+    }
+
+    fn channel(&self) -> ChannelId {
+        *self.channel.lock().unwrap()
+    }
+
+    // The voice channel the bridge joins
+    fn channel_id(&self) -> ChannelId {
+        self.temporary_channel.lock().unwrap().unwrap_or_else(|| self.channel())
+    }
+
+    fn is_paused(&self) -> bool {
+        matches!(*self.state.lock().unwrap(), BridgeState::Paused(_))
+    }
+}
+
+// Owns the configured bridges and their running sessions. Discord commands
+// and the admin socket both go through here.
+pub struct BridgeManager {
+    bridges: Vec<ManagedBridge>,
+    bots: Vec<DiscordBot>,
+    store: Arc<Store>,
+    loops: SharedLoopGuard,
+}
+
+impl BridgeManager {
+    pub fn new(
+        definitions: Vec<BridgeDefinition>,
+        bots: Vec<DiscordBot>,
+        store: Arc<Store>,
+        loops: SharedLoopGuard,
+    ) -> Self {
+        let bridges = definitions
+            .into_iter()
+            .map(|definition| {
+                let channel = store
+                    .discord_channel(&definition.name)
+                    .map(ChannelId::new)
+                    .unwrap_or(definition.channel_id);
+                ManagedBridge {
+                    shared: SessionShared {
+                        mixer: Mixer::new(
+                            definition.config.audio.nextcloud_to_discord.channels.count(),
+                            store.talk_volumes(),
+                            &definition.config.audio.ducking,
+                            &definition.config.audio.jitter,
+                        ),
+                        speakers: SpeakerMap::new(),
+                        stats: CallStats::new(),
+                        cues: CuePlayer::new(&definition.config.cues, &definition.config.announcements),
+                        recorder: RecorderSlot::new(definition.config.recording.clone(), &definition.name),
+                        discord_muted: Arc::new(AtomicBool::new(false)),
+                        talk_muted: Default::default(),
+                        talk_down: Default::default(),
+                        encryption: EncryptionMonitor::new(definition.config.encryption),
+                        loops: loops.clone(),
+                        access: AccessGate::new(definition.config.access_codes),
+                        roster: Roster::new(),
+                        effects: DirectionEffects {
+                            discord_to_nextcloud: ChainSlot::new(
+                                definition.config.audio.discord_to_nextcloud.effects.clone(),
+                            ),
+                            nextcloud_to_discord: ChainSlot::new(
+                                definition.config.audio.nextcloud_to_discord.effects.clone(),
+                            ),
+                        },
+                    },
+                    state: Arc::new(std::sync::Mutex::new(BridgeState::Stopped)),
+                    task: tokio::sync::Mutex::new(None),
+                    control: std::sync::Mutex::new(None),
+                    temporary_channel: std::sync::Mutex::new(None),
+                    room_token: std::sync::Mutex::new(
+                        store
+                            .talk_room(channel.get())
+                            .unwrap_or_else(|| definition.room_token.clone()),
+                    ),
+                    channel: std::sync::Mutex::new(channel),
+                    definition,
+                }
+            })
+            .collect();
+
+        for user_id in store.excluded_discord_users() {
+            loops.set_excluded(user_id, true);
+        }
+        let manager = Self {
+            bridges,
+            bots,
+            store,
+            loops,
+        };
+        manager.apply_settings();
+        manager
+    }
+
+    // Push the stored settings hierarchy into every bridge. Running sessions
+    // pick the changes up on their next cue or mixer input.
+    fn apply_settings(&self) {
+        let tree = self.store.settings();
+        for bridge in &self.bridges {
+            let def = &bridge.definition;
+            let settings = tree.resolve(def.guild_id.get(), &def.name);
+            let mut cues = def.config.cues.clone();
+            let mut announcements = def.config.announcements.clone();
+            if let Some(style) = settings.announcements {
+                cues.enabled = style.chime();
+                announcements.enabled = style.speech();
+            }
+            if let Some(language) = settings.language {
+                announcements.language = language;
+            }
+            bridge.shared.cues.reconfigure(cues.enabled, &announcements);
+            bridge
+                .shared
+                .mixer
+                .lock()
+                .unwrap()
+                .set_default_gain_db(settings.gain_db.unwrap_or(0.0).clamp(MIN_GAIN_DB, MAX_GAIN_DB));
+        }
+    }
+
+    // What applies to a bridge after inheritance; unset fields come from the
+    // environment
+    pub fn settings(&self, name: Option<&str>) -> Result<Settings> {
+        let def = &self.get(name)?.definition;
+        Ok(self.store.settings().resolve(def.guild_id.get(), &def.name))
+    }
+
+    // `value: None` unsets the key at that level
+    pub fn set_setting(&self, scope: &Scope, key: &str, value: Option<&str>) -> Result<()> {
+        if let Scope::Bridge(name) = scope {
+            self.get(Some(name))?;
+        }
+        self.store.set_setting(scope, key, value)?;
+        self.apply_settings();
+        Ok(())
+    }
+
+    // One of our bots logged in; its voice is never forwarded
+    pub fn add_bot_user(&self, user_id: u64) {
+        self.loops.add_discord_user(user_id);
+    }
+
+    // The bridge a bot runs in a guild, for its slash commands
+    pub fn bridge_for(&self, bot: usize, guild_id: GuildId) -> Option<String> {
+        self.bridges
+            .iter()
+            .find(|b| b.definition.bot == bot && b.definition.guild_id == guild_id)
+            .map(|b| b.definition.name.clone())
+    }
+
+    // Guilds a bot has bridges in
+    pub fn guilds_for(&self, bot: usize) -> Vec<GuildId> {
+        let mut guilds: Vec<GuildId> = self
+            .bridges
+            .iter()
+            .filter(|b| b.definition.bot == bot)
+            .map(|b| b.definition.guild_id)
+            .collect();
+        guilds.sort();
+        guilds.dedup();
+        guilds
+    }
+
+    // `None` picks the only bridge, for single-bridge setups
+    fn get(&self, name: Option<&str>) -> Result<&ManagedBridge> {
+        match name {
+            Some(name) => self
+                .bridges
+                .iter()
+                .find(|b| b.definition.name == name)
+                .with_context(|| format!("No bridge named {:?}", name)),
+            None if self.bridges.len() == 1 => Ok(&self.bridges[0]),
+            None => anyhow::bail!("Several bridges are configured, name one of them"),
+        }
+    }
+
+    pub fn list(&self) -> Vec<BridgeStatus> {
+        self.bridges.iter().map(status_of).collect()
+    }
+
+    // Without waiting on any lock, for crash reports; None where the state
+    // is locked
+    pub fn try_states(&self) -> Vec<(String, Option<BridgeState>)> {
+        self.bridges
+            .iter()
+            .map(|b| {
+                let state = match b.state.try_lock() {
+                    Ok(state) => Some(state.clone()),
+                    Err(std::sync::TryLockError::Poisoned(e)) => Some(e.into_inner().clone()),
+                    Err(std::sync::TryLockError::WouldBlock) => None,
+                };
+                (b.definition.name.clone(), state)
+            })
+            .collect()
+    }
+
+    pub fn status(&self, name: Option<&str>) -> Result<BridgeStatus> {
+        Ok(status_of(self.get(name)?))
+    }
+
+    pub fn is_running(&self, name: Option<&str>) -> bool {
+        self.get(name)
+            .is_ok_and(|b| matches!(*b.state.lock().unwrap(), BridgeState::Starting | BridgeState::Running))
+    }
+
+    pub async fn start(&self, name: Option<&str>) -> Result<()> {
+        let bridge = self.get(name)?;
+        let mut task = bridge.task.lock().await;
+        if task.as_ref().is_some_and(|t| !t.is_finished()) {
+            anyhow::bail!("Bridge {} is already running", bridge.definition.name);
+        }
+
+        if let BridgeState::Paused(reason) = &*bridge.state.lock().unwrap() {
+            anyhow::bail!(
+                "Bridge {} is paused ({}); use /bridge rebind to bind it to a channel again",
+                bridge.definition.name,
+                reason
+            );
+        }
+
+        let mut definition = bridge.definition.clone();
+        definition.room_token = bridge.room_token();
+        definition.channel_id = bridge.channel();
+        if definition.config.temporary_channel {
+            definition.channel_id = bridge
+                .temporary_channel
+                .lock()
+                .unwrap()
+                .with_context(|| format!("Bridge {} starts when the Talk call does", definition.name))?;
+        }
+        *bridge.state.lock().unwrap() = BridgeState::Starting;
+        let shared = bridge.shared.clone();
+        let bot = &self.bots[definition.bot];
+        let songbird = bot.songbird.clone();
+        let http = bot.http.clone();
+        let state = bridge.state.clone();
+        let (control_tx, control) = mpsc::channel(CONTROL);
+        *bridge.control.lock().unwrap() = Some(control_tx);
+
+        *task = Some(tokio::spawn(async move {
+            let name = definition.name.clone();
+            let result = run_session(definition, shared, songbird, http, state.clone(), control).await;
+            *state.lock().unwrap() = match result {
+                Ok(()) => BridgeState::Stopped,
+                Err(e) => {
+                    log!("Bridge {} failed: {:?}", name, e);
+                    BridgeState::Failed(format!("{:#}", e))
+                }
+            };
+        }));
+        Ok(())
+    }
+
+    pub async fn stop(&self, name: Option<&str>) -> Result<()> {
+        let bridge = self.get(name)?;
+        let control = bridge.control.lock().unwrap().take();
+        if let Some(mut task) = bridge.task.lock().await.take() {
+            // Given a while to leave cleanly; a session that already ended
+            // has nobody listening
+            let asked = control.is_some_and(|c| c.try_send(SessionControl::Stop).is_ok());
+            if !asked || tokio::time::timeout(STOP_TIMEOUT, &mut task).await.is_err() {
+                task.abort();
+            }
+        }
+
+        // Aborting the session doesn't leave the voice channel by itself
+        let songbird = &self.bots[bridge.definition.bot].songbird;
+        if songbird.get(bridge.definition.guild_id).is_some() {
+            songbird
+                .remove(bridge.definition.guild_id)
+                .await
+                .context("Failed to leave Discord voice channel")?;
+        }
+
+        bridge.shared.speakers.lock().unwrap().clear();
+        *bridge.state.lock().unwrap() = BridgeState::Stopped;
+        Ok(())
+    }
+
+    // Lifts a Talk moderator's mute, where the bridge's config allows it
+    pub fn unmute_talk(&self, name: Option<&str>) -> Result<()> {
+        let bridge = self.get(name)?;
+        if !bridge.definition.config.talk_unmute {
+            anyhow::bail!("Unmuting the bridge from Discord is turned off (TALK_ALLOW_UNMUTE)");
+        }
+        // Only a change is passed on, to Talk and the Discord channel
+        if !bridge.shared.talk_muted.send_if_modified(|muted| std::mem::replace(muted, false)) {
+            anyhow::bail!("The bridge isn't muted in Talk");
+        }
+        log!("Bridge {} unmuted in Talk from Discord", bridge.definition.name);
+        Ok(())
+    }
+
+    // Ring the Talk room members who aren't in the call. Returns how many were rung.
+    pub async fn ring(&self, name: Option<&str>) -> Result<usize> {
+        let bridge = self.get(name)?;
+        CallClient::new(bridge.definition.nextcloud.clone())
+            .ring_all(&bridge.room_token())
+            .await
+    }
+
+    // Swap a direction's effect chain; running streams pick it up on their
+    // next frame. Returns the chain now in place.
+    pub fn set_effects(&self, name: Option<&str>, direction: Direction, chain: &str) -> Result<Vec<EffectSpec>> {
+        let bridge = self.get(name)?;
+        let specs = parse_chain(chain).map_err(|e| anyhow::anyhow!(e))?;
+        bridge.shared.effects.get(direction).set(specs.clone());
+        Ok(specs)
+    }
+
+    // Someone joined, left or moved between voice channels in a guild, as
+    // seen by one bot (every bot in the guild gets the same update)
+    pub fn discord_voice_state(
+        &self,
+        bot: usize,
+        guild_id: GuildId,
+        user_id: u64,
+        name: Option<String>,
+        before: Option<ChannelId>,
+        after: Option<ChannelId>,
+    ) {
+        for bridge in &self.bridges {
+            let def = &bridge.definition;
+            if def.bot != bot || def.guild_id != guild_id {
+                continue;
+            }
+            let was_here = before == Some(bridge.channel_id());
+            let is_here = after == Some(bridge.channel_id());
+            if was_here || is_here {
+                bridge.shared.cues.discord_voice_state(user_id, name.clone(), was_here, is_here);
+            }
+        }
+    }
+
+    // Someone played a soundboard sound in a voice channel, as seen by one bot
+    pub async fn discord_soundboard(&self, bot: usize, soundboard: &Soundboard, effect: &VoiceChannelEffect) {
+        let Some(sound_id) = effect.sound_id else {
+            return;
+        };
+        if self.loops.ignores_discord_user(effect.user_id) {
+            return;
+        }
+        let bridges: Vec<_> = self
+            .bridges
+            .iter()
+            .filter(|b| {
+                b.definition.bot == bot
+                    && b.definition.guild_id.get() == effect.guild_id
+                    && b.channel_id().get() == effect.channel_id
+                    && b.shared.access.allows(Some(effect.user_id))
+            })
+            .collect();
+        if bridges.is_empty() {
+            return;
+        }
+        match soundboard.sound(sound_id).await {
+            Ok(samples) => {
+                for bridge in bridges {
+                    bridge.shared.cues.discord_sound(&samples, effect.sound_volume.unwrap_or(1.0));
+                }
+            }
+            Err(e) => log!("Failed to play soundboard sound {}: {:#}", sound_id, e),
+        }
+    }
+
+    // Audio from this Discord user is never forwarded (DISCORD_IGNORE_BOTS)
+    pub fn ignore_discord_user(&self, user_id: u64) {
+        self.loops.add_discord_user(user_id);
+    }
+
+    // A direct message to one bot, checked against the access codes of its
+    // bridges. None if none of them uses access codes; otherwise the
+    // bridges the user was let into.
+    pub fn redeem_access_code(&self, bot: usize, user_id: u64, code: &str) -> Option<Vec<String>> {
+        let gated: Vec<_> = self
+            .bridges
+            .iter()
+            .filter(|b| b.definition.bot == bot && b.definition.config.access_codes)
+            .collect();
+        if gated.is_empty() {
+            return None;
+        }
+        let names: Vec<String> = gated
+            .into_iter()
+            .filter(|b| b.shared.access.redeem(user_id, code))
+            .map(|b| b.definition.name.clone())
+            .collect();
+        for name in &names {
+            log!("Discord user {} sent the access code for bridge {}", user_id, name);
+        }
+        Some(names)
+    }
+
+    // Stops or resumes forwarding a Discord user's audio to Talk, in every
+    // bridge and across restarts. Returns whether anything changed.
+    pub fn set_discord_user_excluded(&self, user_id: u64, excluded: bool) -> Result<bool> {
+        if !excluded && self.loops.is_own_discord_user(user_id) {
+            anyhow::bail!("User {} is a bridge bot or listed in DISCORD_IGNORE_USERS", user_id);
+        }
+        self.store.set_discord_user_excluded(user_id, excluded)?;
+        let changed = self.loops.set_excluded(user_id, excluded);
+        if changed {
+            log!("Discord user {} {} Talk", user_id, if excluded { "excluded from" } else { "forwarded to" });
+        }
+        Ok(changed)
+    }
+
+    pub fn excluded_discord_users(&self) -> Vec<u64> {
+        let mut users: Vec<_> = self.store.excluded_discord_users().into_iter().collect();
+        users.sort();
+        users
+    }
+
+    // The bot's own voice state changed: while it is server-muted or
+    // suppressed in a guild, its bridges there send Discord silence
+    pub fn discord_bot_muted(&self, bot: usize, guild_id: GuildId, muted: bool) {
+        for bridge in &self.bridges {
+            let def = &bridge.definition;
+            if def.bot != bot || def.guild_id != guild_id {
+                continue;
+            }
+            if bridge.shared.discord_muted.swap(muted, Ordering::Relaxed) != muted {
+                log!("Bridge {} {} in Discord", def.name, if muted { "muted" } else { "unmuted" });
+            }
+        }
+    }
+
+    // Start or stop recording the running session. Returns the file written to.
+    pub fn set_recording(&self, name: Option<&str>, enabled: bool) -> Result<Option<PathBuf>> {
+        let bridge = self.get(name)?;
+        if !enabled {
+            return Ok(bridge.shared.recorder.stop());
+        }
+        if !matches!(*bridge.state.lock().unwrap(), BridgeState::Running) {
+            anyhow::bail!("Bridge {} is not running", bridge.definition.name);
+        }
+        bridge.shared.recorder.start().map(Some)
+    }
+
+    // Saves the last `seconds` of the running call from the replay buffer and
+    // posts it to the bridged Discord channel. Returns the file and how much
+    // audio it holds, which is less early in a call.
+    pub async fn clip(&self, name: Option<&str>, seconds: u64) -> Result<(PathBuf, Duration)> {
+        let bridge = self.get(name)?;
+        let (path, length) = bridge.shared.recorder.clip(seconds)?;
+        log!("Saved a {}s clip of bridge {} to {}", length.as_secs(), bridge.definition.name, path.display());
+
+        let attachment = CreateAttachment::path(&path)
+            .await
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let message = CreateMessage::new()
+            .content(format!("Last {} seconds of the call", length.as_secs()))
+            .add_file(attachment);
+        bridge
+            .channel_id()
+            .send_message(&self.bots[bridge.definition.bot].http, message)
+            .await
+            .context("Failed to post the clip to Discord")?;
+        Ok((path, length))
+    }
+
+    // Keeps bridges with a temporary channel in step with their Talk call:
+    // once someone is in the call a voice channel named after the room is
+    // created and bridged, when the call is empty again it is deleted.
+    pub async fn follow_calls(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(CALL_POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            for bridge in self
+                .bridges
+                .iter()
+                .filter(|b| b.definition.config.temporary_channel && !b.is_paused())
+            {
+                if let Err(e) = self.follow_call(bridge).await {
+                    log!("Bridge {}: {:#}", bridge.definition.name, e);
+                }
+            }
+        }
+    }
+
+    async fn follow_call(&self, bridge: &ManagedBridge) -> Result<()> {
+        let def = &bridge.definition;
+        let name = Some(def.name.as_str());
+        let room_token = bridge.room_token();
+        let calls = CallClient::new(def.nextcloud.clone());
+        let call_active = calls.others_in_call(&room_token).await? > 0;
+        let channel = *bridge.temporary_channel.lock().unwrap();
+
+        match channel {
+            None if call_active => {
+                let room = calls.room_name(&room_token).await?;
+                let builder = CreateChannel::new(room).kind(ChannelType::Voice).category(bridge.channel());
+                let channel = def
+                    .guild_id
+                    .create_channel(&self.bots[def.bot].http, builder)
+                    .await
+                    .context("Failed to create voice channel")?;
+                log!("Created voice channel {} for bridge {}", channel.name, def.name);
+                *bridge.temporary_channel.lock().unwrap() = Some(channel.id);
+                self.start(name).await
+            }
+            Some(channel) if !call_active => {
+                self.stop(name).await?;
+                *bridge.temporary_channel.lock().unwrap() = None;
+                channel
+                    .delete(&self.bots[def.bot].http)
+                    .await
+                    .context("Failed to delete voice channel")?;
+                log!("Deleted voice channel of bridge {}", def.name);
+                Ok(())
+            }
+            // A session that failed mid-call is retried; one stopped by
+            // hand stays stopped until the next call
+            Some(_) if matches!(*bridge.state.lock().unwrap(), BridgeState::Failed(_)) => self.start(name).await,
+            _ => Ok(()),
+        }
+    }
+
+    // On shutdown, all at once so the time it takes doesn't add up
+    pub async fn stop_all(&self) {
+        let running = self.bridges.iter().filter(|b| b.control.lock().unwrap().is_some());
+        let stops = running.map(|b| async move {
+            if let Err(e) = self.stop(Some(&b.definition.name)).await {
+                log!("Failed to stop bridge {}: {:?}", b.definition.name, e);
+            }
+        });
+        futures_util::future::join_all(stops).await;
+    }
+
+    // On shutdown, so no empty channels are left behind
+    pub async fn remove_temporary_channels(&self) {
+        for bridge in &self.bridges {
+            let Some(channel) = bridge.temporary_channel.lock().unwrap().take() else {
+                continue;
+            };
+            if let Err(e) = channel.delete(&self.bots[bridge.definition.bot].http).await {
+                log!("Failed to delete voice channel of bridge {}: {:?}", bridge.definition.name, e);
+            }
+        }
+    }
+
+    // Bridge an existing Talk room (token or link) to this bridge's Discord
+    // channel from now on. A running session moves over without leaving
+    // Discord. Returns the room's name.
+    pub async fn switch_room(&self, name: Option<&str>, room: &str) -> Result<String> {
+        let bridge = self.get(name)?;
+        let room_token = room.trim().trim_end_matches('/').rsplit('/').next().unwrap_or_default();
+        if room_token.is_empty() {
+            anyhow::bail!("No Talk room given");
+        }
+        let room_name = CallClient::new(bridge.definition.nextcloud.clone())
+            .room_name(room_token)
+            .await
+            .with_context(|| format!("Can't open Talk room {}", room_token))?;
+
+        // Saved first, so a session that fails to move comes back in the
+        // new room
+        self.store
+            .set_talk_room(bridge.channel().get(), room_token)
+            .context("Failed to save the room mapping")?;
+        *bridge.room_token.lock().unwrap() = room_token.to_string();
+
+        let control = bridge.control.lock().unwrap().clone();
+        if let Some(control) = control {
+            let (done, moved) = oneshot::channel();
+            // Nobody listening means no session is running
+            if control.send(SessionControl::SwitchRoom(room_token.to_string(), done)).await.is_ok() {
+                moved.await.context("Bridge session ended while switching rooms")??;
+            }
+        }
+        log!("Bridge {} now bridges Talk room {} ({})", bridge.definition.name, room_name, room_token);
+        Ok(room_name)
+    }
+
+    // Create a Talk room and bridge it to this bridge's Discord channel from
+    // now on, restarting a running session. Returns the room's join link.
+    pub async fn new_room(
+        &self,
+        name: Option<&str>,
+        room_name: &str,
+        public: bool,
+        password: Option<&str>,
+    ) -> Result<String> {
+        let bridge = self.get(name)?;
+        let def = &bridge.definition;
+        if password.is_some() && !public {
+            anyhow::bail!("Only public rooms can have a password");
+        }
+
+        let calls = CallClient::new(def.nextcloud.clone());
+        let room_token = calls.create_room(room_name, public).await.context("Failed to create Talk room")?;
+        log!("Created Talk room {} ({}) for bridge {}", room_name, room_token, def.name);
+        calls.set_listable(&room_token).await.context("Failed to make the room listable")?;
+        if let Some(password) = password {
+            calls.set_password(&room_token, password).await.context("Failed to set the room password")?;
+        }
+
+        self.store
+            .set_talk_room(bridge.channel().get(), &room_token)
+            .context("Room created but the mapping could not be saved")?;
+        *bridge.room_token.lock().unwrap() = room_token.clone();
+
+        let name = Some(def.name.as_str());
+        if matches!(*bridge.state.lock().unwrap(), BridgeState::Running) {
+            self.stop(name).await?;
+            self.start(name).await?;
+        }
+
+        let base_url = url::Url::parse(&def.nextcloud.nextcloud_url).context("Invalid Nextcloud URL")?;
+        Ok(base_url.join(&format!("/call/{}", room_token))?.to_string())
+    }
+
+    // A channel was deleted in a guild, as seen by one bot. A bridged voice
+    // channel (or the category of a temporary one) going away pauses its
+    // bridge until it is rebound, instead of failing to rejoin over and
+    // over; a temporary channel is simply created again for the next call.
+    pub async fn discord_channel_deleted(&self, bot: usize, guild_id: GuildId, channel_id: ChannelId) {
+        for bridge in &self.bridges {
+            let def = &bridge.definition;
+            if def.bot != bot || def.guild_id != guild_id {
+                continue;
+            }
+            if *bridge.temporary_channel.lock().unwrap() == Some(channel_id) {
+                log!("Voice channel of bridge {} was deleted", def.name);
+                *bridge.temporary_channel.lock().unwrap() = None;
+                if let Err(e) = self.stop(Some(&def.name)).await {
+                    log!("Failed to stop bridge {}: {:#}", def.name, e);
+                }
+            } else if bridge.channel() == channel_id {
+                self.pause(bridge, "its Discord channel was deleted".to_string()).await;
+            }
+        }
+    }
+
+    // The bridged channel was renamed, moved to another category or changed
+    // type. `old` is only known when channels are cached; without it only a
+    // type change is noticed.
+    pub async fn discord_channel_updated(&self, bot: usize, old: Option<&GuildChannel>, channel: &GuildChannel) {
+        for bridge in &self.bridges {
+            let def = &bridge.definition;
+            if def.bot != bot || def.guild_id != channel.guild_id || bridge.channel() != channel.id {
+                continue;
+            }
+            let reason = if !channel_kind_fits(def, channel.kind) {
+                format!("its Discord channel #{} changed type", channel.name)
+            } else {
+                match old {
+                    Some(old) if old.name != channel.name => {
+                        format!("its Discord channel #{} was renamed to #{}", old.name, channel.name)
+                    }
+                    Some(old) if old.parent_id != channel.parent_id => {
+                        format!("its Discord channel #{} was moved to another category", channel.name)
+                    }
+                    _ => continue,
+                }
+            };
+            self.pause(bridge, reason).await;
+        }
+    }
+
+    async fn pause(&self, bridge: &ManagedBridge, reason: String) {
+        let def = &bridge.definition;
+        if bridge.is_paused() {
+            return;
+        }
+        if let Err(e) = self.stop(Some(&def.name)).await {
+            log!("Failed to stop bridge {}: {:#}", def.name, e);
+        }
+        log!("Bridge {} paused: {}", def.name, reason);
+        *bridge.state.lock().unwrap() = BridgeState::Paused(reason.clone());
+
+        // The Discord side is what changed, so operators hear about it in Talk
+        let message = format!("The Discord bridge is paused: {}. Use /bridge rebind in Discord to resume.", reason);
+        if let Err(e) = ChatClient::new(def.nextcloud.clone())
+            .send_message(&bridge.room_token(), &message)
+            .await
+        {
+            log!("Failed to post pause notice for bridge {}: {:#}", def.name, e);
+        }
+    }
+
+    // Bind a bridge to another Discord channel (or the same one again, after
+    // a rename or move) and resume it if it was paused or running. Rooms
+    // created with /bridge newroom move along with the channel.
+    pub async fn rebind(&self, name: Option<&str>, channel_id: ChannelId) -> Result<()> {
+        let bridge = self.get(name)?;
+        let def = &bridge.definition;
+        let channel = channel_id
+            .to_channel(&self.bots[def.bot].http)
+            .await
+            .context("Failed to look up the channel")?
+            .guild()
+            .context("Not a server channel")?;
+        if channel.guild_id != def.guild_id {
+            anyhow::bail!("#{} is in another server", channel.name);
+        }
+        if !channel_kind_fits(def, channel.kind) {
+            let wanted = if def.config.temporary_channel { "category" } else { "voice channel" };
+            anyhow::bail!("#{} is not a {}", channel.name, wanted);
+        }
+
+        let old = bridge.channel();
+        if old != channel_id {
+            self.store
+                .set_discord_channel(&def.name, channel_id.get())
+                .context("Failed to save the new channel")?;
+            if let Some(room_token) = self.store.talk_room(old.get()) {
+                self.store.set_talk_room(channel_id.get(), &room_token)?;
+            }
+            *bridge.channel.lock().unwrap() = channel_id;
+        }
+        log!("Bridge {} bound to Discord channel #{}", def.name, channel.name);
+
+        let resume = matches!(
+            *bridge.state.lock().unwrap(),
+            BridgeState::Starting | BridgeState::Running | BridgeState::Paused(_)
+        );
+        let name = Some(def.name.as_str());
+        self.stop(name).await?;
+        // Temporary channels come back with the next call by themselves
+        if resume && !def.config.temporary_channel {
+            self.start(name).await?;
+        }
+        Ok(())
+    }
+
+    // Returns the normalized participant name and the applied gain
+    pub fn set_volume(&self, name: Option<&str>, participant: &str, db: f32) -> Result<(String, f32)> {
+        let bridge = self.get(name)?;
+        let participant = normalize_participant(participant);
+        let db = db.clamp(MIN_GAIN_DB, MAX_GAIN_DB);
+        bridge.shared.mixer.lock().unwrap().set_gain_db(&participant, db);
+        self.store
+            .set_talk_volume(&participant, db)
+            .context("Volume applied but could not be saved")?;
+        Ok((participant, db))
+    }
+}
+
+// A temporary-channel bridge is bound to a category, the others to a voice
+// (or stage) channel
+fn channel_kind_fits(definition: &BridgeDefinition, kind: ChannelType) -> bool {
+    if definition.config.temporary_channel {
+        kind == ChannelType::Category
+    } else {
+        matches!(kind, ChannelType::Voice | ChannelType::Stage)
+    }
+}
+
+fn status_of(bridge: &ManagedBridge) -> BridgeStatus {
+    BridgeStatus {
+        name: bridge.definition.name.clone(),
+        state: bridge.state.lock().unwrap().clone(),
+        guild_id: bridge.definition.guild_id.get(),
+        channel_id: bridge.channel_id().get(),
+        room_token: bridge.room_token(),
+        discord_users: bridge.shared.speakers.lock().unwrap().user_ids(),
+        talk_participants: bridge.shared.roster.participants(),
+        rtp: bridge.shared.stats.lock().unwrap().rtp,
+        talk_rtp: bridge.shared.stats.lock().unwrap().talk_rtp,
+        concealment: bridge.shared.stats.lock().unwrap().concealment,
+        webrtc: bridge.shared.stats.lock().unwrap().webrtc,
+        connection: bridge.shared.stats.lock().unwrap().connection.clone(),
+        encryption: bridge.shared.encryption.status(),
+        buffer: bridge.shared.mixer.lock().unwrap().buffer_counters(),
+        recording: bridge.shared.recorder.path().map(|p| p.display().to_string()),
+        temporary_channel: bridge.definition.config.temporary_channel,
+        version: update::version(),
+        update_available: update::available(),
+    }
+}
+
+// Talk keeps everyone but moderators out of the call while the room's lobby
+// is on. Waits for it to open, telling the Discord channel. Returns false
+// when stopped in the meantime; a room switch waits for the new room.
+async fn wait_for_lobby(
+    definition: &mut BridgeDefinition,
+    http: &Http,
+    control: &mut mpsc::Receiver<SessionControl>,
+) -> Result<bool> {
+    let calls = CallClient::new(definition.nextcloud.clone());
+    let mut waited = false;
+    loop {
+        let Some(lobby) = calls
+            .lobby(&definition.room_token)
+            .await
+            .context("Failed to look up the Talk room")?
+        else {
+            break;
+        };
+        if !waited {
+            let opens = lobby.opens_at.map(|t| format!(" (it opens <t:{}:R>)", t)).unwrap_or_default();
+            log!("Talk room {} has its lobby on, waiting for it to open", definition.room_token);
+            let text = format!("Waiting in the Talk lobby until a moderator opens the call{}.", opens);
+            if let Err(e) = definition.channel_id.say(http, text).await {
+                log!("Failed to tell Discord about the Talk lobby: {:?}", e);
+            }
+            waited = true;
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(LOBBY_POLL_INTERVAL) => {}
+            request = control.recv() => match request {
+                Some(SessionControl::SwitchRoom(room_token, done)) => {
+                    definition.room_token = room_token;
+                    let _ = done.send(Ok(()));
+                }
+                Some(SessionControl::Stop) | None => return Ok(false),
+            },
+        }
+    }
+    if waited {
+        log!("Talk lobby of {} opened", definition.room_token);
+        if let Err(e) = definition.channel_id.say(http, "The Talk lobby opened, joining the call.").await {
+            log!("Failed to tell Discord about the Talk lobby: {:?}", e);
+        }
+    }
+    Ok(true)
+}
+
+// Tells the Discord channel when the media connection to Talk is lost and
+// when it is back, as neither side hears the other in between
+async fn announce_talk_down(mut down: watch::Receiver<bool>, http: Arc<Http>, channel_id: ChannelId) {
+    while down.changed().await.is_ok() {
+        let text = match *down.borrow_and_update() {
+            true => "Lost the connection to Talk, reconnecting. Talk and this channel don't hear each other until it is back.",
+            false => "The connection to Talk is back.",
+        };
+        if let Err(e) = channel_id.say(&http, text).await {
+            log!("Failed to tell Discord about the Talk connection: {:?}", e);
+        }
+    }
+}
+
+// Tells the Discord channel why the bridge went silent in Talk, and when
+// it is heard again
+async fn announce_talk_mute(mut muted: watch::Receiver<bool>, http: Arc<Http>, channel_id: ChannelId, unmute: bool) {
+    while muted.changed().await.is_ok() {
+        let text = match (*muted.borrow_and_update(), unmute) {
+            (true, true) => "A Talk moderator muted the bridge, so Talk no longer hears this channel. `/bridge unmute` turns it back on.",
+            (true, false) => "A Talk moderator muted the bridge, so Talk no longer hears this channel until the bridge rejoins the call.",
+            (false, _) => "The bridge is unmuted, Talk hears this channel again.",
+        };
+        if let Err(e) = channel_id.say(&http, text).await {
+            log!("Failed to tell Discord about the Talk mute: {:?}", e);
+        }
+    }
+}
+
+async fn run_session(
+    mut definition: BridgeDefinition,
+    shared: SessionShared,
+    songbird: Arc<Songbird>,
+    http: Arc<Http>,
+    state: Arc<std::sync::Mutex<BridgeState>>,
+    mut control: mpsc::Receiver<SessionControl>,
+) -> Result<()> {
+    if !wait_for_lobby(&mut definition, &http, &mut control).await? {
+        return Ok(());
+    }
+
+    log!("Initializing Nextcloud Signaling...");
+    let mut signaling = nextcloud::signaling::SignalingClient::new(definition.nextcloud.clone());
+    signaling
+        .connect(&definition.room_token)
+        .await
+        .context("Failed to connect to Signaling")?;
+
+    let calls = CallClient::new(definition.nextcloud.clone()).with_session(signaling.session_cookie());
+    let in_call = InCall::join(calls, &definition.room_token, definition.config.silent_call)
+        .await
+        .context("Failed to join Talk call")?;
+
+    if let Some(code) = shared.access.start() {
+        let message = format!(
+            "Discord access code for this call: {}. Discord users are only heard here after sending it to the bridge bot in a direct message.",
+            code
+        );
+        if let Err(e) = ChatClient::new(definition.nextcloud.clone())
+            .send_message(&definition.room_token, &message)
+            .await
+        {
+            log!("Failed to post the access code into Talk, nobody on Discord can be let in: {:#}", e);
+        }
+    }
+
+    log!("Initializing Nextcloud WebRTC...");
+    bridge::resolve_pipeline(&mut definition.config.audio, &shared.effects.discord_to_nextcloud);
+    let audio = &definition.config.audio;
+    let passthrough = audio.pipeline == PipelineMode::Passthrough;
+    log!("Discord -> Nextcloud: {}", if passthrough { "Opus passthrough" } else { "transcoding" });
+    if passthrough && audio.talk_bandwidth.is_some() {
+        log!("TALK_AUDIO_BANDWIDTH_KBPS is only announced in the SDP with passthrough; Discord's encoder picks the bitrate");
+    }
+    let nc_webrtc = nextcloud::webrtc::NextcloudWebRTC::new(
+        &audio.talk_fmtp(),
+        passthrough,
+        shared.encryption.clone(),
+        signaling.ice_servers(),
+    )
+        .await
+        .context("Failed to init WebRTC")?;
+
+    let call_summary = definition.config.call_summary;
+    let screen_share_notices = definition.config.screen_share_notices;
+    let reaction_notices = definition.config.reaction_notices;
+    let raise_hand_notices = definition.config.raise_hand_notices;
+    let talk_unmute = definition.config.talk_unmute;
+    let (channel_id, discord) = (definition.channel_id, http.clone());
+    let session = BridgeSession::new(
+        nc_webrtc,
+        signaling,
+        songbird,
+        definition.guild_id,
+        definition.channel_id,
+        definition.config,
+        shared,
+    );
+
+    // Lives as long as this future, so the summary is posted even when the
+    // session is aborted by a stop
+    let _summary = call_summary.then(|| CallSummary {
+        stats: session.shared.stats.clone(),
+        http,
+        guild_id: definition.guild_id,
+        channel_id: definition.channel_id,
+        chat: Arc::new(ChatClient::new(definition.nextcloud)),
+        room_token: definition.room_token,
+    });
+
+    let _screens = screen_share_notices.then(|| {
+        TaskGuard(tokio::spawn(roster::announce_screens(session.shared.roster.subscribe(), discord.clone(), channel_id)))
+    });
+    let _hands = raise_hand_notices.then(|| {
+        TaskGuard(tokio::spawn(roster::announce_hands(session.shared.roster.subscribe(), discord.clone(), channel_id)))
+    });
+    let _mute = TaskGuard(tokio::spawn(announce_talk_mute(
+        session.shared.talk_muted.subscribe(),
+        discord.clone(),
+        channel_id,
+        talk_unmute,
+    )));
+    let _down = TaskGuard(tokio::spawn(announce_talk_down(session.shared.talk_down.subscribe(), discord.clone(), channel_id)));
+    let _reactions = reaction_notices.then(|| {
+        TaskGuard(tokio::spawn(roster::mirror_reactions(session.shared.roster.reactions(), discord.clone(), channel_id)))
+    });
+
+    log!("Starting Bridge Session...");
+    *state.lock().unwrap() = BridgeState::Running;
+    // Not a failure, so it isn't retried either
+    if let Some(reason) = session.start(in_call, control).await? {
+        if let Err(e) = channel_id.say(&discord, format!("The bridge to Talk stopped: {}.", reason)).await {
+            log!("Failed to tell Discord why the bridge stopped: {:?}", e);
+        }
+    }
+    Ok(())
+}
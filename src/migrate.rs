@@ -0,0 +1,141 @@
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+// The .env config and the state file both carry a schema version. A release
+// that changes either in a way older files can't be read with bumps the
+// version and adds a step below; on start, files are brought up to date
+// after the original is copied to <file>.v<old version>.bak.
+pub const STATE_VERSION: u32 = 1;
+pub const CONFIG_VERSION: u32 = 1;
+
+const CONFIG_VERSION_KEY: &str = "CONFIG_VERSION";
+
+// STATE_STEPS[n] turns a version n state file into version n + 1
+const STATE_STEPS: [fn(&mut Value) -> Result<()>; STATE_VERSION as usize] = [state_v0];
+
+// CONFIG_STEPS[n] turns the lines of a version n .env into version n + 1
+const CONFIG_STEPS: [fn(&mut Vec<String>); CONFIG_VERSION as usize] = [config_v0];
+
+// Files from before versioning: same layout, the version is just stamped
+fn state_v0(_: &mut Value) -> Result<()> {
+    Ok(())
+}
+
+fn config_v0(_: &mut Vec<String>) {}
+
+// Upgrades parsed state file JSON in place; returns the version it was at
+pub fn state(value: &mut Value) -> Result<u32> {
+    let from = value.get("version").and_then(Value::as_u64).unwrap_or(0) as u32;
+    check_version("state file", from, STATE_VERSION)?;
+    for (version, step) in STATE_STEPS.iter().enumerate().skip(from as usize) {
+        step(value).with_context(|| format!("Failed to migrate state file from version {}", version))?;
+    }
+    if let Some(object) = value.as_object_mut() {
+        object.insert("version".to_string(), STATE_VERSION.into());
+    }
+    Ok(from)
+}
+
+// Upgrades the .env file, if there is one. Runs before it is loaded, so
+// the settings read afterwards already have their current names.
+pub fn config_file(path: &Path) -> Result<()> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    let mut lines: Vec<String> = text.lines().map(str::to_string).collect();
+    let version_line = lines.iter().position(|l| {
+        l.split_once('=')
+            .is_some_and(|(k, _)| k.trim() == CONFIG_VERSION_KEY)
+    });
+    let from = match version_line {
+        Some(i) => {
+            let value = lines[i].split_once('=').map_or("", |(_, v)| v.trim());
+            value
+                .parse()
+                .with_context(|| format!("Invalid {} {:?} in {}", CONFIG_VERSION_KEY, value, path.display()))?
+        }
+        None => 0,
+    };
+    check_version(&path.display().to_string(), from, CONFIG_VERSION)?;
+    if from == CONFIG_VERSION {
+        return Ok(());
+    }
+
+    let original = lines.clone();
+    for step in &CONFIG_STEPS[from as usize..] {
+        step(&mut lines);
+    }
+    // Not even stamped when nothing changed; the file may be read-only,
+    // e.g. a mounted secret, and the same steps find nothing to do next time
+    if lines == original {
+        return Ok(());
+    }
+    let line = format!("{}={}", CONFIG_VERSION_KEY, CONFIG_VERSION);
+    match version_line {
+        Some(i) => lines[i] = line,
+        None => lines.insert(0, line),
+    }
+
+    let backup = backup(path, from)?;
+    write(path, &(lines.join("\n") + "\n"))?;
+    log!(
+        "Upgraded {} from config version {} to {} (original kept as {})",
+        path.display(),
+        from,
+        CONFIG_VERSION,
+        backup.display()
+    );
+    Ok(())
+}
+
+// Environment-only setups (containers, the ExApp) have no file to upgrade,
+// but settings meant for a newer release shouldn't be half understood
+pub fn check_env() -> Result<()> {
+    match std::env::var(CONFIG_VERSION_KEY) {
+        Ok(v) => {
+            let version = v
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid {} {:?}", CONFIG_VERSION_KEY, v))?;
+            check_version("the environment's config", version, CONFIG_VERSION)
+        }
+        Err(_) => Ok(()),
+    }
+}
+
+fn check_version(what: &str, version: u32, current: u32) -> Result<()> {
+    if version > current {
+        anyhow::bail!(
+            "{} is at version {}, this release only understands up to {}; upgrade the bridge or restore a backup",
+            what,
+            version,
+            current
+        );
+    }
+    Ok(())
+}
+
+// Copies the file as it was before an upgrade, without overwriting an
+// earlier backup of the same version
+pub fn backup(path: &Path, version: u32) -> Result<PathBuf> {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".v{}.bak", version));
+    let backup = PathBuf::from(name);
+    if !backup.exists() {
+        std::fs::copy(path, &backup).with_context(|| format!("Failed to back up {}", path.display()))?;
+    }
+    Ok(backup)
+}
+
+// Temp file and rename, so a crash can't leave half a file
+pub fn write(path: &Path, text: &str) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    std::fs::write(&tmp, text).with_context(|| format!("Failed to write {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("Failed to replace {}", path.display()))?;
+    Ok(())
+}
@@ -0,0 +1,96 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use tokio::sync::Mutex;
+use url::Url;
+
+use crate::store::Store;
+use crate::update;
+
+// An OAuth2 access token for the OCS API, sent as a bearer token instead of
+// basic auth. Nextcloud's tokens expire after an hour; with a client and a
+// refresh token, a 401 renews them. Nextcloud hands out a new refresh token
+// with every renewal and forgets the old one, so the latest is kept in the
+// state file.
+pub struct BearerToken {
+    access: RwLock<String>,
+    refresh: Option<Refresh>,
+    // One renewal at a time; a refresh token only works once
+    renewing: Mutex<()>,
+}
+
+pub struct Refresh {
+    pub client_id: String,
+    pub client_secret: String,
+    // As configured; the state file's is used while this doesn't change
+    pub token: String,
+    pub store: Arc<Store>,
+}
+
+#[derive(Deserialize)]
+struct TokenReply {
+    access_token: String,
+    refresh_token: String,
+}
+
+// Without the tokens, which would end up in logs
+impl fmt::Debug for BearerToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BearerToken").field("renewable", &self.refresh.is_some()).finish_non_exhaustive()
+    }
+}
+
+impl BearerToken {
+    pub fn new(access: String, refresh: Option<Refresh>) -> Arc<Self> {
+        Arc::new(Self { access: RwLock::new(access), refresh, renewing: Mutex::new(()) })
+    }
+
+    pub fn access(&self) -> String {
+        self.access.read().unwrap().clone()
+    }
+
+    // Swaps in a new access token unless `stale` was already replaced, e.g.
+    // by another request that got a 401 at the same time. Returns whether
+    // there is a token to try again with.
+    pub async fn renew(&self, http: &reqwest::Client, nextcloud_url: &str, stale: &str) -> Result<bool> {
+        let Some(refresh) = &self.refresh else {
+            return Ok(false);
+        };
+        let _renewing = self.renewing.lock().await;
+        if self.access() != stale {
+            return Ok(true);
+        }
+
+        let refresh_token = refresh.store.oauth_refresh_token(&refresh.token).unwrap_or_else(|| refresh.token.clone());
+        let url = Url::parse(nextcloud_url)
+            .context("Invalid Nextcloud URL")?
+            .join("/index.php/apps/oauth2/api/v1/token")?;
+        let resp = http
+            .post(url)
+            .header("User-Agent", update::user_agent())
+            .form(&[
+                ("grant_type", "refresh_token"),
+                ("refresh_token", refresh_token.as_str()),
+                ("client_id", refresh.client_id.as_str()),
+                ("client_secret", refresh.client_secret.as_str()),
+            ])
+            .send()
+            .await
+            .context("Failed to renew the Nextcloud access token")?;
+        if !resp.status().is_success() {
+            anyhow::bail!(
+                "Nextcloud refused to renew the access token ({}); NEXTCLOUD_REFRESH_TOKEN needs replacing",
+                resp.status()
+            );
+        }
+        let reply: TokenReply = resp.json().await.context("Failed to parse Nextcloud's token reply")?;
+        refresh
+            .store
+            .set_oauth_refresh_token(&refresh.token, &reply.refresh_token)
+            .context("Failed to keep the new refresh token")?;
+        *self.access.write().unwrap() = reply.access_token;
+        log!("Renewed the Nextcloud access token");
+        Ok(true)
+    }
+}
@@ -0,0 +1,289 @@
+use anyhow::{Context, Result};
+use reqwest::Method;
+use serde_json::Value;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use url::Url;
+
+use super::signaling::Config;
+use crate::roster::RosterChange;
+
+// Talk call REST API: joining the call, ringing room members, looking at
+// who is in the call and setting up new rooms
+pub struct CallClient {
+    config: Config,
+    http: reqwest::Client,
+    // Talk's session cookie, with internal signaling
+    cookie: Option<String>,
+}
+
+// Participant flags sent when joining: in call + audio
+const FLAGS_IN_CALL_AUDIO: u64 = 1 | 2;
+
+// Conversation types and listable scopes in the OCS API
+const ROOM_TYPE_GROUP: u8 = 2;
+const ROOM_TYPE_PUBLIC: u8 = 3;
+const LISTABLE_REGULAR_USERS: u8 = 1;
+
+// Participant types the lobby lets through: owner, moderator, guest
+// moderator
+const MODERATOR_TYPES: [u64; 3] = [1, 2, 6];
+
+// The lobby of a room is on and keeps the bridge user out of the call
+pub struct Lobby {
+    // When Talk opens it by itself (unix seconds), if a moderator set that
+    pub opens_at: Option<u64>,
+}
+
+impl CallClient {
+    pub fn new(config: Config) -> Self {
+        Self {
+            http: config.tls.http.clone(),
+            config,
+            cookie: None,
+        }
+    }
+
+    // Makes requests as the session that joined the room, which internal
+    // signaling needs for the call join to count
+    pub fn with_session(mut self, cookie: Option<String>) -> Self {
+        self.cookie = cookie;
+        self
+    }
+
+    async fn request(&self, method: Method, path: &str, body: Option<Value>) -> Result<Value> {
+        let base_url = Url::parse(&self.config.nextcloud_url).context("Invalid Nextcloud URL")?;
+        let api_url = base_url.join(&format!("/ocs/v2.php/apps/spreed/api/v{}/{}", self.config.api.conversation, path))?;
+
+        let mut req = self
+            .http
+            .request(method, api_url)
+            .header("OCS-APIRequest", "true")
+            .header("Accept", "application/json");
+        if let Some(cookie) = &self.cookie {
+            req = req.header("Cookie", cookie);
+        }
+        if let Some(body) = body {
+            req = req.json(&body);
+        }
+
+        let resp = self.config.send(req).await.context("Failed to send request to Nextcloud")?;
+        if !resp.status().is_success() {
+            anyhow::bail!("Nextcloud call API returned error: {}", resp.status());
+        }
+        Ok(resp.json().await.unwrap_or(Value::Null))
+    }
+
+    // A silent join doesn't send call notifications to the room members
+    pub async fn join(&self, room_token: &str, silent: bool) -> Result<()> {
+        let body = serde_json::json!({ "flags": FLAGS_IN_CALL_AUDIO, "silent": silent });
+        self.request(Method::POST, &format!("call/{}", room_token), Some(body)).await?;
+        Ok(())
+    }
+
+    // Changes the flags of a call already joined
+    pub async fn set_flags(&self, room_token: &str) -> Result<()> {
+        let body = serde_json::json!({ "flags": FLAGS_IN_CALL_AUDIO });
+        self.request(Method::PUT, &format!("call/{}", room_token), Some(body)).await?;
+        Ok(())
+    }
+
+    pub async fn leave(&self, room_token: &str) -> Result<()> {
+        self.request(Method::DELETE, &format!("call/{}", room_token), None).await?;
+        Ok(())
+    }
+
+    // Leaves the room itself, ending the Talk session that joined it
+    pub async fn leave_room(&self, room_token: &str) -> Result<()> {
+        self.request(Method::DELETE, &format!("room/{}/participants/active", room_token), None)
+            .await?;
+        Ok(())
+    }
+
+    async fn participants(&self, room_token: &str) -> Result<Vec<Value>> {
+        let mut body = self
+            .request(Method::GET, &format!("room/{}/participants", room_token), None)
+            .await?;
+        match body.get_mut("ocs").and_then(|o| o.get_mut("data")).map(Value::take) {
+            Some(Value::Array(participants)) => Ok(participants),
+            _ => anyhow::bail!("No participants in response"),
+        }
+    }
+
+    fn is_self(&self, participant: &Value) -> bool {
+        participant.get("actorType").and_then(|v| v.as_str()) == Some("users")
+            && participant.get("actorId").and_then(|v| v.as_str()) == Some(self.config.username.as_str())
+    }
+
+    // The room's name as shown in Talk
+    pub async fn room_name(&self, room_token: &str) -> Result<String> {
+        let body = self.request(Method::GET, &format!("room/{}", room_token), None).await?;
+        body.get("ocs")
+            .and_then(|o| o.get("data"))
+            .and_then(|d| d.get("displayName"))
+            .and_then(|n| n.as_str())
+            .map(str::to_string)
+            .context("No room name in response")
+    }
+
+    // None once the bridge may join the call
+    pub async fn lobby(&self, room_token: &str) -> Result<Option<Lobby>> {
+        // Guests only see the room from inside their session; the call join
+        // tells them about the lobby instead
+        if self.config.is_guest() {
+            return Ok(None);
+        }
+        let body = self.request(Method::GET, &format!("room/{}", room_token), None).await?;
+        let room = &body["ocs"]["data"];
+        let closed = room["lobbyState"].as_u64() == Some(1)
+            && !room["participantType"].as_u64().is_some_and(|t| MODERATOR_TYPES.contains(&t));
+        Ok(closed.then(|| Lobby {
+            opens_at: room["lobbyTimer"].as_u64().filter(|t| *t != 0),
+        }))
+    }
+
+    // A new conversation with the bridge user as its owner. Public rooms can
+    // be joined by anyone with the link, group rooms only by invited users.
+    // Returns the room token.
+    pub async fn create_room(&self, name: &str, public: bool) -> Result<String> {
+        let room_type = if public { ROOM_TYPE_PUBLIC } else { ROOM_TYPE_GROUP };
+        let body = serde_json::json!({ "roomType": room_type, "roomName": name });
+        let body = self.request(Method::POST, "room", Some(body)).await?;
+        body.get("ocs")
+            .and_then(|o| o.get("data"))
+            .and_then(|d| d.get("token"))
+            .and_then(|t| t.as_str())
+            .map(str::to_string)
+            .context("No room token in response")
+    }
+
+    // Shows up in the room list of every regular user
+    pub async fn set_listable(&self, room_token: &str) -> Result<()> {
+        let body = serde_json::json!({ "scope": LISTABLE_REGULAR_USERS });
+        self.request(Method::PUT, &format!("room/{}/listable", room_token), Some(body))
+            .await?;
+        Ok(())
+    }
+
+    // Public rooms only
+    pub async fn set_password(&self, room_token: &str, password: &str) -> Result<()> {
+        let body = serde_json::json!({ "password": password });
+        self.request(Method::PUT, &format!("room/{}/password", room_token), Some(body))
+            .await?;
+        Ok(())
+    }
+
+    // How many participants other than the bridge are in the call
+    pub async fn others_in_call(&self, room_token: &str) -> Result<usize> {
+        Ok(self
+            .participants(room_token)
+            .await?
+            .iter()
+            .filter(|p| p.get("inCall").and_then(|v| v.as_u64()).unwrap_or(0) != 0 && !self.is_self(p))
+            .count())
+    }
+
+    // Ring every user in the room who isn't in the call yet. Needs the bridge
+    // user to be a moderator. Returns how many were rung.
+    pub async fn ring_all(&self, room_token: &str) -> Result<usize> {
+        let participants = self.participants(room_token).await?;
+
+        let mut rung = 0;
+        for p in &participants {
+            let is_user = p.get("actorType").and_then(|v| v.as_str()) == Some("users");
+            let is_self = self.is_self(p);
+            let in_call = p.get("inCall").and_then(|v| v.as_u64()).unwrap_or(0) != 0;
+            let Some(attendee_id) = p.get("attendeeId").and_then(|v| v.as_u64()) else {
+                continue;
+            };
+            if !is_user || is_self || in_call {
+                continue;
+            }
+
+            match self
+                .request(Method::POST, &format!("call/{}/ring/{}", room_token, attendee_id), None)
+                .await
+            {
+                Ok(_) => rung += 1,
+                Err(e) => log!("Failed to ring attendee {}: {:?}", attendee_id, e),
+            }
+        }
+        Ok(rung)
+    }
+}
+
+// Being in a Talk call with audio, from join until dropped. Talk keeps a
+// participant in the call until it times out otherwise, so the bridge would
+// linger there after stopping.
+pub struct InCall {
+    calls: Arc<CallClient>,
+    room_token: String,
+}
+
+impl InCall {
+    pub async fn join(calls: CallClient, room_token: &str, silent: bool) -> Result<Self> {
+        calls.join(room_token, silent).await?;
+        Ok(Self {
+            calls: Arc::new(calls),
+            room_token: room_token.to_string(),
+        })
+    }
+
+    // Puts the audio flag back whenever Talk reports our session (by its
+    // signaling session id) in the call without it, e.g. after a reconnect
+    pub fn keep_flags(&self, mut changes: broadcast::Receiver<RosterChange>, session_id: String) -> JoinHandle<()> {
+        let calls = self.calls.clone();
+        let room_token = self.room_token.clone();
+        tokio::spawn(async move {
+            loop {
+                let entry = match changes.recv().await {
+                    Ok(RosterChange::Joined(entry) | RosterChange::Updated(entry)) => entry,
+                    Ok(RosterChange::Left(_)) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                let missing = entry.in_call != 0 && entry.in_call & FLAGS_IN_CALL_AUDIO != FLAGS_IN_CALL_AUDIO;
+                if entry.session_id != session_id || !missing {
+                    continue;
+                }
+                log!("Talk shows the bridge in the call with flags {}, raising audio again", entry.in_call);
+                if let Err(e) = calls.set_flags(&room_token).await {
+                    log!("Failed to update the Talk call flags: {:#}", e);
+                }
+            }
+        })
+    }
+
+    // Leaves the call and the room, waiting for Talk to confirm, for when
+    // the process may exit right after. Once left, it does nothing.
+    pub async fn leave(&mut self) {
+        let room_token = std::mem::take(&mut self.room_token);
+        if room_token.is_empty() {
+            return;
+        }
+        match self.calls.leave(&room_token).await {
+            Ok(()) => log!("Left the Talk call in {}", room_token),
+            Err(e) => log!("Failed to leave the Talk call in {}: {:#}", room_token, e),
+        }
+        if let Err(e) = self.calls.leave_room(&room_token).await {
+            log!("Failed to leave the Talk room {}: {:#}", room_token, e);
+        }
+    }
+}
+
+impl Drop for InCall {
+    fn drop(&mut self) {
+        // Already left
+        if self.room_token.is_empty() {
+            return;
+        }
+        let calls = self.calls.clone();
+        let room_token = self.room_token.clone();
+        tokio::spawn(async move {
+            match calls.leave(&room_token).await {
+                Ok(()) => log!("Left the Talk call in {}", room_token),
+                Err(e) => log!("Failed to leave the Talk call in {}: {:#}", room_token, e),
+            }
+        });
+    }
+}
@@ -0,0 +1,82 @@
+use anyhow::{Context, Result};
+use serde_json::Value;
+use url::Url;
+
+use super::signaling::Config;
+use crate::update;
+
+// Which versions of Talk's OCS APIs to use. Talk moved them on over the
+// years (signaling v1 to v3, conversations v1 to v4) and drops old ones, so
+// they are taken from its capabilities unless configured.
+#[derive(Debug, Clone, Copy)]
+pub struct ApiVersions {
+    // Signaling settings, internal signaling and the backend the signaling
+    // server checks with
+    pub signaling: u8,
+    // Rooms, participants and calls
+    pub conversation: u8,
+}
+
+// What current Talk serves
+impl Default for ApiVersions {
+    fn default() -> Self {
+        Self { signaling: 3, conversation: 4 }
+    }
+}
+
+impl ApiVersions {
+    // What isn't configured is detected; without capabilities the current
+    // versions are assumed
+    pub async fn resolve(config: &Config, signaling: Option<u8>, conversation: Option<u8>) -> Self {
+        let detected = match (signaling, conversation) {
+            (Some(_), Some(_)) => Self::default(),
+            _ => detect(config).await.unwrap_or_else(|e| {
+                log!("Failed to detect Talk's API versions, assuming the current ones: {:#}", e);
+                Self::default()
+            }),
+        };
+        let versions = Self {
+            signaling: signaling.unwrap_or(detected.signaling),
+            conversation: conversation.unwrap_or(detected.conversation),
+        };
+        log!("Talk APIs: signaling v{}, conversations v{}", versions.signaling, versions.conversation);
+        versions
+    }
+}
+
+async fn detect(config: &Config) -> Result<ApiVersions> {
+    let base_url = Url::parse(&config.nextcloud_url).context("Invalid Nextcloud URL")?;
+    let request = config
+        .tls
+        .http
+        .get(base_url.join("/ocs/v2.php/cloud/capabilities")?)
+        .header("OCS-APIRequest", "true")
+        .header("Accept", "application/json")
+        .header("User-Agent", update::user_agent());
+    let resp = config
+        .send(request)
+        .await
+        .context("Failed to fetch Nextcloud capabilities")?;
+    if !resp.status().is_success() {
+        anyhow::bail!("Nextcloud capabilities returned {}", resp.status());
+    }
+    let body: Value = resp.json().await.context("Failed to parse Nextcloud capabilities")?;
+    let features: Vec<&str> = body["ocs"]["data"]["capabilities"]["spreed"]["features"]
+        .as_array()
+        .context("Talk isn't enabled on this Nextcloud")?
+        .iter()
+        .filter_map(Value::as_str)
+        .collect();
+    let has = |feature: &str| features.contains(&feature);
+
+    Ok(ApiVersions {
+        signaling: if has("signaling-v3") {
+            3
+        } else if has("conversation-v2") {
+            2
+        } else {
+            1
+        },
+        conversation: (2..=4).rev().find(|v| has(&format!("conversation-v{}", v))).unwrap_or(1),
+    })
+}
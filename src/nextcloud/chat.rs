@@ -0,0 +1,41 @@
+use anyhow::{Context, Result};
+use url::Url;
+
+use super::signaling::Config;
+
+// Posts messages into a Talk room's chat as the bridge user
+pub struct ChatClient {
+    config: Config,
+    http: reqwest::Client,
+}
+
+impl ChatClient {
+    pub fn new(config: Config) -> Self {
+        Self {
+            http: config.tls.http.clone(),
+            config,
+        }
+    }
+
+    pub async fn send_message(&self, room_token: &str, message: &str) -> Result<()> {
+        let base_url = Url::parse(&self.config.nextcloud_url).context("Invalid Nextcloud URL")?;
+        let api_url = base_url.join(&format!("/ocs/v2.php/apps/spreed/api/v1/chat/{}", room_token))?;
+
+        let request = self
+            .http
+            .post(api_url)
+            .header("OCS-APIRequest", "true")
+            .header("Accept", "application/json")
+            .json(&serde_json::json!({ "message": message }));
+        let resp = self
+            .config
+            .send(request)
+            .await
+            .context("Failed to send chat message to Nextcloud")?;
+
+        if !resp.status().is_success() {
+            anyhow::bail!("Nextcloud chat API returned error: {}", resp.status());
+        }
+        Ok(())
+    }
+}
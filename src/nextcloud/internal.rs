@@ -0,0 +1,294 @@
+use anyhow::{Context, Result};
+use reqwest::{Method, RequestBuilder};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use url::Url;
+
+use super::protocol::SignalingMessage;
+use super::signaling::{Config, Incoming, Outbox};
+use crate::update;
+
+// Talk holds a poll open for up to 30 seconds when there is nothing to say
+const POLL_TIMEOUT: Duration = Duration::from_secs(60);
+
+// What guests are called without TALK_DISPLAY_NAME
+const GUEST_NAME: &str = "Discord";
+
+// Talk's own signaling, for instances without a High Performance Backend:
+// messages are sent and long-polled over OCS. Talk ties them to the PHP
+// session that joined the room, so its cookies go with every request,
+// including the call join. Like the WebSocket, it is read and written by
+// tasks of its own, poll() and write().
+pub struct InternalSignaling {
+    endpoint: Endpoint,
+}
+
+#[derive(Clone)]
+struct Endpoint {
+    config: Config,
+    http: reqwest::Client,
+    base_url: Url,
+    room_token: String,
+    cookie: String,
+    session_id: String,
+}
+
+impl Endpoint {
+    // Sent through Config::send, which adds the credentials
+    fn request(&self, method: Method, path: &str) -> Result<RequestBuilder> {
+        let url = self.base_url.join(&format!("/ocs/v2.php/apps/spreed/api/{}", path))?;
+        let mut req = self
+            .http
+            .request(method, url)
+            .header("OCS-APIRequest", "true")
+            .header("Accept", "application/json")
+            .header("User-Agent", update::user_agent());
+        if !self.cookie.is_empty() {
+            req = req.header("Cookie", &self.cookie);
+        }
+        Ok(req)
+    }
+
+    // One long poll; Talk answers as soon as there is something queued
+    async fn pull(&self) -> Result<Vec<Value>> {
+        let request = self
+            .request(Method::GET, &format!("v{}/signaling/{}", self.config.api.signaling, self.room_token))?
+            .timeout(POLL_TIMEOUT);
+        let resp = self
+            .config
+            .send(request)
+            .await
+            .context("Failed to poll Talk signaling")?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            // The room is gone, or we were removed from it; told like the
+            // signaling server would
+            return Ok(vec![serde_json::json!({ "type": "bye", "data": "room not found" })]);
+        }
+        if !resp.status().is_success() {
+            anyhow::bail!("Talk signaling poll returned {}", resp.status());
+        }
+        let mut body: Value = resp.json().await.context("Failed to parse Talk signaling")?;
+        match body.get_mut("ocs").and_then(|o| o.get_mut("data")).map(Value::take) {
+            Some(Value::Array(messages)) => Ok(messages),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    // Takes a message as it would go to the signaling server
+    async fn send(&self, payload: &Value) -> Result<()> {
+        // Only call messages to a single session go through Talk; their
+        // data is already in its format
+        let message = &payload["message"];
+        if message["recipient"]["type"] != "session" || message["data"]["to"].is_null() {
+            return Ok(());
+        }
+        let messages = serde_json::json!([{
+            "ev": "message",
+            "fn": message["data"].to_string(),
+            "sessionId": self.session_id,
+        }]);
+        let request = self
+            .request(Method::POST, &format!("v{}/signaling/{}", self.config.api.signaling, self.room_token))?
+            .json(&serde_json::json!({ "messages": messages.to_string() }));
+        let resp = self
+            .config
+            .send(request)
+            .await
+            .context("Failed to send Talk signaling")?;
+        if !resp.status().is_success() {
+            anyhow::bail!("Talk signaling send returned {}", resp.status());
+        }
+        Ok(())
+    }
+}
+
+// A Talk session in a room, from joining it over OCS. Talk ties it to the
+// PHP session, so the cookies go with every request that belongs to it.
+#[derive(Clone)]
+pub struct TalkSession {
+    pub cookie: String,
+    pub session_id: String,
+}
+
+// Joins the room, which starts a session. Guests are named right away;
+// until then Talk shows them as a nameless guest.
+pub async fn join_room(config: &Config, room_token: &str) -> Result<TalkSession> {
+    let mut endpoint = Endpoint {
+        config: config.clone(),
+        http: config.tls.http.clone(),
+        base_url: Url::parse(&config.nextcloud_url).context("Invalid Nextcloud URL")?,
+        room_token: room_token.to_string(),
+        cookie: String::new(),
+        session_id: String::new(),
+    };
+    let request = endpoint
+        .request(Method::POST, &format!("v{}/room/{}/participants/active", config.api.conversation, room_token))?
+        .json(&serde_json::json!({ "force": true, "password": config.room_password.as_deref().unwrap_or_default() }));
+    let resp = config
+        .send(request)
+        .await
+        .context("Failed to join the Talk room")?;
+    if resp.status() == reqwest::StatusCode::FORBIDDEN {
+        match &config.room_password {
+            Some(_) => anyhow::bail!("Talk refused the room password (NEXTCLOUD_ROOM_PASSWORD)"),
+            None if config.is_guest() => anyhow::bail!("Talk doesn't let guests into the room; only public rooms take guests"),
+            None => anyhow::bail!("Talk refused to let the bridge into the room; set NEXTCLOUD_ROOM_PASSWORD if it has a password"),
+        }
+    }
+    if !resp.status().is_success() {
+        anyhow::bail!("Joining the Talk room returned {}", resp.status());
+    }
+    endpoint.cookie = cookies(resp.headers());
+    let body: Value = resp.json().await.context("Failed to parse Nextcloud response")?;
+    let session_id = body["ocs"]["data"]["sessionId"]
+        .as_str()
+        .context("No session id in response")?
+        .to_string();
+
+    if config.is_guest() {
+        let name = config.nick.as_deref().unwrap_or(GUEST_NAME);
+        let request = endpoint
+            .request(Method::POST, &format!("v1/guest/{}/name", room_token))?
+            .json(&serde_json::json!({ "displayName": name }));
+        let resp = config
+            .send(request)
+            .await
+            .context("Failed to set the guest name")?;
+        if !resp.status().is_success() {
+            log!("Talk didn't take the guest name {:?}: {}", name, resp.status());
+        }
+    }
+    log!("Joined Talk room {}, session {}", room_token, session_id);
+    Ok(TalkSession { cookie: endpoint.cookie, session_id })
+}
+
+impl InternalSignaling {
+    // Messages belong to the session that joined the room
+    pub fn new(config: &Config, room_token: &str, session: TalkSession) -> Result<Self> {
+        Ok(Self {
+            endpoint: Endpoint {
+                config: config.clone(),
+                http: config.tls.http.clone(),
+                base_url: Url::parse(&config.nextcloud_url).context("Invalid Nextcloud URL")?,
+                room_token: room_token.to_string(),
+                cookie: session.cookie,
+                session_id: session.session_id,
+            },
+        })
+    }
+
+    // Long-polls into `incoming` until polling fails or Talk says the
+    // session is over
+    pub fn poll(&self, incoming: mpsc::Sender<Incoming>) -> JoinHandle<()> {
+        let endpoint = self.endpoint.clone();
+        tokio::spawn(async move {
+            // Everyone in the room at the last poll, to tell who left
+            let mut sessions = HashSet::new();
+            loop {
+                let messages = match endpoint.pull().await {
+                    Ok(messages) => messages,
+                    Err(e) => {
+                        let _ = incoming.send(Err(e)).await;
+                        return;
+                    }
+                };
+                let done = messages.iter().any(|m| m["type"] == "bye");
+                for message in messages.into_iter().flat_map(|m| translate(m, &mut sessions)) {
+                    if incoming.send(Ok(Some(message))).await.is_err() {
+                        return;
+                    }
+                }
+                if done {
+                    // No session to resume
+                    let _ = incoming.send(Ok(None)).await;
+                    return;
+                }
+            }
+        })
+    }
+
+    // Sends what is queued until told to stop. Failed sends are dropped;
+    // there is no connection that could come back.
+    pub fn write(&self, outbox: Arc<Outbox>, mut stop: oneshot::Receiver<()>) -> JoinHandle<()> {
+        let endpoint = self.endpoint.clone();
+        tokio::spawn(async move {
+            loop {
+                let payload = tokio::select! {
+                    biased;
+                    payload = outbox.next() => payload,
+                    _ = &mut stop => break,
+                };
+                if let Err(e) = endpoint.send(&payload).await {
+                    log!("Talk signaling send failed: {:#}", e);
+                }
+            }
+        })
+    }
+}
+
+// Into the frames the signaling server would have sent
+fn translate(mut message: Value, sessions: &mut HashSet<String>) -> Vec<SignalingMessage> {
+    let mut frames = Vec::new();
+    match message["type"].as_str() {
+        Some("message") => {
+            let Some(data) = message["data"].as_str().and_then(|d| serde_json::from_str::<Value>(d).ok()) else {
+                return frames;
+            };
+            frames.push(SignalingMessage::from_value(serde_json::json!({
+                "type": "message",
+                "message": {
+                    "sender": { "type": "session", "sessionid": data["from"] },
+                    "data": data,
+                },
+            })));
+        }
+        Some("usersInRoom") => {
+            let users = message["data"].take();
+            let present: HashSet<String> = users
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|u| u.get("sessionId").and_then(Value::as_str))
+                .map(str::to_string)
+                .collect();
+            let left: Vec<&String> = sessions.difference(&present).collect();
+            if !left.is_empty() {
+                frames.push(SignalingMessage::from_value(serde_json::json!({
+                    "type": "event",
+                    "event": { "target": "room", "type": "leave", "leave": left },
+                })));
+            }
+            frames.push(SignalingMessage::from_value(serde_json::json!({
+                "type": "event",
+                "event": { "target": "participants", "type": "update", "update": { "users": users } },
+            })));
+            *sessions = present;
+        }
+        Some("bye") => {
+            frames.push(SignalingMessage::from_value(serde_json::json!({
+                "type": "bye",
+                "bye": { "reason": message["data"] },
+            })));
+        }
+        _ => {}
+    }
+    frames
+}
+
+// The name=value part of every Set-Cookie, later ones replacing earlier
+fn cookies(headers: &reqwest::header::HeaderMap) -> String {
+    let mut jar = BTreeMap::new();
+    for header in headers.get_all(reqwest::header::SET_COOKIE) {
+        let Some(pair) = header.to_str().ok().and_then(|h| h.split(';').next()) else {
+            continue;
+        };
+        if let Some((name, value)) = pair.trim().split_once('=') {
+            jar.insert(name.to_string(), value.to_string());
+        }
+    }
+    jar.iter().map(|(name, value)| format!("{}={}", name, value)).collect::<Vec<_>>().join("; ")
+}
@@ -0,0 +1,101 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use url::Url;
+
+use super::signaling::Config;
+use crate::config::MetricsConfig;
+use crate::manager::{BridgeManager, BridgeStatus};
+
+// Bumped on incompatible payload changes so the receiving app can tell
+const SCHEMA_VERSION: u32 = 1;
+
+// What gets POSTed to the metrics endpoint. The receiving Nextcloud app
+// (or ExApp) only needs to store the latest report per instance.
+#[derive(Serialize)]
+struct MetricsReport<'a> {
+    schema: u32,
+    instance: &'a str,
+    version: &'static str,
+    // Unix seconds
+    timestamp: u64,
+    uptime_secs: u64,
+    bridges: Vec<BridgeStatus>,
+}
+
+// Pushes bridge health to an endpoint on the Nextcloud server, authenticated
+// as the bridge user
+pub struct MetricsClient {
+    config: Config,
+    endpoint: Url,
+    http: reqwest::Client,
+}
+
+impl MetricsClient {
+    // `endpoint` may be a full URL or a path on the Nextcloud server
+    pub fn new(config: Config, endpoint: &str) -> Result<Self> {
+        let base_url = Url::parse(&config.nextcloud_url).context("Invalid Nextcloud URL")?;
+        let endpoint = base_url.join(endpoint).context("Invalid metrics endpoint")?;
+        Ok(Self {
+            http: config.tls.http.clone(),
+            config,
+            endpoint,
+        })
+    }
+
+    async fn push(&self, report: &MetricsReport<'_>) -> Result<()> {
+        let request = self
+            .http
+            .post(self.endpoint.clone())
+            .header("OCS-APIRequest", "true")
+            .header("Accept", "application/json")
+            .json(report);
+        let resp = self
+            .config
+            .send(request)
+            .await
+            .context("Failed to push metrics to Nextcloud")?;
+
+        if !resp.status().is_success() {
+            anyhow::bail!("Nextcloud metrics endpoint returned error: {}", resp.status());
+        }
+        Ok(())
+    }
+}
+
+// Reports every interval until the process exits. Failures are logged once
+// per outage, not on every attempt.
+pub async fn run(client: MetricsClient, config: MetricsConfig, manager: Arc<BridgeManager>) {
+    let started = Instant::now();
+    let mut interval = tokio::time::interval(config.interval);
+    let mut failing = false;
+
+    loop {
+        interval.tick().await;
+        let report = MetricsReport {
+            schema: SCHEMA_VERSION,
+            instance: &config.instance,
+            version: env!("CARGO_PKG_VERSION"),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            uptime_secs: started.elapsed().as_secs(),
+            bridges: manager.list(),
+        };
+
+        match client.push(&report).await {
+            Ok(()) if failing => {
+                log!("Metrics push recovered");
+                failing = false;
+            }
+            Ok(()) => {}
+            Err(e) if !failing => {
+                log!("Metrics push failed: {:#}", e);
+                failing = true;
+            }
+            Err(_) => {}
+        }
+    }
+}
@@ -1,2 +1,12 @@
+pub mod auth;
+pub mod call;
+pub mod capabilities;
+pub mod chat;
+pub mod internal;
+pub mod metrics;
+pub mod peers;
+pub mod protocol;
+pub mod sdp_diff;
 pub mod signaling;
+pub mod tls;
 pub mod webrtc;
@@ -0,0 +1,259 @@
+use anyhow::{Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::AtomicU8;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::track::track_remote::TrackRemote;
+
+use super::protocol::CallMessage;
+use super::webrtc::{IceServers, LocalAudioTrack, NextcloudWebRTC, StatusHandler};
+use crate::config::SdpMunging;
+use crate::encryption::SharedEncryption;
+
+// A remote track, with the Talk session it comes from when the connection
+// is to a single participant
+pub type TrackHandler = Arc<dyn Fn(Arc<TrackRemote>, Option<String>) + Send + Sync>;
+
+// A local ICE candidate for one peer: session id, candidate, sdpMid, sdpMLineIndex
+pub type PeerCandidate = (String, String, String, u16);
+
+// A state message from one peer's data channel, with their session id
+pub type PeerStatus = (String, CallMessage);
+
+// A peer connection's new state, with the session it is to
+pub type PeerState = (String, RTCPeerConnectionState);
+
+// P2P mode, for signaling servers without an MCU: one peer connection per
+// remote participant, keyed by their signaling session. Every connection
+// publishes the same local track, so Discord audio is encoded once however
+// many participants there are, and every remote track goes to `on_track`.
+// With an MCU it holds one subscriber connection per Talk publisher instead,
+// keyed by the publisher's session. Those carry the local track as well, but
+// answer Janus' send-only offers as receive-only, so nothing is sent on them.
+pub struct PeerManager {
+    track: LocalAudioTrack,
+    publish_loss: Arc<AtomicU8>,
+    bandwidth: Option<u32>,
+    munging: SdpMunging,
+    encryption: SharedEncryption,
+    // Replaced when Talk's TURN credentials are renewed
+    ice_servers: std::sync::Mutex<IceServers>,
+    // Each peer is a full DTLS/SRTP connection, so only small rooms
+    max_peers: usize,
+    on_track: TrackHandler,
+    candidates: mpsc::Sender<PeerCandidate>,
+    statuses: mpsc::Sender<PeerStatus>,
+    states: mpsc::Sender<PeerState>,
+    // Our signaling session, which decides who gives way when offers cross
+    own_session: Option<String>,
+    peers: Mutex<HashMap<String, Arc<NextcloudWebRTC>>>,
+    // Publishers whose offer was asked for and hasn't arrived yet
+    requested: Mutex<HashSet<String>>,
+}
+
+impl PeerManager {
+    // The peers publish what `main`, the connection to Talk, does
+    pub fn new(
+        main: &NextcloudWebRTC,
+        own_session: Option<String>,
+        max_peers: usize,
+        on_track: TrackHandler,
+        candidates: mpsc::Sender<PeerCandidate>,
+        statuses: mpsc::Sender<PeerStatus>,
+        states: mpsc::Sender<PeerState>,
+    ) -> Self {
+        Self {
+            track: main.audio_track.clone(),
+            publish_loss: main.publish_loss.clone(),
+            bandwidth: main.bandwidth,
+            munging: main.munging.clone(),
+            encryption: main.encryption.clone(),
+            ice_servers: std::sync::Mutex::new(main.ice_servers.clone()),
+            max_peers,
+            on_track,
+            candidates,
+            statuses,
+            states,
+            own_session,
+            peers: Mutex::new(HashMap::new()),
+            requested: Mutex::new(HashSet::new()),
+        }
+    }
+
+    // The connection to a participant, made on first use. None once the
+    // limit is reached.
+    async fn peer(&self, session: &str) -> Result<Option<Arc<NextcloudWebRTC>>> {
+        let mut peers = self.peers.lock().await;
+        if let Some(peer) = peers.get(session) {
+            if !peer.is_dead() {
+                return Ok(Some(peer.clone()));
+            }
+            log!("Peer connection to {} is gone, reconnecting", session);
+            if let Some(peer) = peers.remove(session) {
+                let _ = peer.close().await;
+            }
+        }
+        if peers.len() >= self.max_peers {
+            log!("Not connecting to {}: already at {} peer connections", session, self.max_peers);
+            return Ok(None);
+        }
+
+        let ice_servers = self.ice_servers.lock().unwrap().clone();
+        let peer = NextcloudWebRTC::with_track(
+            self.track.clone(),
+            self.publish_loss.clone(),
+            self.bandwidth,
+            self.munging.clone(),
+            self.encryption.clone(),
+            ice_servers,
+        )
+            .await
+            .context("Failed to create peer connection")?
+            .polite(self.polite_to(session));
+        let on_track = self.on_track.clone();
+        let from = session.to_string();
+        peer.on_audio_track(Box::new(move |track| on_track(track, Some(from.clone()))));
+        let candidates = self.candidates.clone();
+        let to = session.to_string();
+        peer.on_ice_candidate(Box::new(move |candidate, mid, line| {
+            let _ = candidates.try_send((to.clone(), candidate, mid, line));
+        }));
+        peer.on_status(self.status_handler(session));
+        let mut state = peer.state();
+        let states = self.states.clone();
+        let of = session.to_string();
+        tokio::spawn(async move {
+            while state.changed().await.is_ok() {
+                let now = *state.borrow_and_update();
+                if states.send((of.clone(), now)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        log!("Opened peer connection to {} ({} open)", session, peers.len() + 1);
+        self.requested.lock().await.remove(session);
+        let peer = Arc::new(peer);
+        peers.insert(session.to_string(), peer.clone());
+        Ok(Some(peer))
+    }
+
+    // Returns the answer to send back, unless the limit is reached. A
+    // renegotiation that fails leaves the connection with the media it had.
+    pub async fn handle_offer(&self, session: &str, sdp: String) -> Result<Option<String>> {
+        let Some(peer) = self.peer(session).await? else {
+            return Ok(None);
+        };
+        let renegotiation = peer.is_negotiated().await;
+        match peer.handle_offer(sdp).await {
+            Err(e) if renegotiation => {
+                log!("Failed to renegotiate with {}, keeping the media as it was: {:#}", session, e);
+                Ok(None)
+            }
+            answer => answer,
+        }
+    }
+
+    // Whoever has the greater session id makes the first offer, as in Talk,
+    // and keeps its offers when they cross; the other side gives way. Janus
+    // never offers into ours, so subscriptions stay polite either way.
+    fn polite_to(&self, session: &str) -> bool {
+        self.own_session.as_deref().is_none_or(|own| own < session)
+    }
+
+    // Answers and candidates only make sense for connections we already have
+    pub async fn handle_answer(&self, session: &str, sdp: String) -> Result<()> {
+        let peer = self.peers.lock().await.get(session).cloned();
+        match peer {
+            Some(peer) => peer.handle_answer(sdp).await,
+            None => Ok(()),
+        }
+    }
+
+    pub async fn add_ice_candidate(&self, session: &str, candidate: String, mid: String, line: u16) -> Result<()> {
+        let peer = self.peers.lock().await.get(session).cloned();
+        match peer {
+            Some(peer) => peer.add_ice_candidate(candidate, mid, line).await,
+            None => Ok(()),
+        }
+    }
+
+    // Starts a connection to a participant we aren't connected to yet;
+    // returns the offer to send them
+    pub async fn offer(&self, session: &str) -> Result<Option<String>> {
+        if self.peers.lock().await.get(session).is_some_and(|p| !p.is_dead()) {
+            return Ok(None);
+        }
+        let Some(peer) = self.peer(session).await? else {
+            return Ok(None);
+        };
+        peer.open_status_channel(self.status_handler(session)).await?;
+        peer.create_offer().await.map(Some)
+    }
+
+    // Passes a peer's state messages on with who they are from
+    fn status_handler(&self, session: &str) -> StatusHandler {
+        let statuses = self.statuses.clone();
+        let from = session.to_string();
+        Arc::new(move |status| {
+            let _ = statuses.try_send((from.clone(), status));
+        })
+    }
+
+    // MCU: whether to ask for a publisher's offer, which is only done once
+    // until the connection is gone again
+    pub async fn request(&self, session: &str) -> bool {
+        if self.peers.lock().await.get(session).is_some_and(|p| !p.is_dead()) {
+            return false;
+        }
+        self.requested.lock().await.insert(session.to_string())
+    }
+
+    // The participant left the call
+    pub async fn remove(&self, session: &str) {
+        self.requested.lock().await.remove(session);
+        let peer = self.peers.lock().await.remove(session);
+        if let Some(peer) = peer {
+            log!("Closed peer connection to {}", session);
+            if let Err(e) = peer.close().await {
+                log!("Failed to close peer connection: {:?}", e);
+            }
+        }
+    }
+
+    // Drops the connection to a participant once it failed or was closed
+    // from their end, so they can be connected to again. Returns whether it
+    // was dropped; not if it is already gone or was replaced.
+    pub async fn remove_dead(&self, session: &str) -> bool {
+        let peer = {
+            let mut peers = self.peers.lock().await;
+            if !peers.get(session).is_some_and(|p| p.is_dead()) {
+                return false;
+            }
+            peers.remove(session)
+        };
+        self.requested.lock().await.remove(session);
+        if let Some(peer) = peer {
+            log!("Peer connection to {} is gone, dropping it", session);
+            let _ = peer.close().await;
+        }
+        true
+    }
+
+    // For connections made from now on
+    pub fn set_ice_servers(&self, ice_servers: IceServers) {
+        *self.ice_servers.lock().unwrap() = ice_servers;
+    }
+
+    pub async fn connections(&self) -> Vec<Arc<NextcloudWebRTC>> {
+        self.peers.lock().await.values().cloned().collect()
+    }
+
+    pub async fn close(&self) {
+        let peers: Vec<_> = self.peers.lock().await.drain().collect();
+        for (_, peer) in peers {
+            let _ = peer.close().await;
+        }
+    }
+}
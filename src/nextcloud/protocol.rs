@@ -0,0 +1,593 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use std::fmt;
+
+// What the standalone signaling server (nextcloud-spreed-signaling) sends.
+// Each frame's body is under a key named like its type:
+// {"type": "hello", "hello": {...}}. Types not modelled here, and bodies that
+// don't match their type, come through as Unknown instead of being dropped.
+#[derive(Debug, Clone)]
+pub enum SignalingMessage {
+    Welcome(Welcome),
+    Hello(Hello),
+    Error(SignalingError),
+    // The answer to a join; an empty roomid means we are in no room
+    Room(Room),
+    Message(Envelope),
+    // Like Message, but only accepted from participants who may control
+    // others (moderators)
+    Control(Envelope),
+    Event(Event),
+    Bye(Bye),
+    Unknown { kind: String, body: Value },
+}
+
+impl SignalingMessage {
+    pub fn parse(text: &str) -> Result<Self> {
+        let value: Value = serde_json::from_str(text).context("Signaling frame isn't JSON")?;
+        Ok(Self::from_value(value))
+    }
+
+    pub fn from_value(mut value: Value) -> Self {
+        let kind = value.get("type").and_then(Value::as_str).unwrap_or_default().to_string();
+        // bye may come without a body
+        let body = match value.get_mut(&kind).map(Value::take) {
+            Some(Value::Null) | None => Value::Object(Default::default()),
+            Some(body) => body,
+        };
+        let parsed = match kind.as_str() {
+            "welcome" => serde_json::from_value(body.clone()).map(Self::Welcome),
+            "hello" => serde_json::from_value(body.clone()).map(Self::Hello),
+            "error" => serde_json::from_value(body.clone()).map(Self::Error),
+            "room" => serde_json::from_value(body.clone()).map(Self::Room),
+            "message" => serde_json::from_value(body.clone()).map(Self::Message),
+            "control" => serde_json::from_value(body.clone()).map(Self::Control),
+            "event" => serde_json::from_value(body.clone()).map(Self::Event),
+            "bye" => serde_json::from_value(body.clone()).map(Self::Bye),
+            _ => return Self::Unknown { kind, body },
+        };
+        parsed.unwrap_or_else(|e| {
+            log!("Unexpected signaling {} message ({}): {}", kind, e, body);
+            Self::Unknown { kind, body }
+        })
+    }
+}
+
+// Sent by the server right after connecting, before any hello
+#[derive(Deserialize, Debug, Clone)]
+pub struct Welcome {
+    pub version: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Hello {
+    pub sessionid: String,
+    // Lets a dropped connection pick the session up again
+    pub resumeid: Option<String>,
+    // Who Talk confirmed us as, and the hello version the server speaks
+    pub userid: Option<String>,
+    pub version: Option<String>,
+    #[serde(default)]
+    pub server: ServerInfo,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ServerInfo {
+    pub version: Option<String>,
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+// An error frame, as an error of its own so callers can tell the code from
+// a chain of context
+#[derive(Deserialize, Debug, Clone)]
+pub struct SignalingError {
+    pub code: String,
+    #[serde(default)]
+    pub message: String,
+    pub details: Option<Value>,
+}
+
+impl SignalingError {
+    // Whether the session can't go on after it: we aren't who we said,
+    // or the room is gone or won't have us. Other errors are about a
+    // single message, e.g. one to a session that just left.
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self.code.as_str(),
+            "hello_expected"
+                | "invalid_token"
+                | "token_expired"
+                | "token_not_valid_yet"
+                | "invalid_backend"
+                | "room_not_found"
+                | "no_such_room"
+                | "room_join_failed"
+        )
+    }
+}
+
+impl fmt::Display for SignalingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = if self.message.is_empty() { &self.code } else { &self.message };
+        write!(f, "{} ({})", message, self.code)?;
+        if let Some(details) = &self.details {
+            write!(f, ": {}", details)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SignalingError {}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Room {
+    #[serde(default)]
+    pub roomid: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Bye {
+    pub reason: Option<String>,
+}
+
+// A session, user or the whole room; only sessions have a sessionid
+#[derive(Deserialize, Debug, Clone)]
+pub struct Address {
+    pub sessionid: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Envelope {
+    pub sender: Option<Address>,
+    #[serde(default)]
+    pub data: Value,
+}
+
+impl Envelope {
+    // Talk's WebRTC messages (offers, candidates, ...) in `data`
+    pub fn call(&self) -> Option<CallMessage> {
+        serde_json::from_value(self.data.clone()).ok()
+    }
+
+    // Who sent it: the call message's own "from", set when the MCU relays
+    // for a publisher, or else the envelope's sender
+    pub fn sender_session(&self, call: &CallMessage) -> Option<String> {
+        call.from
+            .clone()
+            .or_else(|| self.sender.as_ref().and_then(|s| s.sessionid.clone()))
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct CallMessage {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub from: Option<String>,
+    // The stream an offer is for, echoed in whatever answers it
+    pub sid: Option<String>,
+    // "video" for the call's media, "screen" for a screen share
+    #[serde(rename = "roomType")]
+    pub room_type: Option<String>,
+    #[serde(default)]
+    pub payload: Value,
+}
+
+impl CallMessage {
+    pub fn sdp(&self) -> Option<&str> {
+        self.payload.get("sdp").and_then(Value::as_str)
+    }
+
+    // A nickChanged message's name; older clients send just the name
+    pub fn nick(&self) -> Option<&str> {
+        match &self.payload {
+            Value::String(name) => Some(name.as_str()),
+            payload => payload.get("name").and_then(Value::as_str),
+        }
+        .filter(|name| !name.trim().is_empty())
+    }
+
+    // Whether a raiseHand message raises or lowers the hand
+    pub fn hand(&self) -> Option<bool> {
+        self.payload.get("state").and_then(Value::as_bool)
+    }
+
+    // An in-call reaction's emoji
+    pub fn reaction(&self) -> Option<&str> {
+        self.payload.get("reaction").and_then(Value::as_str).filter(|r| !r.trim().is_empty())
+    }
+
+    // candidate, sdpMid, sdpMLineIndex
+    pub fn candidate(&self) -> Option<(String, String, u16)> {
+        let candidate = self.payload.get("candidate")?;
+        Some((
+            candidate.get("candidate")?.as_str()?.to_string(),
+            candidate.get("sdpMid")?.as_str()?.to_string(),
+            candidate.get("sdpMLineIndex")?.as_u64()? as u16,
+        ))
+    }
+}
+
+// Changes in the room, the call or the room list. Which fields are set
+// depends on target ("room", "participants", "roomlist", "message") and
+// type ("join", "leave", "change", "update", ...).
+#[derive(Deserialize, Debug, Clone)]
+pub struct Event {
+    pub target: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub join: Vec<Joined>,
+    // Session ids
+    #[serde(default)]
+    pub leave: Vec<String>,
+    pub update: Option<Update>,
+    pub disinvite: Option<Disinvite>,
+}
+
+// We may no longer be in a room: removed from it, or it was deleted
+#[derive(Deserialize, Debug, Clone)]
+pub struct Disinvite {
+    pub roomid: String,
+    // "disinvited" or "deleted"
+    #[serde(default)]
+    pub reason: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Joined {
+    pub sessionid: String,
+    pub userid: Option<String>,
+    // What Talk knows about the user, e.g. their displayname
+    pub user: Option<Value>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Update {
+    #[serde(default)]
+    pub users: Vec<Participant>,
+    // With all set, the in-call flags of everyone at once, e.g. 0 when the
+    // call was ended for everyone
+    #[serde(default)]
+    pub all: bool,
+    #[serde(default, deserialize_with = "flags")]
+    pub incall: u64,
+}
+
+// A participant as Talk reports them in participant updates
+#[derive(Deserialize, Debug, Clone)]
+pub struct Participant {
+    #[serde(rename = "sessionId", default)]
+    pub session_id: String,
+    // Empty for guests
+    #[serde(rename = "userId")]
+    pub user_id: Option<String>,
+    #[serde(rename = "displayName")]
+    pub display_name: Option<String>,
+    // Talk's in-call flags: 1 in call, 2 audio, 4 video, 8 phone
+    #[serde(rename = "inCall", default, deserialize_with = "flags")]
+    pub in_call: u64,
+}
+
+// Old Talk versions send in-call state as a bool
+fn flags<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    Ok(match Value::deserialize(deserializer)? {
+        Value::Bool(in_call) => in_call as u64,
+        value => value.as_u64().unwrap_or(0),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // Frames as nextcloud-spreed-signaling sends them, trimmed of fields the
+    // bridge doesn't read
+    fn parse(frame: Value) -> SignalingMessage {
+        SignalingMessage::parse(&frame.to_string()).unwrap()
+    }
+
+    #[test]
+    fn welcome() {
+        let frame = json!({
+            "type": "welcome",
+            "welcome": { "features": ["mcu", "simulcast"], "version": "1.2.4~docker" },
+        });
+        let SignalingMessage::Welcome(welcome) = parse(frame) else { panic!("not a welcome") };
+        assert_eq!(welcome.version.as_deref(), Some("1.2.4~docker"));
+    }
+
+    #[test]
+    fn hello_v1() {
+        let frame = json!({
+            "id": "1",
+            "type": "hello",
+            "hello": {
+                "sessionid": "c2Vzc2lvbi0x",
+                "resumeid": "cmVzdW1lLTE",
+                "userid": "discord",
+                "server": { "version": "1.1.3", "features": ["audio-video-permissions", "mcu"] },
+            },
+        });
+        let SignalingMessage::Hello(hello) = parse(frame) else { panic!("not a hello") };
+        assert_eq!(hello.sessionid, "c2Vzc2lvbi0x");
+        assert_eq!(hello.resumeid.as_deref(), Some("cmVzdW1lLTE"));
+        assert_eq!(hello.userid.as_deref(), Some("discord"));
+        assert_eq!(hello.version, None);
+        assert_eq!(hello.server.features, ["audio-video-permissions", "mcu"]);
+    }
+
+    #[test]
+    fn hello_v2() {
+        let frame = json!({
+            "id": "1",
+            "type": "hello",
+            "hello": {
+                "version": "2.0",
+                "sessionid": "c2Vzc2lvbi0y",
+                "resumeid": "cmVzdW1lLTI",
+                "userid": "",
+                "server": { "version": "1.2.4", "features": ["hello-v2", "mcu", "transient-data"] },
+            },
+        });
+        let SignalingMessage::Hello(hello) = parse(frame) else { panic!("not a hello") };
+        assert_eq!(hello.version.as_deref(), Some("2.0"));
+        assert_eq!(hello.userid.as_deref(), Some(""));
+        assert!(hello.server.features.iter().any(|f| f == "hello-v2"));
+    }
+
+    #[test]
+    fn hello_without_server() {
+        let frame = json!({ "type": "hello", "hello": { "sessionid": "s", "resumeid": "r" } });
+        let SignalingMessage::Hello(hello) = parse(frame) else { panic!("not a hello") };
+        assert!(hello.server.version.is_none());
+        assert!(hello.server.features.is_empty());
+    }
+
+    #[test]
+    fn fatal_error() {
+        let frame = json!({
+            "id": "1",
+            "type": "error",
+            "error": { "code": "invalid_token", "message": "The passed token is invalid." },
+        });
+        let SignalingMessage::Error(error) = parse(frame) else { panic!("not an error") };
+        assert!(error.is_fatal());
+        assert_eq!(error.to_string(), "The passed token is invalid. (invalid_token)");
+    }
+
+    #[test]
+    fn non_fatal_error() {
+        let frame = json!({
+            "type": "error",
+            "error": {
+                "code": "no_such_session",
+                "message": "The session to which the message was sent does not exist.",
+                "details": { "sessionid": "gone" },
+            },
+        });
+        let SignalingMessage::Error(error) = parse(frame) else { panic!("not an error") };
+        assert!(!error.is_fatal());
+        assert!(error.to_string().ends_with(r#"(no_such_session): {"sessionid":"gone"}"#));
+    }
+
+    #[test]
+    fn room() {
+        let frame = json!({
+            "id": "2",
+            "type": "room",
+            "room": { "roomid": "abc123", "properties": { "name": "Standup", "type": 3 } },
+        });
+        let SignalingMessage::Room(room) = parse(frame) else { panic!("not a room") };
+        assert_eq!(room.roomid, "abc123");
+
+        // Left every room
+        let SignalingMessage::Room(room) = parse(json!({ "type": "room", "room": {} })) else { panic!("not a room") };
+        assert!(room.roomid.is_empty());
+    }
+
+    #[test]
+    fn message_to_us() {
+        let frame = json!({
+            "type": "message",
+            "message": {
+                "sender": { "type": "session", "sessionid": "cGVlcg", "userid": "alice" },
+                "data": {
+                    "to": "c2Vzc2lvbi0x",
+                    "sid": "1700000000000",
+                    "roomType": "video",
+                    "type": "offer",
+                    "payload": { "type": "offer", "sdp": "v=0\r\n", "nick": "Alice" },
+                },
+            },
+        });
+        let SignalingMessage::Message(envelope) = parse(frame) else { panic!("not a message") };
+        let call = envelope.call().unwrap();
+        assert_eq!(call.kind, "offer");
+        assert_eq!(call.sid.as_deref(), Some("1700000000000"));
+        assert_eq!(call.room_type.as_deref(), Some("video"));
+        assert_eq!(call.sdp(), Some("v=0\r\n"));
+        assert_eq!(envelope.sender_session(&call).as_deref(), Some("cGVlcg"));
+    }
+
+    #[test]
+    fn message_relayed_by_the_mcu() {
+        let frame = json!({
+            "type": "message",
+            "message": {
+                "sender": { "type": "session", "sessionid": "bWN1" },
+                "data": {
+                    "to": "c2Vzc2lvbi0x",
+                    "from": "cHVibGlzaGVy",
+                    "type": "candidate",
+                    "roomType": "video",
+                    "payload": {
+                        "candidate": {
+                            "candidate": "candidate:1 1 UDP 2122252543 192.0.2.1 40000 typ host",
+                            "sdpMid": "0",
+                            "sdpMLineIndex": 0,
+                        },
+                    },
+                },
+            },
+        });
+        let SignalingMessage::Message(envelope) = parse(frame) else { panic!("not a message") };
+        let call = envelope.call().unwrap();
+        assert_eq!(envelope.sender_session(&call).as_deref(), Some("cHVibGlzaGVy"));
+        let (candidate, mid, index) = call.candidate().unwrap();
+        assert!(candidate.starts_with("candidate:1 "));
+        assert_eq!((mid.as_str(), index), ("0", 0));
+    }
+
+    #[test]
+    fn control() {
+        let frame = json!({
+            "type": "control",
+            "control": {
+                "sender": { "type": "session", "sessionid": "bW9kZXJhdG9y" },
+                "data": { "to": "c2Vzc2lvbi0x", "type": "forceMute", "payload": { "peerId": "c2Vzc2lvbi0x" } },
+            },
+        });
+        let SignalingMessage::Control(envelope) = parse(frame) else { panic!("not a control") };
+        assert_eq!(envelope.call().unwrap().kind, "forceMute");
+    }
+
+    #[test]
+    fn participants_update() {
+        let frame = json!({
+            "type": "event",
+            "event": {
+                "target": "participants",
+                "type": "update",
+                "update": {
+                    "roomid": "abc123",
+                    "users": [
+                        { "sessionId": "cGVlcg", "userId": "alice", "displayName": "Alice", "inCall": 7 },
+                        { "sessionId": "Z3Vlc3Q", "inCall": false },
+                    ],
+                },
+            },
+        });
+        let SignalingMessage::Event(event) = parse(frame) else { panic!("not an event") };
+        assert_eq!((event.target.as_str(), event.kind.as_str()), ("participants", "update"));
+        let update = event.update.unwrap();
+        assert!(!update.all);
+        assert_eq!(update.users.len(), 2);
+        assert_eq!(update.users[0].user_id.as_deref(), Some("alice"));
+        assert_eq!(update.users[0].in_call, 7);
+        assert_eq!(update.users[1].user_id, None);
+        assert_eq!(update.users[1].in_call, 0);
+    }
+
+    #[test]
+    fn call_ended_for_everyone() {
+        let frame = json!({
+            "type": "event",
+            "event": {
+                "target": "participants",
+                "type": "update",
+                "update": { "roomid": "abc123", "all": true, "incall": 0 },
+            },
+        });
+        let SignalingMessage::Event(event) = parse(frame) else { panic!("not an event") };
+        let update = event.update.unwrap();
+        assert!(update.all);
+        assert_eq!(update.incall, 0);
+        assert!(update.users.is_empty());
+    }
+
+    #[test]
+    fn room_join_and_leave() {
+        let frame = json!({
+            "type": "event",
+            "event": {
+                "target": "room",
+                "type": "join",
+                "join": [{ "sessionid": "cGVlcg", "userid": "alice", "user": { "displayname": "Alice" } }],
+            },
+        });
+        let SignalingMessage::Event(event) = parse(frame) else { panic!("not an event") };
+        assert_eq!(event.join[0].sessionid, "cGVlcg");
+        assert_eq!(event.join[0].user.as_ref().unwrap()["displayname"], "Alice");
+
+        let frame = json!({
+            "type": "event",
+            "event": { "target": "room", "type": "leave", "leave": ["cGVlcg", "Z3Vlc3Q"] },
+        });
+        let SignalingMessage::Event(event) = parse(frame) else { panic!("not an event") };
+        assert_eq!((event.target.as_str(), event.kind.as_str()), ("room", "leave"));
+        assert_eq!(event.leave, ["cGVlcg", "Z3Vlc3Q"]);
+        assert!(event.join.is_empty());
+    }
+
+    #[test]
+    fn roomlist_disinvite() {
+        let frame = json!({
+            "type": "event",
+            "event": {
+                "target": "roomlist",
+                "type": "disinvite",
+                "disinvite": { "roomid": "abc123", "reason": "deleted", "properties": { "name": "Standup" } },
+            },
+        });
+        let SignalingMessage::Event(event) = parse(frame) else { panic!("not an event") };
+        let disinvite = event.disinvite.unwrap();
+        assert_eq!(disinvite.roomid, "abc123");
+        assert_eq!(disinvite.reason, "deleted");
+    }
+
+    #[test]
+    fn bye() {
+        let frame = json!({ "type": "bye", "bye": { "reason": "session_expired" } });
+        let SignalingMessage::Bye(bye) = parse(frame) else { panic!("not a bye") };
+        assert_eq!(bye.reason.as_deref(), Some("session_expired"));
+
+        // Without a body at all
+        let SignalingMessage::Bye(bye) = parse(json!({ "type": "bye" })) else { panic!("not a bye") };
+        assert!(bye.reason.is_none());
+    }
+
+    #[test]
+    fn unknown_type() {
+        let frame = json!({ "type": "transient", "transient": { "type": "set", "key": "k", "value": 1 } });
+        let SignalingMessage::Unknown { kind, body } = parse(frame) else { panic!("not unknown") };
+        assert_eq!(kind, "transient");
+        assert_eq!(body["key"], "k");
+    }
+
+    #[test]
+    fn malformed_bodies() {
+        let frames = [
+            json!({ "type": "hello", "hello": { "resumeid": "no session id" } }),
+            json!({ "type": "error", "error": { "message": "no code" } }),
+            json!({ "type": "room", "room": { "roomid": 42 } }),
+            json!({ "type": "event", "event": { "type": "update" } }),
+            json!({ "type": "event", "event": { "target": "room", "type": "leave", "leave": "not a list" } }),
+            json!({ "type": "message", "message": "not an object" }),
+        ];
+        for frame in frames {
+            let kind = frame["type"].as_str().unwrap().to_string();
+            let body = frame[&kind].clone();
+            match parse(frame) {
+                SignalingMessage::Unknown { kind: parsed, body: kept } => {
+                    assert_eq!(parsed, kind);
+                    assert_eq!(kept, body);
+                }
+                other => panic!("{} parsed as {:?}", kind, other),
+            }
+        }
+    }
+
+    #[test]
+    fn no_type() {
+        let SignalingMessage::Unknown { kind, .. } = parse(json!({ "id": "1" })) else { panic!("not unknown") };
+        assert!(kind.is_empty());
+    }
+
+    #[test]
+    fn not_json() {
+        assert!(SignalingMessage::parse("{\"type\": \"hello\"").is_err());
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::BTreeMap;
+use webrtc::sdp::description::media::MediaDescription;
+use webrtc::sdp::SessionDescription;
+
+const DIRECTIONS: [&str; 4] = ["sendrecv", "sendonly", "recvonly", "inactive"];
+
+// The parts of an m-line worth comparing across renegotiations
+#[derive(PartialEq)]
+struct MediaSummary {
+    kind: String,
+    direction: String,
+    rejected: bool,
+    // rtpmap encodings without the payload type, which the MCU may renumber
+    codecs: Vec<String>,
+}
+
+fn summarize(media: &MediaDescription) -> MediaSummary {
+    let direction = DIRECTIONS
+        .iter()
+        .find(|d| media.attribute(d).is_some())
+        .unwrap_or(&"sendrecv");
+
+    let codecs = media
+        .attributes
+        .iter()
+        .filter(|a| a.key == "rtpmap")
+        .filter_map(|a| a.value.as_deref())
+        .filter_map(|v| v.split_once(' ').map(|(_, codec)| codec.to_string()))
+        .collect();
+
+    MediaSummary {
+        kind: media.media_name.media.clone(),
+        direction: direction.to_string(),
+        rejected: media.media_name.port.value == 0,
+        codecs,
+    }
+}
+
+// Keyed by mid, falling back to the m-line index
+fn sections(sdp: &SessionDescription) -> BTreeMap<String, MediaSummary> {
+    sdp.media_descriptions
+        .iter()
+        .enumerate()
+        .map(|(i, m)| {
+            let mid = m.attribute("mid").flatten().map(str::to_string).unwrap_or_else(|| format!("#{}", i));
+            (mid, summarize(m))
+        })
+        .collect()
+}
+
+// One line per change: "+" added m-line, "-" removed, "~" changed
+pub fn diff(old: &SessionDescription, new: &SessionDescription) -> Vec<String> {
+    let old = sections(old);
+    let new = sections(new);
+    let mut changes = Vec::new();
+
+    for (mid, before) in &old {
+        if !new.contains_key(mid) {
+            changes.push(format!("- m={} mid={}", before.kind, mid));
+        }
+    }
+
+    for (mid, after) in &new {
+        let Some(before) = old.get(mid) else {
+            changes.push(format!(
+                "+ m={} mid={} {} ({})",
+                after.kind,
+                mid,
+                after.direction,
+                after.codecs.join(", ")
+            ));
+            continue;
+        };
+        if before == after {
+            continue;
+        }
+
+        let prefix = format!("~ m={} mid={}", after.kind, mid);
+        if before.rejected != after.rejected {
+            let state = if after.rejected { "rejected" } else { "accepted" };
+            changes.push(format!("{} {}", prefix, state));
+        }
+        if before.direction != after.direction {
+            changes.push(format!("{} direction {} -> {}", prefix, before.direction, after.direction));
+        }
+        let added: Vec<_> = after.codecs.iter().filter(|c| !before.codecs.contains(c)).collect();
+        let removed: Vec<_> = before.codecs.iter().filter(|c| !after.codecs.contains(c)).collect();
+        if !added.is_empty() || !removed.is_empty() {
+            let mut parts: Vec<String> = added.iter().map(|c| format!("+{}", c)).collect();
+            parts.extend(removed.iter().map(|c| format!("-{}", c)));
+            changes.push(format!("{} codecs {}", prefix, parts.join(" ")));
+        } else if before.codecs != after.codecs {
+            changes.push(format!("{} codec order {}", prefix, after.codecs.join(", ")));
+        }
+    }
+
+    changes
+}
@@ -1,74 +1,473 @@
 use anyhow::{Context, Result};
+use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{SinkExt, StreamExt};
-use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use reqwest::{RequestBuilder, Response, StatusCode};
 use tokio::net::TcpStream;
-use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use tokio::sync::{mpsc, oneshot, Mutex, Notify};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::{connect_async_tls_with_config, MaybeTlsStream, WebSocketStream};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
 use tokio_tungstenite::tungstenite::protocol::Message;
 use url::Url;
+use webrtc::ice_transport::ice_server::RTCIceServer;
+
+use super::auth::BearerToken;
+use super::capabilities::ApiVersions;
+use super::internal::{self, InternalSignaling, TalkSession};
+use super::protocol::{Hello, SignalingError, SignalingMessage};
+use super::tls::Tls;
+use super::webrtc::IceServers;
+use crate::config::{IceConfig, IceTransportPolicy, IceTrickle, OutboxConfig, OutboxOverflow, ReconnectConfig};
+use crate::update;
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub nextcloud_url: String,
     pub username: String,
-    pub password: String, // Or token
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(tag = "type", rename_all = "lowercase")]
-pub enum SignalingMessage {
-    Hello {
-        version: String,
-        checksum: String,
-    },
-    Join {
-        #[serde(rename = "roomType")]
-        room_type: String,
-        #[serde(rename = "roomToken")]
-        room_token: String,
-        #[serde(rename = "participantToken")]
-        participant_token: String,
-    },
-    Joined {
-        #[serde(rename = "roomType")]
-        room_type: String,
-        quit: bool,
-    },
-    Message {
-        data: Value,
-    },
-    Bye,
+    pub password: String, // Or an app password
+    // Instead of the password: an OAuth2 access token
+    pub bearer: Option<Arc<BearerToken>>,
+    // Silence on the WebSocket after which it is given up
+    pub timeout: Duration,
+    pub outbox: OutboxConfig,
+    pub reconnect: ReconnectConfig,
+    // Certificates for Nextcloud and the signaling server
+    pub tls: Tls,
+    // More signaling servers of the same cluster, tried in order when the
+    // one Talk names can't be reached
+    pub servers: Vec<String>,
+    // STUN/TURN servers of our own and the ICE transport policy
+    pub ice: IceConfig,
+    pub api: ApiVersions,
+    // Shown in Talk for the bridge instead of the account's display name
+    pub nick: Option<String>,
+    // For a public room with a password that the bridge user isn't a
+    // member of
+    pub room_password: Option<String>,
 }
 
+impl Config {
+    // Without a login the bridge joins public rooms as a guest
+    pub fn is_guest(&self) -> bool {
+        self.username.is_empty() && self.bearer.is_none()
+    }
+
+    // Guests are only known by their session cookie
+    fn authenticate(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.bearer {
+            Some(bearer) => request.bearer_auth(bearer.access()),
+            None if self.is_guest() => request,
+            None => request.basic_auth(&self.username, Some(&self.password)),
+        }
+    }
+
+    // Sends an OCS request with the credentials. An expired access token is
+    // renewed and the request sent once more.
+    pub async fn send(&self, request: RequestBuilder) -> reqwest::Result<Response> {
+        let retry = request.try_clone();
+        let used = self.bearer.as_ref().map(|b| b.access());
+        let resp = self.authenticate(request).send().await?;
+        let (Some(bearer), Some(used), Some(retry)) = (&self.bearer, used, retry) else {
+            return Ok(resp);
+        };
+        if resp.status() != StatusCode::UNAUTHORIZED {
+            return Ok(resp);
+        }
+        match bearer.renew(&self.tls.http, &self.nextcloud_url, &used).await {
+            Ok(true) => self.authenticate(retry).send().await,
+            Ok(false) => Ok(resp),
+            Err(e) => {
+                log!("{:#}", e);
+                Ok(resp)
+            }
+        }
+    }
+}
+
+// Echoed back by the server, so the reply can be told apart
+const HELLO_ID: &str = "hello";
+
+// Frames waiting for whoever reads the signaling; the reader task waits
+// when that falls behind
+const INCOMING: usize = 256;
+
+// For the close handshake after a bye
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+// What the reader tasks hand over: a frame, or Ok(None) or an error once
+// the connection is gone, which resume() may bring back
+pub type Incoming = Result<Option<SignalingMessage>>;
+
+// From Talk's signaling settings for a room
+struct SignalingSettings {
+    // The signaling servers' WebSocket endpoints, Talk's first; none with
+    // internal signaling
+    urls: Vec<Url>,
+    // Where the signaling server checks our hello against Talk
+    backend: String,
+    // helloAuthParams per hello version; 2.0 only when Talk can sign tokens
+    hello_v1: Option<Value>,
+    hello_v2: Option<Value>,
+    ticket: String,
+    ice_servers: Vec<RTCIceServer>,
+    // For a room hosted on another Nextcloud, its id there
+    federated_room: Option<String>,
+}
 
+enum HelloReply {
+    Accepted(Hello),
+    Rejected(SignalingError),
+}
+
+// The connection is read and written by tasks of its own, so sending never
+// waits for a frame to come in and the other way round. Sends go through a
+// SignalingSender; frames come out of incoming(), whose reader holds its
+// lock while reading. Both outlive a connection: what is sent while it is
+// down waits in the outbox until resume() starts a new writer.
 pub struct SignalingClient {
     config: Config,
-    socket: Option<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+    room_token: Option<String>,
+    // Our session as the signaling server knows it, from its hello
+    session_id: Option<String>,
+    // What the signaling server announced it supports, e.g. "mcu"
+    features: Vec<String>,
+    // Where we connected and how, for resuming
+    url: Option<Url>,
+    hello_version: &'static str,
+    resume_id: Option<String>,
+    // STUN and TURN servers configured in Talk, with credentials
+    ice_servers: Vec<RTCIceServer>,
+    // Only while connecting: the hello and join are answered on the socket
+    // itself, before it is split between the tasks
+    socket: Option<Socket>,
+    link: Option<Link>,
+    outbox: Arc<Outbox>,
+    incoming_tx: mpsc::Sender<Incoming>,
+    incoming: Arc<Mutex<mpsc::Receiver<Incoming>>>,
+    // Used instead of the socket when Talk has no signaling server
+    internal: Option<InternalSignaling>,
+    // Joined over OCS: with internal signaling, and always as a guest
+    talk_session: Option<TalkSession>,
+}
+
+// The reader and writer tasks of one connection: the WebSocket's, or the
+// poller and sender of internal signaling
+struct Link {
+    reader: JoinHandle<()>,
+    writer: JoinHandle<()>,
+    stop: oneshot::Sender<()>,
+}
+
+// Messages waiting for a writer. When full, it makes room as configured;
+// what it drops is counted and reported on resume.
+pub struct Outbox {
+    queue: std::sync::Mutex<Queue>,
+    ready: Notify,
+    config: OutboxConfig,
+}
+
+struct Queue {
+    messages: VecDeque<Value>,
+    dropped: usize,
+}
+
+// Sends to the signaling server without waiting for anything; cheap to
+// clone and usable while the connection is down
+#[derive(Clone)]
+pub struct SignalingSender(Arc<Outbox>);
+
+// The other end of a call message: a participant's session, or with an MCU
+// the publisher's session it relays for. `sid` is the stream id of the
+// offer being answered, which the MCU matches answers and candidates by.
+#[derive(Debug, Clone)]
+pub struct CallTarget {
+    pub session: String,
+    pub sid: Option<String>,
+}
+
+// Who the signaling server routes a message to
+enum Recipient<'a> {
+    Session(&'a str),
+    Room,
+}
+
+impl Recipient<'_> {
+    fn to_value(&self) -> Value {
+        match self {
+            Self::Session(session) => serde_json::json!({ "type": "session", "sessionid": session }),
+            Self::Room => serde_json::json!({ "type": "room" }),
+        }
+    }
 }
 
 impl SignalingClient {
     pub fn new(config: Config) -> Self {
-        Self { config, socket: None }
+        let outbox = Arc::new(Outbox {
+            queue: std::sync::Mutex::new(Queue { messages: VecDeque::new(), dropped: 0 }),
+            ready: Notify::new(),
+            config: config.outbox,
+        });
+        let (incoming_tx, incoming) = mpsc::channel(INCOMING);
+        Self {
+            config,
+            room_token: None,
+            session_id: None,
+            features: Vec::new(),
+            url: None,
+            hello_version: "2.0",
+            resume_id: None,
+            ice_servers: Vec::new(),
+            socket: None,
+            link: None,
+            outbox,
+            incoming_tx,
+            incoming: Arc::new(Mutex::new(incoming)),
+            internal: None,
+            talk_session: None,
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    // The room joined by the last successful connect
+    pub fn room_token(&self) -> Option<&str> {
+        self.room_token.as_deref()
+    }
+
+    pub fn session_id(&self) -> Option<&str> {
+        self.session_id.as_deref()
+    }
+
+    // For the peer connections of the room joined by the last connect: the
+    // configured servers, then Talk's. No servers if neither has any.
+    pub fn ice_servers(&self) -> IceServers {
+        let configured = self.config.ice.servers.iter().map(|server| RTCIceServer {
+            urls: vec![server.url.clone()],
+            username: server.username.clone(),
+            credential: server.credential.clone(),
+            ..Default::default()
+        });
+        IceServers {
+            servers: configured.chain(self.ice_servers.iter().cloned()).collect(),
+            relay_only: self.config.ice.policy == IceTransportPolicy::Relay,
+            gathering: self.config.ice.gathering.clone(),
+            trickle: match self.config.ice.trickle {
+                IceTrickle::Auto => self.internal.is_none(),
+                IceTrickle::On => true,
+                IceTrickle::Off => false,
+            },
+            expires: turn_expiry(&self.ice_servers),
+        }
+    }
+
+    // Fetches Talk's TURN credentials again, for the connections made from
+    // now on; those it hands out only last so long
+    pub async fn refresh_ice_servers(&mut self) -> Result<()> {
+        let room_token = self.room_token.clone().context("Not in a Talk room")?;
+        self.ice_servers = self.settings(&room_token).await?.ice_servers;
+        Ok(())
+    }
+
+    // Without an MCU (Janus) behind the signaling server, participants
+    // exchange media directly with each other
+    pub fn has_mcu(&self) -> bool {
+        self.features.iter().any(|f| f == "mcu")
+    }
+
+    pub fn sender(&self) -> SignalingSender {
+        SignalingSender(self.outbox.clone())
+    }
+
+    // Frames from the server across connections and rooms
+    pub fn incoming(&self) -> Arc<Mutex<mpsc::Receiver<Incoming>>> {
+        self.incoming.clone()
+    }
+
+    // With a Talk session joined over OCS, what Talk's REST calls for this
+    // room need to send to count as the same session
+    pub fn session_cookie(&self) -> Option<String> {
+        self.talk_session.as_ref().map(|session| session.cookie.clone())
     }
 
     pub async fn connect(&mut self, room_token: &str) -> Result<()> {
+        // Signaling, internal or not, joins with the Talk session of the
+        // room; joining it is also where the room's password goes
+        self.talk_session = None;
+        let session = internal::join_room(&self.config, room_token).await?;
+        self.talk_session = Some(session.clone());
+        let settings = self.settings(room_token).await?;
+        self.ice_servers = settings.ice_servers.clone();
+
+        if settings.urls.is_empty() {
+            log!("No High Performance Backend configured, using Talk's internal signaling");
+            self.session_id = Some(session.session_id.clone());
+            let internal = InternalSignaling::new(&self.config, room_token, session.clone())?;
+            self.talk_session = Some(session);
+            let (stop, stopped) = oneshot::channel();
+            self.link = Some(Link {
+                reader: internal.poll(self.incoming_tx.clone()),
+                writer: internal.write(self.outbox.clone(), stopped),
+                stop,
+            });
+            self.features.clear();
+            self.internal = Some(internal);
+            self.room_token = Some(room_token.to_string());
+            return Ok(());
+        }
+        self.open_any(&settings.urls).await?;
+
+        self.hello(&settings).await?;
+        self.join(room_token, &session, &settings).await?;
+        self.room_token = Some(room_token.to_string());
+        self.split()?;
+        self.announce_nick();
+        Ok(())
+    }
+
+    // Tells everyone in the room the bridge's name. Those joining later
+    // don't hear earlier ones, so it is said again for them.
+    pub fn announce_nick(&self) {
+        if let Some(nick) = &self.config.nick {
+            if let Err(e) = self.sender().send_nick(nick, &self.config.username) {
+                log!("Failed to send the bridge's Talk name: {:#}", e);
+            }
+        }
+    }
+
+    // Hands the socket over to a reader and a writer task
+    fn split(&mut self) -> Result<()> {
+        let socket = self.socket.take().context("Not connected")?;
+        let (sink, stream) = socket.split();
+        let (stop, stopped) = oneshot::channel();
+        let ping = self.config.timeout / 3;
+        self.link = Some(Link {
+            reader: tokio::spawn(read(stream, self.incoming_tx.clone(), self.config.timeout)),
+            writer: tokio::spawn(write(sink, self.outbox.clone(), ping, stopped)),
+            stop,
+        });
+        Ok(())
+    }
+
+    // Stops the tasks of the current connection; the writer gets out what
+    // it can first
+    async fn unlink(&mut self) {
+        let Some(link) = self.link.take() else {
+            return;
+        };
+        link.reader.abort();
+        let _ = link.stop.send(());
+        if let Err(e) = link.writer.await {
+            log!("Signaling writer task failed: {}", e);
+        }
+    }
+
+    // Tries the servers in turn until one takes the connection
+    async fn open_any(&mut self, urls: &[Url]) -> Result<()> {
+        let mut failed = None;
+        for url in urls {
+            log!("Connecting to Signaling Server: {}", url);
+            match self.open(url).await {
+                Ok(()) => {
+                    self.url = Some(url.clone());
+                    return Ok(());
+                }
+                Err(e) => {
+                    log!("Signaling server {} unreachable: {:#}", url, e);
+                    failed = Some(e);
+                }
+            }
+        }
+        Err(failed.unwrap_or_else(|| anyhow::anyhow!("No signaling server to connect to")))
+    }
+
+    async fn open(&mut self, url: &Url) -> Result<()> {
+        // The signaling server logs the user agent of each session
+        let mut request = url.as_str().into_client_request().context("Invalid signaling URL")?;
+        request
+            .headers_mut()
+            .insert("User-Agent", HeaderValue::from_str(&update::user_agent())?);
+        let (ws_stream, _) = connect_async_tls_with_config(request, None, false, self.config.tls.connector())
+            .await
+            .context("Failed to connect to Signaling WebSocket")?;
+
+        log!("WebSocket connected!");
+        self.socket = Some(ws_stream);
+        Ok(())
+    }
+
+    // Reconnects after the WebSocket dropped and takes the session over
+    // again, still in the room and the call, then sends what was queued in
+    // the meantime. Fails if the server has already expired the session;
+    // only a full connect helps then.
+    pub async fn resume(&mut self) -> Result<()> {
+        let url = self.url.clone().context("Never connected")?;
+        let resume_id = self.resume_id.clone().context("Signaling server gave no resume id")?;
+        self.unlink().await;
+        self.open(&url).await?;
+
+        let hello = serde_json::json!({
+            "id": HELLO_ID,
+            "type": "hello",
+            "hello": { "version": self.hello_version, "resumeid": resume_id },
+        });
+        let socket = self.socket.as_mut().context("Not connected")?;
+        socket.send(Message::Text(hello.to_string())).await?;
+        match self.hello_reply().await? {
+            HelloReply::Accepted(hello) => {
+                if self.session_id.as_deref() != Some(hello.sessionid.as_str()) {
+                    log!("Resumed signaling with a different session {}", hello.sessionid);
+                }
+                self.accept_hello(self.hello_version, hello);
+            }
+            HelloReply::Rejected(error) => {
+                self.resume_id = None;
+                return Err(anyhow::Error::new(error).context("Signaling resume rejected"));
+            }
+        }
+
+        let (queued, dropped) = self.outbox.backlog();
+        if dropped > 0 {
+            log!("Signaling session resumed, replaying {} queued messages ({} dropped, outbox full)", queued, dropped);
+        } else {
+            log!("Signaling session resumed, replaying {} queued messages", queued);
+        }
+        self.split()
+    }
+
+    // Where the High Performance Backend is, if there is one, and what to
+    // authenticate with, from Talk's signaling settings for the room
+    async fn settings(&self, room_token: &str) -> Result<SignalingSettings> {
         let base_url = Url::parse(&self.config.nextcloud_url)
             .context("Invalid Nextcloud URL")?;
+        let mut api_url =
+            base_url.join(&format!("/ocs/v2.php/apps/spreed/api/v{}/signaling/settings", self.config.api.signaling))?;
+        api_url.query_pairs_mut().append_pair("token", room_token);
 
-        // 1. Call Nextcloud API to get Signaling credentials
-        // Endpoint: /ocs/v2.php/apps/spreed/api/v4/room/{token}
-        // Note: For public rooms, authentication might be optional or handled differently.
-        // For now assuming logged-in user or at least valid credentials provided in Config.
-        let api_url = base_url.join(&format!("/ocs/v2.php/apps/spreed/api/v4/room/{}", room_token))?;
-
-        println!("Fetching room details from: {}", api_url);
+        log!("Fetching signaling settings from: {}", api_url);
 
-        let client = reqwest::Client::new();
-        let resp = client.get(api_url.clone())
-            .basic_auth(&self.config.username, Some(&self.config.password))
+        let mut request = self
+            .config
+            .tls
+            .http
+            .get(api_url.clone())
             .header("OCS-APIRequest", "true")
             .header("Accept", "application/json")
-            .send()
+            .header("User-Agent", update::user_agent());
+        if let Some(cookie) = self.session_cookie() {
+            request = request.header("Cookie", cookie);
+        }
+        let resp = self
+            .config
+            .send(request)
             .await
             .context("Failed to send request to Nextcloud")?;
 
@@ -76,140 +475,521 @@ impl SignalingClient {
             anyhow::bail!("Nextcloud API returned error: {}", resp.status());
         }
 
-        let body: serde_json::Value = resp.json().await.context("Failed to parse Nextcloud response")?;
-
-        // 2. Extract Signaling settings
-        // Expected structure: ocs.data.signaling.url and ocs.data.signaling.ticket
-        let signaling = body.get("ocs")
+        let body: Value = resp.json().await.context("Failed to parse Nextcloud response")?;
+        let data = body.get("ocs")
             .and_then(|o| o.get("data"))
-            .and_then(|d| d.get("signaling"))
-            .context("No signaling info found in response")?;
-
-        // Handling both internal signaling (no dedicated URL usually, just standard repeated poll)
-        // OR High Performance Backend (HPB) which gives a URL.
-        // This implementation focuses on HPB (WebSocket).
-
-        let ws_url_str = signaling.get("url").and_then(|v| v.as_str())
-            .context("No signaling URL found (is High Performance Backend enabled?)")?;
-
-        let ticket = signaling.get("ticket").and_then(|v| v.as_str())
-             .context("No signaling ticket found")?;
-
-        println!("Connecting to Signaling Server: {}", ws_url_str);
-
-        let (ws_stream, _) = connect_async(ws_url_str).await
-            .context("Failed to connect to Signaling WebSocket")?;
+            .context("No signaling settings found in response")?;
+
+        // Internal signaling (polling Nextcloud itself) has no server URL,
+        // and then the configured ones aren't trusted by Talk either
+        let server = data.get("server").and_then(Value::as_str).filter(|s| !s.is_empty());
+        let mut urls = Vec::new();
+        if let Some(server) = server {
+            for server in std::iter::once(server).chain(self.config.servers.iter().map(String::as_str)) {
+                let url = websocket_url(server).with_context(|| format!("Invalid signaling server URL {}", server))?;
+                if !urls.contains(&url) {
+                    urls.push(url);
+                }
+            }
+        }
 
-        println!("WebSocket connected!");
-        self.socket = Some(ws_stream);
+        let ticket = data.get("ticket").and_then(Value::as_str)
+            .context("No signaling ticket found")?
+            .to_string();
+
+        // A federated room's call is held on the Nextcloud hosting it: the
+        // bridge talks to that one's signaling server, with a token from
+        // ours that it checks back with
+        if let Some(federation) = data.get("federation").filter(|f| f.is_object()) {
+            let field = |key: &str| federation.get(key).and_then(Value::as_str).filter(|v| !v.is_empty());
+            let server = field("server").context("No signaling server for the federated room")?;
+            let remote = field("nextcloudServer").context("No host for the federated room")?;
+            let room_id = field("roomId").context("No remote id for the federated room")?;
+            log!("Talk room {} is hosted on {} as room {}", room_token, remote, room_id);
+            return Ok(SignalingSettings {
+                urls: vec![websocket_url(server).context("Invalid signaling server URL")?],
+                // Federation came with signaling v3
+                backend: backend_url(&Url::parse(remote).context("Invalid federated Nextcloud URL")?, 3)?,
+                hello_v1: None,
+                hello_v2: Some(federation.get("helloAuthParams").cloned().context("No federation token")?),
+                ticket,
+                ice_servers: ice_servers(data),
+                federated_room: Some(room_id.to_string()),
+            });
+        }
 
-        // 3. Handshake
-        // First message should be Hello from server? Or we wait for it.
-        // Usually: Client connects.
-        // Server sends: {"type":"hello", ...}
-        // Client sends: {"type":"hello", ...} (optional/dependent on version)
-        // Client sends: {"type":"join", ...}
+        let params = data.get("helloAuthParams");
+        Ok(SignalingSettings {
+            urls,
+            backend: backend_url(&base_url, self.config.api.signaling)?,
+            hello_v1: params.and_then(|p| p.get("1.0")).cloned(),
+            hello_v2: params.and_then(|p| p.get("2.0")).cloned(),
+            ticket,
+            ice_servers: ice_servers(data),
+            federated_room: None,
+        })
+    }
 
-        // Let's implement a simple loop outside or helper here to authenticate
-        self.authenticate(room_token, ticket).await?;
+    // Authenticates the connection. Hello v2 carries a JWT signed by Talk;
+    // servers that don't know it yet get v1 with the user id and ticket.
+    // Federated rooms only have v2.
+    async fn hello(&mut self, settings: &SignalingSettings) -> Result<()> {
+        let v1 = settings.hello_v1.clone().unwrap_or_else(|| {
+            // Guests have no user id
+            let userid = (!self.config.is_guest()).then_some(&self.config.username);
+            serde_json::json!({ "userid": userid, "ticket": settings.ticket })
+        });
+        let federated = settings.federated_room.is_some();
+        let mut versions: Vec<(&'static str, Value)> = settings
+            .hello_v2
+            .iter()
+            .map(|params| ("2.0", params.clone()))
+            .chain((!federated).then_some(("1.0", v1)))
+            .collect();
+
+        loop {
+            let (version, params) = versions.remove(0);
+            let mut auth = serde_json::json!({ "url": settings.backend, "params": params });
+            if federated {
+                auth["type"] = "federation".into();
+            }
+            let hello = serde_json::json!({
+                "id": HELLO_ID,
+                "type": "hello",
+                "hello": { "version": version, "auth": auth },
+            });
+            let socket = self.socket.as_mut().context("Not connected")?;
+            socket.send(Message::Text(hello.to_string())).await?;
+
+            match self.hello_reply().await? {
+                HelloReply::Accepted(hello) => {
+                    // An older server may accept a hello it doesn't speak
+                    if let Some(answered) = hello.version.as_deref().filter(|v| *v != version) {
+                        anyhow::bail!(
+                            "Signaling server answered hello {} with version {}; it is too old or too new for the bridge",
+                            version,
+                            answered
+                        );
+                    }
+                    // The server vouches for who we are only after Talk
+                    // confirmed it; guests stay without a user
+                    if !self.config.is_guest() && hello.userid.as_deref().is_none_or(str::is_empty) {
+                        anyhow::bail!(
+                            "Signaling server accepted hello {} without a user; it couldn't confirm the bridge's login with {}",
+                            version,
+                            settings.backend
+                        );
+                    }
+                    log!(
+                        "Signaling hello {} accepted: session {}, server {} ({})",
+                        version,
+                        hello.sessionid,
+                        hello.server.version.as_deref().unwrap_or("unknown version"),
+                        hello.server.features.join(", ")
+                    );
+                    self.accept_hello(version, hello);
+                    return Ok(());
+                }
+                HelloReply::Rejected(error) if error.code == "invalid_hello_version" && !versions.is_empty() => {
+                    log!("Signaling server doesn't support hello {}, trying {}", version, versions[0].0);
+                }
+                HelloReply::Rejected(error) => return Err(rejected("Signaling hello rejected", error, &settings.backend)),
+            }
+        }
+    }
 
-        Ok(())
+    fn accept_hello(&mut self, version: &'static str, hello: Hello) {
+        self.hello_version = version;
+        self.session_id = Some(hello.sessionid);
+        self.resume_id = hello.resumeid;
+        self.features = hello.server.features;
     }
 
-    async fn authenticate(&mut self, room_token: &str, ticket: &str) -> Result<()> {
+    // Skips the server's welcome, which may arrive before the answer
+    async fn hello_reply(&mut self) -> Result<HelloReply> {
         let socket = self.socket.as_mut().context("Not connected")?;
-
-        // Read Hello
-        if let Some(msg) = socket.next().await {
-            let msg = msg?;
-            if let Message::Text(text) = msg {
-                println!("Received: {}", text);
-                 // TODO: Validate Hello
+        loop {
+            let msg = socket
+                .next()
+                .await
+                .context("Signaling connection closed during hello")??;
+            let Message::Text(text) = msg else {
+                continue;
+            };
+            match SignalingMessage::parse(&text).context("Invalid message during hello")? {
+                SignalingMessage::Welcome(welcome) => {
+                    log!("Signaling server {}", welcome.version.as_deref().unwrap_or("(no version)"));
+                }
+                SignalingMessage::Hello(hello) => return Ok(HelloReply::Accepted(hello)),
+                SignalingMessage::Error(error) => {
+                    return Ok(HelloReply::Rejected(error));
+                }
+                _ => continue,
             }
         }
+    }
+
+    // Takes the signaling session into the room, tied to the Talk session
+    // that joined it over OCS, and waits for the server to confirm; whatever
+    // arrives before that goes to the incoming channel
+    async fn join(&mut self, room_token: &str, session: &TalkSession, settings: &SignalingSettings) -> Result<()> {
+        let socket = self.socket.as_mut().context("Not connected")?;
+        // The remote server knows the room by its own id
+        let room_token = settings.federated_room.as_deref().unwrap_or(room_token);
 
-        // Send Join
         let join_msg = serde_json::json!({
-            "type": "join",
-            "roomType": "room", // token is for a room
-            "roomToken": room_token,
-             "participantToken": ticket,
+            "type": "room",
+            "room": { "roomid": room_token, "sessionid": session.session_id },
         });
-
         socket.send(Message::Text(join_msg.to_string())).await?;
-        println!("Sent Join request");
-
-        // Wait for Joined
-         if let Some(msg) = socket.next().await {
-            let msg = msg?;
-            if let Message::Text(text) = msg {
-                 println!("Received after join: {}", text);
-                 // Expect "joined"
+        log!("Sent Join request");
+        log!("Sent Join request");
+
+        loop {
+            let msg = socket
+                .next()
+                .await
+                .context("Signaling connection closed during join")??;
+            let Message::Text(text) = msg else {
+                continue;
+            };
+            match SignalingMessage::parse(&text).context("Invalid message during join")? {
+                SignalingMessage::Room(room) if room.roomid == room_token => {
+                    log!("Joined signaling room {}", room.roomid);
+                    return Ok(());
+                }
+                SignalingMessage::Room(room) => {
+                    anyhow::bail!("Signaling server put us in room {:?} instead of {}", room.roomid, room_token)
+                }
+                SignalingMessage::Error(error) => return Err(rejected("Signaling join rejected", error, &settings.backend)),
+                // Nobody reads these before the session is set up; a room
+                // busy enough to fill the channel by then loses the rest
+                other => {
+                    if self.incoming_tx.try_send(Ok(Some(other))).is_err() {
+                        log!("Too many signaling frames during join, dropping one");
+                    }
+                }
             }
         }
+    }
 
-        Ok(())
+    // Ends the session for good, so the signaling server tells the room
+    // we left instead of waiting for the session to time out. Internal
+    // signaling just stops polling; leaving the room over OCS ends it.
+    pub async fn bye(&mut self) {
+        self.resume_id = None;
+        let internal = self.internal.take().is_some();
+        if self.link.is_some() && !internal {
+            // The writer sends what is queued before it stops
+            let _ = self.outbox.push(serde_json::json!({ "type": "bye", "bye": {} }));
+        }
+        self.unlink().await;
     }
 
-    pub async fn next_message(&mut self) -> Result<Option<SignalingMessage>> {
-        let socket = self.socket.as_mut().context("Not connected")?;
+    // Leaves the current room for another one without giving up the
+    // channels, so whoever sends and reads carries on. The session is
+    // ended and a new one started in the new room; Talk may even serve it
+    // from another signaling server. What was still queued for the old
+    // room is dropped.
+    pub async fn switch_room(&mut self, room_token: &str) -> Result<()> {
+        log!("Switching signaling from room {} to {}", self.room_token.as_deref().unwrap_or("(none)"), room_token);
+        self.bye().await;
+        self.outbox.clear();
+        if let Ok(mut incoming) = self.incoming.try_lock() {
+            while incoming.try_recv().is_ok() {}
+        }
+        self.room_token = None;
+        self.session_id = None;
+        self.talk_session = None;
+        self.features.clear();
+        self.url = None;
+        self.hello_version = "2.0";
+        self.connect(room_token).await
+    }
 
-        while let Some(msg) = socket.next().await {
-            let msg = msg?;
-            match msg {
-                Message::Text(text) => {
-                    // println!("Raw Message: {}", text); // Debug
-                    match serde_json::from_str::<SignalingMessage>(&text) {
-                        Ok(parsed) => return Ok(Some(parsed)),
-                        Err(e) => {
-                             println!("Failed to parse message: {}. Error: {}", text, e);
-                             continue;
-                        }
-                    }
+    // Whether a dropped connection can be picked up again with resume()
+    pub fn can_resume(&self) -> bool {
+        self.resume_id.is_some()
+    }
+}
+
+impl Drop for SignalingClient {
+    fn drop(&mut self) {
+        if let Some(link) = &self.link {
+            link.reader.abort();
+            link.writer.abort();
+        }
+    }
+}
+
+impl Outbox {
+    // Fails only when full with the fail policy
+    fn push(&self, payload: Value) -> Result<()> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.messages.len() >= self.config.capacity {
+            if queue.dropped == 0 {
+                log!(
+                    "Signaling outbox full ({} messages), overflow policy {:?}",
+                    self.config.capacity,
+                    self.config.overflow
+                );
+            }
+            match self.config.overflow {
+                OutboxOverflow::DropOldest => {
+                    queue.messages.pop_front();
+                }
+                OutboxOverflow::DropNewest => {
+                    queue.dropped += 1;
+                    return Ok(());
+                }
+                OutboxOverflow::Fail => {
+                    anyhow::bail!("Signaling outbox full ({} messages)", self.config.capacity)
                 }
-                Message::Close(_) => return Ok(None),
-                _ => continue,
             }
+            queue.dropped += 1;
         }
-        Ok(None)
+        queue.messages.push_back(payload);
+        drop(queue);
+        self.ready.notify_one();
+        Ok(())
     }
 
-    pub async fn send_sdp(&mut self, sdp_type: &str, sdp: String, recipient: String) -> Result<()> {
-        let socket = self.socket.as_mut().context("Not connected")?;
+    // What a failed write didn't get out goes first next time
+    pub fn retry(&self, payload: Value) {
+        self.queue.lock().unwrap().messages.push_front(payload);
+        self.ready.notify_one();
+    }
+
+    // Waits for the next message; nothing is lost when this is cancelled
+    pub async fn next(&self) -> Value {
+        loop {
+            let next = self.queue.lock().unwrap().messages.pop_front();
+            if let Some(payload) = next {
+                return payload;
+            }
+            self.ready.notified().await;
+        }
+    }
+
+    // How many are queued, and how many were dropped since last asked
+    fn backlog(&self) -> (usize, usize) {
+        let mut queue = self.queue.lock().unwrap();
+        (queue.messages.len(), std::mem::take(&mut queue.dropped))
+    }
+
+    fn clear(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.messages.clear();
+        queue.dropped = 0;
+    }
+}
 
-        // Structure for sending messages in Nextcloud Talk Signaling
-        // { "type": "message", "data": { "type": "offer", "sdp": "...", "roomToken": "..." } }
-        // Note: The recipient handling might depend on if it's p2p or mcu.
-        // For HPB (MCU), we usually send to the server.
+impl SignalingSender {
+    // While the connection is down, messages wait in the outbox for resume()
+    fn send(&self, payload: Value) -> Result<()> {
+        self.0.push(payload)
+    }
 
-        let payload = serde_json::json!({
+    // A message in the envelope the server routes by, with the data in
+    // the format Talk's clients exchange
+    fn message(&self, recipient: Recipient, data: Value) -> Result<()> {
+        self.send(serde_json::json!({
             "type": "message",
-            "data": {
-                "type": sdp_type, // "offer" or "answer"
-                "sdp": sdp,
-                "recipient": recipient
-            }
+            "message": { "recipient": recipient.to_value(), "data": data },
+        }))
+    }
+
+    // An offer, answer or candidate for one connection
+    fn call(&self, to: &CallTarget, kind: &str, payload: Value) -> Result<()> {
+        let mut data = serde_json::json!({
+            "to": to.session,
+            "type": kind,
+            "roomType": "video",
+            "payload": payload,
         });
+        if let Some(sid) = &to.sid {
+            data["sid"] = sid.clone().into();
+        }
+        self.message(Recipient::Session(&to.session), data)
+    }
 
-        socket.send(Message::Text(payload.to_string())).await?;
-        Ok(())
+    // sdp_type is "offer" or "answer"
+    pub fn send_sdp(&self, sdp_type: &str, sdp: String, to: &CallTarget) -> Result<()> {
+        self.call(to, sdp_type, serde_json::json!({ "type": sdp_type, "sdp": sdp }))
     }
 
-    pub async fn send_candidate(&mut self, candidate: String, sdp_mid: String, sdp_mline_index: u16, recipient: String) -> Result<()> {
-         let socket = self.socket.as_mut().context("Not connected")?;
+    // Asks the MCU for a subscriber offer for a publisher's audio; it comes
+    // in as an offer from the publisher's session
+    pub fn request_offer(&self, publisher: &str) -> Result<()> {
+        self.message(
+            Recipient::Session(publisher),
+            serde_json::json!({ "type": "requestoffer", "roomType": "video" }),
+        )
+    }
 
-         let payload = serde_json::json!({
-            "type": "message",
-            "data": {
-                "type": "candidate",
-                "candidate": candidate,
-                "sdpMid": sdp_mid,
-                "sdpMLineIndex": sdp_mline_index,
-                 "recipient": recipient
-            }
+    // To everyone in the room, as Talk's clients do when their name changes
+    pub fn send_nick(&self, name: &str, user_id: &str) -> Result<()> {
+        self.message(
+            Recipient::Room,
+            serde_json::json!({
+                "type": "nickChanged",
+                "roomType": "video",
+                "payload": { "name": name, "userid": user_id },
+            }),
+        )
+    }
+
+    // Whether the bridge's audio is muted, to everyone in the room
+    pub fn send_mute(&self, muted: bool) -> Result<()> {
+        let kind = if muted { "mute" } else { "unmute" };
+        self.message(
+            Recipient::Room,
+            serde_json::json!({ "type": kind, "roomType": "video", "payload": { "name": "audio" } }),
+        )
+    }
+
+    pub fn send_candidate(&self, candidate: String, sdp_mid: String, sdp_mline_index: u16, to: &CallTarget) -> Result<()> {
+        let candidate = serde_json::json!({
+            "candidate": candidate,
+            "sdpMid": sdp_mid,
+            "sdpMLineIndex": sdp_mline_index,
         });
+        self.call(to, "candidate", serde_json::json!({ "candidate": candidate }))
+    }
+}
 
-        socket.send(Message::Text(payload.to_string())).await?;
-        Ok(())
+// Where Talk answers what signaling servers ask about sessions and rooms
+fn backend_url(nextcloud: &Url, version: u8) -> Result<String> {
+    Ok(nextcloud
+        .join(&format!("/ocs/v2.php/apps/spreed/api/v{}/signaling/backend", version))?
+        .to_string())
+}
+
+// Talk names the server's base URL; clients connect to its /spreed
+// WebSocket
+fn websocket_url(server: &str) -> Result<Url> {
+    let mut url = Url::parse(server)?;
+    let scheme = if url.scheme() == "https" || url.scheme() == "wss" { "wss" } else { "ws" };
+    url.set_scheme(scheme).map_err(|_| anyhow::anyhow!("Can't connect to a {} URL", url.scheme()))?;
+    let path = format!("{}/spreed", url.path().trim_end_matches('/'));
+    url.set_path(&path);
+    Ok(url)
+}
+
+// The server's error with what it means when the signaling server and
+// Nextcloud don't trust each other; its own messages rarely say
+fn rejected(what: &str, error: SignalingError, backend: &str) -> anyhow::Error {
+    let hint = match error.code.as_str() {
+        "invalid_backend" => format!(
+            "the signaling server has no backend for {}; add this Nextcloud to its [backend] settings",
+            backend
+        ),
+        "invalid_token" | "token_not_valid_yet" | "token_expired" => format!(
+            "the signaling server couldn't verify Talk's hello token; check that it can reach {} and that both clocks are right",
+            backend
+        ),
+        "auth_failed" | "room_join_failed" => format!(
+            "{} refused the signaling server; check that Talk's signaling secret matches the server's backend secret",
+            backend
+        ),
+        _ => return anyhow::Error::new(error).context(what.to_string()),
+    };
+    anyhow::Error::new(error).context(format!("{}; {}", what, hint))
+}
+
+// Hands the server's frames over until the connection ends or goes quiet
+// for `timeout`; the writer's pings make sure a live one never does
+async fn read(mut stream: SplitStream<Socket>, incoming: mpsc::Sender<Incoming>, timeout: Duration) {
+    let end = loop {
+        let msg = match tokio::time::timeout(timeout, stream.next()).await {
+            Err(_) => break Err(anyhow::anyhow!("Nothing from the signaling server for {}s", timeout.as_secs())),
+            Ok(None) | Ok(Some(Ok(Message::Close(_)))) => break Ok(None),
+            Ok(Some(Err(e))) => break Err(e.into()),
+            Ok(Some(Ok(msg))) => msg,
+        };
+        let Message::Text(text) = msg else {
+            continue;
+        };
+        match SignalingMessage::parse(&text) {
+            Ok(parsed) => {
+                if incoming.send(Ok(Some(parsed))).await.is_err() {
+                    return;
+                }
+            }
+            Err(e) => log!("Failed to parse signaling frame {}: {:#}", text, e),
+        }
+    };
+    let _ = incoming.send(end).await;
+}
+
+// Sends everything queued, pinging the server every `ping` in between,
+// until told to stop or a write fails. A failed write is left for the
+// reader to notice; the message goes back into the outbox for the next
+// connection.
+async fn write(
+    mut sink: SplitSink<Socket, Message>,
+    outbox: Arc<Outbox>,
+    ping: Duration,
+    mut stop: oneshot::Receiver<()>,
+) {
+    let mut pings = tokio::time::interval(ping);
+    pings.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        // None is a ping
+        let payload = tokio::select! {
+            // Whatever is queued goes out before stopping
+            biased;
+            payload = outbox.next() => Some(payload),
+            _ = pings.tick() => None,
+            _ = &mut stop => break,
+        };
+        let message = match &payload {
+            Some(payload) => Message::Text(payload.to_string()),
+            None => Message::Ping(Vec::new()),
+        };
+        if let Err(e) = sink.send(message).await {
+            log!("Signaling send failed, queueing until resumed: {}", e);
+            if let Some(payload) = payload {
+                outbox.retry(payload);
+            }
+            return;
+        }
     }
+    let _ = tokio::time::timeout(CLOSE_TIMEOUT, sink.close()).await;
+}
+
+// When the first of Talk's TURN credentials expires. They follow the TURN
+// REST scheme: the username starts with the expiry as a Unix timestamp.
+fn turn_expiry(servers: &[RTCIceServer]) -> Option<SystemTime> {
+    servers
+        .iter()
+        .filter_map(|server| server.username.split_once(':')?.0.parse::<u64>().ok())
+        .min()
+        .map(|expiry| SystemTime::UNIX_EPOCH + Duration::from_secs(expiry))
+}
+
+// Talk lists STUN servers without and TURN servers with credentials (the
+// latter time-limited, derived from the TURN secret). Older versions give a
+// single "url" instead of "urls".
+fn ice_servers(settings: &Value) -> Vec<RTCIceServer> {
+    let servers = ["stunservers", "turnservers"]
+        .into_iter()
+        .filter_map(|key| settings.get(key).and_then(Value::as_array))
+        .flatten();
+    servers
+        .filter_map(|server| {
+            let urls: Vec<String> = match server.get("urls").or_else(|| server.get("url")) {
+                Some(Value::String(url)) => vec![url.clone()],
+                Some(Value::Array(urls)) => urls.iter().filter_map(Value::as_str).map(str::to_string).collect(),
+                _ => Vec::new(),
+            };
+            let field = |key: &str| server.get(key).and_then(Value::as_str).unwrap_or_default().to_string();
+            (!urls.is_empty()).then(|| RTCIceServer {
+                urls,
+                username: field("username"),
+                credential: field("credential"),
+                ..Default::default()
+            })
+        })
+        .collect()
 }
@@ -0,0 +1,74 @@
+use anyhow::{Context, Result};
+use tokio_tungstenite::Connector;
+
+use crate::config::TlsConfig;
+
+// How the bridge checks Nextcloud's and the signaling server's certificates,
+// for deployments behind an internal CA. The OCS requests share one client;
+// the WebSocket gets a connector of its own, as tungstenite doesn't use
+// reqwest's TLS.
+#[derive(Debug, Clone)]
+pub struct Tls {
+    pub http: reqwest::Client,
+    // None leaves tungstenite to the system's roots and checks
+    websocket: Option<native_tls::TlsConnector>,
+}
+
+impl Tls {
+    pub fn new(config: &TlsConfig) -> Result<Self> {
+        let mut http = reqwest::Client::builder();
+        let mut websocket = native_tls::TlsConnector::builder();
+        for path in &config.ca_files {
+            let pem = std::fs::read(path).with_context(|| format!("Failed to read CA file {}", path.display()))?;
+            let certs = reqwest::Certificate::from_pem_bundle(&pem)
+                .with_context(|| format!("Invalid certificates in {}", path.display()))?;
+            if certs.is_empty() {
+                anyhow::bail!("No certificates in CA file {}", path.display());
+            }
+            for cert in certs {
+                http = http.add_root_certificate(cert);
+            }
+            // native-tls takes them one at a time
+            for cert in split_pem(&pem) {
+                let cert = native_tls::Certificate::from_pem(cert.as_bytes())
+                    .with_context(|| format!("Invalid certificates in {}", path.display()))?;
+                websocket.add_root_certificate(cert);
+            }
+            log!("Trusting the certificates in {}", path.display());
+        }
+        if config.insecure {
+            log!("TLS_INSECURE_SKIP_VERIFY is set: Nextcloud's and the signaling server's certificates are NOT checked");
+            http = http.danger_accept_invalid_certs(true);
+            websocket.danger_accept_invalid_certs(true);
+        }
+
+        let custom = !config.ca_files.is_empty() || config.insecure;
+        Ok(Self {
+            http: http.build().context("Failed to set up the HTTP client")?,
+            websocket: match custom {
+                true => Some(websocket.build().context("Failed to set up TLS for the signaling server")?),
+                false => None,
+            },
+        })
+    }
+
+    pub fn connector(&self) -> Option<Connector> {
+        self.websocket.clone().map(Connector::NativeTls)
+    }
+}
+
+// The certificates of a PEM bundle, each with its BEGIN and END lines
+fn split_pem(pem: &[u8]) -> Vec<String> {
+    const END: &str = "-----END CERTIFICATE-----";
+    let text = String::from_utf8_lossy(pem);
+    let mut certs = Vec::new();
+    let mut rest = text.as_ref();
+    while let Some(end) = rest.find(END) {
+        let cert = &rest[..end + END.len()];
+        if let Some(begin) = cert.find("-----BEGIN CERTIFICATE-----") {
+            certs.push(cert[begin..].to_string());
+        }
+        rest = &rest[end + END.len()..];
+    }
+    certs
+}
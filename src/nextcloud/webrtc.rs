@@ -1,94 +1,408 @@
-use anyhow::{Result, Context};
-use std::sync::Arc;
+use anyhow::{Context, Result};
+use std::io::{ErrorKind, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::{Duration, SystemTime};
+use tokio::sync::{watch, Mutex as AsyncMutex};
+use webrtc::api::setting_engine::SettingEngine;
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::data_channel::RTCDataChannel;
 use webrtc::api::APIBuilder;
-use webrtc::api::interceptor_registry::register_default_interceptors;
-use webrtc::api::media_engine::MediaEngine;
+use webrtc::api::interceptor_registry::{configure_rtcp_reports, configure_twcc_receiver_only};
+use webrtc::api::media_engine::{MediaEngine, MIME_TYPE_H264, MIME_TYPE_VP8, MIME_TYPE_VP9};
+use webrtc::ice::mdns::MulticastDnsMode;
+use webrtc::ice::network_type::NetworkType;
 use webrtc::ice_transport::ice_server::RTCIceServer;
+use webrtc::interceptor::nack::generator::Generator;
+use webrtc::interceptor::nack::responder::Responder;
 use webrtc::interceptor::registry::Registry;
+use webrtc::dtls::crypto::Certificate;
+use webrtc::peer_connection::certificate::RTCCertificate;
 use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::offer_answer_options::RTCOfferOptions;
 use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::policy::ice_transport_policy::RTCIceTransportPolicy;
+use webrtc::peer_connection::sdp::sdp_type::RTCSdpType;
+use webrtc::peer_connection::signaling_state::RTCSignalingState;
 use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
+use webrtc::rtcp::receiver_report::ReceiverReport;
+use webrtc::rtcp::sender_report::SenderReport;
+use webrtc::rtp_transceiver::rtp_sender::RTCRtpSender;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
 
+use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
 use webrtc::track::track_local::track_local_static_sample::TrackLocalStaticSample;
 use webrtc::track::track_local::TrackLocal;
-use webrtc::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+use webrtc::track::track_remote::TrackRemote;
+use webrtc::rtp_transceiver::rtp_codec::RTPCodecType;
+use webrtc::rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTCRtpCodecParameters};
+use webrtc::rtp_transceiver::RTCPFeedback;
+
+use super::protocol::CallMessage;
+use super::sdp_diff;
+use super::signaling::CallTarget;
+use crate::audio::{audit, red};
+use crate::config::{IceGathering, OpusFmtp, SdpMunging, SdpRule};
+use crate::encryption::SharedEncryption;
+
+// What browsers and Janus use for Opus, and for RED carrying it
+pub const OPUS_PAYLOAD_TYPE: u8 = 111;
+const RED_PAYLOAD_TYPE: u8 = 63;
+
+// The video codecs browsers send, accepted only to be thrown away, with the
+// payload types Chrome gives them
+const VIDEO_CODECS: [(&str, &str, u8); 3] = [
+    (MIME_TYPE_VP8, "", 96),
+    (MIME_TYPE_VP9, "profile-id=0", 98),
+    (MIME_TYPE_H264, "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e01f", 102),
+];
+
+// How often lost packets are asked for again. The reorder buffer holds
+// Talk's audio for a few 20 ms frames, so a retransmission has to be asked
+// for within one to be of use.
+const NACK_INTERVAL: Duration = Duration::from_millis(20);
+
+// How long an offer or answer waits for our candidates without trickle ICE;
+// those found by then go in
+const GATHERING_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Only used when Talk has no STUN server configured
+const FALLBACK_STUN: &str = "stun:stun.l.google.com:19302";
+
+// Peers only compare the certificate's fingerprint, so a generated one is
+// kept for long
+const CERTIFICATE_LIFETIME: Duration = Duration::from_secs(10 * 365 * 24 * 60 * 60);
+
+// The DTLS certificate every connection presents, so Talk and the MCU see
+// the same fingerprint across restarts. Without it each connection
+// generates one of its own.
+static CERTIFICATE: OnceLock<RTCCertificate> = OnceLock::new();
+
+// Loads the certificate from `path`, or generates one and saves it there.
+// The file holds the private key, so only we may read it.
+pub fn load_certificate(path: &Path) -> Result<()> {
+    let certificate = match std::fs::read_to_string(path) {
+        Ok(pem) => RTCCertificate::from_pem(&pem)
+            .with_context(|| format!("Invalid DTLS certificate in {}", path.display()))?,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let generated = Certificate::generate_self_signed(vec!["webrtc-rs".to_owned()])?;
+            let certificate = RTCCertificate::from_existing(generated, SystemTime::now() + CERTIFICATE_LIFETIME);
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(path)
+                .and_then(|mut file| file.write_all(certificate.serialize_pem().as_bytes()))
+                .with_context(|| format!("Failed to save the DTLS certificate to {}", path.display()))?;
+            log!("Generated a DTLS certificate in {}", path.display());
+            certificate
+        }
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    if let Some(fingerprint) = certificate.get_fingerprints().first() {
+        log!("DTLS certificate fingerprint: {} {}", fingerprint.algorithm, fingerprint.value);
+    }
+    let _ = CERTIFICATE.set(certificate);
+    Ok(())
+}
+
+// The track we publish to Talk. Encoded audio goes out as samples, which the
+// track packetizes; passed-through Discord Opus as ready-made RTP packets.
+// With RED either goes out as RTP packets that also carry the frame before,
+// on a track whose codec is RED; the Opus inside is kept alongside.
+#[derive(Clone)]
+pub enum LocalAudioTrack {
+    Sample(Arc<TrackLocalStaticSample>),
+    Rtp(Arc<TrackLocalStaticRTP>),
+    Red(Arc<TrackLocalStaticRTP>, RTCRtpCodecCapability),
+}
+
+impl LocalAudioTrack {
+    // `fmtp` describes what we publish to Talk (Discord -> Nextcloud leg),
+    // `passthrough` picks the RTP track for untouched Discord Opus
+    pub fn new(fmtp: &OpusFmtp, passthrough: bool) -> Self {
+        // The Opus rtpmap is always opus/48000/2; whether the stream is really
+        // stereo is signalled with the stereo/sprop-stereo fmtp parameters.
+        let codec = RTCRtpCodecCapability {
+            mime_type: "audio/opus".to_owned(),
+            clock_rate: 48000,
+            channels: 2,
+            sdp_fmtp_line: fmtp.line(),
+            ..Default::default()
+        };
+        if fmtp.red {
+            let track = TrackLocalStaticRTP::new(red_codec(), "audio".to_owned(), "webrtc-rs".to_owned());
+            Self::Red(Arc::new(track), codec)
+        } else if passthrough {
+            Self::Rtp(Arc::new(TrackLocalStaticRTP::new(codec, "audio".to_owned(), "webrtc-rs".to_owned())))
+        } else {
+            Self::Sample(Arc::new(TrackLocalStaticSample::new(codec, "audio".to_owned(), "webrtc-rs".to_owned())))
+        }
+    }
+
+    // The Opus, also when it goes out in RED
+    fn codec(&self) -> RTCRtpCodecCapability {
+        match self {
+            Self::Sample(track) => track.codec(),
+            Self::Rtp(track) => track.codec(),
+            Self::Red(_, opus) => opus.clone(),
+        }
+    }
+
+    fn red(&self) -> bool {
+        matches!(self, Self::Red(..))
+    }
+
+    // Whether we tell Talk the stream is stereo
+    fn stereo(&self) -> bool {
+        self.codec().sdp_fmtp_line.contains("sprop-stereo=1")
+    }
+
+    // A track can be added to several peer connections (P2P mode); what is
+    // written to it goes out on all of them
+    fn local(&self) -> Arc<dyn TrackLocal + Send + Sync> {
+        match self {
+            Self::Sample(track) => track.clone(),
+            Self::Rtp(track) => track.clone(),
+            Self::Red(track, _) => track.clone(),
+        }
+    }
+}
+
+// RED around our Opus: "111/111", its payload type for each block
+fn red_codec() -> RTCRtpCodecCapability {
+    RTCRtpCodecCapability {
+        mime_type: red::MIME_TYPE.to_owned(),
+        clock_rate: 48000,
+        channels: 2,
+        sdp_fmtp_line: format!("{}/{}", OPUS_PAYLOAD_TYPE, OPUS_PAYLOAD_TYPE),
+        ..Default::default()
+    }
+}
+
+// The STUN/TURN servers a connection gathers candidates with, whether it
+// may only use TURN relays, and which of the host's addresses it may use
+#[derive(Debug, Clone)]
+pub struct IceServers {
+    pub servers: Vec<RTCIceServer>,
+    pub relay_only: bool,
+    pub gathering: IceGathering,
+    // Candidates go out one by one; otherwise offers and answers wait for
+    // them and carry them all
+    pub trickle: bool,
+    // When Talk's TURN credentials among them run out
+    pub expires: Option<SystemTime>,
+}
+
+// What Talk calls the data channel its clients send their state on
+const STATUS_CHANNEL: &str = "status";
+
+// A state message from the other end's data channel
+pub type StatusHandler = Arc<dyn Fn(CallMessage) + Send + Sync>;
 
 pub struct NextcloudWebRTC {
     pub peer_connection: Arc<RTCPeerConnection>,
-    pub audio_track: Arc<TrackLocalStaticSample>,
+    pub audio_track: LocalAudioTrack,
+    // Packet loss (percent) Talk reports for the audio we publish
+    pub publish_loss: Arc<AtomicU8>,
+    // Checks the remote SDPs and picks the SRTP profiles we accept
+    pub encryption: SharedEncryption,
+    // From the configuration and Talk's signaling settings, for P2P
+    // connections to reuse
+    pub ice_servers: IceServers,
+    // The cap (bits/s) put on the audio of the SDPs we send
+    pub bandwidth: Option<u32>,
+    // Configured edits of Talk's SDPs and ours
+    pub munging: SdpMunging,
+    // Whose offer was answered last; our candidates go there
+    remote: Mutex<Option<CallTarget>>,
+    // Follows the connection state, to restart ICE when it drops
+    state: watch::Receiver<RTCPeerConnectionState>,
+    // Perfect negotiation: when offers cross, the polite side rolls its own
+    // back and answers, the impolite one keeps its own and ignores theirs,
+    // along with the candidates that come with it
+    polite: bool,
+    ignoring_offer: AtomicBool,
+    // One offer or answer at a time, so a crossing offer is always seen
+    negotiation: AsyncMutex<()>,
 }
 
 impl NextcloudWebRTC {
-    pub async fn new() -> Result<Self> {
-        // Create a MediaEngine object to configure the supported codec
+    pub async fn new(
+        fmtp: &OpusFmtp,
+        passthrough: bool,
+        encryption: SharedEncryption,
+        ice_servers: IceServers,
+    ) -> Result<Self> {
+        let track = LocalAudioTrack::new(fmtp, passthrough);
+        let loss = Arc::new(AtomicU8::new(0));
+        Self::with_track(track, loss, fmtp.bandwidth, fmtp.sdp.clone(), encryption, ice_servers).await
+    }
+
+    // A connection publishing an existing track, reporting its loss into
+    // `publish_loss` as well
+    pub async fn with_track(
+        audio_track: LocalAudioTrack,
+        publish_loss: Arc<AtomicU8>,
+        bandwidth: Option<u32>,
+        munging: SdpMunging,
+        encryption: SharedEncryption,
+        ice_servers: IceServers,
+    ) -> Result<Self> {
+        // Only the track's Opus, so what Talk and the MCU negotiate is what we
+        // publish and what Discord takes, and RED around it, which newer
+        // Janus and Talk clients send when it is on offer. Video is taken
+        // too, receive-only, so a camera turned on mid-call renegotiates like
+        // any other track instead of failing the offer; on_audio_track drops
+        // its packets.
         let mut m = MediaEngine::default();
-        m.register_default_codecs()?;
+        m.register_codec(
+            RTCRtpCodecParameters {
+                capability: audio_track.codec(),
+                payload_type: OPUS_PAYLOAD_TYPE,
+                ..Default::default()
+            },
+            RTPCodecType::Audio,
+        )?;
+        m.register_codec(
+            RTCRtpCodecParameters {
+                capability: red_codec(),
+                payload_type: RED_PAYLOAD_TYPE,
+                ..Default::default()
+            },
+            RTPCodecType::Audio,
+        )?;
+        for (mime_type, fmtp, payload_type) in VIDEO_CODECS {
+            m.register_codec(
+                RTCRtpCodecParameters {
+                    capability: RTCRtpCodecCapability {
+                        mime_type: mime_type.to_owned(),
+                        clock_rate: 90000,
+                        sdp_fmtp_line: fmtp.to_owned(),
+                        ..Default::default()
+                    },
+                    payload_type,
+                    ..Default::default()
+                },
+                RTPCodecType::Video,
+            )?;
+        }
 
-        // Create a InterceptorRegistry. This is the user configurable RTP/RTCP Pipeline.
-        // This provides NACKs, RTCP Reports and other features. If you use `webrtc.NewPeerConnection`
-        // this is enabled by default. If you use `APIBuilder` you must enable it yourself.
+        // NACKs for what goes missing of the audio the MCU sends us, and
+        // retransmissions of ours when it asks; webrtc-rs's defaults only
+        // offer NACK for video. PLI is offered for the video we take, so the
+        // MCU knows we can ask for a keyframe. Besides, RTCP reports and
+        // transport-wide congestion control feedback.
+        let nack = || RTCPFeedback { typ: "nack".to_owned(), parameter: String::new() };
+        let pli = RTCPFeedback { typ: "nack".to_owned(), parameter: "pli".to_owned() };
+        m.register_feedback(nack(), RTPCodecType::Audio);
+        m.register_feedback(nack(), RTPCodecType::Video);
+        m.register_feedback(pli, RTPCodecType::Video);
         let mut registry = Registry::new();
+        registry.add(Box::new(Generator::builder().with_interval(NACK_INTERVAL)));
+        registry.add(Box::new(Responder::builder()));
+        registry = configure_rtcp_reports(registry);
+        registry = configure_twcc_receiver_only(registry, &mut m)?;
 
-        // Use the default set of Interceptors
-        registry = register_default_interceptors(registry, &mut m)?;
+        let mut settings = SettingEngine::default();
+        settings.set_srtp_protection_profiles(encryption.srtp_profiles());
+        restrict_gathering(&mut settings, &ice_servers.gathering);
 
         // Create the API object with the MediaEngine
         let api = APIBuilder::new()
             .with_media_engine(m)
             .with_interceptor_registry(registry)
+            .with_setting_engine(settings)
             .build();
 
-        // Prepare the configuration
-        // TODO: Get TURN servers from Nextcloud Signaling!
+        // The configured and Talk's STUN/TURN servers; a public STUN server
+        // if there are none. Relayed only, there is nothing without TURN.
+        let relays = ice_servers.servers.iter().flat_map(|s| &s.urls).any(|url| url.starts_with("turn"));
+        if ice_servers.relay_only && !relays {
+            log!("ICE_TRANSPORT_POLICY=relay, but neither ICE_SERVERS nor Talk has a TURN server; Talk can't be reached");
+        }
         let config = RTCConfiguration {
-            ice_servers: vec![RTCIceServer {
-                urls: vec!["stun:stun.l.google.com:19302".to_owned()],
-                ..Default::default()
-            }],
+            ice_servers: if ice_servers.servers.is_empty() {
+                vec![RTCIceServer {
+                    urls: vec![FALLBACK_STUN.to_owned()],
+                    ..Default::default()
+                }]
+            } else {
+                ice_servers.servers.clone()
+            },
+            ice_transport_policy: match ice_servers.relay_only {
+                true => RTCIceTransportPolicy::Relay,
+                false => RTCIceTransportPolicy::All,
+            },
+            certificates: CERTIFICATE.get().cloned().into_iter().collect(),
             ..Default::default()
         };
 
         // Create a new RTCPeerConnection
         let peer_connection = api.new_peer_connection(config).await?;
 
-        // Create a local audio track (Opus)
-        let audio_track = Arc::new(TrackLocalStaticSample::new(
-            RTCRtpCodecCapability {
-                mime_type: "audio/opus".to_owned(),
-                ..Default::default()
-            },
-            "audio".to_owned(),
-            "webrtc-rs".to_owned(),
-        ));
-
-        // Add this track to the PeerConnection
-        peer_connection
-            .add_track(Arc::clone(&audio_track) as Arc<dyn TrackLocal + Send + Sync>)
-            .await?;
+        // Add the local audio track (Opus) to the PeerConnection
+        let audio_sender = peer_connection.add_track(audio_track.local()).await?;
+        tokio::spawn(read_publisher_rtcp(audio_sender, publish_loss.clone()));
 
         // Set the handler for Peer connection state
         // This will notify you when the peer has connected/disconnected
+        let (state_tx, state) = watch::channel(RTCPeerConnectionState::New);
          peer_connection
             .on_peer_connection_state_change(Box::new(move |s: RTCPeerConnectionState| {
-                println!("Peer Connection State has changed: {s}");
+                log!("Peer Connection State has changed: {s}");
+                state_tx.send_replace(s);
                 Box::pin(async {})
             }));
 
         Ok(Self {
             peer_connection: Arc::new(peer_connection),
             audio_track,
+            publish_loss,
+            encryption,
+            ice_servers,
+            bandwidth,
+            munging,
+            remote: Mutex::new(None),
+            state,
+            polite: true,
+            ignoring_offer: AtomicBool::new(false),
+            negotiation: AsyncMutex::new(()),
         })
     }
 
+    // Connections start out polite, which suits Janus and answering only
+    pub fn polite(mut self, polite: bool) -> Self {
+        self.polite = polite;
+        self
+    }
+
+    pub fn state(&self) -> watch::Receiver<RTCPeerConnectionState> {
+        self.state.clone()
+    }
+
+    pub fn remote(&self) -> Option<CallTarget> {
+        self.remote.lock().unwrap().clone()
+    }
+
+    pub fn set_remote(&self, remote: CallTarget) {
+        *self.remote.lock().unwrap() = Some(remote);
+    }
+
     // Register callback for local ICE candidates
+    // Not called without trickle ICE, the candidates are in the SDPs then
     pub fn on_ice_candidate(&self, f: Box<dyn Fn(String, String, u16) + Send + Sync>) {
         let f = Arc::new(f);
+        let trickle = self.ice_servers.trickle;
         self.peer_connection.on_ice_candidate(Box::new(move |c| {
             let f = f.clone();
             Box::pin(async move {
-                if let Some(c) = c {
+                if let Some(c) = c.filter(|_| trickle) {
                     if let Ok(json) = c.to_json() {
                          let sdp = json.candidate;
                          let mid = json.sdp_mid.unwrap_or_default();
@@ -100,8 +414,103 @@ impl NextcloudWebRTC {
         }));
     }
 
-    pub async fn handle_offer(&self, sdp: String) -> Result<String> {
-        let desc = RTCSessionDescription::offer(sdp)?;
+    // Register callback for remote audio tracks (Nextcloud -> Discord).
+    // Has to be set before the offer is handled or early tracks are missed.
+    // Video tracks are read and thrown away.
+    pub fn on_audio_track(&self, f: Box<dyn Fn(Arc<TrackRemote>) + Send + Sync>) {
+        let f = Arc::new(f);
+        let pc = Arc::downgrade(&self.peer_connection);
+        self.peer_connection.on_track(Box::new(move |track, _receiver, _transceiver| {
+            match track.kind() {
+                RTPCodecType::Audio => {
+                    log!("Received remote audio track (ssrc {})", track.ssrc());
+                    f(track);
+                }
+                RTPCodecType::Video => {
+                    log!("Received remote video track (ssrc {}), discarding it", track.ssrc());
+                    tokio::spawn(discard(track, pc.clone()));
+                }
+                _ => {}
+            }
+            Box::pin(async {})
+        }));
+    }
+
+    // Register callback for the state messages Talk clients send on their
+    // data channels, whatever the channel's label: "status" between peers,
+    // Janus' own through the MCU
+    pub fn on_status(&self, f: StatusHandler) {
+        self.peer_connection.on_data_channel(Box::new(move |channel: Arc<RTCDataChannel>| {
+            log!("Talk opened data channel {:?}", channel.label());
+            read_status(&channel, f.clone());
+            Box::pin(async {})
+        }));
+    }
+
+    // Offers need a data channel of our own, or they have no m-line for the
+    // ones Talk opens
+    pub async fn open_status_channel(&self, f: StatusHandler) -> Result<()> {
+        let channel = self
+            .peer_connection
+            .create_data_channel(STATUS_CHANNEL, None)
+            .await
+            .context("Failed to open the status data channel")?;
+        read_status(&channel, f);
+        Ok(())
+    }
+
+    // On renegotiation, log what changed in the remote SDP rather than the
+    // whole thing
+    async fn log_renegotiation(&self, desc: &RTCSessionDescription) {
+        let Some(previous) = self.peer_connection.remote_description().await else {
+            return;
+        };
+        let (old, new) = match (previous.unmarshal(), desc.unmarshal()) {
+            (Ok(old), Ok(new)) => (old, new),
+            (Err(e), _) | (_, Err(e)) => {
+                log!("Renegotiation ({}): could not parse SDP for diff: {}", desc.sdp_type, e);
+                return;
+            }
+        };
+
+        let changes = sdp_diff::diff(&old, &new);
+        if changes.is_empty() {
+            log!("Renegotiation ({}): no media changes", desc.sdp_type);
+        } else {
+            log!("Renegotiation ({}):\n  {}", desc.sdp_type, changes.join("\n  "));
+        }
+    }
+
+    // Whether an offer and answer went through already, so another offer
+    // renegotiates a connection that carries media
+    pub async fn is_negotiated(&self) -> bool {
+        self.peer_connection.current_remote_description().await.is_some()
+    }
+
+    // Janus and Talk's clients send new offers on an established connection
+    // whenever tracks change. One that crosses an offer of ours wins if we
+    // are polite: ours is rolled back first. Otherwise it is ignored and
+    // there is no answer; the other side answers ours instead.
+    pub async fn handle_offer(&self, sdp: String) -> Result<Option<String>> {
+        let _negotiation = self.negotiation.lock().await;
+        let desc = RTCSessionDescription::offer(munge(&sdp, &self.munging.remote))?;
+        let collision = self.peer_connection.signaling_state() == RTCSignalingState::HaveLocalOffer;
+        self.ignoring_offer.store(collision && !self.polite, Ordering::Relaxed);
+        if collision && !self.polite {
+            log!("Offer crossed our own, keeping ours");
+            return Ok(None);
+        }
+        let parsed = desc.unmarshal()?;
+        self.encryption.talk_sdp(&parsed)?;
+        audit::sdp(&parsed, self.audio_track.stereo(), self.audio_track.red());
+        self.log_renegotiation(&desc).await;
+        if collision {
+            if let Some(mut ours) = self.peer_connection.pending_local_description().await {
+                log!("Offer crossed our own, rolling ours back");
+                ours.sdp_type = RTCSdpType::Rollback;
+                self.peer_connection.set_local_description(ours).await?;
+            }
+        }
         self.peer_connection.set_remote_description(desc).await?;
 
         let answer = self.peer_connection.create_answer(None).await?;
@@ -110,16 +519,84 @@ impl NextcloudWebRTC {
         // Poller starts gathering ICE candidates here usually
         self.peer_connection.set_local_description(answer).await?;
 
-        Ok(answer_sdp)
+        Ok(Some(self.outgoing(self.gathered(answer_sdp).await)))
+    }
+
+    // Without trickle ICE, the description just set once its candidates are
+    // in, or as far as they got within GATHERING_TIMEOUT
+    async fn gathered(&self, sdp: String) -> String {
+        if self.ice_servers.trickle {
+            return sdp;
+        }
+        let mut complete = self.peer_connection.gathering_complete_promise().await;
+        if tokio::time::timeout(GATHERING_TIMEOUT, complete.recv()).await.is_err() {
+            log!("ICE gathering not done after {:?}, sending the candidates found so far", GATHERING_TIMEOUT);
+        }
+        match self.peer_connection.local_description().await {
+            Some(description) => description.sdp,
+            None => sdp,
+        }
+    }
+
+    // webrtc-rs only takes its own descriptions back unchanged, so the
+    // bandwidth cap and the configured edits go into the copy that is sent
+    fn outgoing(&self, sdp: String) -> String {
+        let sdp = match self.bandwidth {
+            Some(bandwidth) => cap_audio(&sdp, bandwidth),
+            None => sdp,
+        };
+        munge(&sdp, &self.munging.local)
+    }
+
+    // We start the negotiation (P2P mode, towards participants that joined
+    // after us)
+    pub async fn create_offer(&self) -> Result<String> {
+        let _negotiation = self.negotiation.lock().await;
+        let offer = self.peer_connection.create_offer(None).await?;
+        let offer_sdp = offer.sdp.clone();
+        self.peer_connection.set_local_description(offer).await?;
+        Ok(self.outgoing(self.gathered(offer_sdp).await))
+    }
+
+    // An offer with new ICE credentials, for when the connection dropped;
+    // its answer goes to handle_answer as usual
+    pub async fn restart_ice(&self) -> Result<String> {
+        let options = RTCOfferOptions { ice_restart: true, ..Default::default() };
+        let _negotiation = self.negotiation.lock().await;
+        let offer = self.peer_connection.create_offer(Some(options)).await?;
+        let offer_sdp = offer.sdp.clone();
+        self.peer_connection.set_local_description(offer).await?;
+        Ok(self.outgoing(self.gathered(offer_sdp).await))
+    }
+
+    // Failed or closed for good; a new connection is needed
+    pub fn is_dead(&self) -> bool {
+        matches!(
+            self.peer_connection.connection_state(),
+            RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed
+        )
+    }
+
+    pub async fn close(&self) -> Result<()> {
+        self.peer_connection.close().await?;
+        Ok(())
     }
 
     pub async fn handle_answer(&self, sdp: String) -> Result<()> {
-        let desc = RTCSessionDescription::answer(sdp)?;
+        let _negotiation = self.negotiation.lock().await;
+        let desc = RTCSessionDescription::answer(munge(&sdp, &self.munging.remote))?;
+        let parsed = desc.unmarshal()?;
+        self.encryption.talk_sdp(&parsed)?;
+        audit::sdp(&parsed, self.audio_track.stereo(), self.audio_track.red());
+        self.log_renegotiation(&desc).await;
         self.peer_connection.set_remote_description(desc).await?;
         Ok(())
     }
 
     pub async fn add_ice_candidate(&self, candidate: String, sdp_mid: String, sdp_mline_index: u16) -> Result<()> {
+        if !self.ice_servers.gathering.link_local && is_link_local_candidate(&candidate) {
+            return Ok(());
+        }
         let candidate_init = RTCIceCandidateInit {
             candidate,
             sdp_mid: Some(sdp_mid),
@@ -127,7 +604,200 @@ impl NextcloudWebRTC {
             username_fragment: None,
         };
 
-        self.peer_connection.add_ice_candidate(candidate_init).await?;
-        Ok(())
+        match self.peer_connection.add_ice_candidate(candidate_init).await {
+            // Meant for the offer we ignored
+            Err(_) if self.ignoring_offer.load(Ordering::Relaxed) => Ok(()),
+            result => Ok(result?),
+        }
+    }
+}
+
+// Replaces the bandwidth lines of the audio m-sections with b=AS (kbps) and
+// b=TIAS (bits/s) for `bits_per_second`. They go after the section's i= and
+// c= lines, before its attributes.
+fn cap_audio(sdp: &str, bits_per_second: u32) -> String {
+    let limits = [format!("b=AS:{}", bits_per_second.div_ceil(1000)), format!("b=TIAS:{}", bits_per_second)];
+    let mut lines = Vec::new();
+    let (mut audio, mut capped) = (false, false);
+    for line in sdp.lines() {
+        if line.starts_with("m=") {
+            audio = line.starts_with("m=audio");
+            capped = false;
+        } else if audio && line.starts_with("b=") {
+            continue;
+        } else if audio && !capped && !line.starts_with("i=") && !line.starts_with("c=") {
+            lines.extend(limits.iter().map(String::as_str));
+            capped = true;
+        }
+        lines.push(line);
+    }
+    lines.join("\r\n") + "\r\n"
+}
+
+// Applies `rules` to the Opus of each audio m-section: parameters are set or
+// removed on its fmtp line, which is added after its rtpmap if there is none,
+// and ptime and maxptime lines replaced or removed
+fn munge(sdp: &str, rules: &[SdpRule]) -> String {
+    if rules.is_empty() {
+        return sdp.to_string();
     }
+    let mut lines = Vec::new();
+    let mut section = Vec::new();
+    for line in sdp.lines() {
+        if line.starts_with("m=") {
+            lines.extend(munge_section(std::mem::take(&mut section), rules));
+        }
+        section.push(line.to_string());
+    }
+    lines.extend(munge_section(section, rules));
+    lines.join("\r\n") + "\r\n"
+}
+
+fn munge_section(mut lines: Vec<String>, rules: &[SdpRule]) -> Vec<String> {
+    if !lines.first().is_some_and(|m| m.starts_with("m=audio")) {
+        return lines;
+    }
+    let opus = lines.iter().enumerate().find_map(|(at, line)| {
+        let (pt, codec) = line.strip_prefix("a=rtpmap:")?.split_once(' ')?;
+        codec.to_lowercase().starts_with("opus/").then(|| (at, pt.to_string()))
+    });
+    let Some((rtpmap, pt)) = opus else {
+        return lines;
+    };
+
+    let prefix = format!("a=fmtp:{} ", pt);
+    let fmtp = lines.iter().position(|line| line.starts_with(&prefix));
+    let mut params: Vec<(String, String)> = fmtp
+        .map(|at| lines[at][prefix.len()..].split(';').map(str::trim).filter(|p| !p.is_empty()))
+        .into_iter()
+        .flatten()
+        .map(|p| {
+            let (key, value) = p.split_once('=').unwrap_or((p, ""));
+            (key.to_string(), value.to_string())
+        })
+        .collect();
+    for rule in rules.iter().filter(|rule| !rule.is_attribute()) {
+        params.retain(|(key, _)| !key.eq_ignore_ascii_case(&rule.key));
+        if let Some(value) = &rule.value {
+            params.push((rule.key.clone(), value.clone()));
+        }
+    }
+    let line = params
+        .iter()
+        .map(|(key, value)| if value.is_empty() { key.clone() } else { format!("{}={}", key, value) })
+        .collect::<Vec<_>>()
+        .join(";");
+    match fmtp {
+        Some(at) if params.is_empty() => {
+            lines.remove(at);
+        }
+        Some(at) => lines[at] = format!("{}{}", prefix, line),
+        None if params.is_empty() => {}
+        None => lines.insert(rtpmap + 1, format!("{}{}", prefix, line)),
+    }
+
+    // Attributes go after the fmtp line, or the rtpmap without one
+    let mut attributes = Vec::new();
+    for rule in rules.iter().filter(|rule| rule.is_attribute()) {
+        let prefix = format!("a={}:", rule.key.to_lowercase());
+        lines.retain(|line| !line.starts_with(&prefix));
+        attributes.retain(|line: &String| !line.starts_with(&prefix));
+        if let Some(value) = &rule.value {
+            attributes.push(format!("{}{}", prefix, value));
+        }
+    }
+    let rtpmap = format!("a=rtpmap:{} ", pt);
+    let anchor = lines.iter().position(|line| line.starts_with(&prefix));
+    let at = anchor.or_else(|| lines.iter().position(|line| line.starts_with(&rtpmap))).map_or(lines.len(), |at| at + 1);
+    lines.splice(at..at, attributes);
+    lines
+}
+
+// Reads a video track until it ends, so its packets don't pile up in the
+// receive buffers. It starts with one PLI, as browsers do, so what arrives
+// begins with a keyframe; nothing decodes it, so none follow on loss, which
+// would only have the sender encode keyframes for nobody.
+async fn discard(track: Arc<TrackRemote>, pc: Weak<RTCPeerConnection>) {
+    if let Some(pc) = pc.upgrade() {
+        let pli = PictureLossIndication { sender_ssrc: 0, media_ssrc: track.ssrc() };
+        if let Err(e) = pc.write_rtcp(&[Box::new(pli)]).await {
+            log!("Failed to send a PLI for video track (ssrc {}): {}", track.ssrc(), e);
+        }
+    }
+    let mut packets = 0u64;
+    while track.read_rtp().await.is_ok() {
+        packets += 1;
+    }
+    log!("Remote video track (ssrc {}) ended after {} packets", track.ssrc(), packets);
+}
+
+// Leaves out the interfaces, addresses and address families the
+// configuration rules out of our candidates
+fn restrict_gathering(settings: &mut SettingEngine, gathering: &IceGathering) {
+    if !gathering.interfaces.is_empty() {
+        let interfaces = gathering.interfaces.clone();
+        settings.set_interface_filter(Box::new(move |name| interfaces.iter().any(|i| i == name)));
+    }
+    let (addresses, link_local) = (gathering.addresses.clone(), gathering.link_local);
+    settings.set_ip_filter(Box::new(move |ip| {
+        (addresses.is_empty() || addresses.contains(&ip)) && (link_local || !is_link_local(ip))
+    }));
+    if !gathering.ipv6 {
+        settings.set_network_types(vec![NetworkType::Udp4, NetworkType::Tcp4]);
+    }
+    if !gathering.link_local {
+        settings.set_ice_multicast_dns_mode(MulticastDnsMode::Disabled);
+    }
+}
+
+fn is_link_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_link_local(),
+        IpAddr::V6(ip) => ip.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
+// A remote candidate with an mDNS name or a link-local address, e.g.
+// "candidate:1 1 udp 2122260223 169.254.3.4 54321 typ host"
+fn is_link_local_candidate(candidate: &str) -> bool {
+    let Some(address) = candidate.split_whitespace().nth(4) else {
+        return false;
+    };
+    address.ends_with(".local") || address.parse().is_ok_and(is_link_local)
+}
+
+// Tracks the loss reported in RTCP receiver reports for our audio. Draining
+// the sender's RTCP is also what keeps the interceptors (NACK, reports) going.
+async fn read_publisher_rtcp(sender: Arc<RTCRtpSender>, loss: Arc<AtomicU8>) {
+    let mut smoothed = 0.0f32;
+    while let Ok((packets, _)) = sender.read_rtcp().await {
+        for packet in packets {
+            let packet = packet.as_any();
+            let reports = if let Some(rr) = packet.downcast_ref::<ReceiverReport>() {
+                &rr.reports
+            } else if let Some(sr) = packet.downcast_ref::<SenderReport>() {
+                &sr.reports
+            } else {
+                continue;
+            };
+
+            for report in reports {
+                let percent = report.fraction_lost as f32 * 100.0 / 256.0;
+                // React to new loss right away, back off slowly once it clears
+                smoothed = if percent > smoothed { percent } else { smoothed * 0.8 + percent * 0.2 };
+                loss.store(smoothed.round() as u8, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+// Talk's state messages are JSON in the call message format; anything else
+// on the channel is ignored
+fn read_status(channel: &RTCDataChannel, f: StatusHandler) {
+    channel.on_message(Box::new(move |message: DataChannelMessage| {
+        if let Ok(status) = serde_json::from_slice::<CallMessage>(&message.data) {
+            f(status);
+        }
+        Box::pin(async {})
+    }));
 }
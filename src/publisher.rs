@@ -0,0 +1,248 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::bridge::{run_signaling, PeerToPeer, SessionShared, TaskGuard};
+use crate::config::{AudioConfig, OpusFmtp, PipelineMode};
+use crate::encryption::SharedEncryption;
+use crate::loop_guard::{SharedLoopGuard, TalkSessionGuard};
+use crate::nextcloud::call::{CallClient, InCall};
+use crate::nextcloud::peers::TrackHandler;
+use crate::nextcloud::signaling::{Config, SignalingClient};
+use crate::nextcloud::webrtc::NextcloudWebRTC;
+use crate::talk_track::TalkTrack;
+
+// A Talk connection of its own that publishes a single Discord speaker, so
+// Talk shows them as a separate tile with their own speaking indicator.
+// Dropping it closes the connection.
+struct SpeakerPublisher {
+    track: Arc<TalkTrack>,
+    _signaling: TaskGuard,
+    _session: TalkSessionGuard,
+    // Declared after the task, so the call is left once signaling stopped
+    _call: InCall,
+}
+
+impl SpeakerPublisher {
+    async fn connect(
+        config: Config,
+        room_token: &str,
+        fmtp: OpusFmtp,
+        passthrough: bool,
+        loops: SharedLoopGuard,
+        encryption: SharedEncryption,
+    ) -> Result<Self> {
+        // Extra publishers never ring anyone
+        let (signaling, in_call) =
+            join_room(config, room_token, true).await.context("Failed to connect publisher to Talk")?;
+
+        let nextcloud = NextcloudWebRTC::new(&fmtp, passthrough, encryption, signaling.ice_servers())
+            .await
+            .context("Failed to init WebRTC")?;
+        let track = TalkTrack::new(nextcloud.audio_track.clone(), Default::default());
+        let signaling_track = track.clone();
+        let session = loops.add_talk_session(signaling.session_id());
+        let task = tokio::spawn(async move {
+            let nextcloud = Arc::new(Mutex::new(nextcloud));
+            let signaling = Arc::new(Mutex::new(signaling));
+            match run_signaling(nextcloud, signaling, signaling_track, loops, None, None, None).await {
+                Ok(Some(reason)) => log!("Speaker publisher ended: {}", reason),
+                Ok(None) => {}
+                Err(e) => log!("Speaker publisher failed: {:?}", e),
+            }
+        });
+
+        Ok(Self {
+            track,
+            _signaling: TaskGuard(task),
+            _session: session,
+            _call: in_call,
+        })
+    }
+}
+
+// The second Talk room of a merged call: a connection of its own that
+// publishes Discord and the first room, and hands what it receives to
+// `on_track`. Dropping it leaves the room.
+pub struct MergedRoom {
+    pub track: Arc<TalkTrack>,
+    _signaling: TaskGuard,
+    _session: TalkSessionGuard,
+    // Declared after the task, so the call is left once signaling stopped
+    _call: InCall,
+}
+
+impl MergedRoom {
+    pub async fn connect(
+        config: Config,
+        room_token: &str,
+        silent: bool,
+        audio: &AudioConfig,
+        shared: &SessionShared,
+        on_track: TrackHandler,
+        p2p_max_peers: Option<usize>,
+    ) -> Result<Self> {
+        let (signaling, in_call) = join_room(config, room_token, silent)
+            .await
+            .with_context(|| format!("Failed to join merged Talk room {}", room_token))?;
+
+        // Same track type as the main connection, it gets the same packets
+        let passthrough = audio.pipeline == PipelineMode::Passthrough;
+        let nextcloud = NextcloudWebRTC::new(
+            &audio.talk_fmtp(),
+            passthrough,
+            shared.encryption.clone(),
+            signaling.ice_servers(),
+        )
+        .await
+            .context("Failed to init WebRTC")?;
+        {
+            let on_track = on_track.clone();
+            nextcloud.on_audio_track(Box::new(move |track| on_track(track, None)));
+        }
+        let track = TalkTrack::new(nextcloud.audio_track.clone(), Default::default());
+        let signaling_track = track.clone();
+        let loops = shared.loops.clone();
+        let session = loops.add_talk_session(signaling.session_id());
+        let subscribe = Some(on_track.clone());
+        let p2p = p2p_max_peers.map(|max_peers| PeerToPeer { max_peers, on_track });
+        let room = room_token.to_string();
+        let task = tokio::spawn(async move {
+            let nextcloud = Arc::new(Mutex::new(nextcloud));
+            let signaling = Arc::new(Mutex::new(signaling));
+            match run_signaling(nextcloud, signaling, signaling_track, loops, p2p, subscribe, None).await {
+                Ok(Some(reason)) => log!("Merged Talk room {} ended: {}", room, reason),
+                Ok(None) => {}
+                Err(e) => log!("Merged Talk room {} failed: {:?}", room, e),
+            }
+        });
+        log!("Merged Talk room {} into the call", room_token);
+
+        Ok(Self {
+            track,
+            _signaling: TaskGuard(task),
+            _session: session,
+            _call: in_call,
+        })
+    }
+}
+
+// Signaling connection and call membership for an extra Talk connection
+async fn join_room(config: Config, room_token: &str, silent: bool) -> Result<(SignalingClient, InCall)> {
+    let mut signaling = SignalingClient::new(config.clone());
+    signaling
+        .connect(room_token)
+        .await
+        .context("Failed to connect to Signaling")?;
+    let calls = CallClient::new(config).with_session(signaling.session_cookie());
+    let in_call = InCall::join(calls, room_token, silent)
+        .await
+        .context("Failed to join Talk call")?;
+    Ok((signaling, in_call))
+}
+
+enum Slot {
+    Connecting,
+    Ready(SpeakerPublisher),
+    // Not retried, or every packet from the speaker would try again
+    Failed,
+}
+
+// Per-speaker publishers for multi-track mode, created on a speaker's first
+// frame. Until one is ready (or when the limit is hit) the caller keeps
+// using the shared track.
+pub struct PublisherPool {
+    config: Config,
+    room_token: std::sync::Mutex<String>,
+    fmtp: OpusFmtp,
+    max: usize,
+    // Same track type as the main connection
+    passthrough: bool,
+    loops: SharedLoopGuard,
+    encryption: SharedEncryption,
+    slots: std::sync::Mutex<HashMap<u64, Slot>>,
+}
+
+impl PublisherPool {
+    pub fn new(
+        config: Config,
+        room_token: String,
+        fmtp: OpusFmtp,
+        max: usize,
+        passthrough: bool,
+        shared: &SessionShared,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            config,
+            room_token: std::sync::Mutex::new(room_token),
+            fmtp,
+            max,
+            passthrough,
+            loops: shared.loops.clone(),
+            encryption: shared.encryption.clone(),
+            slots: std::sync::Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn track(self: &Arc<Self>, user_id: u64) -> Option<Arc<TalkTrack>> {
+        let mut slots = self.slots.lock().unwrap();
+        if let Some(slot) = slots.get(&user_id) {
+            return match slot {
+                Slot::Ready(publisher) => Some(publisher.track.clone()),
+                Slot::Connecting | Slot::Failed => None,
+            };
+        }
+
+        let active = slots.values().filter(|s| !matches!(s, Slot::Failed)).count();
+        if active >= self.max {
+            return None;
+        }
+
+        slots.insert(user_id, Slot::Connecting);
+        let pool = self.clone();
+        let room_token = self.room_token.lock().unwrap().clone();
+        tokio::spawn(async move {
+            log!("Starting Talk publisher for Discord user {}", user_id);
+            let result = SpeakerPublisher::connect(
+                pool.config.clone(),
+                &room_token,
+                pool.fmtp.clone(),
+                pool.passthrough,
+                pool.loops.clone(),
+                pool.encryption.clone(),
+            )
+            .await;
+            let mut slots = pool.slots.lock().unwrap();
+            // The speaker may have left, or the bridge moved to another
+            // room, while we were connecting
+            let moved = *pool.room_token.lock().unwrap() != room_token;
+            if moved || !matches!(slots.get(&user_id), Some(Slot::Connecting)) {
+                return;
+            }
+            match result {
+                Ok(publisher) => {
+                    slots.insert(user_id, Slot::Ready(publisher));
+                }
+                Err(e) => {
+                    log!("Failed to start publisher for Discord user {}: {:?}", user_id, e);
+                    slots.insert(user_id, Slot::Failed);
+                }
+            }
+        });
+        None
+    }
+
+    // Publishers in the old room are dropped, leaving it; speakers get new
+    // ones in the new room on their next frame
+    pub fn switch_room(&self, room_token: &str) {
+        *self.room_token.lock().unwrap() = room_token.to_string();
+        self.slots.lock().unwrap().clear();
+    }
+
+    pub fn remove(&self, user_id: u64) {
+        if self.slots.lock().unwrap().remove(&user_id).is_some() {
+            log!("Closed Talk publisher for Discord user {}", user_id);
+        }
+    }
+}
@@ -0,0 +1,355 @@
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::audio::codec::{OpusDecoder, OpusEncoder};
+use crate::audio::mixer::{Mixer, MixerInput, SharedMixer};
+use crate::audio::ogg::OggOpusWriter;
+use crate::audio::FRAME_SAMPLES;
+use crate::config::{ChannelLayout, DuckingConfig, JitterConfig, RecordingConfig};
+
+// An .ogg recording in progress. Both directions are fed into a mixer of its
+// own, which a writer thread drains every 20ms; dropping the recorder stops
+// the thread and finishes the file.
+struct Recorder {
+    mixer: SharedMixer,
+    path: PathBuf,
+    stop: Arc<AtomicBool>,
+}
+
+impl Recorder {
+    fn start(config: &RecordingConfig, bridge: &str) -> Result<Self> {
+        std::fs::create_dir_all(&config.dir)
+            .with_context(|| format!("Failed to create {}", config.dir.display()))?;
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let path = config.dir.join(format!("{}-{}.ogg", bridge, started.as_secs()));
+
+        let file = File::create(&path).with_context(|| format!("Failed to create {}", path.display()))?;
+        let channels = config.channels;
+        let mut writer = OggOpusWriter::new(BufWriter::new(file), channels.count(), started.as_nanos() as u32)?;
+        let encoder = OpusEncoder::new(channels)?;
+        let mixer = archive_mixer(channels);
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread_mixer = mixer.clone();
+        let thread_stop = stop.clone();
+        let thread_path = path.clone();
+        std::thread::spawn(move || {
+            let result = mix_loop(thread_mixer, encoder, thread_stop, |packet| {
+                writer.write_packet(packet, FRAME_SAMPLES as u64)?;
+                Ok(())
+            })
+            .and_then(|()| Ok(writer.finish()?));
+            if let Err(e) = result {
+                log!("Recording to {} failed: {:?}", thread_path.display(), e);
+            }
+        });
+
+        log!("Recording to {}", path.display());
+        Ok(Self { mixer, path, stop })
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        log!("Finished recording {}", self.path.display());
+    }
+}
+
+// The last few seconds of the call, mixed like a recording but kept in
+// memory as Opus packets, for /bridge clip. Runs for the whole session.
+struct Replay {
+    mixer: SharedMixer,
+    packets: Arc<Mutex<VecDeque<Bytes>>>,
+    channels: ChannelLayout,
+    stop: Arc<AtomicBool>,
+}
+
+impl Replay {
+    fn start(config: &RecordingConfig) -> Result<Self> {
+        let channels = config.channels;
+        let encoder = OpusEncoder::new(channels)?;
+        let mixer = archive_mixer(channels);
+        let frames = (config.replay_secs * 50) as usize;
+        let packets = Arc::new(Mutex::new(VecDeque::with_capacity(frames)));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread_mixer = mixer.clone();
+        let thread_packets = packets.clone();
+        let thread_stop = stop.clone();
+        std::thread::spawn(move || {
+            let result = mix_loop(thread_mixer, encoder, thread_stop, |packet| {
+                let mut packets = thread_packets.lock().unwrap();
+                if packets.len() == frames {
+                    packets.pop_front();
+                }
+                packets.push_back(Bytes::copy_from_slice(packet));
+                Ok(())
+            });
+            if let Err(e) = result {
+                log!("Instant replay buffer failed: {:?}", e);
+            }
+        });
+
+        Ok(Self {
+            mixer,
+            packets,
+            channels,
+            stop,
+        })
+    }
+}
+
+impl Drop for Replay {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+// No ducking in the archive; it hears every source at its own level
+fn archive_mixer(channels: ChannelLayout) -> SharedMixer {
+    Mixer::new(
+        channels.count(),
+        HashMap::new(),
+        &DuckingConfig::default(),
+        &JitterConfig::default(),
+    )
+}
+
+// Mixes and encodes a frame every 20ms until stopped
+fn mix_loop(
+    mixer: SharedMixer,
+    mut encoder: OpusEncoder,
+    stop: Arc<AtomicBool>,
+    mut sink: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<()> {
+    let frame = Duration::from_millis(20);
+    let mut next = Instant::now();
+    while !stop.load(Ordering::Relaxed) {
+        next += frame;
+        std::thread::sleep(next.saturating_duration_since(Instant::now()));
+
+        let pcm: Vec<i16> = mixer
+            .lock()
+            .unwrap()
+            .mix_frame()
+            .iter()
+            .map(|s| (s * i16::MAX as f32) as i16)
+            .collect();
+        sink(&encoder.encode(&pcm)?)?;
+    }
+    Ok(())
+}
+
+// A bridge's recording, if any. Can be started and stopped while a session
+// is running; streams notice on their next frame, like effect chains.
+pub struct RecorderSlot {
+    config: RecordingConfig,
+    bridge: String,
+    current: Mutex<Option<Recorder>>,
+    replay: Mutex<Option<Replay>>,
+    version: AtomicU64,
+}
+
+pub type SharedRecorder = Arc<RecorderSlot>;
+
+impl RecorderSlot {
+    pub fn new(config: RecordingConfig, bridge: &str) -> SharedRecorder {
+        Arc::new(Self {
+            config,
+            bridge: bridge.to_string(),
+            current: Mutex::new(None),
+            replay: Mutex::new(None),
+            version: AtomicU64::new(0),
+        })
+    }
+
+    pub fn enabled_by_default(&self) -> bool {
+        self.config.enabled
+    }
+
+    // Starts a new file unless one is already being written; returns its path
+    pub fn start(&self) -> Result<PathBuf> {
+        let mut current = self.current.lock().unwrap();
+        if let Some(recorder) = current.as_ref() {
+            return Ok(recorder.path.clone());
+        }
+        let recorder = Recorder::start(&self.config, &self.bridge)?;
+        let path = recorder.path.clone();
+        *current = Some(recorder);
+        self.version.fetch_add(1, Ordering::Release);
+        Ok(path)
+    }
+
+    // Returns the finished file, if there was a recording
+    pub fn stop(&self) -> Option<PathBuf> {
+        let recorder = self.current.lock().unwrap().take()?;
+        self.version.fetch_add(1, Ordering::Release);
+        Some(recorder.path.clone())
+    }
+
+    pub fn path(&self) -> Option<PathBuf> {
+        self.current.lock().unwrap().as_ref().map(|r| r.path.clone())
+    }
+
+    // Starts filling the instant replay buffer, unless it is turned off
+    pub fn start_replay(&self) -> Result<()> {
+        if self.config.replay_secs == 0 {
+            return Ok(());
+        }
+        *self.replay.lock().unwrap() = Some(Replay::start(&self.config)?);
+        self.version.fetch_add(1, Ordering::Release);
+        Ok(())
+    }
+
+    fn stop_replay(&self) {
+        if self.replay.lock().unwrap().take().is_some() {
+            self.version.fetch_add(1, Ordering::Release);
+        }
+    }
+
+    // Writes up to the last `seconds` of the call to a file; returns it with
+    // the length actually saved
+    pub fn clip(&self, seconds: u64) -> Result<(PathBuf, Duration)> {
+        let (packets, channels) = {
+            let replay = self.replay.lock().unwrap();
+            let Some(replay) = replay.as_ref() else {
+                anyhow::bail!("No call in progress, or RECORDING_REPLAY_SECS is 0");
+            };
+            let packets = replay.packets.lock().unwrap();
+            let skip = packets.len().saturating_sub((seconds * 50) as usize);
+            let clip: Vec<Bytes> = packets.iter().skip(skip).cloned().collect();
+            (clip, replay.channels)
+        };
+        if packets.is_empty() {
+            anyhow::bail!("Nothing to clip yet");
+        }
+
+        std::fs::create_dir_all(&self.config.dir)
+            .with_context(|| format!("Failed to create {}", self.config.dir.display()))?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let path = self.config.dir.join(format!("{}-clip-{}.ogg", self.bridge, now.as_secs()));
+        let file = File::create(&path).with_context(|| format!("Failed to create {}", path.display()))?;
+        let mut writer = OggOpusWriter::new(BufWriter::new(file), channels.count(), now.as_nanos() as u32)?;
+        for packet in &packets {
+            writer.write_packet(packet, FRAME_SAMPLES as u64)?;
+        }
+        writer.finish()?;
+        Ok((path, Duration::from_millis(packets.len() as u64 * 20)))
+    }
+
+    fn inputs(&self, source: &str) -> Vec<MixerInput> {
+        let current = self.current.lock().unwrap();
+        let replay = self.replay.lock().unwrap();
+        let recording = current.as_ref().map(|r| &r.mixer);
+        let replay = replay.as_ref().map(|r| &r.mixer);
+        recording
+            .into_iter()
+            .chain(replay)
+            .map(|mixer| Mixer::add_input(mixer, source))
+            .collect()
+    }
+}
+
+// Stops the recording and the replay buffer when a session ends
+pub struct RecordingGuard(pub SharedRecorder);
+
+impl Drop for RecordingGuard {
+    fn drop(&mut self) {
+        self.0.stop();
+        self.0.stop_replay();
+    }
+}
+
+// One stream's feed into the recording and the replay buffer (a Discord
+// speaker or a Talk track). Audio arrives at the stream's own layout and is
+// remixed to the file's.
+pub struct RecorderTap {
+    slot: SharedRecorder,
+    source: String,
+    channels: ChannelLayout,
+    version: Option<u64>,
+    inputs: Vec<MixerInput>,
+    // Only created for streams that hand over Opus
+    decoder: Option<OpusDecoder>,
+}
+
+impl RecorderTap {
+    pub fn new(slot: SharedRecorder, source: String, channels: ChannelLayout) -> Self {
+        Self {
+            slot,
+            source,
+            channels,
+            version: None,
+            inputs: Vec::new(),
+            decoder: None,
+        }
+    }
+
+    fn inputs(&mut self) -> &[MixerInput] {
+        let version = self.slot.version.load(Ordering::Acquire);
+        if self.version != Some(version) {
+            self.inputs = self.slot.inputs(&self.source);
+            self.version = Some(version);
+        }
+        &self.inputs
+    }
+
+    pub fn push_pcm(&mut self, pcm: &[i16]) {
+        let from = self.channels.count();
+        let to = self.slot.config.channels.count();
+        let inputs = self.inputs();
+        if inputs.is_empty() {
+            return;
+        }
+        let pcm = remix(pcm, from, to);
+        for input in inputs {
+            input.push(&pcm);
+        }
+    }
+
+    pub fn push_opus(&mut self, payload: &[u8]) {
+        if self.inputs().is_empty() {
+            return;
+        }
+        if self.decoder.is_none() {
+            match OpusDecoder::new(self.slot.config.channels) {
+                Ok(decoder) => self.decoder = Some(decoder),
+                Err(e) => {
+                    log!("Failed to create recording decoder: {:?}", e);
+                    return;
+                }
+            }
+        }
+        let Some(decoder) = self.decoder.as_mut() else {
+            return;
+        };
+        match decoder.decode(payload) {
+            Ok(pcm) => {
+                for input in &self.inputs {
+                    input.push(pcm);
+                }
+            }
+            Err(e) => log!("Failed to decode audio for recording: {:?}", e),
+        }
+    }
+}
+
+// Interleaved mono <-> stereo
+fn remix(pcm: &[i16], from: usize, to: usize) -> Vec<i16> {
+    match (from, to) {
+        (2, 1) => pcm
+            .chunks_exact(2)
+            .map(|lr| ((lr[0] as i32 + lr[1] as i32) / 2) as i16)
+            .collect(),
+        (1, 2) => pcm.iter().flat_map(|&s| [s, s]).collect(),
+        _ => pcm.to_vec(),
+    }
+}
@@ -0,0 +1,343 @@
+use serde::Serialize;
+use serenity::http::Http;
+use serenity::model::id::ChannelId;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+use crate::loop_guard::SharedLoopGuard;
+use crate::nextcloud::protocol::{CallMessage, Event};
+
+pub type SharedRoster = Arc<Roster>;
+
+// Changes missed by a slow subscriber are dropped for it, oldest first
+const CHANGES: usize = 64;
+
+// Talk's in-call flag for being in the call at all
+const IN_CALL: u64 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RosterEntry {
+    pub session_id: String,
+    pub user_id: Option<String>,
+    pub display_name: Option<String>,
+    // Talk's in-call flags, 0 when only in the room
+    pub in_call: u64,
+    // From their screen share offers and unshareScreen messages
+    pub screen: bool,
+    // From their raiseHand messages
+    pub hand: bool,
+    // From their mute messages, or their status data channel
+    pub audio_muted: bool,
+    pub video_muted: bool,
+    // Only said on the status data channel
+    pub speaking: bool,
+}
+
+impl RosterEntry {
+    // Someone we know nothing else about yet
+    fn new(session: &str) -> Self {
+        Self {
+            session_id: session.to_string(),
+            user_id: None,
+            display_name: None,
+            in_call: 0,
+            screen: false,
+            hand: false,
+            audio_muted: false,
+            video_muted: false,
+            speaking: false,
+        }
+    }
+
+    pub fn in_call(&self) -> bool {
+        self.in_call & IN_CALL != 0
+    }
+
+    // For logs and messages: the display name, else the user id
+    pub fn name(&self) -> &str {
+        self.display_name
+            .as_deref()
+            .or(self.user_id.as_deref())
+            .filter(|n| !n.is_empty())
+            .unwrap_or("a guest")
+    }
+}
+
+// An in-call reaction from someone in the room
+#[derive(Debug, Clone)]
+pub struct Reaction {
+    pub entry: RosterEntry,
+    pub reaction: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum RosterChange {
+    Joined(RosterEntry),
+    // Name, in-call flags, screen share, hand, mute or speaking changed;
+    // the entry as it is now
+    Updated(RosterEntry),
+    Left(RosterEntry),
+}
+
+// Who is in the main Talk room of a session, kept from the signaling
+// server's room and participant events. Other parts of the bridge read it
+// or subscribe to its changes, and to the reactions of those in it.
+pub struct Roster {
+    participants: Mutex<HashMap<String, RosterEntry>>,
+    changes: broadcast::Sender<RosterChange>,
+    reactions: broadcast::Sender<Reaction>,
+}
+
+impl Roster {
+    pub fn new() -> SharedRoster {
+        Arc::new(Self {
+            participants: Mutex::new(HashMap::new()),
+            changes: broadcast::channel(CHANGES).0,
+            reactions: broadcast::channel(CHANGES).0,
+        })
+    }
+
+    // A new session starts with nobody known
+    pub fn clear(&self) {
+        self.participants.lock().unwrap().clear();
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<RosterChange> {
+        self.changes.subscribe()
+    }
+
+    pub fn reactions(&self) -> broadcast::Receiver<Reaction> {
+        self.reactions.subscribe()
+    }
+
+    pub fn get(&self, session: &str) -> Option<RosterEntry> {
+        self.participants.lock().unwrap().get(session).cloned()
+    }
+
+    // Sorted by session id, so reports are stable
+    pub fn participants(&self) -> Vec<RosterEntry> {
+        let mut entries: Vec<RosterEntry> = self.participants.lock().unwrap().values().cloned().collect();
+        entries.sort_by(|a, b| a.session_id.cmp(&b.session_id));
+        entries
+    }
+
+    // What participants say about themselves, over signaling or the status
+    // data channel of their connection: guests name themselves in
+    // nickChanged messages rather than events, and some clients only
+    // tell their mute and speaking state on the data channel
+    pub fn apply_status(&self, session: &str, status: &CallMessage) {
+        let media = status.payload.get("name").and_then(|name| name.as_str());
+        let update: Box<dyn FnOnce(&mut RosterEntry)> = match (status.kind.as_str(), media) {
+            ("nickChanged", _) => match status.nick() {
+                Some(name) => Box::new(move |entry| entry.display_name = Some(name.to_string())),
+                None => return,
+            },
+            ("audioOn" | "audioOff", _) => Box::new(|entry| entry.audio_muted = status.kind == "audioOff"),
+            ("videoOn" | "videoOff", _) => Box::new(|entry| entry.video_muted = status.kind == "videoOff"),
+            ("mute" | "unmute", Some("audio")) => Box::new(|entry| entry.audio_muted = status.kind == "mute"),
+            ("mute" | "unmute", Some("video")) => Box::new(|entry| entry.video_muted = status.kind == "mute"),
+            ("speaking" | "stoppedSpeaking", _) => Box::new(|entry| entry.speaking = status.kind == "speaking"),
+            _ => return,
+        };
+        let change = upsert(&mut self.participants.lock().unwrap(), session, update);
+        if let Some(change) = change {
+            let _ = self.changes.send(change);
+        }
+    }
+
+    // Screen sharing isn't in the room's events, only in the sharer's call
+    // messages
+    pub fn set_screen(&self, session: &str, sharing: bool) {
+        let change = upsert(&mut self.participants.lock().unwrap(), session, |entry| entry.screen = sharing);
+        if let Some(change) = change {
+            let _ = self.changes.send(change);
+        }
+    }
+
+    // Raised hands, like screen shares, are only in call messages
+    pub fn set_hand(&self, session: &str, raised: bool) {
+        let change = upsert(&mut self.participants.lock().unwrap(), session, |entry| entry.hand = raised);
+        if let Some(change) = change {
+            let _ = self.changes.send(change);
+        }
+    }
+
+    // Passed on with who reacted, as far as they are known
+    pub fn react(&self, session: &str, reaction: &str) {
+        let entry = self.get(session).unwrap_or_else(|| RosterEntry::new(session));
+        let _ = self.reactions.send(Reaction { entry, reaction: reaction.to_string() });
+    }
+
+    pub fn apply(&self, event: &Event) {
+        let mut changes = Vec::new();
+        {
+            let mut participants = self.participants.lock().unwrap();
+            match (event.target.as_str(), event.kind.as_str()) {
+                ("room", "join") => {
+                    for joined in &event.join {
+                        let display_name = joined
+                            .user
+                            .as_ref()
+                            .and_then(|u| u.get("displayname"))
+                            .and_then(|n| n.as_str())
+                            .map(str::to_string);
+                        changes.extend(upsert(&mut participants, &joined.sessionid, |entry| {
+                            merge(&mut entry.user_id, joined.userid.clone());
+                            merge(&mut entry.display_name, display_name.clone());
+                        }));
+                    }
+                }
+                ("room", "leave") => {
+                    for session in &event.leave {
+                        changes.extend(participants.remove(session).map(RosterChange::Left));
+                    }
+                }
+                ("participants", "update") => {
+                    let Some(update) = &event.update else {
+                        return;
+                    };
+                    if update.all {
+                        for entry in participants.values_mut().filter(|e| e.in_call != update.incall) {
+                            entry.in_call = update.incall;
+                            changes.push(RosterChange::Updated(entry.clone()));
+                        }
+                    }
+                    for user in update.users.iter().filter(|u| !u.session_id.is_empty()) {
+                        changes.extend(upsert(&mut participants, &user.session_id, |entry| {
+                            merge(&mut entry.user_id, user.user_id.clone());
+                            merge(&mut entry.display_name, user.display_name.clone());
+                            entry.in_call = user.in_call;
+                        }));
+                    }
+                }
+                _ => {}
+            }
+        }
+        for change in changes {
+            // Nobody listening is fine
+            let _ = self.changes.send(change);
+        }
+    }
+}
+
+// Applies `update` to the session's entry, creating it if needed. Returns
+// what changed, if anything.
+fn upsert(
+    participants: &mut HashMap<String, RosterEntry>,
+    session: &str,
+    update: impl FnOnce(&mut RosterEntry),
+) -> Option<RosterChange> {
+    match participants.get_mut(session) {
+        Some(entry) => {
+            let before = entry.clone();
+            update(entry);
+            (before != *entry).then(|| RosterChange::Updated(entry.clone()))
+        }
+        None => {
+            let mut entry = RosterEntry::new(session);
+            update(&mut entry);
+            participants.insert(session.to_string(), entry.clone());
+            Some(RosterChange::Joined(entry))
+        }
+    }
+}
+
+// Events only carry what they know; keep what an earlier one said
+fn merge(field: &mut Option<String>, value: Option<String>) {
+    if value.is_some() {
+        *field = value;
+    }
+}
+
+// Logs Talk participants joining and leaving the call, leaving out the
+// bridge's own sessions
+pub async fn log_changes(mut changes: broadcast::Receiver<RosterChange>, loops: SharedLoopGuard) {
+    let mut in_call = HashMap::new();
+    loop {
+        let change = match changes.recv().await {
+            Ok(change) => change,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let (entry, now) = match &change {
+            RosterChange::Joined(entry) | RosterChange::Updated(entry) => (entry, entry.in_call()),
+            RosterChange::Left(entry) => (entry, false),
+        };
+        if loops.is_own_talk_session(&entry.session_id) {
+            continue;
+        }
+        let before = match &change {
+            RosterChange::Left(_) => in_call.remove(&entry.session_id),
+            _ => in_call.insert(entry.session_id.clone(), now),
+        };
+        match (before.unwrap_or(false), now) {
+            (false, true) => log!("Talk: {} joined the call", entry.name()),
+            (true, false) => log!("Talk: {} left the call", entry.name()),
+            _ => {}
+        }
+    }
+}
+
+// Tells the Discord channel when someone in Talk starts or stops sharing
+// their screen, which Discord can't see
+pub async fn announce_screens(changes: broadcast::Receiver<RosterChange>, http: Arc<Http>, channel_id: ChannelId) {
+    let said = ("started sharing their screen", "stopped sharing their screen");
+    announce(changes, http, channel_id, |entry| entry.screen, said).await
+}
+
+// Tells the Discord channel when someone in Talk raises or lowers their hand
+pub async fn announce_hands(changes: broadcast::Receiver<RosterChange>, http: Arc<Http>, channel_id: ChannelId) {
+    announce(changes, http, channel_id, |entry| entry.hand, ("raised their hand", "lowered their hand")).await
+}
+
+// Posts "<name> <on|off> in Talk" whenever `flag` of an entry changes
+async fn announce(
+    mut changes: broadcast::Receiver<RosterChange>,
+    http: Arc<Http>,
+    channel_id: ChannelId,
+    flag: fn(&RosterEntry) -> bool,
+    (on, off): (&str, &str),
+) {
+    let mut set = HashSet::new();
+    loop {
+        let change = match changes.recv().await {
+            Ok(change) => change,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let (entry, now) = match &change {
+            RosterChange::Joined(entry) | RosterChange::Updated(entry) => (entry, flag(entry)),
+            RosterChange::Left(entry) => (entry, false),
+        };
+        let text = match (set.contains(&entry.session_id), now) {
+            (false, true) => format!("{} {} in Talk", entry.name(), on),
+            (true, false) => format!("{} {} in Talk", entry.name(), off),
+            _ => continue,
+        };
+        if now {
+            set.insert(entry.session_id.clone());
+        } else {
+            set.remove(&entry.session_id);
+        }
+        if let Err(e) = channel_id.say(&http, text).await {
+            log!("Failed to tell Discord that {} {}: {:?}", entry.name(), if now { on } else { off }, e);
+        }
+    }
+}
+
+// Posts Talk's in-call reactions into the Discord channel, which has
+// nothing like them for voice
+pub async fn mirror_reactions(mut reactions: broadcast::Receiver<Reaction>, http: Arc<Http>, channel_id: ChannelId) {
+    loop {
+        let reaction = match reactions.recv().await {
+            Ok(reaction) => reaction,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let text = format!("{} reacted {} in Talk", reaction.entry.name(), reaction.reaction);
+        if let Err(e) = channel_id.say(&http, text).await {
+            log!("Failed to mirror a Talk reaction to Discord: {:?}", e);
+        }
+    }
+}
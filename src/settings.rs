@@ -0,0 +1,153 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+// What is played when someone joins or leaves on the other side
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AnnouncementStyle {
+    Off,
+    Chime,
+    Speech,
+    Both,
+}
+
+impl FromStr for AnnouncementStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(AnnouncementStyle::Off),
+            "chime" => Ok(AnnouncementStyle::Chime),
+            "speech" => Ok(AnnouncementStyle::Speech),
+            "both" => Ok(AnnouncementStyle::Both),
+            other => Err(format!("unknown announcement style: {} (off, chime, speech or both)", other)),
+        }
+    }
+}
+
+impl AnnouncementStyle {
+    pub fn chime(self) -> bool {
+        matches!(self, AnnouncementStyle::Chime | AnnouncementStyle::Both)
+    }
+
+    pub fn speech(self) -> bool {
+        matches!(self, AnnouncementStyle::Speech | AnnouncementStyle::Both)
+    }
+}
+
+// Options that can be set once globally or per guild instead of in every
+// bridge definition. Unset fields fall through to the next level up, and
+// finally to the environment.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Settings {
+    // Passed to the TTS command as {language}
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub announcements: Option<AnnouncementStyle>,
+    // Playback gain (dB) for Talk participants without a volume of their own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gain_db: Option<f32>,
+}
+
+pub const KEYS: &[&str] = &["language", "announcements", "gain"];
+
+impl Settings {
+    // `value: None` unsets the key
+    pub fn set(&mut self, key: &str, value: Option<&str>) -> Result<(), String> {
+        match key {
+            "language" => self.language = value.map(|v| v.trim().to_string()),
+            "announcements" => self.announcements = value.map(str::parse).transpose()?,
+            "gain" => {
+                self.gain_db = value
+                    .map(|v| crate::commands::parse_gain_db(v).ok_or(format!("invalid gain: {}", v)))
+                    .transpose()?
+            }
+            other => return Err(format!("unknown setting: {} ({})", other, KEYS.join(", "))),
+        }
+        Ok(())
+    }
+
+    // Fields set here win over `fallback`
+    fn or(&self, fallback: &Settings) -> Settings {
+        Settings {
+            language: self.language.clone().or_else(|| fallback.language.clone()),
+            announcements: self.announcements.or(fallback.announcements),
+            gain_db: self.gain_db.or(fallback.gain_db),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Settings::default()
+    }
+}
+
+// Where a setting is stored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Scope {
+    Global,
+    Guild(u64),
+    Bridge(String),
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    // "global", "guild:<id>" or "bridge:<name>"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "global" => Ok(Scope::Global),
+            Some(("guild", id)) => id.parse().map(Scope::Guild).map_err(|_| format!("invalid guild ID: {}", id)),
+            Some(("bridge", name)) if !name.is_empty() => Ok(Scope::Bridge(name.to_string())),
+            _ => Err(format!("invalid scope: {} (global, guild:<id> or bridge:<name>)", s)),
+        }
+    }
+}
+
+// The persisted hierarchy: global -> guild -> bridge
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct SettingsTree {
+    #[serde(default)]
+    pub global: Settings,
+    #[serde(default)]
+    pub guilds: HashMap<u64, Settings>,
+    #[serde(default)]
+    pub bridges: HashMap<String, Settings>,
+}
+
+impl SettingsTree {
+    // What is set at one level; nothing for a level never written to
+    pub fn get(&self, scope: &Scope) -> Settings {
+        match scope {
+            Scope::Global => Some(&self.global),
+            Scope::Guild(id) => self.guilds.get(id),
+            Scope::Bridge(name) => self.bridges.get(name),
+        }
+        .cloned()
+        .unwrap_or_default()
+    }
+
+    pub fn get_mut(&mut self, scope: &Scope) -> &mut Settings {
+        match scope {
+            Scope::Global => &mut self.global,
+            Scope::Guild(id) => self.guilds.entry(*id).or_default(),
+            Scope::Bridge(name) => self.bridges.entry(name.clone()).or_default(),
+        }
+    }
+
+    // Drops levels left with nothing set
+    pub fn prune(&mut self) {
+        self.guilds.retain(|_, s| !s.is_empty());
+        self.bridges.retain(|_, s| !s.is_empty());
+    }
+
+    pub fn resolve(&self, guild_id: u64, bridge: &str) -> Settings {
+        let empty = Settings::default();
+        self.bridges
+            .get(bridge)
+            .unwrap_or(&empty)
+            .or(self.guilds.get(&guild_id).unwrap_or(&empty))
+            .or(&self.global)
+    }
+}
@@ -0,0 +1,100 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::audio::command::WavCommand;
+
+const SOUND_URL: &str = "https://cdn.discordapp.com/soundboard-sounds/";
+const DECODE_TIMEOUT: Duration = Duration::from_secs(10);
+// Discord caps uploads at 512KB; anything much bigger isn't a sound
+const MAX_SOUND_BYTES: usize = 2 * 1024 * 1024;
+// Decoded sounds kept around; servers have a few dozen at most
+const MAX_CACHED: usize = 64;
+
+// VOICE_CHANNEL_EFFECT_SEND, which serenity doesn't model yet. Sent when
+// someone in a voice channel plays a soundboard sound (or an emoji effect,
+// which has no sound).
+#[derive(Debug, Deserialize)]
+pub struct VoiceChannelEffect {
+    #[serde(with = "snowflake")]
+    pub guild_id: u64,
+    #[serde(with = "snowflake")]
+    pub channel_id: u64,
+    #[serde(with = "snowflake")]
+    pub user_id: u64,
+    #[serde(default, with = "snowflake::option")]
+    pub sound_id: Option<u64>,
+    // 0 to 1, as set on the sound
+    pub sound_volume: Option<f32>,
+}
+
+// Soundboard sounds don't travel in the sender's voice stream: every client
+// fetches the sound from Discord's CDN and plays it locally. So the bridge
+// does the same and plays it into Talk.
+pub struct Soundboard {
+    http: reqwest::Client,
+    decoder: WavCommand,
+    // Mono 48kHz, per sound id
+    cache: Mutex<HashMap<u64, Arc<Vec<f32>>>>,
+}
+
+impl Soundboard {
+    // `decoder` reads any audio file on stdin and writes WAV to stdout
+    pub fn new(decoder: &str) -> Result<Self> {
+        Ok(Self {
+            http: reqwest::Client::new(),
+            decoder: WavCommand::new(decoder, &[], DECODE_TIMEOUT).context("Invalid soundboard decoder")?,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub async fn sound(&self, sound_id: u64) -> Result<Arc<Vec<f32>>> {
+        if let Some(samples) = self.cache.lock().unwrap().get(&sound_id) {
+            return Ok(samples.clone());
+        }
+
+        let resp = self
+            .http
+            .get(format!("{}{}", SOUND_URL, sound_id))
+            .send()
+            .await
+            .context("Failed to fetch soundboard sound")?;
+        if !resp.status().is_success() {
+            anyhow::bail!("Discord CDN returned {} for sound {}", resp.status(), sound_id);
+        }
+        let data = resp.bytes().await.context("Failed to fetch soundboard sound")?;
+        if data.len() > MAX_SOUND_BYTES {
+            anyhow::bail!("Soundboard sound {} is too big ({} bytes)", sound_id, data.len());
+        }
+        let samples = Arc::new(self.decoder.run(&data).await.context("Failed to decode soundboard sound")?);
+
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHED {
+            cache.clear();
+        }
+        cache.insert(sound_id, samples.clone());
+        Ok(samples)
+    }
+}
+
+// Discord sends ids as strings
+mod snowflake {
+    use serde::{Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<u64, D::Error> {
+        String::deserialize(d)?.parse().map_err(serde::de::Error::custom)
+    }
+
+    pub mod option {
+        use serde::{Deserialize, Deserializer};
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u64>, D::Error> {
+            match Option::<String>::deserialize(d)? {
+                Some(id) => id.parse().map(Some).map_err(serde::de::Error::custom),
+                None => Ok(None),
+            }
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+pub type SharedSpeakers = Arc<Mutex<SpeakerMap>>;
+
+// Which Discord user is behind each SSRC in the voice channel right now.
+// Filled from Speaking updates, cleared when the user disconnects.
+#[derive(Default)]
+pub struct SpeakerMap {
+    users: HashMap<u32, u64>,
+}
+
+impl SpeakerMap {
+    pub fn new() -> SharedSpeakers {
+        Arc::new(Mutex::new(Self::default()))
+    }
+
+    pub fn insert(&mut self, ssrc: u32, user_id: u64) {
+        self.users.insert(ssrc, user_id);
+    }
+
+    pub fn user(&self, ssrc: u32) -> Option<u64> {
+        self.users.get(&ssrc).copied()
+    }
+
+    // A user can hold more than one SSRC over a session
+    pub fn remove_user(&mut self, user_id: u64) {
+        self.users.retain(|_, u| *u != user_id);
+    }
+
+    pub fn clear(&mut self) {
+        self.users.clear();
+    }
+
+    // Connected users, sorted and deduplicated
+    pub fn user_ids(&self) -> Vec<u64> {
+        let mut ids: Vec<u64> = self.users.values().copied().collect();
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+}
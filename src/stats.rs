@@ -0,0 +1,186 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use webrtc::stats::{StatsReport, StatsReportType};
+
+pub type SharedStats = Arc<Mutex<CallStats>>;
+
+// Per-call bookkeeping for the Discord side: how long the call ran and how
+// long each Discord user spoke, and how cleanly their packets arrived
+#[derive(Default)]
+pub struct CallStats {
+    started: Option<Instant>,
+    speaking: HashMap<u64, Duration>,
+    pub rtp: RtpCounters,
+    // The same for the Talk tracks
+    pub talk_rtp: RtpCounters,
+    pub concealment: ConcealmentCounters,
+    pub webrtc: WebRtcStats,
+    pub connection: ConnectionCounters,
+}
+
+// Sequence number accounting for Discord RTP, summed over all speakers
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct RtpCounters {
+    // Skipped after waiting for them
+    pub lost: u64,
+    // Arrived ahead of an earlier packet
+    pub reordered: u64,
+    pub duplicates: u64,
+    // Turned up after they were already skipped
+    pub late: u64,
+}
+
+// Talk packets lost on the way in, summed over all tracks: rebuilt from the
+// next packet's FEC data, or filled in by the decoder's PLC
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct ConcealmentCounters {
+    pub fec: u64,
+    pub plc: u64,
+    pub red: u64,
+}
+
+// How the session's connection to Talk fared: its state as last reported,
+// how often it or a peer connection failed or was closed under us, and what
+// was done about it
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ConnectionCounters {
+    pub state: Option<String>,
+    pub failures: u64,
+    pub ice_restarts: u64,
+    // Started over on a new connection
+    pub reconnects: u64,
+    pub peer_failures: u64,
+}
+
+// Jitter buffer accounting for the Talk tracks in the Discord-bound mixer.
+// Underruns mean audio arrived too late to play; overruns mean it piled up
+// faster than Discord took it, which usually points at an overloaded host.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct BufferCounters {
+    pub underruns: u64,
+    pub overruns: u64,
+    // Deepest buffer any track currently uses
+    pub depth_ms: u64,
+}
+
+// Talk media as the WebRTC stack reports it, summed over the session's
+// peer connections. webrtc-rs counts neither loss nor jitter for what it
+// receives, so loss is only known for what we send, from Talk's receiver
+// reports.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct WebRtcStats {
+    pub connections: usize,
+    // Talk -> bridge audio
+    pub inbound_packets: u64,
+    pub inbound_bytes: u64,
+    pub inbound_kbps: u64,
+    // Talk video, received only to be thrown away
+    pub inbound_video_packets: u64,
+    // Bridge -> Talk
+    pub outbound_packets: u64,
+    pub outbound_bytes: u64,
+    pub outbound_kbps: u64,
+    pub outbound_lost: u64,
+    // Round trip to Talk or the MCU, the slowest connection's
+    pub rtt_ms: Option<u64>,
+}
+
+impl WebRtcStats {
+    // Adds the RTP streams of one connection's get_stats
+    pub fn add(&mut self, report: &StatsReport) {
+        self.connections += 1;
+        let ms = |seconds: f64| (seconds * 1000.0) as u64;
+        let mut rtt = None;
+        let mut pair_rtt = None;
+        for stats in report.reports.values() {
+            match stats {
+                StatsReportType::InboundRTP(s) if s.kind == "video" => {
+                    self.inbound_video_packets += s.packets_received;
+                }
+                StatsReportType::InboundRTP(s) => {
+                    self.inbound_packets += s.packets_received;
+                    self.inbound_bytes += s.bytes_received;
+                }
+                StatsReportType::OutboundRTP(s) => {
+                    self.outbound_packets += s.packets_sent;
+                    self.outbound_bytes += s.bytes_sent;
+                }
+                StatsReportType::RemoteInboundRTP(s) => {
+                    self.outbound_lost += s.packets_lost.max(0) as u64;
+                    rtt = rtt.max(s.round_trip_time.map(ms));
+                }
+                StatsReportType::CandidatePair(s) if s.nominated => pair_rtt = Some(ms(s.current_round_trip_time)),
+                _ => {}
+            }
+        }
+        // Without receiver reports yet, ICE's own measurement
+        if let Some(rtt) = rtt.or(pair_rtt).filter(|ms| *ms > 0) {
+            self.rtt_ms = self.rtt_ms.max(Some(rtt));
+        }
+    }
+
+    // Bitrates since the `previous` sample, taken `elapsed` ago. Counters of
+    // connections that were replaced in between start again from 0.
+    pub fn rates(&mut self, previous: &WebRtcStats, elapsed: Duration) {
+        let kbps = |now: u64, before: u64| match elapsed.as_millis() as u64 {
+            0 => 0,
+            ms => now.saturating_sub(before) * 8 / ms,
+        };
+        self.inbound_kbps = kbps(self.inbound_bytes, previous.inbound_bytes);
+        self.outbound_kbps = kbps(self.outbound_bytes, previous.outbound_bytes);
+    }
+}
+
+// Speaking time per Discord user id, longest first
+pub struct CallSnapshot {
+    pub duration: Duration,
+    pub speakers: Vec<(u64, Duration)>,
+}
+
+impl CallStats {
+    pub fn new() -> SharedStats {
+        Arc::new(Mutex::new(Self::default()))
+    }
+
+    // Called once the bridge is actually in the Discord channel
+    pub fn mark_started(&mut self) {
+        self.started.get_or_insert_with(Instant::now);
+    }
+
+    // Joined, even if they never say anything
+    pub fn note_user(&mut self, user_id: u64) {
+        self.speaking.entry(user_id).or_default();
+    }
+
+    pub fn add_speech(&mut self, user_id: u64, duration: Duration) {
+        *self.speaking.entry(user_id).or_default() += duration;
+    }
+
+    // None if the call never got going
+    pub fn snapshot(&self) -> Option<CallSnapshot> {
+        let started = self.started?;
+
+        let mut speakers: Vec<_> = self.speaking.iter().map(|(u, d)| (*u, *d)).collect();
+        speakers.sort_by_key(|s| std::cmp::Reverse(s.1));
+
+        Some(CallSnapshot {
+            duration: started.elapsed(),
+            speakers,
+        })
+    }
+}
+
+// "1h 5m", "12m 30s", "45s"
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if h > 0 {
+        format!("{}h {}m", h, m)
+    } else if m > 0 {
+        format!("{}m {}s", m, s)
+    } else {
+        format!("{}s", s)
+    }
+}
@@ -0,0 +1,179 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::migrate::{self, STATE_VERSION};
+use crate::settings::{Scope, Settings, SettingsTree};
+
+// Everything the bridge remembers between runs. New fields need
+// #[serde(default)] so older state files keep loading; anything else that
+// breaks them needs a STATE_VERSION bump and a step in migrate.rs.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct StoreData {
+    #[serde(default)]
+    pub version: u32,
+    // Playback gain (dB) per Talk participant, applied in the Discord-bound mixer
+    #[serde(default)]
+    pub talk_volumes: HashMap<String, f32>,
+    // Talk room per Discord channel, for rooms created with /bridge newroom;
+    // takes precedence over the configured room token
+    #[serde(default)]
+    pub talk_rooms: HashMap<u64, String>,
+    // Discord channel per bridge name, for bridges moved with /bridge rebind;
+    // takes precedence over the configured channel
+    #[serde(default)]
+    pub discord_channels: HashMap<String, u64>,
+    // Discord users whose audio is never forwarded to Talk, from /bridge exclude
+    #[serde(default)]
+    pub excluded_discord_users: HashSet<u64>,
+    #[serde(default)]
+    pub settings: SettingsTree,
+    // The latest OAuth2 refresh token, with the configured one it replaced
+    #[serde(default)]
+    pub oauth_refresh: Option<OAuthRefresh>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OAuthRefresh {
+    pub configured: String,
+    pub current: String,
+}
+
+// Small JSON file store. Every change is written straight back to disk;
+// the data is tiny and only changes on operator commands.
+pub struct Store {
+    path: PathBuf,
+    data: Mutex<StoreData>,
+}
+
+impl Store {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let (data, from) = match std::fs::read_to_string(&path) {
+            Ok(text) => {
+                let mut value: serde_json::Value = serde_json::from_str(&text)
+                    .with_context(|| format!("Failed to parse state file {}", path.display()))?;
+                let from = migrate::state(&mut value)
+                    .with_context(|| format!("Failed to upgrade state file {}", path.display()))?;
+                let data: StoreData = serde_json::from_value(value)
+                    .with_context(|| format!("Failed to parse state file {}", path.display()))?;
+                (data, Some(from))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (
+                StoreData {
+                    version: STATE_VERSION,
+                    ..Default::default()
+                },
+                None,
+            ),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read state file {}", path.display()))
+            }
+        };
+
+        let store = Self { path, data: Mutex::new(data) };
+        // Written back right away so it only upgrades once, next to a copy
+        // an older release can still read
+        if let Some(from) = from.filter(|v| *v < STATE_VERSION) {
+            let backup = migrate::backup(&store.path, from)?;
+            store.save(&store.data.lock().unwrap())?;
+            log!(
+                "Upgraded state file {} from version {} to {} (original kept as {})",
+                store.path.display(),
+                from,
+                STATE_VERSION,
+                backup.display()
+            );
+        }
+        Ok(store)
+    }
+
+    pub fn talk_volumes(&self) -> HashMap<String, f32> {
+        self.data.lock().unwrap().talk_volumes.clone()
+    }
+
+    pub fn set_talk_volume(&self, participant: &str, db: f32) -> Result<()> {
+        self.update(|data| {
+            data.talk_volumes.insert(participant.to_string(), db);
+        })
+    }
+
+    pub fn talk_room(&self, channel_id: u64) -> Option<String> {
+        self.data.lock().unwrap().talk_rooms.get(&channel_id).cloned()
+    }
+
+    pub fn set_talk_room(&self, channel_id: u64, room_token: &str) -> Result<()> {
+        self.update(|data| {
+            data.talk_rooms.insert(channel_id, room_token.to_string());
+        })
+    }
+
+    pub fn discord_channel(&self, bridge: &str) -> Option<u64> {
+        self.data.lock().unwrap().discord_channels.get(bridge).copied()
+    }
+
+    pub fn set_discord_channel(&self, bridge: &str, channel_id: u64) -> Result<()> {
+        self.update(|data| {
+            data.discord_channels.insert(bridge.to_string(), channel_id);
+        })
+    }
+
+    pub fn excluded_discord_users(&self) -> HashSet<u64> {
+        self.data.lock().unwrap().excluded_discord_users.clone()
+    }
+
+    pub fn set_discord_user_excluded(&self, user_id: u64, excluded: bool) -> Result<()> {
+        self.update(|data| {
+            if excluded {
+                data.excluded_discord_users.insert(user_id);
+            } else {
+                data.excluded_discord_users.remove(&user_id);
+            }
+        })
+    }
+
+    // Only while NEXTCLOUD_REFRESH_TOKEN is still the one it replaced
+    pub fn oauth_refresh_token(&self, configured: &str) -> Option<String> {
+        let data = self.data.lock().unwrap();
+        let refresh = data.oauth_refresh.as_ref().filter(|r| r.configured == configured)?;
+        Some(refresh.current.clone())
+    }
+
+    pub fn set_oauth_refresh_token(&self, configured: &str, current: &str) -> Result<()> {
+        self.update(|data| {
+            data.oauth_refresh = Some(OAuthRefresh {
+                configured: configured.to_string(),
+                current: current.to_string(),
+            });
+        })
+    }
+
+    pub fn settings(&self) -> SettingsTree {
+        self.data.lock().unwrap().settings.clone()
+    }
+
+    // `value: None` unsets the key at that level
+    pub fn set_setting(&self, scope: &Scope, key: &str, value: Option<&str>) -> Result<()> {
+        // Validated on a copy, so a bad value isn't half applied and leaves
+        // no empty level behind
+        let mut settings: Settings = self.data.lock().unwrap().settings.get(scope);
+        settings.set(key, value).map_err(anyhow::Error::msg)?;
+        self.update(|data| {
+            *data.settings.get_mut(scope) = settings;
+            data.settings.prune();
+        })
+    }
+
+    fn update(&self, f: impl FnOnce(&mut StoreData)) -> Result<()> {
+        let mut data = self.data.lock().unwrap();
+        f(&mut data);
+        self.save(&data)
+    }
+
+    fn save(&self, data: &StoreData) -> Result<()> {
+        let text = serde_json::to_string_pretty(data)?;
+        migrate::write(&self.path, &text).context("Failed to save state file")
+    }
+}
@@ -0,0 +1,61 @@
+use serenity::http::Http;
+use serenity::model::id::{ChannelId, GuildId, UserId};
+use std::sync::Arc;
+
+use crate::nextcloud::chat::ChatClient;
+use crate::stats::{format_duration, CallSnapshot, SharedStats};
+
+// Posts an end-of-call summary to both the Talk chat and the Discord voice
+// channel's chat when dropped, so it fires however the session ends
+// (error, stop command, shutdown).
+pub struct CallSummary {
+    pub stats: SharedStats,
+    pub http: Arc<Http>,
+    pub guild_id: GuildId,
+    pub channel_id: ChannelId,
+    pub chat: Arc<ChatClient>,
+    pub room_token: String,
+}
+
+impl Drop for CallSummary {
+    fn drop(&mut self) {
+        let Some(snapshot) = self.stats.lock().unwrap().snapshot() else {
+            return;
+        };
+
+        let http = self.http.clone();
+        let guild_id = self.guild_id;
+        let channel_id = self.channel_id;
+        let chat = self.chat.clone();
+        let room_token = self.room_token.clone();
+        tokio::spawn(async move {
+            let text = format_summary(&http, guild_id, &snapshot).await;
+            if let Err(e) = chat.send_message(&room_token, &text).await {
+                log!("Failed to post call summary to Talk: {:?}", e);
+            }
+            if let Err(e) = channel_id.say(&http, &text).await {
+                log!("Failed to post call summary to Discord: {:?}", e);
+            }
+        });
+    }
+}
+
+async fn format_summary(http: &Http, guild_id: GuildId, snapshot: &CallSnapshot) -> String {
+    let mut text = format!("Bridged call ended after {}.", format_duration(snapshot.duration));
+    if snapshot.speakers.is_empty() {
+        text.push_str("\nNobody joined from Discord.");
+        return text;
+    }
+
+    let mut names = Vec::with_capacity(snapshot.speakers.len());
+    for (user_id, speaking) in &snapshot.speakers {
+        let name = match guild_id.member(http, UserId::new(*user_id)).await {
+            Ok(member) => member.display_name().to_string(),
+            Err(_) => format!("user {}", user_id),
+        };
+        names.push(format!("{} ({})", name, format_duration(*speaking)));
+    }
+    text.push_str("\nDiscord participants: ");
+    text.push_str(&names.join(", "));
+    text
+}
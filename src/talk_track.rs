@@ -0,0 +1,203 @@
+use bytes::Bytes;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+use webrtc::media::Sample;
+use webrtc::rtp::header::Header;
+use webrtc::rtp::packet::Packet;
+use webrtc::track::track_local::TrackLocalWriter;
+
+use crate::audio::{red, SAMPLE_RATE};
+use crate::nextcloud::webrtc::{LocalAudioTrack, OPUS_PAYLOAD_TYPE};
+
+// Wall clock slack before a source change counts as a gap; covers
+// repacketizer buffering and network jitter
+const WALL_TOLERANCE: Duration = Duration::from_millis(200);
+
+// Where a frame written to the track came from
+pub enum Timing {
+    // An RTP packet from Discord (48kHz) or a Talk track relayed into another
+    // room (its negotiated clock), and how long it plays, which is where the
+    // source's next one should start
+    Rtp { ssrc: u32, timestamp: u32, clock_rate: u32, duration: Duration },
+    // Generated locally, placed by wall clock
+    Wall,
+}
+
+// Keeps the track's RTP timeline in step with its sources. The track
+// advances its timestamp by each sample's duration, so lost frames and DTX
+// pauses have to be added to a duration or everything after them plays early
+// and the timeline drifts.
+struct TrackClock {
+    started: Instant,
+    // Total duration handed to the track so far
+    written: Duration,
+    // Gap waiting to be added to the next sample
+    pending: Duration,
+    // SSRC and the timestamp its next frame should have
+    source: Option<(u32, u32)>,
+}
+
+impl TrackClock {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            written: Duration::ZERO,
+            pending: Duration::ZERO,
+            source: None,
+        }
+    }
+
+    fn note_frame(&mut self, timing: Timing) {
+        match timing {
+            Timing::Rtp { ssrc, timestamp, clock_rate, duration } => {
+                let gap = match self.source {
+                    Some((last, expected)) if last == ssrc => {
+                        let delta = timestamp.wrapping_sub(expected);
+                        // Anything "behind" is a stray; reordering already ran
+                        if delta < u32::MAX / 2 {
+                            Duration::from_secs_f64(delta as f64 / clock_rate as f64)
+                        } else {
+                            Duration::ZERO
+                        }
+                    }
+                    // New speaker on the track: their timestamps say nothing
+                    // about how long the track was idle
+                    _ => self.wall_gap(),
+                };
+                self.pending += gap;
+                let ticks = (duration.as_secs_f64() * clock_rate as f64).round() as u32;
+                self.source = Some((ssrc, timestamp.wrapping_add(ticks)));
+            }
+            Timing::Wall => {
+                self.pending += self.wall_gap();
+                self.source = None;
+            }
+        }
+    }
+
+    fn wall_gap(&self) -> Duration {
+        let behind = self.started.elapsed().saturating_sub(self.written + self.pending);
+        if behind > WALL_TOLERANCE {
+            behind
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    // A sample's duration only moves the one after it, so a gap lands one
+    // packet late; the timeline is back in step from there on
+    fn duration(&mut self, nominal: Duration) -> Duration {
+        let duration = nominal + std::mem::take(&mut self.pending);
+        self.written += duration;
+        duration
+    }
+}
+
+// Header fields for the RTP track, which takes packets as they are
+struct RtpState {
+    sequence: u16,
+    // Timestamp of the next packet
+    timestamp: u32,
+    // RED only: the last packet's timestamp and Opus, repeated in the next
+    previous: Option<(u32, Bytes)>,
+}
+
+// A Discord -> Nextcloud track together with its timeline
+pub struct TalkTrack {
+    track: LocalAudioTrack,
+    rtp: Mutex<RtpState>,
+    clock: Mutex<TrackClock>,
+    // Muted by a Talk moderator; lasts until unmuted from Discord or the
+    // session rejoins the call
+    muted: watch::Sender<bool>,
+}
+
+impl TalkTrack {
+    // `muted` may be shared with whoever reports or lifts the mute
+    pub fn new(track: LocalAudioTrack, muted: watch::Sender<bool>) -> Arc<Self> {
+        // Random enough starting points, as RFC 3550 asks
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().subsec_nanos();
+        Arc::new(Self {
+            track,
+            rtp: Mutex::new(RtpState {
+                sequence: seed as u16,
+                timestamp: seed.rotate_left(16),
+                previous: None,
+            }),
+            clock: Mutex::new(TrackClock::new()),
+            muted,
+        })
+    }
+
+    pub fn set_muted(&self) {
+        if !self.muted.send_replace(true) {
+            log!("Muted by a Talk moderator, no longer sending audio to Talk");
+        }
+    }
+
+    // Once per 20ms source frame, before any packet containing it is written
+    pub fn note_frame(&self, timing: Timing) {
+        self.clock.lock().unwrap().note_frame(timing);
+    }
+
+    // Dropped while muted; the clock closes the gap once audio flows again
+    pub async fn write(&self, packets: Vec<(Bytes, Duration)>) {
+        if *self.muted.borrow() {
+            return;
+        }
+        for (data, nominal) in packets {
+            let duration = self.clock.lock().unwrap().duration(nominal);
+            match &self.track {
+                LocalAudioTrack::Sample(track) => {
+                    let sample = Sample {
+                        data,
+                        duration,
+                        ..Default::default()
+                    };
+                    let _ = track.write_sample(&sample).await;
+                }
+                LocalAudioTrack::Rtp(track) => {
+                    let packet = self.rtp_packet(data, duration - nominal, nominal);
+                    let _ = track.write_rtp(&packet).await;
+                }
+                LocalAudioTrack::Red(track, _) => {
+                    let mut packet = self.rtp_packet(data, duration - nominal, nominal);
+                    packet.payload = self.with_previous(&packet);
+                    let _ = track.write_rtp(&packet).await;
+                }
+            }
+        }
+    }
+
+    // Unlike a sample's duration, the gap can go right into this packet's
+    // timestamp; the marker bit flags the start of a talkspurt after it
+    fn rtp_packet(&self, payload: Bytes, gap: Duration, nominal: Duration) -> Packet {
+        let samples = |d: Duration| (d.as_secs_f64() * SAMPLE_RATE as f64).round() as u32;
+        let mut rtp = self.rtp.lock().unwrap();
+        let timestamp = rtp.timestamp.wrapping_add(samples(gap));
+        let packet = Packet {
+            header: Header {
+                version: 2,
+                marker: !gap.is_zero(),
+                sequence_number: rtp.sequence,
+                timestamp,
+                // Payload type and SSRC are filled in per connection by the track
+                ..Default::default()
+            },
+            payload,
+        };
+        rtp.sequence = rtp.sequence.wrapping_add(1);
+        rtp.timestamp = timestamp.wrapping_add(samples(nominal));
+        packet
+    }
+
+    // The packet's Opus as RED, behind the packet before it. After a gap
+    // that is too far back to say, and it goes alone.
+    fn with_previous(&self, packet: &Packet) -> Bytes {
+        let mut rtp = self.rtp.lock().unwrap();
+        let previous = rtp.previous.replace((packet.header.timestamp, packet.payload.clone()));
+        let previous = previous.as_ref().map(|(timestamp, frame)| (packet.header.timestamp.wrapping_sub(*timestamp), &frame[..]));
+        red::join(OPUS_PAYLOAD_TYPE, &packet.payload, previous).into()
+    }
+}
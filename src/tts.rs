@@ -0,0 +1,99 @@
+use anyhow::{Context, Result};
+use serenity::async_trait;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::audio::command::WavCommand;
+use crate::audio::wav;
+use crate::config::AnnouncementConfig;
+
+const SYNTHESIS_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Turns text into speech for announcements
+#[async_trait]
+pub trait TtsEngine: Send + Sync {
+    // Mono 48kHz samples
+    async fn synthesize(&self, text: &str) -> Result<Vec<f32>>;
+}
+
+// A local program that reads text on stdin and writes a WAV file to stdout,
+// e.g. `espeak-ng --stdout` or `piper --model voice.onnx --output_file -`.
+// `{language}` in the arguments is replaced with the configured language.
+pub struct CommandTts(WavCommand);
+
+impl CommandTts {
+    pub fn new(command: &str, language: &str) -> Result<Self> {
+        WavCommand::new(command, &[("{language}", language)], SYNTHESIS_TIMEOUT)
+            .context("Invalid TTS command")
+            .map(Self)
+    }
+}
+
+#[async_trait]
+impl TtsEngine for CommandTts {
+    async fn synthesize(&self, text: &str) -> Result<Vec<f32>> {
+        self.0.run(text.as_bytes()).await
+    }
+}
+
+// A TTS service reached over HTTP: the text and language are POSTed as
+// JSON, `{"text": "...", "language": "en"}`, and the response is a WAV file.
+// Puts speech on a machine the bridge can't run a voice model on.
+pub struct HttpTts {
+    http: reqwest::Client,
+    url: String,
+    language: String,
+}
+
+impl HttpTts {
+    pub fn new(url: &str, language: &str) -> Result<Self> {
+        let http = reqwest::Client::builder()
+            .timeout(SYNTHESIS_TIMEOUT)
+            .build()
+            .context("Failed to create the TTS HTTP client")?;
+        Ok(Self {
+            http,
+            url: url.to_string(),
+            language: language.to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl TtsEngine for HttpTts {
+    async fn synthesize(&self, text: &str) -> Result<Vec<f32>> {
+        let resp = self
+            .http
+            .post(&self.url)
+            .header("Accept", "audio/wav")
+            .header("User-Agent", crate::update::user_agent())
+            .json(&serde_json::json!({ "text": text, "language": self.language }))
+            .send()
+            .await
+            .context("Failed to reach the TTS service")?;
+        if !resp.status().is_success() {
+            anyhow::bail!("TTS service returned {}", resp.status());
+        }
+        let data = resp.bytes().await.context("Failed to read the TTS response")?;
+        wav::decode(&data).context("TTS service didn't return a usable WAV file")
+    }
+}
+
+// The bridge's engine, if announcements are on: the HTTP service when
+// TTS_URL is set, the local command otherwise
+pub fn engine(config: &AnnouncementConfig) -> Option<Arc<dyn TtsEngine>> {
+    if !config.enabled {
+        return None;
+    }
+    let engine: Result<Arc<dyn TtsEngine>> = match &config.url {
+        Some(url) => HttpTts::new(url, &config.language).map(|e| Arc::new(e) as _),
+        None => CommandTts::new(&config.command, &config.language).map(|e| Arc::new(e) as _),
+    };
+    match engine {
+        Ok(engine) => Some(engine),
+        Err(e) => {
+            log!("Announcements disabled: {:#}", e);
+            None
+        }
+    }
+}
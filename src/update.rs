@@ -0,0 +1,86 @@
+use anyhow::{Context, Result};
+use serde_json::Value;
+use std::sync::Mutex;
+
+use crate::config::UpdateCheckConfig;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+// Not set when built outside a git checkout
+pub const COMMIT: Option<&str> = option_env!("BRIDGE_GIT_COMMIT");
+
+// Newest release seen by the update check, if it is newer than this one
+static AVAILABLE: Mutex<Option<String>> = Mutex::new(None);
+
+// "0.1.0" or "0.1.0 (abc1234)"
+pub fn version() -> String {
+    match COMMIT {
+        Some(commit) => format!("{} ({})", VERSION, commit),
+        None => VERSION.to_string(),
+    }
+}
+
+// Sent with HTTP and WebSocket requests, so Nextcloud and signaling server
+// logs show which bridge build connected
+pub fn user_agent() -> String {
+    match COMMIT {
+        Some(commit) => format!("{}/{}+{}", env!("CARGO_PKG_NAME"), VERSION, commit),
+        None => format!("{}/{}", env!("CARGO_PKG_NAME"), VERSION),
+    }
+}
+
+pub fn available() -> Option<String> {
+    AVAILABLE.lock().unwrap().clone()
+}
+
+// Checks once at startup and then every interval. Failures are only logged;
+// the check is a convenience and never stops the bridge.
+pub async fn run(config: UpdateCheckConfig) {
+    let http = reqwest::Client::new();
+    loop {
+        match latest_release(&http, &config.url).await {
+            Ok(tag) if is_newer(&tag, VERSION) => {
+                let mut available = AVAILABLE.lock().unwrap();
+                if available.as_deref() != Some(tag.as_str()) {
+                    log!("New version available: {} (running {})", tag, version());
+                    *available = Some(tag);
+                }
+            }
+            Ok(_) => *AVAILABLE.lock().unwrap() = None,
+            Err(e) => log!("Update check failed: {:#}", e),
+        }
+        tokio::time::sleep(config.interval).await;
+    }
+}
+
+// GitHub's releases/latest format; other endpoints need a "tag_name" too
+async fn latest_release(http: &reqwest::Client, url: &str) -> Result<String> {
+    let resp = http
+        .get(url)
+        .header("User-Agent", user_agent())
+        .header("Accept", "application/json")
+        .send()
+        .await
+        .context("Failed to fetch the latest release")?;
+    if !resp.status().is_success() {
+        anyhow::bail!("Release endpoint returned {}", resp.status());
+    }
+    let body: Value = resp.json().await.context("Failed to parse the latest release")?;
+    body.get("tag_name")
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .context("No tag_name in the latest release")
+}
+
+// "v1.2.3" > "1.2.0"; pre-release suffixes are ignored
+fn is_newer(tag: &str, current: &str) -> bool {
+    let parse = |v: &str| -> Vec<u64> {
+        v.trim_start_matches('v')
+            .split(['-', '+'])
+            .next()
+            .unwrap_or_default()
+            .split('.')
+            .map(|part| part.parse().unwrap_or(0))
+            .collect()
+    };
+    parse(tag) > parse(current)
+}